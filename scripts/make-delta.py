@@ -0,0 +1,111 @@
+#!/usr/bin/env python3
+"""Create a takeover delta from a base and a target balenaOS image.
+
+Both images have to be uncompressed and have the given partitions at the
+same location. Only blocks of these partitions that differ between the
+images are written to the delta, takeover applies it in stage2 if the
+partitions on the flash device match the base image, see the section
+"Applying a delta" in README.md.
+
+The partition tables are read with sfdisk (util-linux).
+
+  make-delta.py --base base.img --target target.img --parts 2,3 -o update.delta.gz
+"""
+
+import argparse
+import gzip
+import hashlib
+import json
+import struct
+import subprocess
+import sys
+
+DELTA_MAGIC = "takeover-delta 1"
+SECTOR_SIZE = 512
+MAX_BLOCK_SIZE = 16 * 1024 * 1024
+
+
+def read_parts(image):
+    """Map partition index to (start, size) in bytes."""
+    table = json.loads(
+        subprocess.run(
+            ["sfdisk", "--json", image], check=True, capture_output=True, text=True
+        ).stdout
+    )["partitiontable"]
+    sector_size = table.get("sectorsize", SECTOR_SIZE)
+    if sector_size != SECTOR_SIZE:
+        sys.exit(f"{image} uses {sector_size} byte sectors, expected {SECTOR_SIZE}")
+    parts = {}
+    for part in table["partitions"]:
+        # the index is the number at the end of the device name, eg. base.img2
+        index = int(part["node"][len(part["node"].rstrip("0123456789")):])
+        parts[index] = (part["start"] * SECTOR_SIZE, part["size"] * SECTOR_SIZE)
+    return parts
+
+
+def sha256_range(image, start, size):
+    hasher = hashlib.sha256()
+    image.seek(start)
+    left = size
+    while left > 0:
+        data = image.read(min(left, 1024 * 1024))
+        if not data:
+            sys.exit(f"{image.name} ends inside a partition")
+        hasher.update(data)
+        left -= len(data)
+    return hasher.hexdigest()
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("--base", required=True, help="image the device runs now")
+    parser.add_argument("--target", required=True, help="image to update the device to")
+    parser.add_argument(
+        "--parts", required=True, help="comma separated partition indices, eg. 2,3"
+    )
+    parser.add_argument("--block-size", type=int, default=4096, help="default 4096")
+    parser.add_argument("-o", "--output", required=True, help="the gzipped delta")
+    args = parser.parse_args()
+
+    block_size = args.block_size
+    if block_size <= 0 or block_size > MAX_BLOCK_SIZE:
+        sys.exit(f"The block size has to be between 1 and {MAX_BLOCK_SIZE}")
+
+    indices = [int(index) for index in args.parts.split(",")]
+    base_parts = read_parts(args.base)
+    target_parts = read_parts(args.target)
+    for index in indices:
+        if index not in base_parts or base_parts.get(index) != target_parts.get(index):
+            sys.exit(f"Partition {index} is not at the same location in both images")
+        start, size = base_parts[index]
+        # takeover only accepts blocks that lie within the base partitions
+        if start % block_size or size % block_size:
+            sys.exit(f"Partition {index} is not aligned to {block_size} byte blocks")
+
+    with open(args.base, "rb") as base, open(args.target, "rb") as target:
+        header = [DELTA_MAGIC, f"block-size {block_size}"]
+        for index in indices:
+            header.append(f"base {index} {sha256_range(base, *base_parts[index])}")
+        for index in indices:
+            header.append(f"target {index} {sha256_range(target, *target_parts[index])}")
+
+        blocks = 0
+        with gzip.open(args.output, "wb") as delta:
+            delta.write(("\n".join(header) + "\n\n").encode())
+            for index in indices:
+                start, size = base_parts[index]
+                base.seek(start)
+                target.seek(start)
+                for block_index in range(start // block_size, (start + size) // block_size):
+                    base_block = base.read(block_size)
+                    target_block = target.read(block_size)
+                    if base_block != target_block:
+                        delta.write(struct.pack("<Q", block_index))
+                        delta.write(target_block)
+                        blocks += 1
+
+    print(f"Wrote {blocks} blocks of {block_size} bytes to {args.output}")
+
+
+if __name__ == "__main__":
+    main()
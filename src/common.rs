@@ -1,14 +1,19 @@
 use std::cmp::min;
 use std::ffi::{CStr, CString, OsString};
-use std::fs::{read_to_string, OpenOptions};
-use std::io::Write;
+use std::fs::{read_to_string, File, OpenOptions};
+use std::io::{Read, Write};
 use std::mem::MaybeUninit;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
 
-use log::{debug, error, trace, warn};
+use lazy_static::lazy_static;
+use log::{debug, error, info, trace, warn};
 
+use openssl::sha::Sha256;
 use regex::Regex;
 
 pub(crate) mod stage2_config;
@@ -30,11 +35,19 @@ use nix::unistd::sync;
 pub use options::Options;
 
 pub(crate) mod debug;
+pub(crate) mod delta;
 pub(crate) mod disk_util;
+pub(crate) mod migration_marker;
+pub(crate) mod progress;
+pub(crate) mod split_log;
 pub(crate) mod stream_progress;
 
 const OS_NAME_REGEX: &str = r#"^PRETTY_NAME="([^"]+)"$"#;
-const OS_RELEASE_FILE: &str = "/etc/os-release";
+pub(crate) const OS_RELEASE_FILE: &str = "/etc/os-release";
+
+// attempts made by call_retry and the delay between them
+const CALL_RETRIES: u32 = 3;
+const CALL_RETRY_DELAY: Duration = Duration::from_millis(500);
 
 #[derive(Debug)]
 pub(crate) struct CmdRes {
@@ -43,27 +56,30 @@ pub(crate) struct CmdRes {
     pub status: ExitStatus,
 }
 
-pub(crate) fn call(cmd: &str, args: &[&str], trim_stdout: bool) -> Result<CmdRes> {
-    trace!("call: '{}' called with {:?}, {}", cmd, args, trim_stdout);
-
-    match Command::new(cmd)
+fn run_cmd(cmd: &str, args: &[&str], trim_stdout: bool) -> std::io::Result<CmdRes> {
+    let output = Command::new(cmd)
         .args(args)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .output()
-    {
-        Ok(output) => {
-            debug!("call: output: {:?}", output);
-            Ok(CmdRes {
-                stdout: if trim_stdout {
-                    String::from(String::from_utf8_lossy(&output.stdout).trim())
-                } else {
-                    String::from(String::from_utf8_lossy(&output.stdout))
-                },
-                stderr: String::from(String::from_utf8_lossy(&output.stderr)),
-                status: output.status,
-            })
-        }
+        .output()?;
+
+    debug!("call: output: {:?}", output);
+    Ok(CmdRes {
+        stdout: if trim_stdout {
+            String::from(String::from_utf8_lossy(&output.stdout).trim())
+        } else {
+            String::from(String::from_utf8_lossy(&output.stdout))
+        },
+        stderr: String::from(String::from_utf8_lossy(&output.stderr)),
+        status: output.status,
+    })
+}
+
+pub(crate) fn call(cmd: &str, args: &[&str], trim_stdout: bool) -> Result<CmdRes> {
+    trace!("call: '{}' called with {:?}, {}", cmd, args, trim_stdout);
+
+    match run_cmd(cmd, args, trim_stdout) {
+        Ok(cmd_res) => Ok(cmd_res),
         Err(why) => {
             error!("call: output failed for command: '{}': {:?}", cmd, why);
             Err(Error::with_context(
@@ -74,8 +90,79 @@ pub(crate) fn call(cmd: &str, args: &[&str], trim_stdout: bool) -> Result<CmdRes
     }
 }
 
+/******************************************************************
+ * Like call but retries a command that could not be started or
+ * exited with an error, up to CALL_RETRIES attempts.
+ * Commands that do not exist or may not be executed fail right away.
+ * Returns the result of the last attempt.
+ ******************************************************************/
+
+pub(crate) fn call_retry(cmd: &str, args: &[&str], trim_stdout: bool) -> Result<CmdRes> {
+    trace!(
+        "call_retry: '{}' called with {:?}, {}",
+        cmd,
+        args,
+        trim_stdout
+    );
+
+    let mut attempt = 1;
+    loop {
+        let failure = match run_cmd(cmd, args, trim_stdout) {
+            Ok(cmd_res) if cmd_res.status.success() || attempt >= CALL_RETRIES => {
+                return Ok(cmd_res)
+            }
+            Ok(cmd_res) => format!("{}, stderr: {}", cmd_res.status, cmd_res.stderr.trim()),
+            Err(why) => {
+                let kind = match why.kind() {
+                    std::io::ErrorKind::NotFound => Some(ErrorKind::FileNotFound),
+                    std::io::ErrorKind::PermissionDenied => Some(ErrorKind::Permission),
+                    _ if attempt >= CALL_RETRIES => Some(ErrorKind::Upstream),
+                    _ => None,
+                };
+                if let Some(kind) = kind {
+                    error!(
+                        "call_retry: failed to execute command: '{}': {:?}",
+                        cmd, why
+                    );
+                    return Err(Error::with_context(
+                        kind,
+                        &format!(
+                            "call_retry: failed to execute: command {} '{:?}' after {} attempt(s), error: {}",
+                            cmd, args, attempt, why
+                        ),
+                    ));
+                }
+                why.to_string()
+            }
+        };
+
+        warn!(
+            "Command '{}' failed on attempt {} of {}: {}, retrying in {} ms",
+            cmd,
+            attempt,
+            CALL_RETRIES,
+            failure,
+            CALL_RETRY_DELAY.as_millis()
+        );
+        sleep(CALL_RETRY_DELAY);
+        attempt += 1;
+    }
+}
+
 pub(crate) fn whereis(cmd: &str) -> Result<String> {
     const BIN_DIRS: &[&str] = &["./", "/bin", "/usr/bin", "/sbin", "/usr/sbin"];
+    // a path is not looked up
+    if cmd.contains('/') {
+        return if file_exists(cmd) {
+            Ok(cmd.to_string())
+        } else {
+            Err(Error::with_context(
+                ErrorKind::NotFound,
+                &format!("Command '{}' does not exist", cmd),
+            ))
+        };
+    }
+
     // try manually first
     for path in BIN_DIRS {
         let path = format!("{}/{}", &path, cmd);
@@ -155,6 +242,32 @@ pub(crate) fn get_mem_info() -> Result<(u64, u64)> {
     }
 }
 
+// free memory can drop between checks, eg. when containers are started
+pub(crate) fn check_free_mem(min_free: u64, context: &str) -> Result<()> {
+    let (mem_tot, mem_free) = get_mem_info()?;
+    info!(
+        "{}: found {} total, {} free memory, required minimum is {}",
+        context,
+        format_size_with_unit(mem_tot),
+        format_size_with_unit(mem_free),
+        format_size_with_unit(min_free)
+    );
+
+    if mem_free < min_free {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "{}: not enough free memory, {} is free, required minimum is {}, use --min-free-mem to change it",
+                context,
+                format_size_with_unit(mem_free),
+                format_size_with_unit(min_free)
+            ),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
 /******************************************************************
  * Get OS name from /etc/os-release
  ******************************************************************/
@@ -187,7 +300,7 @@ pub(crate) fn get_os_name() -> Result<String> {
 
 pub(crate) fn is_admin() -> Result<bool> {
     trace!("is_admin: entered");
-    let admin = Some(unsafe { libc::getuid() } == 0);
+    let admin = Some(unsafe { libc::geteuid() } == 0);
     Ok(admin.unwrap())
 }
 
@@ -236,6 +349,25 @@ const GIB_SIZE: u64 = 1024 * 1024 * 1024;
 const MIB_SIZE: u64 = 1024 * 1024;
 const KIB_SIZE: u64 = 1024;
 
+pub(crate) fn sha256_file<P: AsRef<Path>>(file: P) -> Result<[u8; 32]> {
+    let path = file.as_ref();
+    let mut input = File::open(path)
+        .upstream_with_context(&format!("Failed to open file '{}'", path.display()))?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let bytes_read = input
+            .read(&mut buffer)
+            .upstream_with_context(&format!("Failed to read from file '{}'", path.display()))?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[0..bytes_read]);
+    }
+    Ok(hasher.finish())
+}
+
 pub fn format_size_with_unit(size: u64) -> String {
     if size > (10 * GIB_SIZE) {
         format!("{} GiB", size / GIB_SIZE)
@@ -373,11 +505,27 @@ cfg_if::cfg_if! {
     }
 }
 
-#[allow(dead_code)]
+lazy_static! {
+    // the old root stage2 pivoted to, set once the stage2 config is read
+    static ref OLD_ROOT: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+pub(crate) fn set_old_root(old_root: &Path) {
+    if let Ok(mut curr) = OLD_ROOT.lock() {
+        *curr = Some(old_root.to_path_buf());
+    }
+}
+
+// writes to the old root of stage2, see set_old_root, or to / if there is none
 pub(crate) fn log(text: &str) {
-    let log_path = if let Ok(stat) = stat(OLD_ROOT_MP) {
+    let old_root = OLD_ROOT
+        .lock()
+        .ok()
+        .and_then(|old_root| old_root.clone())
+        .unwrap_or_else(|| PathBuf::from(OLD_ROOT_MP));
+    let log_path = if let Ok(stat) = stat(&old_root) {
         if is_dir(&stat) {
-            path_append(OLD_ROOT_MP, "balena-takeover.log")
+            path_append(&old_root, "balena-takeover.log")
         } else {
             PathBuf::from("/balena-takeover.log")
         }
@@ -400,4 +548,10 @@ mod tests {
         let c_path = path_to_cstring(PATH).unwrap();
         assert_eq!(&*c_path.to_string_lossy(), PATH);
     }
+
+    #[test]
+    fn test_call_retry_not_found() {
+        let why = call_retry("/bla/blub", &[], true).unwrap_err();
+        assert_eq!(why.kind(), ErrorKind::FileNotFound);
+    }
 }
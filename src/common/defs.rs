@@ -1,17 +1,24 @@
 pub(crate) const SWAPOFF_CMD: &str = "swapoff";
+pub(crate) const SWAPON_CMD: &str = "swapon";
 pub(crate) const TELINIT_CMD: &str = "telinit";
+pub(crate) const SYSTEMCTL_CMD: &str = "systemctl";
+pub(crate) const CHCON_CMD: &str = "chcon";
 
 pub(crate) const MOKUTIL_CMD: &str = "mokutil";
 pub(crate) const WHEREIS_CMD: &str = "whereis";
 pub(crate) const PIDOF_CMD: &str = "pidof";
-pub(crate) const PIVOT_ROOT_CMD: &str = "pivot_root";
 pub(crate) const MOUNT_CMD: &str = "mount";
 pub(crate) const BLKID_CMD: &str = "blkid";
 
 pub(crate) const EFIBOOTMGR_CMD: &str = "efibootmgr";
 pub(crate) const DD_CMD: &str = "dd";
+pub(crate) const RESIZE2FS_CMD: &str = "resize2fs";
+pub(crate) const E2FSCK_CMD: &str = "e2fsck";
 
 pub(crate) const TAR_CMD: &str = "tar";
+pub(crate) const BUSYBOX_CMD: &str = "busybox";
+pub(crate) const SH_CMD: &str = "sh";
+pub(crate) const SGDISK_CMD: &str = "sgdisk";
 
 pub(crate) const TAKEOVER_DIR: &str = "/balena-takeover";
 pub(crate) const STAGE2_CONFIG_NAME: &str = "stage2-config.yml";
@@ -20,6 +27,18 @@ pub(crate) const BALENA_IMAGE_NAME: &str = "balena.img.gz";
 pub(crate) const BALENA_IMAGE_PATH: &str = "/balena.img.gz";
 
 pub(crate) const BALENA_CONFIG_PATH: &str = "/config.json";
+// device specific boot settings collected by stage1, merged into the boot partition by stage2
+pub(crate) const BOOT_CONFIG_NAME: &str = "boot-config.txt";
+pub(crate) const BALENA_DELTA_NAME: &str = "balena.delta.gz";
+pub(crate) const BALENA_PART_IMAGE_NAME: &str = "balena.part.img";
+// written to the data partition by stage2, read by --verify-boot
+pub(crate) const MIGRATION_MARKER_NAME: &str = "takeover-migration.json";
+// the stage logs are copied to this directory on the data partition with --keep-logs-on-target
+pub(crate) const TAKEOVER_LOGS_DIR: &str = "takeover-logs";
+pub(crate) const BALENA_OS_RELEASE_PATH: &str = "/os-release";
+// mountpoints of the balena-os host os
+pub(crate) const BALENA_HOST_DATA_MP: &str = "/mnt/data";
+pub(crate) const BALENA_HOST_BOOT_MP: &str = "/mnt/boot";
 
 pub const DISK_BY_LABEL_PATH: &str = "/dev/disk/by-label";
 
@@ -0,0 +1,463 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind as IoErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use log::{debug, info, warn};
+use openssl::sha::Sha256;
+
+use crate::common::{
+    disk_util::{get_device_size, Disk, PartitionIterator, DEF_BLOCK_SIZE},
+    format_size_with_unit, Error, ErrorKind, Result, ToError,
+};
+
+/******************************************************************
+ * A delta is a gzipped file starting with a text header:
+ *
+ *   takeover-delta 1
+ *   block-size <bytes>
+ *   base <partition index> <sha256 of the partition>
+ *   ...
+ *   target <partition index> <sha256 of the partition>
+ *   ...
+ *   <empty line>
+ *
+ * followed by records of a little endian u64 block index and
+ * block-size bytes of data to write at that block. Blocks count
+ * from the start of the device and have to lie within the base
+ * partitions, the target hashes are checked after writing.
+ * scripts/make-delta.py creates deltas in this format.
+ ******************************************************************/
+
+const DELTA_MAGIC: &str = "takeover-delta 1";
+const MAX_BLOCK_SIZE: u64 = 16 * 1024 * 1024;
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub(crate) struct DeltaPart {
+    pub part_index: usize,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct DeltaHeader {
+    pub block_size: u64,
+    // the partitions the delta was made against
+    pub bases: Vec<DeltaPart>,
+    // the partitions after applying the delta
+    pub targets: Vec<DeltaPart>,
+}
+
+fn invalid_header(path: &Path, line: &str) -> Error {
+    Error::with_context(
+        ErrorKind::InvParam,
+        &format!(
+            "Invalid delta header in '{}', unexpected line: '{}'",
+            path.display(),
+            line
+        ),
+    )
+}
+
+// parse the header of the delta at path, returns the header and the reader positioned at its records
+pub(crate) fn open_delta(path: &Path) -> Result<(DeltaHeader, BufReader<GzDecoder<File>>)> {
+    let file = File::open(path)
+        .upstream_with_context(&format!("Failed to open delta '{}'", path.display()))?;
+    let mut reader = BufReader::new(GzDecoder::new(file));
+
+    let mut block_size: Option<u64> = None;
+    let mut bases: Vec<DeltaPart> = Vec::new();
+    let mut targets: Vec<DeltaPart> = Vec::new();
+    let mut first = true;
+    loop {
+        let mut line = String::new();
+        if reader
+            .read_line(&mut line)
+            .upstream_with_context(&format!("Failed to read delta '{}'", path.display()))?
+            == 0
+        {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("Unexpected end of delta header in '{}'", path.display()),
+            ));
+        }
+        let line = line.trim_end();
+
+        if first {
+            if line != DELTA_MAGIC {
+                return Err(invalid_header(path, line));
+            }
+            first = false;
+            continue;
+        }
+
+        if line.is_empty() {
+            break;
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["block-size", size] => {
+                block_size = match size.parse::<u64>() {
+                    Ok(size) if size > 0 && size <= MAX_BLOCK_SIZE => Some(size),
+                    _ => return Err(invalid_header(path, line)),
+                }
+            }
+            [kind @ ("base" | "target"), index, sha256] if sha256.len() == 64 => {
+                let part = DeltaPart {
+                    part_index: index
+                        .parse::<usize>()
+                        .map_err(|_| invalid_header(path, line))?,
+                    sha256: sha256.to_lowercase(),
+                };
+                if *kind == "base" {
+                    bases.push(part);
+                } else {
+                    targets.push(part);
+                }
+            }
+            _ => return Err(invalid_header(path, line)),
+        }
+    }
+
+    let block_size = if let Some(block_size) = block_size {
+        block_size
+    } else {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!("No block size found in delta '{}'", path.display()),
+        ));
+    };
+
+    if bases.is_empty() {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!("No base partitions found in delta '{}'", path.display()),
+        ));
+    }
+
+    // without target hashes a wrongly applied delta would go unnoticed
+    if targets.is_empty() {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!("No target partitions found in delta '{}'", path.display()),
+        ));
+    }
+
+    Ok((
+        DeltaHeader {
+            block_size,
+            bases,
+            targets,
+        },
+        reader,
+    ))
+}
+
+fn sha256_range(device: &mut File, offset: u64, size: u64) -> Result<String> {
+    device
+        .seek(SeekFrom::Start(offset))
+        .upstream_with_context("Failed to seek on device")?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+    let mut bytes_left = size;
+    while bytes_left > 0 {
+        let read_size = bytes_left.min(HASH_BUFFER_SIZE as u64) as usize;
+        device
+            .read_exact(&mut buffer[0..read_size])
+            .upstream_with_context("Failed to read from device")?;
+        hasher.update(&buffer[0..read_size]);
+        bytes_left -= read_size as u64;
+    }
+
+    Ok(hasher
+        .finish()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+// the byte ranges (index, start, end) of the partitions on device
+fn part_ranges(device: &Path) -> Result<Vec<(usize, u64, u64)>> {
+    let mut disk = Disk::from_drive_file(device, None)?;
+    Ok(PartitionIterator::new(&mut disk)?
+        .map(|part| {
+            (
+                part.index,
+                part.start_lba * DEF_BLOCK_SIZE as u64,
+                (part.start_lba + part.num_sectors) * DEF_BLOCK_SIZE as u64,
+            )
+        })
+        .collect())
+}
+
+// check the partitions on device have the given hashes, kind names them in messages
+fn verify_parts(device: &Path, parts: &[DeltaPart], kind: &str) -> Result<bool> {
+    let ranges = part_ranges(device)?;
+    let mut device_file = File::open(device)
+        .upstream_with_context(&format!("Failed to open device '{}'", device.display()))?;
+
+    for part in parts {
+        let (start, end) = if let Some((_, start, end)) = ranges
+            .iter()
+            .find(|(index, _, _)| *index == part.part_index)
+        {
+            (*start, *end)
+        } else {
+            warn!(
+                "Delta {} partition {} not found on '{}'",
+                kind,
+                part.part_index,
+                device.display()
+            );
+            return Ok(false);
+        };
+
+        let sha256 = sha256_range(&mut device_file, start, end - start)?;
+        if sha256 != part.sha256 {
+            warn!(
+                "Delta {} partition {} on '{}' does not match, expected sha256 {}, found {}",
+                kind,
+                part.part_index,
+                device.display(),
+                part.sha256,
+                sha256
+            );
+            return Ok(false);
+        }
+        debug!("Delta {} partition {} matches", kind, part.part_index);
+    }
+
+    Ok(true)
+}
+
+// check the partitions on device are the ones the delta was made against
+pub(crate) fn verify_base(device: &Path, header: &DeltaHeader) -> Result<bool> {
+    verify_parts(device, &header.bases, "base")
+}
+
+// fill buffer from reader, returns false on end of input before the first byte
+fn read_record<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<bool> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(IoErrorKind::UnexpectedEof.into()),
+            Ok(size) => filled += size,
+            Err(why) if why.kind() == IoErrorKind::Interrupted => (),
+            Err(why) => return Err(why),
+        }
+    }
+    Ok(true)
+}
+
+// read the next record into block_buffer, returns its block index or None at the end of the delta
+fn next_block<R: Read>(
+    reader: &mut R,
+    block_buffer: &mut [u8],
+    path: &Path,
+) -> Result<Option<u64>> {
+    let mut index_buffer = [0u8; 8];
+    if !read_record(reader, &mut index_buffer)
+        .upstream_with_context(&format!("Failed to read delta '{}'", path.display()))?
+    {
+        return Ok(None);
+    }
+    let block_index = u64::from_le_bytes(index_buffer);
+    if !read_record(reader, block_buffer)
+        .upstream_with_context(&format!("Failed to read delta '{}'", path.display()))?
+    {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Delta '{}' ends without data for block {}",
+                path.display(),
+                block_index
+            ),
+        ));
+    }
+    Ok(Some(block_index))
+}
+
+// every block has to lie within a base partition and the device, checked before anything is written
+fn check_blocks(device: &Path, path: &Path, header: &DeltaHeader) -> Result<()> {
+    let device_size = get_device_size(device)?;
+    let ranges = part_ranges(device)?;
+    let base_ranges: Vec<(u64, u64)> = header
+        .bases
+        .iter()
+        .filter_map(|base| {
+            ranges
+                .iter()
+                .find(|(index, _, _)| *index == base.part_index)
+                .map(|(_, start, end)| (*start, *end))
+        })
+        .collect();
+
+    let (_, mut reader) = open_delta(path)?;
+    let mut block_buffer = vec![0u8; header.block_size as usize];
+    while let Some(block_index) = next_block(&mut reader, &mut block_buffer, path)? {
+        let in_bounds = block_index
+            .checked_mul(header.block_size)
+            .and_then(|start| start.checked_add(header.block_size).map(|end| (start, end)))
+            .is_some_and(|(start, end)| {
+                end <= device_size
+                    && base_ranges
+                        .iter()
+                        .any(|(part_start, part_end)| start >= *part_start && end <= *part_end)
+            });
+        if !in_bounds {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Block {} of delta '{}' is not within the base partitions on '{}'",
+                    block_index,
+                    path.display(),
+                    device.display()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/******************************************************************
+ * Write the blocks of the delta at path to device, calls progress
+ * with the number of bytes written so far after every block. The
+ * delta is read twice, all blocks are checked to lie within the
+ * base partitions before the first one is written. Fails if the
+ * partitions do not match the target hashes afterwards.
+ ******************************************************************/
+
+pub(crate) fn apply_delta(
+    device: &Path,
+    path: &Path,
+    progress: &mut dyn FnMut(u64),
+) -> Result<u64> {
+    let (header, mut reader) = open_delta(path)?;
+    check_blocks(device, path, &header)?;
+
+    let mut device_file = OpenOptions::new()
+        .write(true)
+        .open(device)
+        .upstream_with_context(&format!("Failed to open device '{}'", device.display()))?;
+
+    let mut block_buffer = vec![0u8; header.block_size as usize];
+    let mut tot_bytes: u64 = 0;
+
+    while let Some(block_index) = next_block(&mut reader, &mut block_buffer, path)? {
+        device_file
+            .seek(SeekFrom::Start(block_index * header.block_size))
+            .and_then(|_| device_file.write_all(&block_buffer))
+            .upstream_with_context(&format!(
+                "Failed to write block {} to '{}'",
+                block_index,
+                device.display()
+            ))?;
+
+        tot_bytes += header.block_size;
+        progress(tot_bytes);
+    }
+
+    device_file
+        .sync_all()
+        .upstream_with_context(&format!("Failed to sync device '{}'", device.display()))?;
+
+    if !verify_parts(device, &header.targets, "target")? {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "The partitions on '{}' do not match the targets of delta '{}'",
+                device.display(),
+                path.display()
+            ),
+        ));
+    }
+
+    info!(
+        "Applied delta '{}', wrote {} to '{}'",
+        path.display(),
+        format_size_with_unit(tot_bytes),
+        device.display()
+    );
+    Ok(tot_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::env::temp_dir;
+    use std::fs::{read, write};
+
+    // a device of 3 sectors with partition 1 on sectors 1 and 2
+    fn write_device(path: &Path) {
+        let mut device = vec![0u8; 3 * DEF_BLOCK_SIZE];
+        device[450] = 0x83;
+        device[454..458].copy_from_slice(&1u32.to_le_bytes());
+        device[458..462].copy_from_slice(&2u32.to_le_bytes());
+        device[510] = 0x55;
+        device[511] = 0xAA;
+        write(path, device).unwrap();
+    }
+
+    fn part_sha256(device: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(&device[DEF_BLOCK_SIZE..]);
+        hasher
+            .finish()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn write_delta(path: &Path, base: &str, target: &str, block_index: u64) {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        write!(
+            encoder,
+            "{}\nblock-size 4\nbase 1 {}\ntarget 1 {}\n\n",
+            DELTA_MAGIC, base, target
+        )
+        .unwrap();
+        encoder.write_all(&block_index.to_le_bytes()).unwrap();
+        encoder.write_all(b"abcd").unwrap();
+        write(path, encoder.finish().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let dir = temp_dir().join(format!("takeover-delta-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let delta_path = dir.join("test.delta.gz");
+        let device_path = dir.join("device.img");
+
+        write_device(&device_path);
+        let base = read(&device_path).unwrap();
+        let mut target = base.clone();
+        target[520..524].copy_from_slice(b"abcd");
+
+        write_delta(&delta_path, &part_sha256(&base), &part_sha256(&target), 130);
+        let (header, _) = open_delta(&delta_path).unwrap();
+        assert_eq!(header.block_size, 4);
+        assert_eq!(header.bases[0].part_index, 1);
+        assert!(verify_base(&device_path, &header).unwrap());
+
+        let mut written = 0;
+        apply_delta(&device_path, &delta_path, &mut |bytes| written = bytes).unwrap();
+        assert_eq!(written, 4);
+        assert_eq!(read(&device_path).unwrap(), target);
+
+        // a block in the MBR is outside the base partition, nothing is written
+        write_device(&device_path);
+        write_delta(&delta_path, &part_sha256(&base), &part_sha256(&target), 1);
+        assert!(apply_delta(&device_path, &delta_path, &mut |_| ()).is_err());
+        assert_eq!(read(&device_path).unwrap(), base);
+
+        // the result does not match the target hash
+        write_delta(&delta_path, &part_sha256(&base), &part_sha256(&base), 130);
+        assert!(apply_delta(&device_path, &delta_path, &mut |_| ()).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
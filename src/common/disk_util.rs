@@ -1,10 +1,14 @@
-use log::{debug, error, trace};
-use std::io::{self, Read};
+use libc::{c_int, c_uint, ioctl};
+use log::{debug, error, info, trace};
+use std::convert::{TryFrom, TryInto};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::result;
 
-use crate::common::{Error, ErrorKind, Result};
+use crate::common::{defs::IoctlReq, Error, ErrorKind, Result, ToError};
 
 mod image_file;
 pub(crate) use image_file::ImageFile;
@@ -27,6 +31,80 @@ pub(crate) use plain_file::PlainFile;
 
 pub(crate) const DEF_BLOCK_SIZE: usize = 512;
 
+// offset of the partition table in a MBR / EBR
+const PART_TBL_OFFSET: u64 = 446;
+const PART_ENTRY_SIZE: u64 = 16;
+// offset of num_sectors in a partition table entry
+const PART_ENTRY_SECTORS_OFFSET: u64 = 12;
+// bounds walking a corrupt EBR chain
+const MAX_LOGICAL_PARTS: u32 = 128;
+
+// partition index of the balena data partition
+pub(crate) const BALENA_DATA_PART_IDX: usize = 6;
+// partition names accepted by --flash-partitions and their index in the balena-os layout
+pub(crate) const BALENA_PART_NAMES: [(&str, usize); 5] = [
+    ("boot", 1),
+    ("rootA", 2),
+    ("rootB", 3),
+    ("state", 5),
+    ("data", BALENA_DATA_PART_IDX),
+];
+
+// EFI system partition type GUID C12A7328-F81F-11D2-BA4B-00A0C93EC93B in on-disk byte order
+const GPT_ESP_TYPE_GUID: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b,
+];
+const MBR_ESP_TYPE: u8 = 0xef;
+// GPT partition entries are not expected beyond the 128 of a standard table
+const GPT_MAX_ENTRIES: u32 = 128;
+// the boot partition start is read from the first GPT entry
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+// the EFI directory in a FAT root directory
+const FAT_EFI_DIR_NAME: &str = "EFI";
+const FAT_ATTR_DIR: u8 = 0x10;
+const FAT_ATTR_LFN: u8 = 0x0f;
+// set in the sequence number of the last part of a long name
+const FAT_LFN_LAST: u8 = 0x40;
+const FAT_DIR_ENTRY_SIZE: usize = 32;
+const FAT_MAX_ROOT_DIR_SIZE: usize = 64 * 1024;
+const FAT_MAX_FILE_SIZE: usize = 1024 * 1024;
+// ext and f2fs superblocks start 1024 bytes into the partition
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT_MAGIC_OFFSET: usize = 0x38;
+const EXT_MAGIC: [u8; 2] = [0x53, 0xEF];
+const F2FS_MAGIC: [u8; 4] = [0x10, 0x20, 0xF5, 0xF2];
+// filesystems with less clusters use 12 bit FAT entries
+const FAT12_MAX_CLUSTERS: u64 = 4085;
+
+// #define BLKSSZGET _IO(0x12,104), BLKPBSZGET _IO(0x12,123)
+const IOCTL_BLK_SSZGET: IoctlReq = 0x1268;
+const IOCTL_BLK_PBSZGET: IoctlReq = 0x127b;
+// GPT images made for 4K sector devices have their header at byte 4096
+const LARGE_SECTOR_SIZE: u64 = 4096;
+
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+// byte offsets on the disk and sizes of the parts of a FAT filesystem
+struct FatLayout {
+    fat_type: FatType,
+    fat_offset: u64,
+    data_offset: u64,
+    cluster_size: u64,
+    root_offset: u64,
+    root_size: usize,
+}
+
+// the ways the boot loaders found in a disk image can be started by the firmware
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct BootSupport {
+    pub efi: bool,
+    pub legacy: bool,
+}
+
 #[derive(Debug)]
 pub(crate) enum LabelType {
     GPT,
@@ -123,6 +201,9 @@ pub(crate) struct PartInfo {
     pub status: u8,
     pub start_lba: u64,
     pub num_sectors: u64,
+    // location of the partition table entry describing this partition
+    pub table_lba: u64,
+    pub table_idx: usize,
 }
 #[allow(dead_code)]
 impl PartInfo {
@@ -131,6 +212,32 @@ impl PartInfo {
     }
 }
 
+// a partition as described by the partition table, in sectors of the table
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PartExtent {
+    pub index: usize,
+    pub start_lba: u64,
+    pub num_sectors: u64,
+}
+
+impl PartExtent {
+    // saturates, check_part_table reports partitions ending beyond the addressable sectors
+    fn end_lba(&self) -> u64 {
+        self.start_lba.saturating_add(self.num_sectors)
+    }
+}
+
+// all partitions of a partition table with the bounds they have to respect
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PartTable {
+    pub sector_size: u64,
+    pub parts: Vec<PartExtent>,
+    // the first and last usable sector of a GPT
+    pub usable: Option<(u64, u64)>,
+    // the MBR extended partition containing the logical partitions
+    pub extended: Option<PartExtent>,
+}
+
 pub(crate) struct Disk {
     disk: Box<dyn ImageFile>,
     // writable: bool,
@@ -212,12 +319,529 @@ impl Disk {
 
         Ok(mbr)
     }
+
+    fn read_bytes(&mut self, offset: u64, size: usize) -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; size];
+        self.disk.fill(offset, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    /******************************************************************
+     * Find out how the disk can be booted:
+     * legacy BIOS boot needs boot code in the MBR, EFI boot needs an
+     * EFI system partition, which balena MBR images provide as a FAT
+     * boot partition containing an EFI directory.
+     ******************************************************************/
+
+    pub fn get_boot_support(&mut self) -> Result<BootSupport> {
+        let mbr = self.read_mbr(0)?;
+        let legacy = mbr.boot_code_0.iter().any(|byte| *byte != 0);
+
+        let efi = if let PartitionType::GPT = PartitionType::from_ptype(mbr.part_tbl[0].ptype) {
+            self.has_gpt_esp()?
+        } else {
+            let mut efi = false;
+            for entry in mbr.part_tbl.iter() {
+                let ptype = entry.ptype;
+                let first_lba = entry.first_lba;
+                if ptype == MBR_ESP_TYPE
+                    || (matches!(PartitionType::from_ptype(ptype), PartitionType::Fat)
+                        && self.fat_has_efi_dir(u64::from(first_lba))?)
+                {
+                    efi = true;
+                    break;
+                }
+            }
+            efi
+        };
+
+        debug!("get_boot_support: efi: {}, legacy: {}", efi, legacy);
+        Ok(BootSupport { efi, legacy })
+    }
+
+    // the sector size the partition table was written for, MBR tables are assumed to use 512 bytes
+    pub fn get_sector_size_hint(&mut self) -> Result<Option<u64>> {
+        match self.get_label()? {
+            LabelType::GPT => {
+                for sector_size in &[DEF_BLOCK_SIZE as u64, LARGE_SECTOR_SIZE] {
+                    if self.read_bytes(*sector_size, 8)? == b"EFI PART" {
+                        return Ok(Some(*sector_size));
+                    }
+                }
+                Ok(None)
+            }
+            LabelType::Dos => Ok(Some(DEF_BLOCK_SIZE as u64)),
+            LabelType::Other => Ok(None),
+        }
+    }
+
+    fn has_gpt_esp(&mut self) -> Result<bool> {
+        let header = self.read_bytes(self.block_size, DEF_BLOCK_SIZE)?;
+        if &header[0..8] != b"EFI PART" {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                "Encountered an invalid GPT header signature",
+            ));
+        }
+
+        let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+        let num_entries = u32::from_le_bytes(header[80..84].try_into().unwrap());
+        let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+        if entry_size < GPT_ESP_TYPE_GUID.len() {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("Encountered an invalid GPT entry size: {}", entry_size),
+            ));
+        }
+
+        let num_entries = num_entries.min(GPT_MAX_ENTRIES) as usize;
+        let entries = self.read_bytes(entries_lba * self.block_size, num_entries * entry_size)?;
+        Ok(entries
+            .chunks(entry_size)
+            .any(|entry| entry[0..GPT_ESP_TYPE_GUID.len()] == GPT_ESP_TYPE_GUID))
+    }
+
+    /******************************************************************
+     * Read every partition the partition table describes, the GPT
+     * entries in use or the primary and logical MBR partitions. Unlike
+     * PartitionIterator this accepts any partition type, so a table
+     * can be checked before it is written.
+     ******************************************************************/
+
+    pub fn get_part_table(&mut self) -> Result<PartTable> {
+        let mbr = self.read_mbr(0)?;
+        if let PartitionType::GPT = PartitionType::from_ptype(mbr.part_tbl[0].ptype) {
+            self.get_gpt_part_table()
+        } else {
+            self.get_mbr_part_table(&mbr)
+        }
+    }
+
+    fn get_gpt_part_table(&mut self) -> Result<PartTable> {
+        let sector_size = if let Some(sector_size) = self.get_sector_size_hint()? {
+            sector_size
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                "Encountered an invalid GPT header signature",
+            ));
+        };
+
+        let header = self.read_bytes(sector_size, DEF_BLOCK_SIZE)?;
+        let first_usable = u64::from_le_bytes(header[40..48].try_into().unwrap());
+        let last_usable = u64::from_le_bytes(header[48..56].try_into().unwrap());
+        let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+        let num_entries = u32::from_le_bytes(header[80..84].try_into().unwrap());
+        let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+        if entry_size < GPT_ENTRY_MIN_SIZE {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("Encountered an invalid GPT entry size: {}", entry_size),
+            ));
+        }
+
+        let num_entries = num_entries.min(GPT_MAX_ENTRIES) as usize;
+        let entries_offset = if let Some(entries_offset) = entries_lba.checked_mul(sector_size) {
+            entries_offset
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Encountered an invalid GPT partition entry sector: {}",
+                    entries_lba
+                ),
+            ));
+        };
+        let entries = self.read_bytes(entries_offset, num_entries * entry_size)?;
+        let mut parts: Vec<PartExtent> = Vec::new();
+        for (idx, entry) in entries.chunks(entry_size).enumerate() {
+            // entries with a zero type GUID are unused
+            if entry[0..16].iter().all(|byte| *byte == 0) {
+                continue;
+            }
+            let first_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+            let last_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+            if last_lba < first_lba {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "GPT partition {} ends at sector {} before it starts at sector {}",
+                        idx + 1,
+                        last_lba,
+                        first_lba
+                    ),
+                ));
+            }
+            let num_sectors = if let Some(num_sectors) = (last_lba - first_lba).checked_add(1) {
+                num_sectors
+            } else {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "GPT partition {} spans sectors {}-{}, more than can be addressed",
+                        idx + 1,
+                        first_lba,
+                        last_lba
+                    ),
+                ));
+            };
+            parts.push(PartExtent {
+                index: idx + 1,
+                start_lba: first_lba,
+                num_sectors,
+            });
+        }
+
+        Ok(PartTable {
+            sector_size,
+            parts,
+            usable: Some((first_usable, last_usable)),
+            extended: None,
+        })
+    }
+
+    fn get_mbr_part_table(&mut self, mbr: &MasterBootRecord) -> Result<PartTable> {
+        let mut parts: Vec<PartExtent> = Vec::new();
+        let mut extended: Option<PartExtent> = None;
+        for (slot, entry) in mbr.part_tbl.iter().enumerate() {
+            let part = PartExtent {
+                index: slot + 1,
+                start_lba: u64::from(entry.first_lba),
+                num_sectors: u64::from(entry.num_sectors),
+            };
+            match PartitionType::from_ptype(entry.ptype) {
+                PartitionType::Empty => (),
+                PartitionType::Container if extended.is_none() => extended = Some(part),
+                _ => parts.push(part),
+            }
+        }
+
+        // EBRs link to the next EBR relative to the start of the extended partition
+        if let Some(ext_part) = &extended {
+            let mut ebr_lba = ext_part.start_lba;
+            let mut index = 5;
+            loop {
+                if index > GPT_MAX_ENTRIES as usize {
+                    return Err(Error::with_context(
+                        ErrorKind::InvParam,
+                        &format!(
+                            "Found more than {} logical partitions, the EBR chain is invalid",
+                            GPT_MAX_ENTRIES - 4
+                        ),
+                    ));
+                }
+                let ebr = self.read_mbr(ebr_lba)?;
+                let entry = &ebr.part_tbl[0];
+                if entry.ptype != 0 {
+                    parts.push(PartExtent {
+                        index,
+                        start_lba: ebr_lba + u64::from(entry.first_lba),
+                        num_sectors: u64::from(entry.num_sectors),
+                    });
+                    index += 1;
+                }
+                let next = &ebr.part_tbl[1];
+                match PartitionType::from_ptype(next.ptype) {
+                    PartitionType::Container if next.first_lba != 0 => {
+                        ebr_lba = ext_part.start_lba + u64::from(next.first_lba)
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        Ok(PartTable {
+            sector_size: DEF_BLOCK_SIZE as u64,
+            parts,
+            usable: None,
+            extended,
+        })
+    }
+
+    /******************************************************************
+     * Read the boot sector of the FAT filesystem starting at
+     * start_lba, returns None if there is none.
+     ******************************************************************/
+
+    fn fat_layout(&mut self, start_lba: u64) -> Result<Option<FatLayout>> {
+        let part_offset = start_lba * self.block_size;
+        let boot_sector = self.read_bytes(part_offset, DEF_BLOCK_SIZE)?;
+        let read_u16 = |offset: usize| {
+            u64::from(u16::from_le_bytes([
+                boot_sector[offset],
+                boot_sector[offset + 1],
+            ]))
+        };
+        let read_u32 = |offset: usize| {
+            u64::from(u32::from_le_bytes(
+                boot_sector[offset..offset + 4].try_into().unwrap(),
+            ))
+        };
+
+        let bytes_per_sector = read_u16(11);
+        let sectors_per_cluster = u64::from(boot_sector[13]);
+        let reserved_sectors = read_u16(14);
+        let num_fats = u64::from(boot_sector[16]);
+        let root_entries = read_u16(17);
+        if boot_sector[510] != 0x55
+            || boot_sector[511] != 0xAA
+            || bytes_per_sector == 0
+            || sectors_per_cluster == 0
+        {
+            debug!("fat_layout: no FAT boot sector found at LBA {}", start_lba);
+            return Ok(None);
+        }
+
+        let sector_offset = |sector: u64| part_offset + sector * bytes_per_sector;
+        let layout = if root_entries != 0 {
+            // FAT12 / FAT16, fixed size root directory after the FATs
+            let fat_sectors = read_u16(22);
+            let total_sectors = if read_u16(19) != 0 {
+                read_u16(19)
+            } else {
+                read_u32(32)
+            };
+            let root_sector = reserved_sectors + num_fats * fat_sectors;
+            let root_size = root_entries * FAT_DIR_ENTRY_SIZE as u64;
+            let data_sector = root_sector + root_size.div_ceil(bytes_per_sector);
+            let clusters = total_sectors.saturating_sub(data_sector) / sectors_per_cluster;
+            FatLayout {
+                fat_type: if clusters < FAT12_MAX_CLUSTERS {
+                    FatType::Fat12
+                } else {
+                    FatType::Fat16
+                },
+                fat_offset: sector_offset(reserved_sectors),
+                data_offset: sector_offset(data_sector),
+                cluster_size: sectors_per_cluster * bytes_per_sector,
+                root_offset: sector_offset(root_sector),
+                root_size: root_size as usize,
+            }
+        } else {
+            // FAT32, only the first cluster of the root directory is searched
+            let data_sector = reserved_sectors + num_fats * read_u32(36);
+            let root_cluster = read_u32(44).max(2);
+            FatLayout {
+                fat_type: FatType::Fat32,
+                fat_offset: sector_offset(reserved_sectors),
+                data_offset: sector_offset(data_sector),
+                cluster_size: sectors_per_cluster * bytes_per_sector,
+                root_offset: sector_offset(data_sector + (root_cluster - 2) * sectors_per_cluster),
+                root_size: (sectors_per_cluster * bytes_per_sector) as usize,
+            }
+        };
+        Ok(Some(layout))
+    }
+
+    // find name in the root directory, matched case insensitive against long and short names
+    fn fat_find_entry(
+        &mut self,
+        layout: &FatLayout,
+        name: &str,
+    ) -> Result<Option<[u8; FAT_DIR_ENTRY_SIZE]>> {
+        let root_dir = self.read_bytes(
+            layout.root_offset,
+            layout.root_size.min(FAT_MAX_ROOT_DIR_SIZE),
+        )?;
+
+        // long name entries precede their short entry, last part first
+        let mut long_name: Vec<u16> = Vec::new();
+        for entry in root_dir.chunks_exact(FAT_DIR_ENTRY_SIZE) {
+            match entry[0] {
+                // end of directory
+                0x00 => break,
+                // deleted entry
+                0xE5 => {
+                    long_name.clear();
+                    continue;
+                }
+                _ => (),
+            }
+
+            if entry[11] == FAT_ATTR_LFN {
+                if entry[0] & FAT_LFN_LAST != 0 {
+                    long_name.clear();
+                }
+                let part: Vec<u16> = [1..11, 14..26, 28..32]
+                    .iter()
+                    .flat_map(|range| entry[range.clone()].chunks_exact(2))
+                    .map(|chars| u16::from_le_bytes([chars[0], chars[1]]))
+                    .collect();
+                long_name.splice(0..0, part);
+                continue;
+            }
+
+            let long_len = long_name
+                .iter()
+                .position(|chr| *chr == 0 || *chr == 0xFFFF)
+                .unwrap_or(long_name.len());
+            let found = String::from_utf16_lossy(&long_name[0..long_len]);
+            long_name.clear();
+
+            let base = String::from_utf8_lossy(&entry[0..8]).trim_end().to_string();
+            let ext = String::from_utf8_lossy(&entry[8..11])
+                .trim_end()
+                .to_string();
+            let short_name = if ext.is_empty() {
+                base
+            } else {
+                format!("{}.{}", base, ext)
+            };
+
+            if found.eq_ignore_ascii_case(name) || short_name.eq_ignore_ascii_case(name) {
+                return Ok(Some(entry.try_into().unwrap()));
+            }
+        }
+        Ok(None)
+    }
+
+    fn fat_next_cluster(&mut self, layout: &FatLayout, cluster: u64) -> Result<u64> {
+        Ok(match layout.fat_type {
+            FatType::Fat12 => {
+                let bytes = self.read_bytes(layout.fat_offset + cluster + cluster / 2, 2)?;
+                let value = u64::from(u16::from_le_bytes([bytes[0], bytes[1]]));
+                if cluster & 1 == 1 {
+                    value >> 4
+                } else {
+                    value & 0x0FFF
+                }
+            }
+            FatType::Fat16 => {
+                let bytes = self.read_bytes(layout.fat_offset + cluster * 2, 2)?;
+                u64::from(u16::from_le_bytes([bytes[0], bytes[1]]))
+            }
+            FatType::Fat32 => {
+                let bytes = self.read_bytes(layout.fat_offset + cluster * 4, 4)?;
+                u64::from(u32::from_le_bytes(bytes[0..4].try_into().unwrap()) & 0x0FFF_FFFF)
+            }
+        })
+    }
+
+    // read the file described by entry following its cluster chain
+    fn fat_read_file(
+        &mut self,
+        layout: &FatLayout,
+        entry: &[u8; FAT_DIR_ENTRY_SIZE],
+    ) -> Result<Vec<u8>> {
+        let size = u32::from_le_bytes(entry[28..32].try_into().unwrap()) as usize;
+        if size > FAT_MAX_FILE_SIZE {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Encountered a FAT file of {} bytes, only files up to {} bytes are read",
+                    size, FAT_MAX_FILE_SIZE
+                ),
+            ));
+        }
+
+        let last_cluster = match layout.fat_type {
+            FatType::Fat12 => 0x0FF6,
+            FatType::Fat16 => 0xFFF6,
+            FatType::Fat32 => 0x0FFF_FFF6,
+        };
+
+        let mut cluster = u64::from(u16::from_le_bytes([entry[26], entry[27]]))
+            | u64::from(u16::from_le_bytes([entry[20], entry[21]])) << 16;
+        let mut content: Vec<u8> = Vec::with_capacity(size);
+        while content.len() < size {
+            if cluster < 2 || cluster > last_cluster {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "Encountered an invalid FAT cluster chain, cluster {} after {} of {} bytes",
+                        cluster,
+                        content.len(),
+                        size
+                    ),
+                ));
+            }
+            let read_size = (size - content.len()).min(layout.cluster_size as usize);
+            content.extend_from_slice(&self.read_bytes(
+                layout.data_offset + (cluster - 2) * layout.cluster_size,
+                read_size,
+            )?);
+            cluster = self.fat_next_cluster(layout, cluster)?;
+        }
+        Ok(content)
+    }
+
+    // look for an EFI directory in the root directory of the FAT filesystem starting at start_lba
+    fn fat_has_efi_dir(&mut self, start_lba: u64) -> Result<bool> {
+        let layout = if let Some(layout) = self.fat_layout(start_lba)? {
+            layout
+        } else {
+            return Ok(false);
+        };
+
+        Ok(match self.fat_find_entry(&layout, FAT_EFI_DIR_NAME)? {
+            Some(entry) => (entry[11] & FAT_ATTR_DIR) == FAT_ATTR_DIR,
+            None => false,
+        })
+    }
+
+    // the filesystem type of the partition starting at start_lba as named in /proc/filesystems
+    pub fn get_fs_type(&mut self, start_lba: u64) -> Result<Option<&'static str>> {
+        let superblock = self.read_bytes(start_lba * self.block_size + SUPERBLOCK_OFFSET, 64)?;
+        if superblock[0..4] == F2FS_MAGIC {
+            Ok(Some("f2fs"))
+        } else if superblock[EXT_MAGIC_OFFSET..EXT_MAGIC_OFFSET + 2] == EXT_MAGIC {
+            // the ext4 driver also mounts ext2 and ext3
+            Ok(Some("ext4"))
+        } else if self.fat_layout(start_lba)?.is_some() {
+            Ok(Some("vfat"))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // the start of the boot partition, the first partition of balena images
+    fn get_boot_part_lba(&mut self) -> Result<u64> {
+        let mbr = self.read_mbr(0)?;
+        if let PartitionType::GPT = PartitionType::from_ptype(mbr.part_tbl[0].ptype) {
+            let header = self.read_bytes(self.block_size, DEF_BLOCK_SIZE)?;
+            if &header[0..8] != b"EFI PART" {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    "Encountered an invalid GPT header signature",
+                ));
+            }
+            let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+            let entry = self.read_bytes(entries_lba * self.block_size, GPT_ENTRY_MIN_SIZE)?;
+            Ok(u64::from_le_bytes(entry[32..40].try_into().unwrap()))
+        } else {
+            Ok(u64::from(mbr.part_tbl[0].first_lba))
+        }
+    }
+
+    /******************************************************************
+     * Read a file from the root directory of the FAT boot partition,
+     * returns None if the partition or the file does not exist.
+     ******************************************************************/
+
+    pub fn read_boot_file(&mut self, name: &str) -> Result<Option<Vec<u8>>> {
+        let start_lba = self.get_boot_part_lba()?;
+        let layout = if let Some(layout) = self.fat_layout(start_lba)? {
+            layout
+        } else {
+            debug!("read_boot_file: the boot partition is not a FAT filesystem");
+            return Ok(None);
+        };
+
+        match self.fat_find_entry(&layout, name)? {
+            Some(entry) if (entry[11] & FAT_ATTR_DIR) == 0 => {
+                Ok(Some(self.fat_read_file(&layout, &entry)?))
+            }
+            _ => Ok(None),
+        }
+    }
 }
 
 pub(crate) struct PartitionIterator<'a> {
     disk: &'a mut Disk,
     mbr: MasterBootRecord,
     offset: u64,
+    // the start of the extended partition, the EBR links are relative to it
+    ext_offset: u64,
     index: usize,
     part_idx: usize,
     disk_id: Option<u32>,
@@ -234,6 +858,7 @@ impl<'a> PartitionIterator<'a> {
             disk,
             mbr,
             offset,
+            ext_offset: 0,
             index: 0,
             part_idx: 0,
             disk_id,
@@ -270,12 +895,14 @@ impl<'a> PartitionIterator<'a> {
                     // extended / container
                     // return extended partition
                     self.offset = part.first_lba as u64;
+                    self.ext_offset = self.offset;
                     self.mbr = self.disk.read_mbr(self.offset)?;
                     self.part_idx = 4;
                     self.get_extended_partition()
                 }
                 PartitionType::Fat | PartitionType::Linux => {
                     // return regular partition
+                    let table_idx = self.index;
                     self.index += 1;
                     self.part_idx += 1;
 
@@ -285,6 +912,8 @@ impl<'a> PartitionIterator<'a> {
                         status: part.status,
                         start_lba: u64::from(part.first_lba),
                         num_sectors: u64::from(part.num_sectors),
+                        table_lba: 0,
+                        table_idx,
                     }))
                 }
                 _ => Err(Error::with_context(
@@ -328,6 +957,8 @@ impl<'a> PartitionIterator<'a> {
                         status: part.status,
                         start_lba: self.offset + u64::from(part.first_lba),
                         num_sectors: u64::from(part.num_sectors),
+                        table_lba: self.offset,
+                        table_idx: 0,
                     }));
 
                     debug!("PartitionIterator::get_extended_partition: reading next");
@@ -349,7 +980,7 @@ impl<'a> PartitionIterator<'a> {
                         PartitionType::Container => {
                             // extended / container
                             // return extended partition
-                            self.offset += part.first_lba as u64;
+                            self.offset = self.ext_offset + part.first_lba as u64;
                             self.mbr = self.disk.read_mbr(self.offset)?;
                         }
                         _ => {
@@ -465,14 +1096,723 @@ impl<'a> Read for PartitionReader<'a> {
     }
 }
 
+// the problems found in a partition table: partitions that are empty, overlap, leave the
+// usable area or their extended partition or do not fit device_size bytes
+pub(crate) fn check_part_table(table: &PartTable, device_size: Option<u64>) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    for part in &table.parts {
+        if part.num_sectors == 0 {
+            problems.push(format!("partition {} has no sectors", part.index));
+            continue;
+        }
+        let end_byte = part
+            .start_lba
+            .checked_add(part.num_sectors)
+            .and_then(|end_lba| end_lba.checked_mul(table.sector_size));
+        if end_byte.is_none() {
+            problems.push(format!(
+                "partition {} starts at sector {} with {} sectors, beyond the addressable sectors",
+                part.index, part.start_lba, part.num_sectors
+            ));
+            continue;
+        }
+        let last_lba = part.end_lba() - 1;
+        match (&table.usable, &table.extended) {
+            (Some((first_usable, last_usable)), _)
+                if part.start_lba < *first_usable || last_lba > *last_usable =>
+            {
+                problems.push(format!(
+                    "partition {} spans sectors {}-{}, outside the usable sectors {}-{}",
+                    part.index, part.start_lba, last_lba, first_usable, last_usable
+                ))
+            }
+            (None, _) if part.start_lba == 0 => problems.push(format!(
+                "partition {} starts at sector 0, overwriting the MBR",
+                part.index
+            )),
+            (None, Some(ext_part))
+                if part.index > 4
+                    && (part.start_lba <= ext_part.start_lba
+                        || part.end_lba() > ext_part.end_lba()) =>
+            {
+                problems.push(format!(
+                    "logical partition {} spans sectors {}-{}, outside the extended partition {} at sectors {}-{}",
+                    part.index,
+                    part.start_lba,
+                    last_lba,
+                    ext_part.index,
+                    ext_part.start_lba,
+                    ext_part.end_lba() - 1
+                ))
+            }
+            _ => (),
+        }
+    }
+
+    let last = table.parts.iter().max_by_key(|part| part.end_lba());
+    if let (Some(last), Some(device_size)) = (last, device_size) {
+        // a partition ending beyond the addressable sectors was reported above
+        if let Some(end) = last.end_lba().checked_mul(table.sector_size) {
+            if end > device_size {
+                problems.push(format!(
+                    "partition {} ends at byte {}, the flash device has {} bytes",
+                    last.index, end, device_size
+                ));
+            }
+        }
+    }
+
+    let mut parts: Vec<&PartExtent> = table
+        .parts
+        .iter()
+        .filter(|part| part.num_sectors > 0)
+        .collect();
+    parts.sort_by_key(|part| part.start_lba);
+    for pair in parts.windows(2) {
+        if pair[1].start_lba < pair[0].end_lba() {
+            problems.push(format!(
+                "partition {} at sectors {}-{} overlaps partition {} at sectors {}-{}",
+                pair[0].index,
+                pair[0].start_lba,
+                pair[0].end_lba() - 1,
+                pair[1].index,
+                pair[1].start_lba,
+                pair[1].end_lba() - 1
+            ));
+        }
+    }
+    problems
+}
+
+// the size of a block device or file in bytes
+pub(crate) fn get_device_size(device: &Path) -> Result<u64> {
+    File::open(device)
+        .and_then(|mut file| file.seek(SeekFrom::End(0)))
+        .upstream_with_context(&format!(
+            "Failed to determine the size of '{}'",
+            device.display()
+        ))
+}
+
+/******************************************************************
+ * Catch a corrupt or mis-built image before it touches the disk:
+ * its partitions have to be valid, lie within the image and fit the
+ * flash device. Finding the end of the image means decompressing
+ * all of it.
+ ******************************************************************/
+
+pub(crate) fn check_image_part_table(image: &Path, device: &Path) -> Result<()> {
+    info!(
+        "Checking the partition table of image '{}'",
+        image.display()
+    );
+    let mut disk = Disk::from_gzip_img(image)?;
+    let table = disk.get_part_table().upstream_with_context(&format!(
+        "Failed to read the partition table of image '{}'",
+        image.display()
+    ))?;
+    debug!("check_image_part_table: {:?}", table);
+
+    let mut problems = check_part_table(&table, Some(get_device_size(device)?));
+    if let Some(last) = table.parts.iter().max_by_key(|part| part.end_lba()) {
+        let mut buffer = vec![0u8; table.sector_size as usize];
+        if last.num_sectors > 0 {
+            // check_part_table reported a partition ending beyond the addressable sectors
+            if let Some(last_offset) = (last.end_lba() - 1).checked_mul(table.sector_size) {
+                if disk.disk.fill(last_offset, &mut buffer).is_err() {
+                    problems.push(format!(
+                        "partition {} ends at sector {}, beyond the end of the image",
+                        last.index,
+                        last.end_lba() - 1
+                    ));
+                }
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        info!(
+            "The partition table of image '{}' is valid and fits '{}'",
+            image.display(),
+            device.display()
+        );
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::Validation,
+            &format!(
+                "The image '{}' can not be flashed to '{}': {}",
+                image.display(),
+                device.display(),
+                problems.join(", ")
+            ),
+        ))
+    }
+}
+
+fn find_partition(disk: &mut Disk, index: usize) -> Result<Option<PartInfo>> {
+    Ok(PartitionIterator::new(disk)?.find(|part| part.index == index))
+}
+
+// Check that the data partition of image starts where the data partition on device starts, so
+// everything in front of it can be written from the image and the existing data partition is kept.
+// Returns the data partitions of image and device.
+pub(crate) fn check_data_part_layout(image: &Path, device: &Path) -> Result<(PartInfo, PartInfo)> {
+    let mut image_disk = Disk::from_gzip_img(image)?;
+    let image_part = if let Some(part) = find_partition(&mut image_disk, BALENA_DATA_PART_IDX)? {
+        part
+    } else {
+        return Err(Error::with_context(
+            ErrorKind::NotFound,
+            &format!("No data partition found in image '{}'", image.display()),
+        ));
+    };
+
+    let mut device_disk = Disk::from_drive_file(device, None)?;
+    let device_part = if let Some(part) = find_partition(&mut device_disk, BALENA_DATA_PART_IDX)? {
+        part
+    } else {
+        return Err(Error::with_context(
+            ErrorKind::NotFound,
+            &format!(
+                "No partition with index {} found on '{}'",
+                BALENA_DATA_PART_IDX,
+                device.display()
+            ),
+        ));
+    };
+
+    if image_part.start_lba != device_part.start_lba {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "The data partition in image '{}' starts at sector {}, the data partition on '{}' starts at sector {}",
+                image.display(),
+                image_part.start_lba,
+                device.display(),
+                device_part.start_lba
+            ),
+        ));
+    }
+
+    Ok((image_part, device_part))
+}
+
+// Check that the partitions with the given indices have the same position and size in image
+// and on device, so they can be written in place. Returns the partitions of image.
+pub(crate) fn check_part_layout(
+    image: &Path,
+    device: &Path,
+    indices: &[usize],
+) -> Result<Vec<PartInfo>> {
+    let mut image_disk = Disk::from_gzip_img(image)?;
+    let image_parts: Vec<PartInfo> = PartitionIterator::new(&mut image_disk)?.collect();
+    let mut device_disk = Disk::from_drive_file(device, None)?;
+    let device_parts: Vec<PartInfo> = PartitionIterator::new(&mut device_disk)?.collect();
+
+    let mut parts: Vec<PartInfo> = Vec::new();
+    for index in indices {
+        let image_part = if let Some(part) = image_parts.iter().find(|part| part.index == *index) {
+            part
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::NotFound,
+                &format!(
+                    "No partition with index {} found in image '{}'",
+                    index,
+                    image.display()
+                ),
+            ));
+        };
+
+        let device_part = if let Some(part) = device_parts.iter().find(|part| part.index == *index)
+        {
+            part
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::NotFound,
+                &format!(
+                    "No partition with index {} found on '{}'",
+                    index,
+                    device.display()
+                ),
+            ));
+        };
+
+        if image_part.start_lba != device_part.start_lba
+            || image_part.num_sectors != device_part.num_sectors
+        {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Partition {} in image '{}' spans sectors {}+{}, on '{}' it spans sectors {}+{}",
+                    index,
+                    image.display(),
+                    image_part.start_lba,
+                    image_part.num_sectors,
+                    device.display(),
+                    device_part.start_lba,
+                    device_part.num_sectors
+                ),
+            ));
+        }
+
+        parts.push(image_part.clone());
+    }
+
+    Ok(parts)
+}
+
+// Get the first sector behind the partitions of image. Partitions are appended as logical
+// partitions, so the image has to have a MBR with an extended partition and 512 byte sectors.
+pub(crate) fn get_image_layout_end(image: &Path) -> Result<u64> {
+    let table = Disk::from_gzip_img(image)?.get_part_table()?;
+    let extended = if let (None, Some(extended)) = (&table.usable, &table.extended) {
+        extended
+    } else {
+        return Err(Error::with_context(
+            ErrorKind::NotSupported,
+            &format!(
+                "The image '{}' does not have a MBR partition table with an extended partition",
+                image.display()
+            ),
+        ));
+    };
+
+    if table.sector_size != DEF_BLOCK_SIZE as u64 {
+        return Err(Error::with_context(
+            ErrorKind::NotSupported,
+            &format!(
+                "The image '{}' uses {} byte sectors, expected {} byte sectors",
+                image.display(),
+                table.sector_size,
+                DEF_BLOCK_SIZE
+            ),
+        ));
+    }
+
+    Ok(table
+        .parts
+        .iter()
+        .map(|part| part.end_lba())
+        .fold(extended.end_lba(), u64::max))
+}
+
+// Get the logical and physical block size of a block device
+pub(crate) fn get_block_sizes(device: &Path) -> Result<(u64, u64)> {
+    let device_file = File::open(device)
+        .upstream_with_context(&format!("Failed to open device '{}'", device.display()))?;
+
+    let mut logical: c_int = 0;
+    if unsafe { ioctl(device_file.as_raw_fd(), IOCTL_BLK_SSZGET, &mut logical) } != 0 {
+        return Err(Error::with_context(
+            ErrorKind::Upstream,
+            &format!(
+                "Device BLKSSZGET IOCTRL to '{}' failed with error: {}",
+                device.display(),
+                io::Error::last_os_error()
+            ),
+        ));
+    }
+
+    let mut physical: c_uint = 0;
+    if unsafe { ioctl(device_file.as_raw_fd(), IOCTL_BLK_PBSZGET, &mut physical) } != 0 {
+        return Err(Error::with_context(
+            ErrorKind::Upstream,
+            &format!(
+                "Device BLKPBSZGET IOCTRL to '{}' failed with error: {}",
+                device.display(),
+                io::Error::last_os_error()
+            ),
+        ));
+    }
+
+    Ok((logical as u64, u64::from(physical)))
+}
+
+fn read_sector(device_file: &mut File, device: &Path, lba: u64) -> Result<Vec<u8>> {
+    let mut sector = vec![0u8; DEF_BLOCK_SIZE];
+    device_file
+        .seek(SeekFrom::Start(lba * DEF_BLOCK_SIZE as u64))
+        .and_then(|_| device_file.read_exact(&mut sector))
+        .upstream_with_context(&format!(
+            "Failed to read sector {} of '{}'",
+            lba,
+            device.display()
+        ))?;
+    Ok(sector)
+}
+
+fn write_sector(device_file: &mut File, device: &Path, lba: u64, sector: &[u8]) -> Result<()> {
+    device_file
+        .seek(SeekFrom::Start(lba * DEF_BLOCK_SIZE as u64))
+        .and_then(|_| device_file.write_all(sector))
+        .upstream_with_context(&format!(
+            "Failed to write sector {} of '{}'",
+            lba,
+            device.display()
+        ))
+}
+
+// (ptype, first_lba, num_sectors) of an entry of a MBR / EBR sector
+fn read_part_entry(sector: &[u8], table_idx: usize) -> (u8, u64, u64) {
+    let offset = (PART_TBL_OFFSET + table_idx as u64 * PART_ENTRY_SIZE) as usize;
+    let entry = &sector[offset..offset + PART_ENTRY_SIZE as usize];
+    let first_lba = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]);
+    let num_sectors = u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]);
+    (entry[4], u64::from(first_lba), u64::from(num_sectors))
+}
+
+/******************************************************************
+ * Make the extended partition and the EBR link of the logical
+ * partition described by the EBR at ebr_lba reach up to end_lba,
+ * so a grown logical partition stays inside its container. The
+ * extended partition is never shrunk.
+ ******************************************************************/
+
+fn grow_logical_container(
+    device_file: &mut File,
+    device: &Path,
+    ebr_lba: u64,
+    end_lba: u64,
+) -> Result<()> {
+    let mut mbr = read_sector(device_file, device, 0)?;
+    let (ext_idx, ext_start, ext_sectors) = if let Some(ext) = (0..4).find_map(|idx| {
+        let (ptype, first_lba, num_sectors) = read_part_entry(&mbr, idx);
+        if matches!(PartitionType::from_ptype(ptype), PartitionType::Container) {
+            Some((idx, first_lba, num_sectors))
+        } else {
+            None
+        }
+    }) {
+        ext
+    } else {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!("No extended partition found on '{}'", device.display()),
+        ));
+    };
+
+    // the first EBR is at the start of the extended partition, the others are linked from their predecessor
+    let mut lba = ext_start;
+    let mut hops = 0;
+    while lba != ebr_lba {
+        let mut ebr = read_sector(device_file, device, lba)?;
+        let (ptype, next, _) = read_part_entry(&ebr, 1);
+        if ptype == 0 || hops > MAX_LOGICAL_PARTS {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "The EBR at sector {} is not linked from the extended partition of '{}'",
+                    ebr_lba,
+                    device.display()
+                ),
+            ));
+        }
+        if ext_start + next == ebr_lba {
+            write_part_entry(&mut ebr, 1, ptype, next, end_lba - ebr_lba)?;
+            write_sector(device_file, device, lba, &ebr)?;
+        }
+        lba = ext_start + next;
+        hops += 1;
+    }
+
+    if end_lba > ext_start + ext_sectors {
+        let (ptype, _, _) = read_part_entry(&mbr, ext_idx);
+        write_part_entry(&mut mbr, ext_idx, ptype, ext_start, end_lba - ext_start)?;
+        write_sector(device_file, device, 0, &mbr)?;
+    }
+    Ok(())
+}
+
+// Modify the size of a partition in its partition table entry, the extended partition of a logical partition is grown with it
+pub(crate) fn set_part_size(device: &Path, part: &PartInfo, num_sectors: u64) -> Result<()> {
+    if num_sectors > u64::from(u32::MAX) {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!("Invalid partition size: {} sectors", num_sectors),
+        ));
+    }
+
+    let offset = part.table_lba * DEF_BLOCK_SIZE as u64
+        + PART_TBL_OFFSET
+        + part.table_idx as u64 * PART_ENTRY_SIZE
+        + PART_ENTRY_SECTORS_OFFSET;
+
+    let mut device_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device)
+        .upstream_with_context(&format!("Failed to open device '{}'", device.display()))?;
+
+    // the container is grown first, so the table stays valid if the partition entry is not written
+    if part.table_lba > 0 {
+        grow_logical_container(
+            &mut device_file,
+            device,
+            part.table_lba,
+            part.start_lba + num_sectors,
+        )?;
+    }
+
+    device_file
+        .seek(SeekFrom::Start(offset))
+        .upstream_with_context(&format!("Failed to seek on device '{}'", device.display()))?;
+    device_file
+        .write_all(&(num_sectors as u32).to_le_bytes())
+        .upstream_with_context(&format!(
+            "Failed to write partition table on device '{}'",
+            device.display()
+        ))?;
+    device_file
+        .sync_all()
+        .upstream_with_context(&format!("Failed to sync device '{}'", device.display()))?;
+    Ok(())
+}
+
+// the type of partitions added by add_logical_part
+pub(crate) const LINUX_PART_TYPE: u8 = 0x83;
+// the type of the EBR link to the next logical partition
+const EBR_LINK_TYPE: u8 = 0x05;
+// CHS values of partitions only addressed by LBA
+const CHS_LBA_ONLY: [u8; 3] = [0xfe, 0xff, 0xff];
+
+fn write_part_entry(
+    sector: &mut [u8],
+    table_idx: usize,
+    ptype: u8,
+    first_lba: u64,
+    num_sectors: u64,
+) -> Result<()> {
+    let (first_lba, num_sectors) = match (u32::try_from(first_lba), u32::try_from(num_sectors)) {
+        (Ok(first_lba), Ok(num_sectors)) => (first_lba, num_sectors),
+        _ => {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "A MBR partition can not start at sector {} with {} sectors",
+                    first_lba, num_sectors
+                ),
+            ))
+        }
+    };
+
+    let offset = (PART_TBL_OFFSET + table_idx as u64 * PART_ENTRY_SIZE) as usize;
+    let entry = &mut sector[offset..offset + PART_ENTRY_SIZE as usize];
+    entry[0] = 0;
+    entry[1..4].copy_from_slice(&CHS_LBA_ONLY);
+    entry[4] = ptype;
+    entry[5..8].copy_from_slice(&CHS_LBA_ONLY);
+    entry[8..12].copy_from_slice(&first_lba.to_le_bytes());
+    entry[12..16].copy_from_slice(&num_sectors.to_le_bytes());
+    Ok(())
+}
+
+/******************************************************************
+ * Append a logical partition to the MBR partition table of device,
+ * eg. to list a partition again that the flashed image table does
+ * not know. Its EBR is written to the sector in front of start_lba,
+ * which has to follow the last logical partition. The extended
+ * partition is grown to include it.
+ ******************************************************************/
+
+pub(crate) fn add_logical_part(
+    device: &Path,
+    start_lba: u64,
+    num_sectors: u64,
+    ptype: u8,
+) -> Result<usize> {
+    let mut disk = Disk::from_drive_file(device, None)?;
+    let mbr = disk.read_mbr(0)?;
+    let (ext_idx, ext_start, ext_sectors) = if let Some((idx, part)) =
+        mbr.part_tbl.iter().enumerate().find(|(_, part)| {
+            matches!(
+                PartitionType::from_ptype(part.ptype),
+                PartitionType::Container
+            )
+        }) {
+        (idx, u64::from(part.first_lba), u64::from(part.num_sectors))
+    } else {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!("No extended partition found on '{}'", device.display()),
+        ));
+    };
+
+    let last = if let Some(last) = PartitionIterator::new(&mut disk)?
+        .filter(|part| part.table_lba > 0)
+        .max_by_key(|part| part.table_lba)
+    {
+        last
+    } else {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!("No logical partition found on '{}'", device.display()),
+        ));
+    };
+
+    let ebr_lba = start_lba.saturating_sub(1);
+    if ebr_lba < last.start_lba + last.num_sectors {
+        return Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Can not add a partition at sector {} on '{}', the last logical partition ends at sector {}",
+                start_lba,
+                device.display(),
+                last.start_lba + last.num_sectors
+            ),
+        ));
+    }
+
+    let mut device_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device)
+        .upstream_with_context(&format!("Failed to open device '{}'", device.display()))?;
+    let mut read_sector = |lba: u64| -> Result<Vec<u8>> {
+        let mut sector = vec![0u8; DEF_BLOCK_SIZE];
+        device_file
+            .seek(SeekFrom::Start(lba * DEF_BLOCK_SIZE as u64))
+            .and_then(|_| device_file.read_exact(&mut sector))
+            .upstream_with_context(&format!(
+                "Failed to read sector {} of '{}'",
+                lba,
+                device.display()
+            ))?;
+        Ok(sector)
+    };
+
+    let mut ebr = vec![0u8; DEF_BLOCK_SIZE];
+    write_part_entry(&mut ebr, 0, ptype, start_lba - ebr_lba, num_sectors)?;
+    ebr[DEF_BLOCK_SIZE - 2] = 0x55;
+    ebr[DEF_BLOCK_SIZE - 1] = 0xAA;
+
+    let mut last_ebr = read_sector(last.table_lba)?;
+    write_part_entry(
+        &mut last_ebr,
+        1,
+        EBR_LINK_TYPE,
+        ebr_lba - ext_start,
+        start_lba + num_sectors - ebr_lba,
+    )?;
+
+    let mut mbr_sector = read_sector(0)?;
+    let ext_end = (start_lba + num_sectors).max(ext_start + ext_sectors);
+    write_part_entry(
+        &mut mbr_sector,
+        ext_idx,
+        mbr.part_tbl[ext_idx].ptype,
+        ext_start,
+        ext_end - ext_start,
+    )?;
+
+    // the new EBR is written before it is linked
+    for (lba, sector) in &[(ebr_lba, ebr), (last.table_lba, last_ebr), (0, mbr_sector)] {
+        device_file
+            .seek(SeekFrom::Start(lba * DEF_BLOCK_SIZE as u64))
+            .and_then(|_| device_file.write_all(sector))
+            .upstream_with_context(&format!(
+                "Failed to write sector {} of '{}'",
+                lba,
+                device.display()
+            ))?;
+    }
+    device_file
+        .sync_all()
+        .upstream_with_context(&format!("Failed to sync device '{}'", device.display()))?;
+
+    Ok(last.index + 1)
+}
+
 #[cfg(test)]
 
 mod test {
     use crate::common::disk_util::PartitionIterator;
-    use crate::common::disk_util::{Disk, LabelType};
+    use crate::common::disk_util::{check_part_table, Disk, LabelType, PartExtent, PartTable};
     use crate::common::path_append;
+    use std::env::temp_dir;
+    use std::fs::{remove_file, write};
+    use std::iter::{once, repeat};
     use std::path::{Path, PathBuf};
 
+    #[test]
+    fn test_add_logical_part() {
+        use super::{add_logical_part, write_part_entry, DEF_BLOCK_SIZE, LINUX_PART_TYPE};
+        use std::fs::OpenOptions;
+        use std::io::{Seek, SeekFrom, Write};
+
+        // a primary partition and an extended partition holding one logical partition
+        let mut mbr = vec![0u8; DEF_BLOCK_SIZE];
+        write_part_entry(&mut mbr, 0, LINUX_PART_TYPE, 2048, 2048).unwrap();
+        write_part_entry(&mut mbr, 1, 0x05, 8192, 8192).unwrap();
+        mbr[510] = 0x55;
+        mbr[511] = 0xAA;
+        let mut ebr = vec![0u8; DEF_BLOCK_SIZE];
+        write_part_entry(&mut ebr, 0, LINUX_PART_TYPE, 2048, 4096).unwrap();
+        ebr[510] = 0x55;
+        ebr[511] = 0xAA;
+
+        let disk_path = path_append(
+            temp_dir(),
+            format!("takeover-mbr-{}.img", std::process::id()),
+        );
+        let mut disk_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&disk_path)
+            .unwrap();
+        disk_file.set_len(32768 * DEF_BLOCK_SIZE as u64).unwrap();
+        disk_file.write_all(&mbr).unwrap();
+        disk_file
+            .seek(SeekFrom::Start(8192 * DEF_BLOCK_SIZE as u64))
+            .unwrap();
+        disk_file.write_all(&ebr).unwrap();
+        drop(disk_file);
+
+        // overlaps the logical partition
+        assert!(add_logical_part(&disk_path, 12288, 1024, LINUX_PART_TYPE).is_err());
+
+        assert_eq!(
+            add_logical_part(&disk_path, 20480, 4096, LINUX_PART_TYPE).unwrap(),
+            6
+        );
+        assert_eq!(
+            add_logical_part(&disk_path, 28672, 2048, LINUX_PART_TYPE).unwrap(),
+            7
+        );
+
+        let mut disk = Disk::from_drive_file(&disk_path, None).unwrap();
+        let parts: Vec<(usize, u64, u64)> = PartitionIterator::new(&mut disk)
+            .unwrap()
+            .map(|part| (part.index, part.start_lba, part.num_sectors))
+            .collect();
+        assert_eq!(
+            parts,
+            vec![
+                (1, 2048, 2048),
+                (5, 10240, 4096),
+                (6, 20480, 4096),
+                (7, 28672, 2048)
+            ]
+        );
+        let table = disk.get_part_table().unwrap();
+        assert_eq!(table.extended.unwrap().num_sectors, 28672 + 2048 - 8192);
+
+        // growing the last logical partition to the end of the disk grows the extended partition
+        let last = PartitionIterator::new(&mut disk)
+            .unwrap()
+            .find(|part| part.index == 7)
+            .unwrap();
+        super::set_part_size(&disk_path, &last, 4096).unwrap();
+        let mut disk = Disk::from_drive_file(&disk_path, None).unwrap();
+        let table = disk.get_part_table().unwrap();
+        assert_eq!(table.extended.as_ref().unwrap().num_sectors, 32768 - 8192);
+        assert!(check_part_table(&table, Some(32768 * DEF_BLOCK_SIZE as u64)).is_empty());
+
+        remove_file(&disk_path).unwrap();
+    }
+
     fn get_test_file() -> PathBuf {
         let path_buf = PathBuf::from(file!());
         let mut test_path = path_buf.as_path();
@@ -511,4 +1851,127 @@ mod test {
             panic!("Invalid label type - not Dos");
         }
     }
+
+    #[test]
+    fn test_check_part_table() {
+        let part = |index, start_lba, num_sectors| PartExtent {
+            index,
+            start_lba,
+            num_sectors,
+        };
+        let mut table = PartTable {
+            sector_size: 512,
+            parts: vec![
+                part(1, 2048, 2048),
+                part(2, 4096, 4096),
+                part(5, 8193, 1023),
+            ],
+            usable: None,
+            extended: Some(part(4, 8192, 2048)),
+        };
+        assert!(check_part_table(&table, Some(10240 * 512)).is_empty());
+        assert_eq!(
+            check_part_table(&table, Some(8192 * 512)),
+            vec!["partition 5 ends at byte 4718592, the flash device has 4194304 bytes"]
+        );
+
+        table.parts[1].num_sectors = 4098;
+        table.parts[2].num_sectors = 2048;
+        assert_eq!(
+            check_part_table(&table, None),
+            vec![
+                "logical partition 5 spans sectors 8193-10240, outside the extended partition 4 at sectors 8192-10239",
+                "partition 2 at sectors 4096-8193 overlaps partition 5 at sectors 8193-10240",
+            ]
+        );
+
+        let table = PartTable {
+            sector_size: 512,
+            parts: vec![part(1, 34, 100), part(2, 120, 100)],
+            usable: Some((34, 200)),
+            extended: None,
+        };
+        assert_eq!(
+            check_part_table(&table, None),
+            vec![
+                "partition 2 spans sectors 120-219, outside the usable sectors 34-200",
+                "partition 1 at sectors 34-133 overlaps partition 2 at sectors 120-219",
+            ]
+        );
+
+        // a corrupt GPT entry ending beyond the addressable sectors
+        let table = PartTable {
+            sector_size: 512,
+            parts: vec![part(1, 34, 100), part(2, u64::MAX / 256, 100)],
+            usable: Some((34, u64::MAX)),
+            extended: None,
+        };
+        assert_eq!(
+            check_part_table(&table, Some(1024 * 512)),
+            vec![format!(
+                "partition 2 starts at sector {} with 100 sectors, beyond the addressable sectors",
+                u64::MAX / 256
+            )]
+        );
+    }
+
+    #[test]
+    fn read_fat_boot_file() {
+        let content: Vec<u8> = (0..600).map(|idx| (idx % 251) as u8).collect();
+        let mut image = vec![0u8; 11 * 512];
+        // MBR with a single FAT partition at LBA 1
+        image[446 + 4] = 0x0e;
+        image[446 + 8] = 1;
+        image[446 + 12] = 10;
+        image[510..512].copy_from_slice(&[0x55, 0xAA]);
+        // FAT12 with one sector per cluster, the FAT in sector 1, the root directory in sector 2
+        let boot = 512;
+        image[boot + 11..boot + 13].copy_from_slice(&512u16.to_le_bytes());
+        image[boot + 13] = 1;
+        image[boot + 14] = 1;
+        image[boot + 16] = 1;
+        image[boot + 17] = 16;
+        image[boot + 19] = 10;
+        image[boot + 22] = 1;
+        image[boot + 510..boot + 512].copy_from_slice(&[0x55, 0xAA]);
+        // clusters 2 -> 3 -> end of chain
+        image[1024..1030].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0x03, 0xF0, 0xFF]);
+        // long name entries, last part first, followed by the short entry
+        let name: Vec<u16> = "device-type.json"
+            .encode_utf16()
+            .chain(once(0))
+            .chain(repeat(0xFFFF))
+            .take(26)
+            .collect();
+        for (idx, seq) in [0x42u8, 0x01].iter().enumerate() {
+            let entry = &mut image[1536 + idx * 32..1536 + (idx + 1) * 32];
+            entry[0] = *seq;
+            entry[11] = 0x0f;
+            let part = &name[((*seq & 0x0f) as usize - 1) * 13..];
+            let offsets = (1..11)
+                .step_by(2)
+                .chain((14..26).step_by(2))
+                .chain((28..32).step_by(2));
+            for (chr, offset) in part.iter().zip(offsets) {
+                entry[offset..offset + 2].copy_from_slice(&chr.to_le_bytes());
+            }
+        }
+        let entry = &mut image[1600..1632];
+        entry[0..11].copy_from_slice(b"DEVICE~1JSO");
+        entry[11] = 0x20;
+        entry[26] = 2;
+        entry[28..32].copy_from_slice(&600u32.to_le_bytes());
+        image[2048..2648].copy_from_slice(&content);
+
+        let path = temp_dir().join(format!("takeover-fat-test-{}.img", std::process::id()));
+        write(&path, &image).unwrap();
+        let mut disk = Disk::from_drive_file(&path, None).unwrap();
+        assert_eq!(
+            disk.read_boot_file("Device-Type.json").unwrap(),
+            Some(content)
+        );
+        assert!(disk.read_boot_file("DEVICE~1.JSO").unwrap().is_some());
+        assert_eq!(disk.read_boot_file("config.json").unwrap(), None);
+        remove_file(&path).unwrap();
+    }
 }
@@ -3,6 +3,14 @@ use std::fmt::{self, Display, Formatter};
 use std::io;
 use std::result;
 
+use log::error;
+
+const POST_PIVOT_GUIDANCE: [&str; 3] = [
+    "Do not power off the device, it is mid-migration and its original OS is no longer intact.",
+    "Keep the stage2 log written to the log device or sent with --stage2-netlog, it tells how far the migration got.",
+    "If the device does not come up with balena-os, flash it from external media, eg. a balena-os SD card or USB stick.",
+];
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum ErrorKind {
     Upstream,
@@ -18,9 +26,55 @@ pub enum ErrorKind {
     Permission,
     FileExists,
     NotPermitted,
+    Validation,
+    Timeout,
+    NotSupported,
+    Connectivity,
+    NotRoot,
+    // stage2 failed after it started modifying the flash device
+    PostPivotFailure,
     Displayed,
 }
 
+impl ErrorKind {
+    /******************************************************************
+     * Process exit code for errors of this kind, so wrapper scripts
+     * can tell the failure categories apart:
+     *   1  general failure
+     *   2  invalid parameter or configuration
+     *   3  file, device or other item not found
+     *   4  file exists
+     *   5  permission denied
+     *   6  device or OS not supported
+     *   7  network connectivity check failed
+     *   8  external command failed
+     *   9  invalid system state
+     *   10 validation failed
+     *   11 timeout
+     *   12 not running as root or missing capabilities
+     *   13 failed after the flash device was modified
+     ******************************************************************/
+
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Self::ImageDownloaded => 0,
+            Self::Upstream | Self::NotImpl | Self::Displayed => 1,
+            Self::InvParam => 2,
+            Self::NotFound | Self::FileNotFound | Self::DeviceNotFound => 3,
+            Self::FileExists => 4,
+            Self::Permission | Self::NotPermitted => 5,
+            Self::NotSupported => 6,
+            Self::Connectivity => 7,
+            Self::ExecProcess | Self::CmdIo => 8,
+            Self::InvState => 9,
+            Self::Validation => 10,
+            Self::Timeout => 11,
+            Self::NotRoot => 12,
+            Self::PostPivotFailure => 13,
+        }
+    }
+}
+
 impl Display for ErrorKind {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let output = match *self {
@@ -37,6 +91,12 @@ impl Display for ErrorKind {
             Self::Permission => "Permission was denied",
             Self::NotPermitted => "Operation is not permitted",
             Self::FileExists => "The file exists",
+            Self::Validation => "A validation check failed",
+            Self::Timeout => "An operation timed out",
+            Self::NotSupported => "The device or operating system is not supported",
+            Self::Connectivity => "A network connection could not be established",
+            Self::NotRoot => "The program is not running as root or lacks required capabilities",
+            Self::PostPivotFailure => "The migration failed after the flash device was modified",
             Self::Displayed => "The error was displayed upstream",
         };
         write!(f, "{}", output)
@@ -48,6 +108,8 @@ pub struct Error {
     kind: ErrorKind,
     cause: Option<Box<dyn error::Error + Send + Sync + 'static>>,
     context: Option<String>,
+    // the kind of a displayed error, determines the exit code
+    displayed_kind: Option<ErrorKind>,
 }
 
 impl Error {
@@ -56,6 +118,7 @@ impl Error {
             kind,
             cause: None,
             context: None,
+            displayed_kind: None,
         }
     }
 
@@ -63,11 +126,20 @@ impl Error {
         Error::new(ErrorKind::Displayed)
     }
 
+    // an error that was displayed upstream but still carries the exit code of kind
+    pub fn displayed_with(kind: ErrorKind) -> Error {
+        Error {
+            displayed_kind: Some(kind),
+            ..Error::displayed()
+        }
+    }
+
     pub fn with_context(kind: ErrorKind, context: &str) -> Error {
         Error {
             kind,
             cause: None,
             context: Some(context.to_owned()),
+            displayed_kind: None,
         }
     }
 
@@ -79,6 +151,7 @@ impl Error {
             kind,
             cause: Some(cause),
             context: None,
+            displayed_kind: None,
         }
     }
 
@@ -91,6 +164,7 @@ impl Error {
             kind,
             cause: Some(cause),
             context: Some(context.to_owned()),
+            displayed_kind: None,
         }
     }
 
@@ -103,6 +177,7 @@ impl Error {
             kind: ErrorKind::Upstream,
             cause: Some(cause),
             context: Some(context.to_owned()),
+            displayed_kind: None,
         }
     }
 
@@ -114,12 +189,47 @@ impl Error {
             kind: ErrorKind::Upstream,
             cause: Some(cause),
             context: Some(context.to_owned()),
+            displayed_kind: None,
         }
     }
 
     pub fn kind(&self) -> ErrorKind {
         self.kind
     }
+
+    // the most specific kind found in the chain of upstream errors
+    fn specific_kind(&self) -> ErrorKind {
+        let mut curr_err = self;
+        loop {
+            match curr_err.kind {
+                ErrorKind::Upstream => {
+                    if let Some(cause) = curr_err
+                        .cause
+                        .as_ref()
+                        .and_then(|cause| cause.downcast_ref::<Error>())
+                    {
+                        curr_err = cause;
+                        continue;
+                    }
+                }
+                ErrorKind::Displayed => {
+                    if let Some(kind) = curr_err.displayed_kind {
+                        return kind;
+                    }
+                }
+                _ => (),
+            }
+            return curr_err.kind;
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.specific_kind().exit_code()
+    }
+
+    pub fn is_post_pivot(&self) -> bool {
+        self.specific_kind() == ErrorKind::PostPivotFailure
+    }
 }
 
 impl Display for Error {
@@ -165,6 +275,20 @@ impl error::Error for Error {
     }
 }
 
+/******************************************************************
+ * Log an error of kind PostPivotFailure so it stands out from the
+ * log lines before it, together with the recovery steps.
+ ******************************************************************/
+
+pub(crate) fn report_post_pivot_failure(error: &Error) {
+    error!("**************************************************************");
+    error!("{}", error);
+    for line in POST_PIVOT_GUIDANCE.iter() {
+        error!("{}", line);
+    }
+    error!("**************************************************************");
+}
+
 pub trait ToError<T> {
     fn error(self) -> Result<T>;
     fn upstream_with_context(self, context: &str) -> Result<T>;
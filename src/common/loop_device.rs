@@ -158,6 +158,23 @@ impl LoopDevice {
         }
     }
 
+    /// open the loop device at a path like /dev/loop0
+    pub fn from_path<P: AsRef<Path>>(path: P, auto_unset: bool) -> Result<LoopDevice> {
+        let path = path.as_ref();
+        let loop_index = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("loop"))
+            .and_then(|index| index.parse::<u32>().ok())
+            .ok_or_else(|| {
+                Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!("from_path: Not a loop device path: '{}'", path.display()),
+                )
+            })?;
+        LoopDevice::from_index(loop_index, auto_unset)
+    }
+
     /// create a loop device associated with the given file
     pub fn for_file<P: AsRef<Path>>(
         file: P,
@@ -234,7 +251,6 @@ impl LoopDevice {
         self.path.as_path()
     }
 
-    #[allow(dead_code)]
     pub fn set_auto_unset(&mut self, auto_unset: bool) {
         self.unset = auto_unset;
     }
@@ -0,0 +1,108 @@
+use std::fs::{read_to_string, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::{Error, ErrorKind, Result, ToError};
+
+// the version field compared between the flashed image and the running OS
+const OS_VERSION_KEY: &str = "VERSION_ID";
+
+/******************************************************************
+ * Written to the data partition by stage2 after flashing, read by
+ * --verify-boot under the new OS to confirm the migration.
+ ******************************************************************/
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MigrationMarker {
+    pub uuid: Option<String>,
+    pub device_type: Option<String>,
+    pub timestamp: u64,
+    pub os_version: Option<String>,
+    pub takeover_version: String,
+    // SHA-256 of the config.json stage2 wrote to the boot partition
+    pub config_sha256: Option<String>,
+}
+
+impl MigrationMarker {
+    pub fn new(
+        uuid: Option<String>,
+        device_type: Option<String>,
+        os_version: Option<String>,
+        config_sha256: Option<String>,
+    ) -> MigrationMarker {
+        MigrationMarker {
+            uuid,
+            device_type,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0),
+            os_version,
+            takeover_version: env!("CARGO_PKG_VERSION").to_string(),
+            config_sha256,
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<MigrationMarker> {
+        let marker_txt = read_to_string(path).upstream_with_context(&format!(
+            "Failed to read migration marker '{}'",
+            path.display()
+        ))?;
+        serde_json::from_str(&marker_txt).upstream_with_context(&format!(
+            "Failed to parse migration marker '{}'",
+            path.display()
+        ))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let marker_txt = serde_json::to_string_pretty(self)
+            .upstream_with_context("Failed to serialize migration marker")?;
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o644)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", marker_txt).and_then(|_| file.sync_all()))
+            .upstream_with_context(&format!(
+                "Failed to write migration marker '{}'",
+                path.display()
+            ))
+    }
+}
+
+// the os version from an os-release file, quotes removed
+pub(crate) fn get_os_version(os_release: &Path) -> Result<String> {
+    get_os_release_value(os_release, OS_VERSION_KEY)
+}
+
+// the os version from the contents of an os-release file
+pub(crate) fn parse_os_version(os_release_txt: &str) -> Option<String> {
+    parse_os_release_value(os_release_txt, OS_VERSION_KEY)
+}
+
+fn parse_os_release_value(os_release_txt: &str, key: &str) -> Option<String> {
+    os_release_txt
+        .lines()
+        .filter_map(|line| line.trim().split_once('='))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value.trim_matches('"').to_string())
+}
+
+// a field of an os-release file, quotes removed
+pub(crate) fn get_os_release_value(os_release: &Path, key: &str) -> Result<String> {
+    let os_release_txt = read_to_string(os_release)
+        .upstream_with_context(&format!("Failed to read '{}'", os_release.display()))?;
+
+    parse_os_release_value(&os_release_txt, key).ok_or_else(|| {
+        Error::with_context(
+            ErrorKind::NotFound,
+            &format!("No {} found in '{}'", key, os_release.display()),
+        )
+    })
+}
@@ -1,17 +1,39 @@
+use std::env;
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use log::Level;
-use structopt::StructOpt;
+use structopt::{clap, StructOpt};
+
+use crate::common::stage2_config::{
+    FlashPause, MinWriteSpeed, OldRootCleanup, PartitionScheme, RebootMethod, RebootMode,
+};
+use crate::stage1::defs::{BootMode, NetworkVia, OSArch, UnknownKeys};
 
 const DEFAULT_CHECK_TIMEOUT: u64 = 10;
+const DEFAULT_IMAGE_CACHE_SIZE: u64 = 8192;
+const DEFAULT_LATENCY_WARNING: u64 = 1000;
+const DEFAULT_OLD_ROOT: &str = "mnt/old_root";
+const DEFAULT_MIN_FREE_MEM: u64 = 32;
+const DEFAULT_STOP_SERVICES: [&str; 2] = ["docker", "balena-engine"];
+const DEFAULT_STOP_TIMEOUT: u64 = 30;
+const DEFAULT_PAUSE_TIMEOUT: u64 = 600;
+const ENV_PREFIX: &str = "TAKEOVER_";
 
 #[derive(StructOpt, Debug, Clone)]
-#[structopt(name = env!("CARGO_PKG_NAME"), author, about)]
+#[structopt(
+    name = env!("CARGO_PKG_NAME"),
+    author,
+    about,
+    after_help = "Flags can also be set through TAKEOVER_<FLAG> environment variables, eg. TAKEOVER_NO_ACK=1, values given on the command line take precedence"
+)]
 pub struct Options {
     /// what to do
     #[structopt(
         short,
         long,
+        env = "TAKEOVER_WORK_DIR",
         value_name = "DIRECTORY",
         parse(from_os_str),
         help = "Path to working directory"
@@ -20,14 +42,63 @@ pub struct Options {
     #[structopt(
         short,
         long,
+        env = "TAKEOVER_IMAGE",
         value_name = "IMAGE",
+        use_delimiter = true,
+        help = "Path or http(s) URL of the balena-os image, can be given multiple times or as a comma separated list of mirrors that are tried in order"
+    )]
+    image: Option<Vec<String>>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_IMAGE_SHA256",
+        value_name = "SHA256",
+        help = "Verify the image against SHA256, an image source that does not match is skipped"
+    )]
+    image_sha256: Option<String>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_VERIFY_IMAGE_SIGNATURE",
+        value_name = "PUBKEY",
+        parse(from_os_str),
+        help = "Verify the detached signature of the image with the PEM encoded RSA, EC or Ed25519 public key PUBKEY before flashing"
+    )]
+    verify_image_signature: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_IMAGE_SIGNATURE",
+        value_name = "SIG",
+        parse(from_os_str),
+        help = "The detached signature checked by --verify-image-signature, defaults to the image path with .sig appended"
+    )]
+    image_signature: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_MAX_IMAGE_AGE",
+        value_name = "DAYS",
+        help = "Skip an image source last modified more than DAYS days ago, by the Last-Modified header of a download or the modification time of a local image"
+    )]
+    max_image_age: Option<u64>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_IMAGE_CACHE_DIR",
+        value_name = "DIR",
         parse(from_os_str),
-        help = "Path to balena-os image"
+        help = "Keep downloaded images in DIR and reuse them in later runs while their checksum matches"
+    )]
+    image_cache_dir: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_IMAGE_CACHE_SIZE",
+        value_name = "MIB",
+        help = "Evict the least recently used images when the image cache grows beyond MIB MiB, defaults to 8192"
     )]
-    image: Option<PathBuf>,
+    image_cache_size: Option<u64>,
+    #[structopt(long, help = "Do not use the image cache, download images again")]
+    no_cache: bool,
     #[structopt(
         short,
         long,
+        env = "TAKEOVER_VERSION",
         value_name = "VERSION",
         help = "Version of balena-os image to download"
     )]
@@ -35,26 +106,38 @@ pub struct Options {
     #[structopt(
         short,
         long,
+        env = "TAKEOVER_CONFIG",
         value_name = "CONFIG_JSON",
         parse(from_os_str),
-        help = "Path to balena config.json"
+        help = "Path to balena config.json, - to read it from stdin, can be given multiple times - later files override values from earlier ones"
     )]
-    config: Option<PathBuf>,
+    config: Option<Vec<PathBuf>>,
     #[structopt(
         long,
-        default_value = "info",
-        help = "Set log level, one of [error,warn,info,debug,trace]"
+        env = "TAKEOVER_LOG_LEVEL",
+        help = "Set log level, one of [error,warn,info,debug,trace], defaults to info, takes precedence over -V and -q"
     )]
-    log_level: Level,
+    log_level: Option<Level>,
     #[structopt(
+        short = "V",
         long,
+        parse(from_occurrences),
+        help = "Raise the log level, -V for debug, -VV for trace"
+    )]
+    verbose: u8,
+    #[structopt(short, long, conflicts_with = "verbose", help = "Only log errors")]
+    quiet: bool,
+    #[structopt(
+        long,
+        env = "TAKEOVER_LOG_FILE",
         value_name = "LOG_FILE",
         parse(from_os_str),
-        help = "Set stage1 log file name"
+        help = "Also write the stage1 log to LOG_FILE, missing directories are created"
     )]
     log_file: Option<PathBuf>,
     #[structopt(
         long,
+        env = "TAKEOVER_BACKUP_CFG",
         value_name = "BACKUP-CONFIG",
         parse(from_os_str),
         help = "Backup configuration file"
@@ -62,9 +145,16 @@ pub struct Options {
     backup_cfg: Option<PathBuf>,
     #[structopt(
         long,
+        env = "TAKEOVER_S2_LOG_LEVEL",
         help = "Set stage2 log level, one of [error,warn,info,debug,trace]"
     )]
     s2_log_level: Option<Level>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_S2_CONSOLE_LOG_LEVEL",
+        help = "Set the stage2 log level on the console, the log file keeps the stage2 log level, one of [error,warn,info,debug,trace]"
+    )]
+    s2_console_log_level: Option<Level>,
     #[structopt(
         long,
         help = "Scripted mode - no interactive acknoledgement of takeover"
@@ -72,24 +162,128 @@ pub struct Options {
     no_ack: bool,
     #[structopt(long, help = "Pretend mode, do not flash device")]
     pretend: bool,
+    #[structopt(
+        long,
+        help = "Prepare the migration including the RAMFS but do not restart init, print the commands that complete it"
+    )]
+    no_flash: bool,
+    #[structopt(
+        long,
+        help = "Prepare the migration, print the generated stage2 config to stdout instead of writing it, then undo the preparation"
+    )]
+    print_stage2_config: bool,
+    #[structopt(
+        long,
+        env = "TAKEOVER_PLAN_OUT",
+        value_name = "FILE",
+        parse(from_os_str),
+        help = "Run the checks and write the resolved migration plan as JSON to FILE for review, no migration is performed"
+    )]
+    plan_out: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_PLAN_IN",
+        value_name = "FILE",
+        parse(from_os_str),
+        help = "Migrate with the options of the plan written by --plan-out to FILE, abort if the device no longer matches the plan"
+    )]
+    plan_in: Option<PathBuf>,
     #[structopt(long, help = "Internal - stage2 invocation")]
     stage2: bool,
     #[structopt(long, help = "Use internal tar instead of external command")]
     tar_internal: bool,
     #[structopt(long, help = "Debug - do not cleanup after stage1 failure")]
     no_cleanup: bool,
+    #[structopt(
+        long,
+        help = "Migrate even if the device already runs the target balena-os, the flash device is mounted read-write outside of / and /boot or --flash-partitions might leave it unbootable"
+    )]
+    force: bool,
+    #[structopt(
+        long,
+        help = "Abort if the sector size or partition alignment of the image does not match the flash device"
+    )]
+    strict_geometry: bool,
+    #[structopt(
+        long,
+        env = "TAKEOVER_PARTITION_ALIGNMENT",
+        value_name = "KIB",
+        help = "Check that the partitions of the image are aligned to KIB KiB besides the physical block size of the flash device"
+    )]
+    partition_alignment: Option<u64>,
+    #[structopt(
+        long,
+        help = "Abort if the partitions of the image are not aligned instead of warning"
+    )]
+    strict_alignment: bool,
+    #[structopt(
+        long,
+        env = "TAKEOVER_DELTA",
+        value_name = "PATH_OR_URL",
+        help = "Write only the blocks of the delta instead of the image if the flash device matches its base, the image is flashed otherwise"
+    )]
+    delta: Option<String>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_FLASH_PARTITIONS",
+        value_name = "PARTITIONS",
+        use_delimiter = true,
+        help = "Write only the named partitions from the image, a comma separated list of boot, rootA, rootB, state, data"
+    )]
+    flash_partitions: Option<Vec<String>>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_FLASH_FROM_PARTITION_IMAGE",
+        value_name = "PARTITION=IMAGE",
+        help = "Write the filesystem image IMAGE into the existing partition PARTITION of the flash device instead of flashing the image, PARTITION is one of boot, rootA, rootB, state, data"
+    )]
+    flash_from_partition_image: Option<String>,
+    #[structopt(
+        long,
+        help = "Undo a failed or interrupted stage1: unmount and remove the takeover directory"
+    )]
+    abort: bool,
+    #[structopt(
+        long,
+        help = "Continue an interrupted migration, reuse the image fetched by the previous run if it is intact"
+    )]
+    resume: bool,
     #[structopt(long, help = "Do not check if OS is supported")]
     no_os_check: bool,
     #[structopt(long, help = "Do not check if balena API is available")]
     no_api_check: bool,
+    #[structopt(
+        long,
+        env = "TAKEOVER_EXPECT_API_FINGERPRINT",
+        value_name = "SHA256",
+        help = "Abort unless the certificate of the balena API server has the SHA-256 fingerprint SHA256, checked independently of the system CA store"
+    )]
+    expect_api_fingerprint: Option<String>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_HEALTH_ENDPOINT",
+        value_name = "URL",
+        help = "Check that a GET of the http(s) URL succeeds instead of connecting to the apiEndpoint of config.json"
+    )]
+    health_endpoint: Option<String>,
     #[structopt(long, help = "Do not check if balena VPN is available")]
     no_vpn_check: bool,
+    #[structopt(
+        long,
+        help = "Do not check if the device type of config.json is supported by the device"
+    )]
+    no_device_type_check: bool,
     #[structopt(long, help = "Do not setup EFI boot")]
     no_efi_setup: bool,
     #[structopt(long, help = "Do not check network manager files exist")]
     no_nwmgr_check: bool,
     #[structopt(long, help = "Do not migrate host-name")]
     no_keep_name: bool,
+    #[structopt(
+        long,
+        help = "Drop the uuid and deviceApiKey of config.json so the device registers as a new device"
+    )]
+    new_uuid: bool,
     #[structopt(
         short,
         long,
@@ -98,6 +292,7 @@ pub struct Options {
     download_only: bool,
     #[structopt(
         long,
+        env = "TAKEOVER_CHECK_TIMEOUT",
         value_name = "TIMEOUT",
         parse(try_from_str),
         help = "API/VPN check timeout in seconds."
@@ -105,6 +300,21 @@ pub struct Options {
     check_timeout: Option<u64>,
     #[structopt(
         long,
+        env = "TAKEOVER_WAIT_FOR_NETWORK",
+        value_name = "TIMEOUT",
+        help = "Wait up to TIMEOUT seconds for a default route before the API/VPN checks"
+    )]
+    wait_for_network: Option<u64>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_LATENCY_WARNING",
+        value_name = "MILLISECONDS",
+        help = "Warn about API/VPN servers that take longer than MILLISECONDS to connect to, defaults to 1000"
+    )]
+    latency_warning: Option<u64>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_LOG_TO",
         short,
         value_name = "LOG_DEVICE",
         parse(from_os_str),
@@ -114,11 +324,21 @@ pub struct Options {
     #[structopt(
         short,
         long,
+        alias = "target-device",
+        env = "TAKEOVER_FLASH_TO",
         value_name = "INSTALL_DEVICE",
         parse(from_os_str),
-        help = "Use INSTALL_DEVICE to flash balena to"
+        help = "Use INSTALL_DEVICE to flash balena to, a regular file is flashed through a loop device"
     )]
     flash_to: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_SOURCE_ROOT_DEVICE",
+        value_name = "DEVICE",
+        parse(from_os_str),
+        help = "Use DEVICE as the device hosting the running OS instead of the detected one"
+    )]
+    source_root_device: Option<PathBuf>,
     #[structopt(
         long,
         help = "Do not create network manager configurations for configured wifis"
@@ -126,20 +346,450 @@ pub struct Options {
     no_wifis: bool,
     #[structopt(
         long,
+        env = "TAKEOVER_WIFI",
         value_name = "SSID",
         help = "Create a network manager configuation for configured wifi with SSID"
     )]
     wifi: Option<Vec<String>>,
     #[structopt(
         long,
+        env = "TAKEOVER_NWMGR_CFG",
         value_name = "NWMGR_FILE",
         parse(from_os_str),
         help = "Supply a network manager file to inject into balena-os"
     )]
     nwmgr_cfg: Option<Vec<PathBuf>>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_NETWORK_VIA",
+        value_name = "TARGET",
+        help = "Also list migrated wifis in the network section of config.json (config) or only write them as network manager files (system-connections), defaults to system-connections. The network manager files are written either way, balenaOS does not set up networks from config.json"
+    )]
+    network_via: Option<NetworkVia>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_UNKNOWN_KEYS",
+        value_name = "MODE",
+        help = "Warn about (warn), fail on (error) or ignore (ignore) top level keys of config.json that are not known, defaults to warn"
+    )]
+    unknown_keys: Option<UnknownKeys>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_CONFIG_VALIDATE_SCHEMA",
+        value_name = "FILE",
+        parse(from_os_str),
+        help = "Validate the final config.json against this JSON Schema and abort before anything is changed if it does not conform"
+    )]
+    config_validate_schema: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_SET",
+        value_name = "KEY=VALUE",
+        help = "Override a config.json value, VALUE is parsed as JSON or used as string, KEY can be a dotted path like os.sshKeys.0"
+    )]
+    set: Option<Vec<String>>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_CONFIG_OVERLAY",
+        value_name = "JSON",
+        help = "Merge a JSON object into config.json, objects are merged recursively, other values are replaced"
+    )]
+    config_overlay: Option<Vec<String>>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_REGISTRY_MIRROR",
+        value_name = "HOST[:PORT]",
+        help = "Set the registryEndpoint of config.json to a registry mirror, a http or https url is reduced to its host and port"
+    )]
+    registry_mirror: Option<String>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_DELTA_ENDPOINT",
+        value_name = "URL",
+        help = "Set the deltaEndpoint of config.json to this http or https url"
+    )]
+    delta_endpoint: Option<String>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_CARRY_FROM",
+        value_name = "CONFIG_JSON",
+        parse(from_os_str),
+        help = "Source config.json to copy the --carry-keys values from, needs --carry-keys"
+    )]
+    carry_from: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_CARRY_KEYS",
+        value_name = "KEY",
+        use_delimiter = true,
+        help = "Copy only these config.json keys from --carry-from, KEY can be a dotted path like os.sshKeys, list uuid,deviceApiKey to keep the device"
+    )]
+    carry_keys: Option<Vec<String>>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_FLEET",
+        value_name = "FLEET",
+        help = "Migrate to fleet FLEET (eg. myorg/myfleet) instead of the config.json applicationId"
+    )]
+    fleet: Option<String>,
+    #[structopt(
+        long,
+        help = "Keep the existing data partition, only write the boot, root and state partitions from the image"
+    )]
+    keep_data_partition: bool,
+    #[structopt(
+        long,
+        help = "Grow the data partition and its filesystem to fill the device after flashing"
+    )]
+    expand_data: bool,
+    #[structopt(
+        long,
+        env = "TAKEOVER_PRESERVE_LABELS",
+        value_name = "LABELS",
+        use_delimiter = true,
+        help = "Keep the partitions with these filesystem labels on the flash device, a comma separated list, they have to be located behind the partitions of the image"
+    )]
+    preserve_labels: Option<Vec<String>>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_TARGET_PARTITION_SCHEME",
+        value_name = "SCHEME",
+        help = "Convert the flashed partition table to mbr or gpt using sgdisk, gpt needs EFI boot unless --force is given, image keeps the partition table of the image, defaults to image"
+    )]
+    target_partition_scheme: Option<PartitionScheme>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_REBOOT",
+        value_name = "MODE",
+        help = "Reboot behaviour after a successful migration: auto, delay:<SECS> or manual"
+    )]
+    reboot: Option<RebootMode>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_REBOOT_COMMAND",
+        value_name = "METHOD",
+        help = "How stage2 reboots into balena OS after a successful migration: restart, restart2:<ARG>, watchdog or a command, overrides the default of the device type"
+    )]
+    reboot_command: Option<RebootMethod>,
+    #[structopt(
+        long,
+        help = "Run an interactive shell on the tty in stage2 before flashing, exiting the shell continues the migration"
+    )]
+    pause_after_pivot: bool,
+    #[structopt(
+        long,
+        env = "TAKEOVER_PAUSE_TIMEOUT",
+        value_name = "SECS",
+        help = "Continue the migration after SECS seconds if the --pause-after-pivot shell is still running, defaults to 600"
+    )]
+    pause_timeout: Option<u64>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_FLASH_PAUSE",
+        value_name = "MS/MIB",
+        help = "Pause flashing for MS milliseconds after every MIB MiB written, eg. 500/64 for media that throttle under sustained writes"
+    )]
+    flash_pause: Option<FlashPause>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_MIN_DISK_WRITE_SPEED",
+        value_name = "KIB[/SECS]",
+        help = "Abort flashing if less than KIB KiB/s are written to the flash device for SECS seconds, SECS defaults to 60"
+    )]
+    min_disk_write_speed: Option<MinWriteSpeed>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_STAGE2_TIMEOUT",
+        value_name = "TIMEOUT",
+        help = "Abort stage2 and reboot if no progress was made for TIMEOUT seconds, partition table changes and resizing the data partition are not interrupted"
+    )]
+    stage2_timeout: Option<u64>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_MIN_FREE_MEM",
+        value_name = "MIB",
+        help = "Refuse to pivot to the RAMFS if less than MIB MiB of memory are free, defaults to 32"
+    )]
+    min_free_mem: Option<u64>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_TMPFS_SIZE",
+        value_name = "MIB",
+        help = "Size of the tmpfs takeover pivots into in MiB, defaults to the kernel default of half the RAM"
+    )]
+    tmpfs_size: Option<u64>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_TMPFS_OPTIONS",
+        value_name = "OPTIONS",
+        help = "Additional comma separated mount options for the tmpfs takeover pivots into, eg. nr_inodes=0"
+    )]
+    tmpfs_options: Option<String>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_STOP_SERVICES",
+        value_name = "SERVICE",
+        min_values = 0,
+        use_delimiter = true,
+        help = "Stop services before restarting init, defaults to docker,balena-engine if no SERVICE is given"
+    )]
+    stop_services: Option<Vec<String>>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_STOP_TIMEOUT",
+        value_name = "TIMEOUT",
+        help = "Wait up to TIMEOUT seconds for each stopped service to exit, defaults to 30"
+    )]
+    stop_timeout: Option<u64>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_STAGE1_TIMEOUT",
+        value_name = "TIMEOUT",
+        help = "Abort the migration and clean up if stage1 did not complete within TIMEOUT seconds of confirming the migration"
+    )]
+    stage1_timeout: Option<u64>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_STAGE2_NETLOG",
+        value_name = "HOST:PORT",
+        help = "Send the stage2 log to a UDP log collector listening on HOST:PORT"
+    )]
+    stage2_netlog: Option<String>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_OLD_ROOT",
+        value_name = "DIRECTORY",
+        parse(from_os_str),
+        help = "Mount the old root filesystem on DIRECTORY, relative to the new root [default: mnt/old_root]"
+    )]
+    old_root: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_OLD_ROOT_CLEANUP",
+        value_name = "MODE",
+        help = "How stage2 frees the flash device from the old root: lazy (kill processes, unmount lazily if busy and verify the device is free), remount-ro (kill processes, remount busy partitions read-only) or keep (leave the old root alone), defaults to lazy"
+    )]
+    old_root_cleanup: Option<OldRootCleanup>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_INIT_BINARY",
+        value_name = "PATH",
+        parse(from_os_str),
+        help = "Path of the takeover binary in the new root, used to run init and stage2 [default: /bin/<executable name>]"
+    )]
+    init_binary: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_ARCH",
+        value_name = "ARCH",
+        help = "Override the detected architecture, one of amd64, armhf, armv6, arm64"
+    )]
+    arch: Option<OSArch>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_REPORT_URL",
+        value_name = "URL",
+        help = "Post migration status reports to URL, paths starting with / are relative to the config.json apiEndpoint"
+    )]
+    report_url: Option<String>,
+    #[structopt(
+        long,
+        help = "Write config.json indented instead of in the compact balena format"
+    )]
+    pretty_config: bool,
+    #[structopt(
+        long,
+        help = "Write config.json for stage2 even if it was not modified, eg. to normalize its formatting"
+    )]
+    always_write_config: bool,
+    #[structopt(
+        long,
+        help = "Flash the image straight from its location instead of copying it to RAM first, the image must not reside on the flash device"
+    )]
+    stream_image: bool,
+    #[structopt(
+        long,
+        help = "Switch SELinux to permissive mode instead of relabeling the files copied to the takeover directory, it is switched back to enforcing if stage1 fails or is aborted"
+    )]
+    selinux_permissive: bool,
+    #[structopt(
+        long,
+        help = "Copy the stage1 and stage2 logs to the data partition of the new OS, secrets are redacted"
+    )]
+    keep_logs_on_target: bool,
+    #[structopt(
+        long,
+        env = "TAKEOVER_TTY",
+        value_name = "TTY",
+        parse(from_os_str),
+        help = "Send stage2 output to the console TTY, eg. /dev/ttyS0, as well as to the log files"
+    )]
+    tty: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_ASSETS_DIR",
+        value_name = "DIR",
+        parse(from_os_str),
+        help = "Take the executables copied for stage2 from DIR/<arch> instead of the system, eg. DIR/armhf/dd"
+    )]
+    assets_dir: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_ASSET_SHA256",
+        value_name = "NAME=SHA256",
+        help = "Verify the asset NAME in the assets directory against SHA256 before using it"
+    )]
+    asset_sha256: Option<Vec<String>>,
+    #[structopt(
+        long,
+        help = "Print progress events as JSON lines to stdout, implies --no-ack"
+    )]
+    progress_json: bool,
+    #[structopt(
+        long,
+        env = "TAKEOVER_REPORT_TO_FILE",
+        value_name = "PATH",
+        parse(from_os_str),
+        help = "Append progress events as JSON lines to PATH while stage1 runs"
+    )]
+    report_to_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Keep appending progress events to --report-to-file in stage2, PATH must not reside on the flash device"
+    )]
+    report_file_stage2: bool,
+    #[structopt(
+        long,
+        env = "TAKEOVER_PARTITION_TABLE_BACKUP",
+        value_name = "PATH",
+        parse(from_os_str),
+        help = "Save the partition table of the flash device to PATH before flashing, PATH must not reside on the flash device"
+    )]
+    partition_table_backup: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "TAKEOVER_BOOT_MODE",
+        value_name = "MODE",
+        help = "Boot mode of the device to check the image against: efi, legacy or auto to detect it"
+    )]
+    boot_mode: Option<BootMode>,
+    #[structopt(
+        long,
+        help = "Check the environment without migrating and report the results"
+    )]
+    self_test: bool,
+    #[structopt(
+        long,
+        help = "Probe the endpoints of the given config.json and print a connectivity report without migrating"
+    )]
+    probe_only: bool,
+    #[structopt(
+        long,
+        help = "Print the detected architecture, device type and target device without migrating"
+    )]
+    detect_only: bool,
+    #[structopt(
+        long,
+        env = "TAKEOVER_REPORT_DEVICE_INFO",
+        value_name = "PATH",
+        parse(from_os_str),
+        help = "Write a JSON report of the device, its block devices, the redacted config.json and a probe of its endpoints to PATH for a support ticket, no migration is performed"
+    )]
+    report_device_info: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Run under the migrated balena-os: confirm the running OS version matches the flashed one and report the result"
+    )]
+    verify_boot: bool,
+}
+
+// flags from the environment accept 1, true, yes, on and 0, false, no, off
+fn parse_env_flag(name: &str, value: &OsStr) -> Result<bool, clap::Error> {
+    match value.to_str().map(str::to_lowercase).as_deref() {
+        Some("1") | Some("true") | Some("yes") | Some("on") => Ok(true),
+        Some("") | Some("0") | Some("false") | Some("no") | Some("off") => Ok(false),
+        _ => Err(clap::Error::with_description(
+            &format!(
+                "Invalid value '{}' for environment variable {}, expected one of 1, true, yes, on, 0, false, no, off",
+                value.to_string_lossy(),
+                name
+            ),
+            clap::ErrorKind::InvalidValue,
+        )),
+    }
 }
 
 impl Options {
+    // options taking a value fall back to their TAKEOVER_* variable in clap,
+    // clap would require a value for flags with an env fallback so those are applied here
+    pub fn from_args_and_env() -> Options {
+        let mut opts = Options::from_args();
+        if let Err(why) = opts.apply_env_flags() {
+            why.exit();
+        }
+        opts
+    }
+
+    // the options saved in a migration plan, see stage1::plan
+    pub(crate) fn from_plan_args(args: &[String]) -> Result<Options, clap::Error> {
+        let mut opts = Options::from_iter_safe(args)?;
+        opts.apply_env_flags()?;
+        Ok(opts)
+    }
+
+    fn apply_env_flags(&mut self) -> Result<(), clap::Error> {
+        // --stage2 is internal and deliberately left out
+        let flags: Vec<(&str, &mut bool)> = vec![
+            ("no_ack", &mut self.no_ack),
+            ("quiet", &mut self.quiet),
+            ("pretend", &mut self.pretend),
+            ("no_flash", &mut self.no_flash),
+            ("print_stage2_config", &mut self.print_stage2_config),
+            ("tar_internal", &mut self.tar_internal),
+            ("no_cleanup", &mut self.no_cleanup),
+            ("force", &mut self.force),
+            ("strict_geometry", &mut self.strict_geometry),
+            ("strict_alignment", &mut self.strict_alignment),
+            ("abort", &mut self.abort),
+            ("resume", &mut self.resume),
+            ("no_cache", &mut self.no_cache),
+            ("no_os_check", &mut self.no_os_check),
+            ("no_api_check", &mut self.no_api_check),
+            ("no_vpn_check", &mut self.no_vpn_check),
+            ("no_device_type_check", &mut self.no_device_type_check),
+            ("no_efi_setup", &mut self.no_efi_setup),
+            ("no_nwmgr_check", &mut self.no_nwmgr_check),
+            ("no_keep_name", &mut self.no_keep_name),
+            ("new_uuid", &mut self.new_uuid),
+            ("download_only", &mut self.download_only),
+            ("no_wifis", &mut self.no_wifis),
+            ("keep_data_partition", &mut self.keep_data_partition),
+            ("expand_data", &mut self.expand_data),
+            ("pretty_config", &mut self.pretty_config),
+            ("pause_after_pivot", &mut self.pause_after_pivot),
+            ("always_write_config", &mut self.always_write_config),
+            ("stream_image", &mut self.stream_image),
+            ("selinux_permissive", &mut self.selinux_permissive),
+            ("keep_logs_on_target", &mut self.keep_logs_on_target),
+            ("progress_json", &mut self.progress_json),
+            ("report_file_stage2", &mut self.report_file_stage2),
+            ("self_test", &mut self.self_test),
+            ("probe_only", &mut self.probe_only),
+            ("detect_only", &mut self.detect_only),
+            ("verify_boot", &mut self.verify_boot),
+        ];
+
+        for (name, flag) in flags {
+            let env_name = format!("{}{}", ENV_PREFIX, name.to_uppercase());
+            if !*flag {
+                if let Some(value) = env::var_os(&env_name) {
+                    *flag = parse_env_flag(&env_name, &value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn backup_config(&self) -> Option<&Path> {
         if let Some(backup_cfg) = &self.backup_cfg {
             Some(backup_cfg.as_path())
@@ -164,8 +814,42 @@ impl Options {
         }
     }
 
-    pub fn image(&self) -> &Option<PathBuf> {
-        &self.image
+    pub fn images(&self) -> &[String] {
+        if let Some(images) = &self.image {
+            images.as_slice()
+        } else {
+            const NO_IMAGES: [String; 0] = [];
+            &NO_IMAGES
+        }
+    }
+
+    pub fn image_sha256(&self) -> Option<&str> {
+        self.image_sha256.as_deref()
+    }
+
+    pub fn verify_image_signature(&self) -> Option<&Path> {
+        self.verify_image_signature.as_deref()
+    }
+
+    pub fn image_signature(&self) -> Option<&Path> {
+        self.image_signature.as_deref()
+    }
+
+    pub fn max_image_age(&self) -> Option<u64> {
+        self.max_image_age
+    }
+
+    pub fn image_cache_dir(&self) -> Option<&Path> {
+        if self.no_cache {
+            None
+        } else {
+            self.image_cache_dir.as_deref()
+        }
+    }
+
+    // in bytes
+    pub fn image_cache_size(&self) -> u64 {
+        self.image_cache_size.unwrap_or(DEFAULT_IMAGE_CACHE_SIZE) * 1024 * 1024
     }
 
     pub fn version(&self) -> &str {
@@ -177,34 +861,111 @@ impl Options {
     }
 
     pub fn no_ack(&self) -> bool {
-        self.no_ack
+        // the prompt would end up in the progress stream
+        self.no_ack || self.progress_json
     }
 
+    // --detect-only and --report-device-info identify the device without the checks that only
+    // matter when migrating
     pub fn migrate(&self) -> bool {
-        !self.download_only
+        !self.download_only && !self.detect_only && self.report_device_info.is_none()
     }
 
-    pub fn config(&self) -> &Option<PathBuf> {
-        &self.config
+    pub fn config(&self) -> &[PathBuf] {
+        const NO_CONFIGS: [PathBuf; 0] = [];
+        if let Some(configs) = &self.config {
+            configs.as_slice()
+        } else {
+            &NO_CONFIGS
+        }
     }
 
     pub fn pretend(&self) -> bool {
         self.pretend
     }
 
+    pub fn force(&self) -> bool {
+        self.force
+    }
+
+    pub fn strict_geometry(&self) -> bool {
+        self.strict_geometry
+    }
+
+    // in bytes
+    pub fn partition_alignment(&self) -> Option<u64> {
+        self.partition_alignment.map(|kib| kib * 1024)
+    }
+
+    pub fn strict_alignment(&self) -> bool {
+        self.strict_alignment
+    }
+
+    pub fn delta(&self) -> Option<&str> {
+        self.delta.as_deref()
+    }
+
+    pub fn flash_from_partition_image(&self) -> Option<&str> {
+        self.flash_from_partition_image.as_deref()
+    }
+
+    pub fn flash_partitions(&self) -> &[String] {
+        if let Some(partitions) = &self.flash_partitions {
+            partitions.as_slice()
+        } else {
+            const NO_PARTITIONS: [String; 0] = [];
+            &NO_PARTITIONS
+        }
+    }
+
+    pub fn no_flash(&self) -> bool {
+        self.no_flash
+    }
+
+    pub fn print_stage2_config(&self) -> bool {
+        self.print_stage2_config
+    }
+
+    pub fn plan_out(&self) -> Option<&Path> {
+        self.plan_out.as_deref()
+    }
+
+    pub fn plan_in(&self) -> Option<&Path> {
+        self.plan_in.as_deref()
+    }
+
     pub fn log_file(&self) -> &Option<PathBuf> {
         &self.log_file
     }
 
+    // an explicit --log-level wins over -V and -q
     pub fn log_level(&self) -> Level {
-        self.log_level
+        if let Some(level) = self.log_level {
+            level
+        } else if self.quiet {
+            Level::Error
+        } else {
+            match self.verbose {
+                0 => Level::Info,
+                1 => Level::Debug,
+                _ => Level::Trace,
+            }
+        }
     }
 
     pub fn s2_log_level(&self) -> Level {
         if let Some(level) = self.s2_log_level {
             level
         } else {
-            self.log_level
+            self.log_level()
+        }
+    }
+
+    pub fn s2_console_log_level(&self) -> Level {
+        if let Some(level) = self.s2_console_log_level {
+            level
+        } else {
+            self.s2_log_level()
         }
     }
 
@@ -220,10 +981,22 @@ impl Options {
         !self.no_api_check
     }
 
+    pub fn expect_api_fingerprint(&self) -> Option<&str> {
+        self.expect_api_fingerprint.as_deref()
+    }
+
+    pub fn health_endpoint(&self) -> Option<&str> {
+        self.health_endpoint.as_deref()
+    }
+
     pub fn vpn_check(&self) -> bool {
         !self.no_vpn_check
     }
 
+    pub fn device_type_check(&self) -> bool {
+        !self.no_device_type_check
+    }
+
     pub fn log_to(&self) -> &Option<PathBuf> {
         &self.log_to
     }
@@ -232,6 +1005,18 @@ impl Options {
         &self.flash_to
     }
 
+    pub fn source_root_device(&self) -> &Option<PathBuf> {
+        &self.source_root_device
+    }
+
+    pub fn wait_for_network(&self) -> Option<u64> {
+        self.wait_for_network
+    }
+
+    pub fn latency_warning(&self) -> Duration {
+        Duration::from_millis(self.latency_warning.unwrap_or(DEFAULT_LATENCY_WARNING))
+    }
+
     pub fn check_timeout(&self) -> u64 {
         if let Some(timeout) = self.check_timeout {
             timeout
@@ -240,6 +1025,18 @@ impl Options {
         }
     }
 
+    pub fn network_via(&self) -> NetworkVia {
+        self.network_via.unwrap_or(NetworkVia::SystemConnections)
+    }
+
+    pub fn unknown_keys(&self) -> UnknownKeys {
+        self.unknown_keys.unwrap_or(UnknownKeys::Warn)
+    }
+
+    pub fn config_validate_schema(&self) -> Option<&Path> {
+        self.config_validate_schema.as_deref()
+    }
+
     pub fn no_wifis(&self) -> bool {
         self.no_wifis
     }
@@ -270,7 +1067,269 @@ impl Options {
         !self.no_keep_name
     }
 
+    pub fn new_uuid(&self) -> bool {
+        self.new_uuid
+    }
+
     pub fn cleanup(&self) -> bool {
         !self.no_cleanup
     }
+
+    pub fn report_url(&self) -> Option<&str> {
+        if let Some(report_url) = &self.report_url {
+            Some(report_url.as_str())
+        } else {
+            None
+        }
+    }
+
+    pub fn pretty_config(&self) -> bool {
+        self.pretty_config
+    }
+
+    pub fn always_write_config(&self) -> bool {
+        self.always_write_config
+    }
+
+    pub fn stream_image(&self) -> bool {
+        self.stream_image
+    }
+
+    pub fn selinux_permissive(&self) -> bool {
+        self.selinux_permissive
+    }
+
+    pub fn tty(&self) -> Option<&Path> {
+        self.tty.as_deref()
+    }
+
+    pub fn assets_dir(&self) -> Option<&Path> {
+        if let Some(assets_dir) = &self.assets_dir {
+            Some(assets_dir.as_path())
+        } else {
+            None
+        }
+    }
+
+    pub fn asset_checksums(&self) -> &[String] {
+        if let Some(checksums) = &self.asset_sha256 {
+            checksums.as_slice()
+        } else {
+            const NO_CHECKSUMS: [String; 0] = [];
+            &NO_CHECKSUMS
+        }
+    }
+
+    pub fn progress_json(&self) -> bool {
+        self.progress_json
+    }
+
+    pub fn report_to_file(&self) -> Option<&Path> {
+        if let Some(report_file) = &self.report_to_file {
+            Some(report_file.as_path())
+        } else {
+            None
+        }
+    }
+
+    pub fn report_file_stage2(&self) -> bool {
+        self.report_file_stage2
+    }
+
+    pub fn boot_mode(&self) -> BootMode {
+        self.boot_mode.unwrap_or(BootMode::Auto)
+    }
+
+    pub fn partition_table_backup(&self) -> Option<&Path> {
+        if let Some(backup_path) = &self.partition_table_backup {
+            Some(backup_path.as_path())
+        } else {
+            None
+        }
+    }
+
+    pub fn abort(&self) -> bool {
+        self.abort
+    }
+
+    pub fn resume(&self) -> bool {
+        self.resume
+    }
+
+    pub fn self_test(&self) -> bool {
+        self.self_test
+    }
+
+    pub fn probe_only(&self) -> bool {
+        self.probe_only
+    }
+
+    pub fn detect_only(&self) -> bool {
+        self.detect_only
+    }
+
+    pub fn report_device_info(&self) -> Option<&Path> {
+        self.report_device_info.as_deref()
+    }
+
+    pub fn verify_boot(&self) -> bool {
+        self.verify_boot
+    }
+
+    pub fn stage1_timeout(&self) -> Option<u64> {
+        self.stage1_timeout
+    }
+
+    pub fn stage2_timeout(&self) -> Option<u64> {
+        self.stage2_timeout
+    }
+
+    pub fn stop_services(&self) -> Vec<&str> {
+        match &self.stop_services {
+            Some(services) if services.is_empty() => DEFAULT_STOP_SERVICES.to_vec(),
+            Some(services) => services.iter().map(String::as_str).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn stop_timeout(&self) -> u64 {
+        self.stop_timeout.unwrap_or(DEFAULT_STOP_TIMEOUT)
+    }
+
+    // in bytes
+    pub fn tmpfs_size(&self) -> Option<u64> {
+        self.tmpfs_size.map(|size| size * 1024 * 1024)
+    }
+
+    pub fn tmpfs_options(&self) -> Option<&str> {
+        self.tmpfs_options.as_deref()
+    }
+
+    // in bytes
+    pub fn min_free_mem(&self) -> u64 {
+        self.min_free_mem.unwrap_or(DEFAULT_MIN_FREE_MEM) * 1024 * 1024
+    }
+
+    pub fn old_root(&self) -> &Path {
+        if let Some(old_root) = &self.old_root {
+            old_root.as_path()
+        } else {
+            Path::new(DEFAULT_OLD_ROOT)
+        }
+    }
+
+    pub fn old_root_cleanup(&self) -> OldRootCleanup {
+        self.old_root_cleanup.unwrap_or(OldRootCleanup::Lazy)
+    }
+
+    pub fn init_binary(&self) -> Option<&Path> {
+        if let Some(init_binary) = &self.init_binary {
+            Some(init_binary.as_path())
+        } else {
+            None
+        }
+    }
+
+    pub fn arch(&self) -> Option<&OSArch> {
+        self.arch.as_ref()
+    }
+
+    pub fn stage2_netlog(&self) -> &Option<String> {
+        &self.stage2_netlog
+    }
+
+    pub fn reboot_command(&self) -> Option<&RebootMethod> {
+        self.reboot_command.as_ref()
+    }
+
+    pub fn pause_after_pivot(&self) -> bool {
+        self.pause_after_pivot
+    }
+
+    pub fn pause_timeout(&self) -> u64 {
+        self.pause_timeout.unwrap_or(DEFAULT_PAUSE_TIMEOUT)
+    }
+
+    pub fn reboot_mode(&self) -> RebootMode {
+        if let Some(reboot_mode) = &self.reboot {
+            reboot_mode.clone()
+        } else {
+            RebootMode::Auto
+        }
+    }
+
+    pub fn flash_pause(&self) -> Option<FlashPause> {
+        self.flash_pause.clone()
+    }
+
+    pub fn min_disk_write_speed(&self) -> Option<MinWriteSpeed> {
+        self.min_disk_write_speed.clone()
+    }
+
+    pub fn expand_data(&self) -> bool {
+        self.expand_data
+    }
+
+    pub fn target_partition_scheme(&self) -> PartitionScheme {
+        self.target_partition_scheme
+            .unwrap_or(PartitionScheme::Image)
+    }
+
+    pub fn preserve_labels(&self) -> &[String] {
+        if let Some(labels) = &self.preserve_labels {
+            labels.as_slice()
+        } else {
+            const NO_LABELS: [String; 0] = [];
+            &NO_LABELS
+        }
+    }
+
+    pub fn keep_data_partition(&self) -> bool {
+        self.keep_data_partition
+    }
+
+    pub fn fleet(&self) -> &Option<String> {
+        &self.fleet
+    }
+
+    pub fn keep_logs_on_target(&self) -> bool {
+        self.keep_logs_on_target
+    }
+
+    pub fn carry_from(&self) -> &Option<PathBuf> {
+        &self.carry_from
+    }
+
+    pub fn carry_keys(&self) -> &[String] {
+        if let Some(carry_keys) = &self.carry_keys {
+            carry_keys.as_slice()
+        } else {
+            &[]
+        }
+    }
+
+    pub fn config_overlays(&self) -> &[String] {
+        if let Some(overlays) = &self.config_overlay {
+            overlays.as_slice()
+        } else {
+            &[]
+        }
+    }
+
+    pub fn registry_mirror(&self) -> Option<&str> {
+        self.registry_mirror.as_deref()
+    }
+
+    pub fn delta_endpoint(&self) -> Option<&str> {
+        self.delta_endpoint.as_deref()
+    }
+
+    pub fn config_overrides(&self) -> &[String] {
+        if let Some(overrides) = &self.set {
+            overrides.as_slice()
+        } else {
+            const NO_OVERRIDES: [String; 0] = [];
+            &NO_OVERRIDES
+        }
+    }
 }
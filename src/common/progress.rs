@@ -0,0 +1,186 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{stdout, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+
+use crate::common::{Result, ToError};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Stage {
+    Stage1,
+    Prepare,
+    Stage2,
+    Flash,
+    Finished,
+    Failed,
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let output = match self {
+            Stage::Stage1 => "stage1",
+            Stage::Prepare => "prepare",
+            Stage::Stage2 => "stage2",
+            Stage::Flash => "flash",
+            Stage::Finished => "finished",
+            Stage::Failed => "failed",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum ProgressEvent {
+    Stage(Stage),
+    CheckStarted(String),
+    CheckPassed(String),
+    CheckFailed {
+        name: String,
+        error: String,
+    },
+    // the verdict of the stage1 connectivity checks: verified, partial or unverified
+    Connectivity {
+        verdict: String,
+        skipped: Vec<String>,
+        summary: String,
+    },
+    Download {
+        bytes: u64,
+        total: Option<u64>,
+    },
+    // paused_ms is the time spent in --flash-pause pauses so far
+    Flash {
+        bytes: u64,
+        total: Option<u64>,
+        paused_ms: u64,
+    },
+    // the flash write speed over the last --min-disk-write-speed period
+    WriteSpeed {
+        bytes_per_sec: u64,
+        min_bytes_per_sec: u64,
+    },
+}
+
+impl ProgressEvent {
+    pub fn to_json(&self) -> Value {
+        match self {
+            ProgressEvent::Stage(stage) => json!({"event": "stage", "stage": stage.to_string()}),
+            ProgressEvent::CheckStarted(name) => json!({"event": "check-started", "name": name}),
+            ProgressEvent::CheckPassed(name) => json!({"event": "check-passed", "name": name}),
+            ProgressEvent::CheckFailed { name, error } => {
+                json!({"event": "check-failed", "name": name, "error": error})
+            }
+            ProgressEvent::Connectivity {
+                verdict,
+                skipped,
+                summary,
+            } => json!({
+                "event": "connectivity",
+                "verdict": verdict,
+                "skipped": skipped,
+                "summary": summary
+            }),
+            ProgressEvent::Download { bytes, total } => {
+                json!({"event": "download", "bytes": bytes, "total": total})
+            }
+            ProgressEvent::Flash {
+                bytes,
+                total,
+                paused_ms,
+            } => {
+                json!({"event": "flash", "bytes": bytes, "total": total, "paused_ms": paused_ms})
+            }
+            ProgressEvent::WriteSpeed {
+                bytes_per_sec,
+                min_bytes_per_sec,
+            } => json!({
+                "event": "write-speed",
+                "bytes_per_sec": bytes_per_sec,
+                "min_bytes_per_sec": min_bytes_per_sec
+            }),
+        }
+    }
+}
+
+// Receives progress events, eg. to drive a UI or an orchestration tool
+pub(crate) trait ProgressObserver: Send {
+    fn on_event(&self, event: &ProgressEvent);
+}
+
+// Prints one JSON object per event to stdout
+pub(crate) struct JsonObserver;
+
+impl ProgressObserver for JsonObserver {
+    fn on_event(&self, event: &ProgressEvent) {
+        let stdout = stdout();
+        let mut stdout = stdout.lock();
+        let _res = writeln!(stdout, "{}", event.to_json());
+        let _res = stdout.flush();
+    }
+}
+
+// Appends one JSON object per event to a file, with the unix time it was emitted at.
+// The file is opened for every event, holding it open would keep stage2 from
+// killing the processes using the old root and unmounting it
+pub(crate) struct FileObserver {
+    path: PathBuf,
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl FileObserver {
+    pub fn new(path: &Path) -> Result<FileObserver> {
+        open_append(path)
+            .upstream_with_context(&format!("Failed to open event file '{}'", path.display()))?;
+        Ok(FileObserver {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl ProgressObserver for FileObserver {
+    fn on_event(&self, event: &ProgressEvent) {
+        let mut event = event.to_json();
+        event["time"] = json!(SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0));
+        // a single unbuffered write per line, a tail sees complete events right away
+        if let Ok(mut file) = open_append(&self.path) {
+            let _res = file.write_all(format!("{}\n", event).as_bytes());
+        }
+    }
+}
+
+lazy_static! {
+    static ref OBSERVERS: Mutex<Vec<Box<dyn ProgressObserver>>> = Mutex::new(Vec::new());
+}
+
+// replaces all registered observers
+pub(crate) fn set_observer(observer: Box<dyn ProgressObserver>) {
+    if let Ok(mut observers) = OBSERVERS.lock() {
+        *observers = vec![observer];
+    }
+}
+
+pub(crate) fn add_observer(observer: Box<dyn ProgressObserver>) {
+    if let Ok(mut observers) = OBSERVERS.lock() {
+        observers.push(observer);
+    }
+}
+
+// a no-op unless an observer was registered
+pub(crate) fn emit(event: ProgressEvent) {
+    if let Ok(observers) = OBSERVERS.lock() {
+        for observer in observers.iter() {
+            observer.on_event(&event);
+        }
+    }
+}
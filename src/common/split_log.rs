@@ -0,0 +1,83 @@
+use std::io::{stderr, Result, Write};
+use std::str::FromStr;
+
+use log::Level;
+
+/******************************************************************
+ * Log stream for LogDestination::Stream that writes records up to
+ * file_level to the log file and records up to console_level to
+ * stderr. mod_logger has a single level, set it to the more verbose
+ * of both and let this stream drop what a destination does not want.
+ ******************************************************************/
+
+pub(crate) struct SplitLog<T> {
+    file: T,
+    file_level: Level,
+    console_level: Level,
+}
+
+impl<T: Write> SplitLog<T> {
+    pub fn new(file: T, file_level: Level, console_level: Level) -> SplitLog<T> {
+        SplitLog {
+            file,
+            file_level,
+            console_level,
+        }
+    }
+}
+
+// the level of a record formatted by mod_logger, eg. '2021-01-01 12:00:00 INFO  [takeover] ..',
+// possibly wrapped in color escape sequences
+fn record_level(record: &[u8]) -> Option<Level> {
+    let record = String::from_utf8_lossy(record);
+    let record = if record.starts_with('\x1b') {
+        &record[record.find('m')? + 1..]
+    } else {
+        &record
+    };
+    Level::from_str(record.split_whitespace().nth(2)?).ok()
+}
+
+impl<T: Write> Write for SplitLog<T> {
+    // mod_logger writes every record in a single call
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let level = record_level(buf);
+        if level.is_none_or(|level| level <= self.console_level) {
+            let _res = stderr().write_all(buf);
+        }
+        if level.is_none_or(|level| level <= self.file_level) {
+            self.file.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let _res = stderr().flush();
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_level() {
+        assert_eq!(
+            record_level(b"2021-01-01 12:00:00 DEBUG [takeover::stage2] flashing\n"),
+            Some(Level::Debug)
+        );
+        assert_eq!(
+            record_level(b"\x1b[32m2021-01-01 12:00:00 INFO  [takeover] done\n\x1b[0m"),
+            Some(Level::Info)
+        );
+        assert_eq!(record_level(b"buffered text"), None);
+
+        let mut log = SplitLog::new(Vec::new(), Level::Info, Level::Error);
+        log.write_all(b"2021-01-01 12:00:00 INFO  [takeover] kept\n")
+            .unwrap();
+        log.write_all(b"2021-01-01 12:00:00 TRACE [takeover] dropped\n")
+            .unwrap();
+        assert_eq!(log.file, b"2021-01-01 12:00:00 INFO  [takeover] kept\n");
+    }
+}
@@ -1,8 +1,12 @@
-use std::path::PathBuf;
+use std::fmt::{self, Display};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
 
 use crate::common::error::{Result, ToError};
+use crate::stage1::defs::DeviceType;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) struct UmountPart {
@@ -17,18 +21,316 @@ pub(crate) struct LogDevice {
     pub fs_type: String,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub enum RebootMode {
+    Auto,
+    Delay(u64),
+    Manual,
+}
+
+impl FromStr for RebootMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> std::result::Result<Self, Self::Err> {
+        match mode {
+            "auto" => Ok(RebootMode::Auto),
+            "manual" => Ok(RebootMode::Manual),
+            _ => {
+                if let Some(secs) = mode.strip_prefix("delay:") {
+                    Ok(RebootMode::Delay(secs.parse::<u64>().map_err(|_| {
+                        format!("Invalid reboot delay '{}', expected seconds", secs)
+                    })?))
+                } else {
+                    Err(format!(
+                        "Invalid reboot mode '{}', expected auto, delay:<SECS> or manual",
+                        mode
+                    ))
+                }
+            }
+        }
+    }
+}
+
+// how stage2 frees the flash device from the old root before flashing
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub(crate) enum OldRootCleanup {
+    // kill the processes using the old root, unmount its partitions of the flash device,
+    // lazily if busy, and verify the flash device is not in use
+    Lazy,
+    // kill the processes, remount partitions that can not be unmounted read-only
+    RemountRo,
+    // leave the old root and its processes alone, the flash device has nothing mounted
+    Keep,
+}
+
+impl FromStr for OldRootCleanup {
+    type Err = String;
+
+    fn from_str(mode: &str) -> std::result::Result<Self, Self::Err> {
+        match mode {
+            "lazy" => Ok(OldRootCleanup::Lazy),
+            "remount-ro" => Ok(OldRootCleanup::RemountRo),
+            "keep" => Ok(OldRootCleanup::Keep),
+            _ => Err(format!(
+                "Invalid old root cleanup '{}', expected one of lazy, remount-ro, keep",
+                mode
+            )),
+        }
+    }
+}
+
+// how stage2 reboots into the flashed OS after a successful migration
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub(crate) enum RebootMethod {
+    // the reboot syscall with LINUX_REBOOT_CMD_RESTART
+    Restart,
+    // the reboot syscall with LINUX_REBOOT_CMD_RESTART2 and this argument, eg. a bootloader mode
+    Restart2(String),
+    // arm the hardware watchdog and let it reset the board
+    Watchdog,
+    // run this command, stage1 copies its program to /bin of the stage2 root
+    Command(Vec<String>),
+}
+
+impl RebootMethod {
+    // the program to copy for stage2
+    pub fn program(&self) -> Option<&str> {
+        if let RebootMethod::Command(args) = self {
+            args.first().map(|program| program.as_str())
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for RebootMethod {
+    type Err = String;
+
+    fn from_str(method: &str) -> std::result::Result<Self, Self::Err> {
+        match method.trim() {
+            "restart" => Ok(RebootMethod::Restart),
+            "watchdog" => Ok(RebootMethod::Watchdog),
+            method => {
+                if let Some(arg) = method.strip_prefix("restart2:") {
+                    Ok(RebootMethod::Restart2(arg.to_string()))
+                } else {
+                    let args: Vec<String> = method.split_whitespace().map(String::from).collect();
+                    if args.is_empty() {
+                        Err("Invalid reboot command, expected restart, restart2:<ARG>, watchdog or a command".to_string())
+                    } else {
+                        Ok(RebootMethod::Command(args))
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Display for RebootMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RebootMethod::Restart => write!(f, "the reboot syscall"),
+            RebootMethod::Restart2(arg) => {
+                write!(f, "the reboot syscall with restart command '{}'", arg)
+            }
+            RebootMethod::Watchdog => write!(f, "the hardware watchdog"),
+            RebootMethod::Command(args) => write!(f, "the command '{}'", args.join(" ")),
+        }
+    }
+}
+
+// the partition table written to the flash device, image keeps the table of the image
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub(crate) enum PartitionScheme {
+    Mbr,
+    Gpt,
+    Image,
+}
+
+impl FromStr for PartitionScheme {
+    type Err = String;
+
+    fn from_str(scheme: &str) -> std::result::Result<Self, Self::Err> {
+        match scheme {
+            "mbr" => Ok(PartitionScheme::Mbr),
+            "gpt" => Ok(PartitionScheme::Gpt),
+            "image" => Ok(PartitionScheme::Image),
+            _ => Err(format!(
+                "Invalid partition scheme '{}', expected one of mbr, gpt, image",
+                scheme
+            )),
+        }
+    }
+}
+
+impl Display for PartitionScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionScheme::Mbr => write!(f, "MBR"),
+            PartitionScheme::Gpt => write!(f, "GPT"),
+            PartitionScheme::Image => write!(f, "the partition table of the image"),
+        }
+    }
+}
+
+// an interactive shell stage2 runs on the tty before flashing, killed after timeout_secs
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct PauseShell {
+    pub program: String,
+    pub timeout_secs: u64,
+}
+
+// pause flashing for pause_ms milliseconds after every interval bytes written
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub(crate) struct FlashPause {
+    pub pause_ms: u64,
+    pub interval: u64,
+}
+
+impl FromStr for FlashPause {
+    type Err = String;
+
+    fn from_str(pause: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid flash pause '{}', expected <MS>/<MIB>, eg. 500/64",
+                pause
+            )
+        };
+        let (pause_ms, interval) = pause.split_once('/').ok_or_else(invalid)?;
+        let pause_ms = pause_ms.trim().parse::<u64>().map_err(|_| invalid())?;
+        let interval = interval.trim().parse::<u64>().map_err(|_| invalid())?;
+        if interval == 0 {
+            return Err(invalid());
+        }
+        Ok(FlashPause {
+            pause_ms,
+            interval: interval * 1024 * 1024,
+        })
+    }
+}
+
+// abort flashing if less than bytes_per_sec are written during period_secs seconds
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub(crate) struct MinWriteSpeed {
+    pub bytes_per_sec: u64,
+    pub period_secs: u64,
+}
+
+const DEFAULT_WRITE_SPEED_PERIOD: u64 = 60;
+
+impl FromStr for MinWriteSpeed {
+    type Err = String;
+
+    fn from_str(speed: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid minimum write speed '{}', expected <KIB>[/<SECS>], eg. 1024/60",
+                speed
+            )
+        };
+        let (kib, period_secs) = match speed.split_once('/') {
+            Some((kib, secs)) => (kib, secs.trim().parse::<u64>().map_err(|_| invalid())?),
+            None => (speed, DEFAULT_WRITE_SPEED_PERIOD),
+        };
+        let kib = kib.trim().parse::<u64>().map_err(|_| invalid())?;
+        if kib == 0 || period_secs == 0 {
+            return Err(invalid());
+        }
+        Ok(MinWriteSpeed {
+            bytes_per_sec: kib * 1024,
+            period_secs,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct KeepDataPart {
+    pub start_lba: u64,
+    pub num_sectors: u64,
+}
+
+// a partition written from the image in place of the whole image
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct FlashPart {
+    pub name: String,
+    pub start_lba: u64,
+    pub num_sectors: u64,
+}
+
+// a filesystem image written into an existing partition of the flash device instead of the image
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct PartImage {
+    pub name: String,
+    pub path: PathBuf,
+    // gzip compressed, size is the uncompressed size
+    pub compressed: bool,
+    pub size: u64,
+    pub start_lba: u64,
+    pub num_sectors: u64,
+    // the sector size of the partition table, start_lba and num_sectors count these sectors
+    pub sector_size: u64,
+}
+
+// a labeled partition of the flash device listed again behind the image partitions
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct PreservePart {
+    pub label: String,
+    pub start_lba: u64,
+    pub num_sectors: u64,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) struct Stage2Config {
     pub log_dev: Option<LogDevice>,
     pub log_level: String,
+    // the level of stage2 output on the tty, log_level applies to the log files
+    pub console_log_level: String,
     pub flash_dev: PathBuf,
     pub pretend: bool,
     pub umount_parts: Vec<UmountPart>,
+    pub old_root_cleanup: OldRootCleanup,
     pub work_dir: PathBuf,
     pub image_path: PathBuf,
     pub config_path: PathBuf,
     pub backup_path: Option<PathBuf>,
-    pub tty: PathBuf,
+    pub tty: Option<PathBuf>,
+    pub keep_data_part: Option<KeepDataPart>,
+    pub expand_data: bool,
+    pub reboot_mode: RebootMode,
+    pub reboot_method: RebootMethod,
+    pub stage2_timeout: Option<u64>,
+    pub pause_shell: Option<PauseShell>,
+    pub stage2_netlog: Option<SocketAddr>,
+    pub old_root: PathBuf,
+    pub init_binary: PathBuf,
+    pub pretty_config: bool,
+    pub stream_image: bool,
+    pub progress_json: bool,
+    pub min_free_mem: u64,
+    // device specific boot settings were written to the work dir
+    pub boot_config: bool,
+    // applied instead of flashing the image if the flash device matches its base
+    pub delta_path: Option<PathBuf>,
+    // only these partitions are written from the image if present
+    pub flash_parts: Option<Vec<FlashPart>>,
+    // written in place of the image, the image is not copied to stage2
+    pub part_image: Option<PartImage>,
+    // added to the flashed partition table, sorted by start
+    pub preserve_parts: Option<Vec<PreservePart>>,
+    // the flashed partition table is converted to this scheme
+    pub convert_part_table: Option<PartitionScheme>,
+    // the flash device is a loop device stage1 attached to an image file, detached after flashing
+    pub flash_loop: bool,
+    // stage2 runs the device specific post flash fixups for it
+    pub device_type: DeviceType,
+    // copy the stage logs to the data partition, the stage1 log is read from the old root
+    pub keep_logs: bool,
+    pub stage1_log: Option<PathBuf>,
+    pub flash_pause: Option<FlashPause>,
+    pub min_write_speed: Option<MinWriteSpeed>,
+    // stage2 keeps appending progress events to this file on the old root
+    pub report_file: Option<PathBuf>,
 }
 
 #[allow(dead_code)]
@@ -41,6 +343,14 @@ impl Stage2Config {
         }
     }
 
+    pub fn tty(&self) -> Option<&Path> {
+        if let Some(tty) = &self.tty {
+            Some(tty.as_path())
+        } else {
+            None
+        }
+    }
+
     pub fn serialize(&self) -> Result<String> {
         Ok(serde_yaml::to_string(self)
             .upstream_with_context("Failed to deserialize stage2 config")?)
@@ -55,3 +365,29 @@ impl Stage2Config {
         &self.flash_dev
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reboot_method_from_str() {
+        assert_eq!(
+            RebootMethod::from_str("restart").unwrap(),
+            RebootMethod::Restart
+        );
+        assert_eq!(
+            RebootMethod::from_str("restart2:bootloader").unwrap(),
+            RebootMethod::Restart2("bootloader".to_string())
+        );
+        assert_eq!(
+            RebootMethod::from_str(" watchdog ").unwrap(),
+            RebootMethod::Watchdog
+        );
+        assert_eq!(
+            RebootMethod::from_str("/sbin/reboot -f").unwrap(),
+            RebootMethod::Command(vec!["/sbin/reboot".to_string(), "-f".to_string()])
+        );
+        assert!(RebootMethod::from_str("  ").is_err());
+    }
+}
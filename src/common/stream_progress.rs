@@ -2,7 +2,10 @@ use log::{debug, error, info, trace, warn, Level};
 use std::io::Read;
 use std::time::Instant;
 
-use crate::common::format_size_with_unit;
+use crate::common::{
+    format_size_with_unit,
+    progress::{emit, ProgressEvent},
+};
 
 pub(crate) struct StreamProgress<T> {
     input: T,
@@ -13,6 +16,7 @@ pub(crate) struct StreamProgress<T> {
     level: Level,
     start_time: Instant,
     done: bool,
+    download: bool,
 }
 
 impl<T: Read> StreamProgress<T> {
@@ -26,6 +30,22 @@ impl<T: Read> StreamProgress<T> {
             level,
             start_time: Instant::now(),
             done: false,
+            download: false,
+        }
+    }
+
+    // also emit download progress events when logging progress
+    pub fn report_download(mut self) -> StreamProgress<T> {
+        self.download = true;
+        self
+    }
+
+    fn emit_download(&self) {
+        if self.download {
+            emit(ProgressEvent::Download {
+                bytes: self.bytes_read,
+                total: self.size,
+            });
         }
     }
 }
@@ -60,6 +80,7 @@ impl<T: Read> Read for StreamProgress<T> {
                     Level::Error => error!("{}", printout),
                     Level::Info => info!("{}", printout),
                 }
+                self.emit_download();
 
                 self.done = true;
             }
@@ -96,6 +117,7 @@ impl<T: Read> Read for StreamProgress<T> {
                 Level::Error => error!("{}", printout),
                 Level::Info => info!("{}", printout),
             }
+            self.emit_download();
         }
         Ok(curr_bytes_read)
     }
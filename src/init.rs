@@ -1,8 +1,10 @@
 use crate::{
     common::{
-        call,
-        defs::{MOUNT_CMD, NIX_NONE, PIVOT_ROOT_CMD, TAKEOVER_DIR},
-        get_mountpoint, path_append, whereis, Error, Result, ToError,
+        call, check_free_mem,
+        defs::{MOUNT_CMD, NIX_NONE, TAKEOVER_DIR},
+        get_mountpoint, path_append,
+        split_log::SplitLog,
+        whereis, Error, Result, ToError,
     },
     stage2::{read_stage2_config, reboot},
     ErrorKind,
@@ -13,28 +15,38 @@ use nix::{
     errno::{errno, Errno},
     fcntl::{fcntl, F_GETFD},
     mount::{mount, umount, MsFlags},
-    unistd::sync,
+    unistd::{pivot_root, sync},
 };
 use std::env::set_current_dir;
 use std::ffi::CString;
-use std::fs::create_dir_all;
-use std::io;
+use std::fs::{create_dir_all, read_to_string, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::iter::once;
 use std::mem::MaybeUninit;
 use std::os::raw::c_int;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str::FromStr;
-use std::thread::sleep;
+use std::thread::{self, sleep};
 use std::time::Duration;
 
 use crate::common::stage2_config::LogDevice;
 use libc::{
-    close, dup2, getpid, open, pipe, sigfillset, sigprocmask, sigset_t, wait, O_CREAT, O_TRUNC,
-    O_WRONLY, SIG_BLOCK, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO,
+    close, dup2, getpid, open, pipe, pipe2, sigfillset, sigprocmask, sigset_t, wait,
+    F_DUPFD_CLOEXEC, O_CLOEXEC, O_CREAT, O_NOCTTY, O_TRUNC, O_WRONLY, SIG_BLOCK, STDERR_FILENO,
+    STDIN_FILENO, STDOUT_FILENO,
 };
 
 const INITIAL_LOG_LEVEL: Level = Level::Trace;
+const PROC_MOUNTINFO: &str = "/proc/self/mountinfo";
+// mounted in the new root by stage1
+const NEW_ROOT_MOUNTS: [&str; 3] = ["proc", "sys", "dev"];
+const PIVOT_ROOT_ATTEMPTS: u32 = 5;
+const PIVOT_ROOT_RETRY_DELAY: Duration = Duration::from_secs(1);
 
-fn setup_log(log_dev: &LogDevice) -> Result<()> {
+fn setup_log(log_dev: &LogDevice, file_level: Level, console_level: Level) -> Result<()> {
     trace!(
         "setup_log entered with '{}', fs type: {}",
         log_dev.dev_name.display(),
@@ -81,8 +93,21 @@ fn setup_log(log_dev: &LogDevice) -> Result<()> {
         );
 
         let logfile = path_append(&mountpoint, "stage2-init.log");
-        Logger::set_log_file(&LogDestination::Stderr, &logfile, false)
-            .upstream_with_context(&format!("Failed set log file to  '{}'", logfile.display()))?;
+        let mut file = File::create(&logfile)
+            .upstream_with_context(&format!("Failed to create file: '{}'", logfile.display()))?;
+        Logger::flush();
+        if let Some(buffer) = Logger::get_buffer() {
+            file.write_all(buffer.as_slice())
+                .upstream_with_context(&format!(
+                    "Failed to write buffers to file: '{}'",
+                    logfile.display()
+                ))?;
+        }
+        Logger::set_log_dest(
+            &LogDestination::Stream,
+            Some(SplitLog::new(file, file_level, console_level)),
+        )
+        .upstream_with_context(&format!("Failed set log file to  '{}'", logfile.display()))?;
         info!(
             "Now logging to '{}' on '{}'",
             logfile.display(),
@@ -201,6 +226,267 @@ fn close_fds() -> Result<i32> {
     Ok(close_count)
 }
 
+// output on a pts or another terminal that goes away with stage1 would block or fail
+fn is_console(tty: &Path) -> bool {
+    let tty = tty.to_string_lossy();
+    if tty == "/dev/console" || tty.starts_with("/dev/ttyS") {
+        true
+    } else if let Some(num) = tty.strip_prefix("/dev/tty") {
+        !num.is_empty() && num.chars().all(|c| c.is_ascii_digit())
+    } else {
+        false
+    }
+}
+
+// copy everything written to fd to its log file and to the console, failing console writes are ignored
+fn tee_fd(fd: c_int, console: &File) -> Result<()> {
+    let log_fd = unsafe { libc::fcntl(fd, F_DUPFD_CLOEXEC, 0) };
+    if log_fd < 0 {
+        return Err(Error::with_context(
+            ErrorKind::Upstream,
+            &format!(
+                "Failed to dup fd {}, error: {}",
+                fd,
+                io::Error::last_os_error()
+            ),
+        ));
+    }
+    let mut log_file = unsafe { File::from_raw_fd(log_fd) };
+
+    let mut pipe_fds: [c_int; 2] = [0; 2];
+    if unsafe { pipe2(pipe_fds.as_mut_ptr(), O_CLOEXEC) } < 0 {
+        return Err(Error::with_context(
+            ErrorKind::Upstream,
+            &format!(
+                "Failed to create pipe for fd {}, error: {}",
+                fd,
+                io::Error::last_os_error()
+            ),
+        ));
+    }
+    let mut pipe_rd = unsafe { File::from_raw_fd(pipe_fds[0]) };
+    // dup2 clears close-on-exec, so stage2 inherits the write end as stdout / stderr
+    let sys_rc = unsafe { dup2(pipe_fds[1], fd) };
+    unsafe { close(pipe_fds[1]) };
+    if sys_rc < 0 {
+        return Err(Error::with_context(
+            ErrorKind::Upstream,
+            &format!(
+                "Failed to dup2 pipe write handle to fd {}, error: {}",
+                fd,
+                io::Error::last_os_error()
+            ),
+        ));
+    }
+
+    let mut console = console
+        .try_clone()
+        .upstream_with_context("Failed to clone console handle")?;
+    thread::spawn(move || {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match pipe_rd.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(count) => {
+                    let _res = log_file.write_all(&buffer[..count]);
+                    let _res = console.write_all(&buffer[..count]);
+                }
+                Err(why) if why.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+/******************************************************************
+ * Send stdout and stderr to the console as well as to the log
+ * files. Only a real console is used, a pts from an ssh session
+ * hangs up when stage1's session goes away. The tee threads live
+ * in init, which never exits, and stage2 inherits the pipes.
+ ******************************************************************/
+
+fn tee_to_console(tty: &Path) -> Result<()> {
+    if !is_console(tty) {
+        warn!(
+            "'{}' is not a console, stage2 output is only written to the log files",
+            tty.display()
+        );
+        return Ok(());
+    }
+
+    let console = OpenOptions::new()
+        .write(true)
+        .custom_flags(O_NOCTTY)
+        .open(tty)
+        .upstream_with_context(&format!("Failed to open console '{}'", tty.display()))?;
+    tee_fd(STDOUT_FILENO, &console)?;
+    tee_fd(STDERR_FILENO, &console)
+}
+
+// (mountpoint, shared) of all mounts in mount order, octal escapes are not decoded
+fn read_mountinfo() -> Result<Vec<(PathBuf, bool)>> {
+    let mountinfo = read_to_string(PROC_MOUNTINFO)
+        .upstream_with_context(&format!("Failed to read '{}'", PROC_MOUNTINFO))?;
+    Ok(mountinfo
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // the optional fields in front of the separator carry the propagation type
+            let separator = fields.iter().position(|field| *field == "-")?;
+            let mountpoint = fields.get(4)?;
+            let shared = fields
+                .get(6..separator)
+                .unwrap_or(&[])
+                .iter()
+                .any(|field| field.starts_with("shared:"));
+            Some((PathBuf::from(mountpoint), shared))
+        })
+        .collect())
+}
+
+// the new root and the filesystems stage1 mounted in it have to be mounted and private
+fn check_pivot_mounts(new_root: &Path) -> Result<()> {
+    let mounts = read_mountinfo()?;
+    let required =
+        once(new_root.to_path_buf()).chain(NEW_ROOT_MOUNTS.iter().map(|dir| new_root.join(dir)));
+    for mountpoint in required {
+        // the last mount on a mountpoint covers the ones before it
+        match mounts.iter().rev().find(|(curr, _)| *curr == mountpoint) {
+            Some((_, false)) => (),
+            Some((_, true)) => {
+                return Err(Error::with_context(
+                    ErrorKind::InvState,
+                    &format!(
+                        "'{}' is still a shared mount after making / private",
+                        mountpoint.display()
+                    ),
+                ))
+            }
+            None => {
+                return Err(Error::with_context(
+                    ErrorKind::InvState,
+                    &format!("'{}' is not mounted", mountpoint.display()),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+// pivot_root fails with EBUSY if something is mounted on the old root directory
+fn describe_busy(new_root: &Path, old_root: &str) -> String {
+    let put_old = new_root.join(old_root);
+    match read_mountinfo() {
+        Ok(mounts) => {
+            let busy: Vec<String> = mounts
+                .iter()
+                .filter(|(mountpoint, _)| mountpoint.starts_with(&put_old))
+                .map(|(mountpoint, _)| format!("'{}'", mountpoint.display()))
+                .collect();
+            if busy.is_empty() {
+                format!(
+                    "nothing is mounted on '{}', the mount of '{}' has not settled",
+                    put_old.display(),
+                    new_root.display()
+                )
+            } else {
+                format!(
+                    "{} mounted on the old root directory '{}'",
+                    busy.join(", "),
+                    put_old.display()
+                )
+            }
+        }
+        Err(why) => format!("the mounts could not be read: {}", why),
+    }
+}
+
+/******************************************************************
+ * Make all mounts private and pivot into the new root, the current
+ * directory. A pivot_root failing with EBUSY while mounts settle is
+ * retried, naming the busy mount. The pivot is verified in
+ * /proc/self/mountinfo, the old root has to be mounted on old_root
+ * below the new root afterwards.
+ ******************************************************************/
+
+fn pivot_new_root(mount_cmd: &str, old_root: &str) -> Result<()> {
+    let new_root = Path::new(TAKEOVER_DIR);
+    let mut attempt = 1;
+    loop {
+        call_command!(
+            mount_cmd,
+            &["--make-rprivate", "/"],
+            &format!("Failed to call '{} --make-rprivate /'", mount_cmd)
+        )?;
+        check_pivot_mounts(new_root)?;
+
+        match pivot_root(".", old_root) {
+            Ok(_) => break,
+            Err(nix::Error::Sys(Errno::EBUSY)) if attempt < PIVOT_ROOT_ATTEMPTS => {
+                warn!(
+                    "pivot_root failed with EBUSY on attempt {} of {}, {}",
+                    attempt,
+                    PIVOT_ROOT_ATTEMPTS,
+                    describe_busy(new_root, old_root)
+                );
+                attempt += 1;
+                sleep(PIVOT_ROOT_RETRY_DELAY);
+            }
+            Err(nix::Error::Sys(Errno::EBUSY)) => {
+                return Err(Error::with_context(
+                    ErrorKind::InvState,
+                    &format!(
+                        "pivot_root failed with EBUSY {} times, {}",
+                        PIVOT_ROOT_ATTEMPTS,
+                        describe_busy(new_root, old_root)
+                    ),
+                ))
+            }
+            Err(why) => {
+                return Err(Error::with_context(
+                    ErrorKind::Upstream,
+                    &format!(
+                        "pivot_root to '{}' with old root '{}' failed, error: {}",
+                        new_root.display(),
+                        old_root,
+                        why
+                    ),
+                ))
+            }
+        }
+    }
+
+    let old_root_mount = Path::new("/").join(old_root);
+    if read_mountinfo()?
+        .iter()
+        .any(|(mountpoint, _)| *mountpoint == old_root_mount)
+    {
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "pivot_root succeeded but the old root is not mounted on '{}'",
+                old_root_mount.display()
+            ),
+        ))
+    }
+}
+
+fn parse_level(level: &str) -> Level {
+    match Level::from_str(level) {
+        Ok(level) => level,
+        Err(why) => {
+            warn!(
+                "Failed to read log level from '{}', error: {:?}",
+                level, why
+            );
+            INITIAL_LOG_LEVEL
+        }
+    }
+}
+
 #[allow(clippy::cognitive_complexity)]
 pub fn init() -> ! {
     Logger::set_default_level(INITIAL_LOG_LEVEL);
@@ -238,15 +524,10 @@ pub fn init() -> ! {
     };
     info!("Stage 2 config was read successfully");
 
-    match Level::from_str(&s2_config.log_level) {
-        Ok(level) => Logger::set_default_level(level),
-        Err(why) => {
-            warn!(
-                "Failed to read log level from '{}', error: {:?}",
-                s2_config.log_level, why
-            );
-        }
-    }
+    let file_level = parse_level(&s2_config.log_level);
+    let console_level = parse_level(&s2_config.console_log_level);
+    // the split log drops records a destination does not want
+    Logger::set_default_level(file_level.max(console_level));
 
     let closed_fds = match close_fds() {
         Ok(fds) => fds,
@@ -257,8 +538,18 @@ pub fn init() -> ! {
     };
     info!("Stage 2 closed {} fd's", closed_fds);
 
+    if let Some(tty) = s2_config.tty() {
+        if let Err(why) = tee_to_console(tty) {
+            warn!(
+                "Failed to send output to '{}', error: {}",
+                tty.display(),
+                why
+            );
+        }
+    }
+
     let ext_log = if let Some(log_dev) = s2_config.log_dev() {
-        match setup_log(log_dev) {
+        match setup_log(log_dev, file_level, console_level) {
             Ok(_) => true,
             Err(why) => {
                 error!("Setup log failed, error: {:?}", why);
@@ -274,17 +565,24 @@ pub fn init() -> ! {
     Logger::flush();
     sync();
 
+    // running out of memory after pivot_root can not be recovered from, rebooting still boots the old system
+    if let Err(why) = check_free_mem(s2_config.min_free_mem, "Before pivot_root") {
+        error!("{}", why);
+        reboot();
+    }
+
+    // pivot_root is called from the new root, so old_root has to be relative
+    let old_root = s2_config
+        .old_root
+        .strip_prefix("/")
+        .unwrap_or(&s2_config.old_root)
+        .to_string_lossy()
+        .to_string();
+
     match whereis(MOUNT_CMD) {
         Ok(mount_cmd) => {
-            if let Err(why) = call_command!(
-                mount_cmd.as_str(),
-                &["--make-rprivate", "/"],
-                "failed to mount / private"
-            ) {
-                error!(
-                    "Failed to call '{} --make-rprivate /' error: {}",
-                    mount_cmd, why
-                );
+            if let Err(why) = pivot_new_root(&mount_cmd, &old_root) {
+                error!("Failed to pivot root, error: {}", why);
                 reboot();
             }
         }
@@ -293,32 +591,19 @@ pub fn init() -> ! {
             reboot();
         }
     }
+    info!(
+        "Pivoted root to '{}', the old root is '/{}'",
+        TAKEOVER_DIR, old_root
+    );
 
-    match whereis(PIVOT_ROOT_CMD) {
-        Ok(pivot_root_cmd) => {
-            if let Err(why) = call_command!(
-                pivot_root_cmd.as_str(),
-                &[".", "mnt/old_root"],
-                "Failed to pivot root"
-            ) {
-                error!(
-                    "Failed to call '{} . mnt/old_root' error: {}",
-                    pivot_root_cmd, why
-                );
-                reboot();
-            }
-        }
-        Err(why) => {
-            error!(
-                "Failed to locate '{}' command, error: {}",
-                PIVOT_ROOT_CMD, why
-            );
-            reboot();
-        }
-    }
-
-    let _child_pid = match Command::new(&format!("/bin/{}", env!("CARGO_PKG_NAME")))
-        .args(&["--stage2", "--s2-log-level", &s2_config.log_level])
+    let _child_pid = match Command::new(&s2_config.init_binary)
+        .args([
+            "--stage2",
+            "--s2-log-level",
+            &s2_config.log_level,
+            "--s2-console-log-level",
+            &s2_config.console_log_level,
+        ])
         .spawn()
     {
         Ok(cmd_res) => cmd_res.id(),
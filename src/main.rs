@@ -6,15 +6,20 @@ mod stage1;
 mod stage2;
 
 use log::error;
+use std::panic;
 use std::process::exit;
 
 use mod_logger::Logger;
-use structopt::StructOpt;
 
 use crate::{
-    common::{error::ErrorKind, Options},
+    common::{
+        error::{report_post_pivot_failure, ErrorKind},
+        log,
+        progress::JsonObserver,
+        Options,
+    },
     init::init,
-    stage1::stage1,
+    stage1::{run_with_observer, stage1},
     stage2::stage2,
 };
 
@@ -23,19 +28,48 @@ fn is_init() -> bool {
     pid == 1
 }
 
+// a panic must not take the buffered log with it, especially during stage2
+fn set_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |panic_info| {
+        error!("{} panicked: {}", env!("CARGO_PKG_NAME"), panic_info);
+        Logger::flush();
+        if let Some(buffer) = Logger::get_buffer() {
+            if !buffer.is_empty() {
+                log(&String::from_utf8_lossy(&buffer));
+            }
+        }
+        log(&format!(
+            "{} panicked: {}",
+            env!("CARGO_PKG_NAME"),
+            panic_info
+        ));
+        default_hook(panic_info);
+    }));
+}
+
 fn main() {
     let mut exit_code = 0;
 
+    set_panic_hook();
+
     if is_init() {
         init();
     } else {
-        let opts = Options::from_args();
+        let opts = Options::from_args_and_env();
 
-        if opts.stage2() {
+        let res = if opts.stage2() {
             stage2(&opts);
-        } else if let Err(why) = stage1(&opts) {
-            exit_code = 1;
+        } else if opts.progress_json() {
+            run_with_observer(&opts, Box::new(JsonObserver))
+        } else {
+            stage1(&opts)
+        };
+
+        if let Err(why) = res {
+            exit_code = why.exit_code();
             match why.kind() {
+                _ if why.is_post_pivot() => report_post_pivot_failure(&why),
                 ErrorKind::Displayed => (),
                 _ => error!("Migrate stage 1 returned an error: {}", why),
             };
@@ -1,20 +1,25 @@
 mod backup;
 
 use std::env::set_current_dir;
-use std::fs::{copy, create_dir, create_dir_all, read_dir, read_link, remove_dir_all, OpenOptions};
-use std::io::Write;
-use std::os::unix::fs::symlink;
-use std::path::{Path, PathBuf};
+use std::fs::{
+    copy, create_dir, create_dir_all, read_dir, read_link, read_to_string, remove_dir_all, File,
+    OpenOptions,
+};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::{symlink, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 use std::rc::Rc;
 use std::thread::sleep;
 use std::time::Duration;
 
+use flate2::read::GzDecoder;
 use nix::{
     mount::{mount, MsFlags},
-    unistd::sync,
+    unistd::{isatty, sync},
 };
 
-use libc::MS_BIND;
+use libc::{MS_BIND, O_NOCTTY, O_NONBLOCK, STDOUT_FILENO};
 
 use log::{debug, error, info, warn, Level};
 
@@ -22,43 +27,107 @@ pub(crate) mod migrate_info;
 
 mod api_calls;
 mod block_device_info;
-mod defs;
-mod device;
-mod device_impl;
+pub(crate) mod defs;
+pub(crate) mod device;
+pub(crate) mod device_impl;
 
 mod exe_copy;
 
+mod cleanup;
+mod detect;
+mod device_report;
+mod image_cache;
 mod image_retrieval;
+mod init_system;
+mod partition_backup;
+mod plan;
+mod probe;
+mod resume;
+mod self_test;
+mod selinux;
+mod status_report;
+mod timeout;
 mod utils;
+mod verify_boot;
 mod wifi_config;
 
 use crate::{
     common::{
-        call,
+        call, check_free_mem,
         defs::{
-            NIX_NONE, OLD_ROOT_MP, STAGE2_CONFIG_NAME, SWAPOFF_CMD, SYSTEM_CONNECTIONS_DIR,
-            SYS_EFIVARS_DIR, SYS_EFI_DIR, TELINIT_CMD,
+            BALENA_BOOT_FSTYPE, BALENA_DATA_FSTYPE, BALENA_DATA_PART, BOOT_CONFIG_NAME, NIX_NONE,
+            STAGE2_CONFIG_NAME, SWAPOFF_CMD, SYSTEM_CONNECTIONS_DIR, SYS_EFIVARS_DIR, SYS_EFI_DIR,
+            TELINIT_CMD,
+        },
+        delta::open_delta,
+        disk_util::{
+            check_data_part_layout, check_image_part_table, check_part_layout, get_block_sizes,
+            get_device_size, get_image_layout_end, Disk, PartTable, PartitionIterator,
+            BALENA_DATA_PART_IDX, BALENA_PART_NAMES, DEF_BLOCK_SIZE,
         },
         error::{Error, ErrorKind, Result, ToError},
-        file_exists, format_size_with_unit, get_mem_info, is_admin,
+        file_exists, format_size_with_unit, get_mem_info,
+        loop_device::LoopDevice,
         options::Options,
         path_append,
-        stage2_config::{Stage2Config, UmountPart},
+        progress::{
+            add_observer, emit, set_observer, FileObserver, ProgressEvent, ProgressObserver, Stage,
+        },
+        stage2_config::{
+            FlashPart, KeepDataPart, OldRootCleanup, PartImage, PartitionScheme, PauseShell,
+            PreservePart, Stage2Config, UmountPart,
+        },
         system::copy_dir,
     },
     stage1::{
-        block_device_info::BlockDevice, block_device_info::BlockDeviceInfo, exe_copy::ExeCopy,
-        migrate_info::MigrateInfo, utils::mount_fs,
+        block_device_info::BlockDevice,
+        block_device_info::BlockDeviceInfo,
+        cleanup::{cleanup, cleanup_staged, set_swap_disabled, set_takeover_dir_used},
+        defs::{BootMode, GZIP_MAGIC_COOKIE},
+        detect::detect_only,
+        device_report::report_device_info,
+        exe_copy::{Assets, ExeCopy},
+        image_retrieval::fetch_delta,
+        init_system::InitSystem,
+        migrate_info::MigrateInfo,
+        partition_backup::backup_partition_table,
+        plan::MigrationPlan,
+        probe::probe,
+        resume::clean_staged,
+        self_test::{self_test, test_tmpfs},
+        status_report::{MigrationStatus, StatusReporter},
+        timeout::Stage1Timeout,
+        utils::{
+            check_privileges, find_missing_commands, get_boot_mode, get_os_arch, mount_fs,
+            mount_fs_with_options, required_commands,
+        },
+        verify_boot::verify_boot,
     },
 };
 
-use crate::common::defs::{DD_CMD, EFIBOOTMGR_CMD, TAKEOVER_DIR};
-use crate::common::dir_exists;
+use crate::common::defs::{
+    BUSYBOX_CMD, DD_CMD, E2FSCK_CMD, EFIBOOTMGR_CMD, RESIZE2FS_CMD, SGDISK_CMD, SH_CMD,
+    TAKEOVER_DIR,
+};
 use crate::common::stage2_config::LogDevice;
-use crate::common::system::{is_dir, mkdir, stat};
+use crate::common::system::{is_chr, is_dir, mkdir, stat};
+use crate::common::{dir_exists, whereis};
 use mod_logger::{LogDestination, Logger, NO_STREAM};
 
 const S1_XTRA_FS_SIZE: u64 = 10 * 1024 * 1024; // const XTRA_MEM_FREE: u64 = 10 * 1024 * 1024; // 10 MB
+const REQUIRED_COMMANDS_CHECK: &str = "external commands";
+const KERNEL_SUPPORT_CHECK: &str = "kernel support";
+const PROC_FILESYSTEMS: &str = "/proc/filesystems";
+// rootA in the balena-os layout
+const IMAGE_ROOT_PART_IDX: usize = 2;
+const PROC_MOUNTS: &str = "/proc/mounts";
+// the partitions a MBR can hold without an extended partition
+const MBR_PRIMARY_PARTS: usize = 4;
+// the sectors a GPT with 128 entries takes up in front of the partitions and the backup behind them
+const GPT_HEADER_SECTORS: u64 = 34;
+const GPT_BACKUP_SECTORS: u64 = 33;
+// boot filesystems of the flash device are mounted here besides /
+const BOOT_MOUNT: &str = "/boot";
 
 fn prepare_configs<P1: AsRef<Path>>(
     work_dir: P1,
@@ -97,49 +166,1447 @@ fn prepare_configs<P1: AsRef<Path>>(
         wifi_config.create_nwmgr_file(&nwmgr_path, nwmgr_cfgs)?;
     }
 
+    // *********************************************************
+    // write device specific boot settings to tmpfs
+    if let Some(boot_config) = mig_info.boot_config() {
+        let boot_config_path = path_append(work_dir, BOOT_CONFIG_NAME);
+        let mut boot_config_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&boot_config_path)
+            .upstream_with_context(&format!(
+                "Failed to open '{}' for writing",
+                boot_config_path.display()
+            ))?;
+        boot_config_file
+            .write_all(boot_config.as_bytes())
+            .upstream_with_context(&format!(
+                "Failed to write boot settings to '{}'",
+                boot_config_path.display()
+            ))?;
+        info!("Wrote boot settings to '{}'", boot_config_path.display());
+    }
+
+    Ok(())
+}
+
+fn get_umount_parts(
+    flash_dev: &Rc<dyn BlockDevice>,
+    block_dev_info: &BlockDeviceInfo,
+) -> Result<Vec<UmountPart>> {
+    let mut umount_parts: Vec<UmountPart> = Vec::new();
+
+    for device in block_dev_info.get_devices().values() {
+        if let Some(parent) = device.get_parent() {
+            // this is a partition rather than a device
+            if parent.get_name() == flash_dev.get_name() {
+                // it is a partition of the flash device
+                if let Some(mount) = device.get_mountpoint() {
+                    let mut inserted = false;
+                    for (idx, mpoint) in umount_parts.iter().enumerate() {
+                        if mpoint.mountpoint.starts_with(mount.get_mountpoint()) {
+                            umount_parts.insert(
+                                idx,
+                                UmountPart {
+                                    dev_name: device.get_dev_path().to_path_buf(),
+                                    mountpoint: PathBuf::from(mount.get_mountpoint()),
+                                    fs_type: mount.get_fs_type().to_string(),
+                                },
+                            );
+                            inserted = true;
+                            break;
+                        }
+                    }
+                    if !inserted {
+                        umount_parts.push(UmountPart {
+                            dev_name: device.get_dev_path().to_path_buf(),
+                            mountpoint: PathBuf::from(mount.get_mountpoint()),
+                            fs_type: mount.get_fs_type().to_string(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    umount_parts.reverse();
+    Ok(umount_parts)
+}
+
+// a regular file given as flash device is attached to a loop device, eg. to test migrations
+fn attach_flash_file(opts: &Options) -> Result<Option<LoopDevice>> {
+    let flash_to = if let Some(flash_to) = opts.flash_to() {
+        flash_to
+    } else {
+        return Ok(None);
+    };
+
+    // a missing device is reported when the flash device is looked up
+    match flash_to.metadata() {
+        Ok(metadata) if metadata.is_file() => (),
+        _ => return Ok(None),
+    }
+
+    let loop_device = LoopDevice::for_file(flash_to, None, None, None, true)
+        .upstream_with_context(&format!(
+            "Failed to attach image file '{}' to a loop device",
+            flash_to.display()
+        ))?;
+
+    info!(
+        "Flashing image file '{}' through loop device '{}'",
+        flash_to.display(),
+        loop_device.get_path().display()
+    );
+    Ok(Some(loop_device))
+}
+
+fn is_on_flash_dev(dev: &Rc<dyn BlockDevice>, flash_dev: &Rc<dyn BlockDevice>) -> bool {
+    dev.get_name() == flash_dev.get_name()
+        || matches!(dev.get_parent(), Some(parent) if parent.get_name() == flash_dev.get_name())
+}
+
+/******************************************************************
+ * Find read-write mounts of the flash device other than the root
+ * and boot filesystems, eg. a data partition in use by another
+ * process. Flashing the device under such a mount corrupts it.
+ ******************************************************************/
+
+fn check_flash_dev_mounts(
+    opts: &Options,
+    flash_dev: &Rc<dyn BlockDevice>,
+    block_dev_info: &BlockDeviceInfo,
+) -> Result<()> {
+    let mounts = read_to_string(PROC_MOUNTS)
+        .upstream_with_context(&format!("Failed to read '{}'", PROC_MOUNTS))?;
+
+    let mut unexpected: Vec<String> = Vec::new();
+    for line in mounts.lines() {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        if columns.len() < 4 {
+            continue;
+        }
+        let (source, mountpoint, mount_opts) = (columns[0], Path::new(columns[1]), columns[3]);
+
+        if !mount_opts.split(',').any(|opt| opt == "rw")
+            || mountpoint == Path::new("/")
+            || mountpoint.starts_with(BOOT_MOUNT)
+        {
+            continue;
+        }
+
+        // the device of a mount is the device of its root directory, this also catches bind mounts
+        if let Ok(Some(mount_dev)) = block_dev_info.get_device_for_path(mountpoint) {
+            if is_on_flash_dev(mount_dev, flash_dev) {
+                unexpected.push(format!("'{}' ({})", mountpoint.display(), source));
+            }
+        }
+    }
+
+    if unexpected.is_empty() {
+        Ok(())
+    } else if opts.force() {
+        warn!(
+            "The flash device '{}' is mounted read-write on {}, proceeding due to --force",
+            flash_dev.get_dev_path().display(),
+            unexpected.join(", ")
+        );
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "The flash device '{}' is mounted read-write on {}, unmount these filesystems first or use --force",
+                flash_dev.get_dev_path().display(),
+                unexpected.join(", ")
+            ),
+        ))
+    }
+}
+
+// stage2 reads the image while writing the flash device, so the image must live elsewhere
+fn check_stream_image(
+    image_path: &Path,
+    flash_dev: &Rc<dyn BlockDevice>,
+    block_dev_info: &BlockDeviceInfo,
+) -> Result<()> {
+    if let Some(image_dev) = block_dev_info.get_device_for_path(image_path)? {
+        if is_on_flash_dev(image_dev, flash_dev) {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Cannot stream the image '{}', it resides on the flash device '{}', drop --stream-image or move the image to a different device",
+                    image_path.display(),
+                    flash_dev.get_dev_path().display()
+                ),
+            ));
+        }
+        info!(
+            "Streaming the image from '{}' on '{}'",
+            image_path.display(),
+            image_dev.get_dev_path().display()
+        );
+    } else {
+        warn!(
+            "Could not determine the device holding the image '{}', make sure it does not reside on the flash device",
+            image_path.display()
+        );
+    }
+    Ok(())
+}
+
+// the backup is useless if the image it is meant to undo overwrites it
+fn save_partition_table(
+    backup_path: &Path,
+    flash_dev: &Rc<dyn BlockDevice>,
+    block_dev_info: &BlockDeviceInfo,
+) -> Result<()> {
+    let backup_dir = match backup_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    if let Some(backup_dev) = block_dev_info.get_device_for_path(backup_dir)? {
+        if is_on_flash_dev(backup_dev, flash_dev) {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Cannot save the partition table backup to '{}', it resides on the flash device '{}', choose a location on a different device",
+                    backup_path.display(),
+                    flash_dev.get_dev_path().display()
+                ),
+            ));
+        }
+    } else {
+        warn!(
+            "Could not determine the device holding '{}', make sure it does not reside on the flash device",
+            backup_path.display()
+        );
+    }
+
+    backup_partition_table(&flash_dev.get_dev_path(), backup_path)
+}
+
+// the device the running OS resides on. The board's install device is only
+// taken if it is that device, any other disk has to be chosen with --flash-to
+fn get_default_flash_dev<'a>(
+    mig_info: &MigrateInfo,
+    block_dev_info: &'a BlockDeviceInfo,
+) -> &'a Rc<dyn BlockDevice> {
+    let root_dev = block_dev_info.get_root_device();
+    let default_dev = if let Some(default_dev) = mig_info.default_target_device() {
+        default_dev
+    } else {
+        return root_dev;
+    };
+
+    match block_dev_info.get_devices().get(&default_dev) {
+        Some(flash_dev) if flash_dev.get_name() == root_dev.get_name() => info!(
+            "Flashing '{}', the install device of the {}",
+            default_dev.display(),
+            mig_info.device_type()
+        ),
+        Some(_) => warn!(
+            "Not flashing '{}', the install device of the {}, the running OS resides on '{}', flashing '{}', use --flash-to to choose the device",
+            default_dev.display(),
+            mig_info.device_type(),
+            root_dev.get_dev_path().display(),
+            root_dev.get_dev_path().display()
+        ),
+        None => warn!(
+            "The install device of the {} '{}' was not found, flashing '{}' the running OS resides on",
+            mig_info.device_type(),
+            default_dev.display(),
+            root_dev.get_dev_path().display()
+        ),
+    }
+    root_dev
+}
+
+// the flash device given with --flash-to or the loop device of an image file, the default device otherwise
+fn get_flash_dev<'a>(
+    flash_dev_path: Option<&Path>,
+    mig_info: &MigrateInfo,
+    block_dev_info: &'a BlockDeviceInfo,
+) -> Result<&'a Rc<dyn BlockDevice>> {
+    let flash_dev = if let Some(flash_dev) = flash_dev_path {
+        if let Some(flash_dev) = block_dev_info.get_devices().get(flash_dev) {
+            flash_dev
+        } else if let Some(mapping) = BlockDeviceInfo::get_crypt_mapping_name(flash_dev) {
+            // writing to the mapping would encrypt the image with the key of the old OS
+            error!(
+                "The flash device '{}' is the dm-crypt mapping '{}', please give the physical device it resides on",
+                flash_dev.display(),
+                mapping
+            );
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Could not find configured flash device '{}'",
+                    flash_dev.display()
+                ),
+            ));
+        }
+    } else {
+        get_default_flash_dev(mig_info, block_dev_info)
+    };
+
+    if !file_exists(&flash_dev.as_ref().get_dev_path()) {
+        return Err(Error::with_context(
+            ErrorKind::DeviceNotFound,
+            &format!(
+                "The device could not be found: '{}'",
+                flash_dev.get_dev_path().display()
+            ),
+        ));
+    }
+
+    Ok(flash_dev)
+}
+
+// the event file stage2 appends to, it is written after flashing so it has to be on another device
+fn get_report_file(
+    opts: &Options,
+    flash_dev: &Rc<dyn BlockDevice>,
+    block_dev_info: &BlockDeviceInfo,
+) -> Result<Option<PathBuf>> {
+    let report_file = match (opts.report_to_file(), opts.report_file_stage2()) {
+        (Some(report_file), true) => report_file,
+        (None, true) => {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                "The option --report-file-stage2 requires --report-to-file",
+            ))
+        }
+        _ => return Ok(None),
+    };
+
+    let report_file = report_file.canonicalize().upstream_with_context(&format!(
+        "Failed to canonicalize event file '{}'",
+        report_file.display()
+    ))?;
+
+    if let Some(report_dev) = block_dev_info.get_device_for_path(&report_file)? {
+        if is_on_flash_dev(report_dev, flash_dev) {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Cannot write events to '{}' in stage2, it resides on the flash device '{}', choose a location on a different device or drop --report-file-stage2",
+                    report_file.display(),
+                    flash_dev.get_dev_path().display()
+                ),
+            ));
+        }
+    } else {
+        warn!(
+            "Could not determine the device holding '{}', make sure it does not reside on the flash device",
+            report_file.display()
+        );
+    }
+
+    Ok(Some(report_file))
+}
+
+/******************************************************************
+ * Compare the sector size the image was made for with the logical
+ * block size of the flash device and check the image partitions
+ * are aligned to its physical blocks and to --partition-alignment.
+ * Returns the sector size mismatches and the misaligned partitions
+ * separately.
+ ******************************************************************/
+
+fn get_geometry_problems(
+    opts: &Options,
+    image_path: &Path,
+    flash_dev: &Path,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let (logical, physical) = match get_block_sizes(flash_dev) {
+        Ok(block_sizes) => block_sizes,
+        Err(why) => {
+            warn!(
+                "Failed to determine the block sizes of '{}', error: {}",
+                flash_dev.display(),
+                why
+            );
+            (0, 0)
+        }
+    };
+    if logical > 0 {
+        info!(
+            "The flash device '{}' has a logical block size of {} bytes, a physical block size of {} bytes",
+            flash_dev.display(),
+            logical,
+            physical
+        );
+    }
+    let alignment = physical.max(opts.partition_alignment().unwrap_or(0));
+
+    let mut disk = Disk::from_gzip_img(image_path)?;
+    let mut mismatches: Vec<String> = Vec::new();
+    let mut misaligned: Vec<String> = Vec::new();
+    let sector_size = if let Some(sector_size) = disk.get_sector_size_hint()? {
+        sector_size
+    } else {
+        debug!(
+            "No sector size found in the partition table of '{}'",
+            image_path.display()
+        );
+        return Ok((mismatches, misaligned));
+    };
+
+    if logical > 0 && sector_size != logical {
+        mismatches.push(format!(
+            "the image uses {} byte sectors, the flash device {} byte logical blocks",
+            sector_size, logical
+        ));
+    }
+    if alignment > 0 {
+        for part in &disk.get_part_table()?.parts {
+            if part.start_lba.saturating_mul(sector_size) % alignment != 0 {
+                misaligned.push(format!(
+                    "image partition {} starting at sector {} is not aligned to {} bytes",
+                    part.index, part.start_lba, alignment
+                ));
+            }
+        }
+    }
+    Ok((mismatches, misaligned))
+}
+
+/******************************************************************
+ * Report a geometry mismatch between the image and the flash
+ * device. Mismatches are only warned about unless --strict-geometry
+ * is given, misaligned partitions also abort with
+ * --strict-alignment. A geometry that can not be checked only
+ * aborts with --strict-geometry.
+ ******************************************************************/
+
+fn check_geometry(opts: &Options, image_path: &Path, flash_dev: &Path) -> Result<()> {
+    let (mut mismatches, mut misaligned) = match get_geometry_problems(opts, image_path, flash_dev)
+    {
+        Ok(problems) => problems,
+        Err(why) if opts.strict_geometry() => return Err(why),
+        Err(why) => {
+            warn!(
+                "Failed to check the geometry of the flash device, error: {}",
+                why
+            );
+            return Ok(());
+        }
+    };
+
+    let strict = opts.strict_geometry() || (opts.strict_alignment() && !misaligned.is_empty());
+    mismatches.append(&mut misaligned);
+    if mismatches.is_empty() {
+        Ok(())
+    } else if strict {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "The image '{}' does not match the geometry of '{}': {}",
+                image_path.display(),
+                flash_dev.display(),
+                mismatches.join(", ")
+            ),
+        ))
+    } else {
+        for mismatch in mismatches {
+            warn!(
+                "Geometry mismatch between '{}' and '{}': {}",
+                image_path.display(),
+                flash_dev.display(),
+                mismatch
+            );
+        }
+        Ok(())
+    }
+}
+
+// running containers hold mounts and memory that get in the way of pivot_root
+fn stop_services(opts: &Options, init_system: InitSystem) -> Result<()> {
+    let timeout = Duration::from_secs(opts.stop_timeout());
+    for service in opts.stop_services() {
+        if init_system.is_running(service)? {
+            info!("Stopping service '{}'", service);
+            init_system.stop(service, timeout)?;
+        } else {
+            info!("Not stopping service '{}', it is not running", service);
+        }
+    }
+    Ok(())
+}
+
+// flashing an image the firmware can not start leaves the device unbootable
+// the filesystem type of the root partition in the image
+fn get_image_root_fs_type(image_path: &Path) -> Result<Option<&'static str>> {
+    let mut disk = Disk::from_gzip_img(image_path)?;
+    let root_part =
+        PartitionIterator::new(&mut disk)?.find(|part| part.index == IMAGE_ROOT_PART_IDX);
+    if let Some(root_part) = root_part {
+        disk.get_fs_type(root_part.start_lba)
+    } else {
+        Ok(None)
+    }
+}
+
+/******************************************************************
+ * Stage2 depends on the kernel for private mounts, pivot_root and
+ * chroot, which are tried on a scratch tmpfs, and for the
+ * filesystems of the RAMFS, of the flashed boot and data partitions
+ * and of the image root partition.
+ ******************************************************************/
+
+fn check_kernel_support(image_path: &Path) -> Result<String> {
+    let filesystems_txt = read_to_string(PROC_FILESYSTEMS)
+        .upstream_with_context(&format!("Failed to read '{}'", PROC_FILESYSTEMS))?;
+    // lines are '[nodev]<TAB>name'
+    let filesystems: Vec<&str> = filesystems_txt
+        .lines()
+        .filter_map(|line| line.split_whitespace().last())
+        .collect();
+
+    let mut required = vec!["tmpfs", BALENA_BOOT_FSTYPE, BALENA_DATA_FSTYPE];
+    match get_image_root_fs_type(image_path) {
+        Ok(Some(fs_type)) => {
+            if !required.contains(&fs_type) {
+                required.push(fs_type);
+            }
+        }
+        Ok(None) => warn!(
+            "Could not determine the filesystem of the root partition in the image '{}'",
+            image_path.display()
+        ),
+        Err(why) => warn!(
+            "Failed to determine the filesystem of the root partition in the image '{}', error: {}",
+            image_path.display(),
+            why
+        ),
+    }
+
+    // modules can not be loaded once stage2 has left the old root behind
+    let missing: Vec<&str> = required
+        .iter()
+        .filter(|fs_type| !filesystems.contains(fs_type))
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        return Err(Error::with_context(
+            ErrorKind::NotSupported,
+            &format!(
+                "The filesystems {} are not listed in {}, the running kernel does not support them or their modules are not loaded, eg. load them with 'modprobe {}'",
+                missing.join(", "),
+                PROC_FILESYSTEMS,
+                missing[0]
+            ),
+        ));
+    }
+
+    let pivot_msg = test_tmpfs()?;
+    Ok(format!(
+        "found filesystems {}, {}",
+        required.join(", "),
+        pivot_msg
+    ))
+}
+
+fn check_boot_mode(opts: &Options, mig_info: &MigrateInfo) -> Result<()> {
+    if !mig_info.is_x86() {
+        debug!("Not checking the boot mode, it only applies to x86 devices");
+        return Ok(());
+    }
+
+    let detected = get_boot_mode()?;
+    let boot_mode = match opts.boot_mode() {
+        BootMode::Auto => {
+            info!("Detected boot mode: {}", detected);
+            detected
+        }
+        boot_mode => {
+            if boot_mode != detected {
+                warn!(
+                    "Using boot mode {} instead of the detected boot mode {}",
+                    boot_mode, detected
+                );
+            }
+            boot_mode
+        }
+    };
+
+    let image_path = mig_info.image_path();
+    let boot_support = match Disk::from_gzip_img(image_path)
+        .and_then(|mut disk| disk.get_boot_support())
+    {
+        Ok(boot_support) => boot_support,
+        Err(why) => {
+            warn!(
+                    "Failed to determine the boot type of the image '{}', make sure it supports {} boot, error: {}",
+                    image_path.display(),
+                    boot_mode,
+                    why
+                );
+            return Ok(());
+        }
+    };
+
+    let supported = match boot_mode {
+        BootMode::Efi => boot_support.efi,
+        _ => boot_support.legacy,
+    };
+
+    if supported {
+        info!(
+            "The image '{}' supports {} boot",
+            image_path.display(),
+            boot_mode
+        );
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::NotSupported,
+            &format!(
+                "The image '{}' does not support {} boot, the device would not boot after flashing, use --boot-mode if the boot mode of the device is different",
+                image_path.display(),
+                boot_mode
+            ),
+        ))
+    }
+}
+
+fn get_keep_data_part(
+    flash_dev: &Rc<dyn BlockDevice>,
+    block_dev_info: &BlockDeviceInfo,
+    image_path: &Path,
+) -> Result<KeepDataPart> {
+    let data_dev = block_dev_info.get_devices().values().find(|device| {
+        if let Some(parent) = device.get_parent() {
+            if parent.get_name() == flash_dev.get_name() {
+                if let Some(partition_info) = device.get_partition_info() {
+                    return partition_info.fs_label() == Some(BALENA_DATA_PART);
+                }
+            }
+        }
+        false
+    });
+
+    let data_dev = if let Some(data_dev) = data_dev {
+        data_dev
+    } else {
+        error!(
+            "Cannot keep the data partition: no partition labeled '{}' was found on '{}'",
+            BALENA_DATA_PART,
+            flash_dev.get_dev_path().display()
+        );
+        return Err(Error::displayed_with(ErrorKind::InvState));
+    };
+
+    let (image_part, device_part) = match check_data_part_layout(
+        image_path,
+        &flash_dev.get_dev_path(),
+    ) {
+        Ok(res) => res,
+        Err(why) => {
+            error!(
+                "Cannot keep the data partition: the partition layout of the image is not compatible with '{}', error: {}",
+                flash_dev.get_dev_path().display(),
+                why
+            );
+            return Err(Error::displayed_with(ErrorKind::InvState));
+        }
+    };
+
+    // the partition number, a name like sda16 must not match index 6
+    let data_part_num = read_part_sectors(data_dev.get_name(), "partition")?;
+    if data_part_num != device_part.index as u64 {
+        error!(
+            "Cannot keep the data partition: partition '{}' labeled '{}' is not the data partition of the image layout",
+            data_dev.get_dev_path().display(),
+            BALENA_DATA_PART
+        );
+        return Err(Error::displayed_with(ErrorKind::InvState));
+    }
+
+    info!(
+        "Keeping data partition '{}' at sector {}, {} sectors, image data partition has {} sectors",
+        data_dev.get_dev_path().display(),
+        device_part.start_lba,
+        device_part.num_sectors,
+        image_part.num_sectors
+    );
+
+    Ok(KeepDataPart {
+        start_lba: device_part.start_lba,
+        num_sectors: device_part.num_sectors,
+    })
+}
+
+/******************************************************************
+ * Resolve --flash-partitions to the partitions of the image, they
+ * have to be laid out identically on the flash device. Flashing the
+ * boot partition or the first root partition without the other
+ * mixes versions and requires --force.
+ ******************************************************************/
+
+fn get_flash_parts(
+    opts: &Options,
+    flash_dev: &Rc<dyn BlockDevice>,
+    image_path: &Path,
+) -> Result<Vec<FlashPart>> {
+    if opts.keep_data_partition() || opts.delta().is_some() {
+        error!("--flash-partitions can not be combined with --keep-data-partition or --delta");
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    }
+
+    let mut names: Vec<&str> = Vec::new();
+    for name in opts.flash_partitions() {
+        let name = name.trim();
+        if !BALENA_PART_NAMES
+            .iter()
+            .any(|(part_name, _)| *part_name == name)
+        {
+            error!(
+                "Unknown partition '{}' in --flash-partitions, expected one of {}",
+                name,
+                BALENA_PART_NAMES
+                    .iter()
+                    .map(|(part_name, _)| *part_name)
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            );
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    if names.contains(&"boot") != names.contains(&"rootA") {
+        let message = "Flashing only one of the partitions boot and rootA combines the boot \
+            partition and operating system of different versions, the device might not boot";
+        if opts.force() {
+            warn!("{}", message);
+        } else {
+            error!("{}, use --force to flash anyway", message);
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+    }
+
+    let indices: Vec<usize> = names
+        .iter()
+        .filter_map(|name| {
+            BALENA_PART_NAMES
+                .iter()
+                .find(|(part_name, _)| part_name == name)
+                .map(|(_, index)| *index)
+        })
+        .collect();
+
+    let parts = match check_part_layout(image_path, &flash_dev.get_dev_path(), &indices) {
+        Ok(parts) => parts,
+        Err(why) => {
+            error!(
+                "Cannot flash partitions {}: the partition layout of the image does not match '{}', error: {}",
+                names.join(", "),
+                flash_dev.get_dev_path().display(),
+                why
+            );
+            return Err(Error::displayed_with(ErrorKind::InvState));
+        }
+    };
+
+    Ok(names
+        .iter()
+        .zip(parts.iter())
+        .map(|(name, part)| {
+            info!(
+                "Flashing partition {} at sector {}, {} sectors",
+                name, part.start_lba, part.num_sectors
+            );
+            FlashPart {
+                name: name.to_string(),
+                start_lba: part.start_lba,
+                num_sectors: part.num_sectors,
+            }
+        })
+        .collect())
+}
+
+fn is_gzip_file(path: &Path) -> Result<bool> {
+    let mut cookie = [0u8; 2];
+    let mut file =
+        File::open(path).upstream_with_context(&format!("Failed to open '{}'", path.display()))?;
+    match file.read_exact(&mut cookie) {
+        Ok(_) => Ok(u16::from_be_bytes(cookie) == GZIP_MAGIC_COOKIE),
+        Err(why) if why.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(why) => Err(Error::from_upstream(
+            Box::new(why),
+            &format!("Failed to read '{}'", path.display()),
+        )),
+    }
+}
+
+// the gzip trailer holds the uncompressed size modulo 4 GiB only, so the file is decompressed
+fn get_gzip_size(path: &Path) -> Result<u64> {
+    let file =
+        File::open(path).upstream_with_context(&format!("Failed to open '{}'", path.display()))?;
+    io::copy(&mut GzDecoder::new(file), &mut io::sink())
+        .upstream_with_context(&format!("Failed to decompress '{}'", path.display()))
+}
+
+/******************************************************************
+ * Resolve --flash-from-partition-image to the partition of the
+ * flash device the filesystem image is written to. The image, raw
+ * or gzip compressed, has to fit the partition and contain the
+ * filesystem the partition holds now, a different filesystem
+ * requires --force. The partition table is left as it is.
+ ******************************************************************/
+
+fn get_part_image(
+    opts: &Options,
+    flash_dev: &Rc<dyn BlockDevice>,
+    part_image: &str,
+) -> Result<PartImage> {
+    if !opts.flash_partitions().is_empty()
+        || opts.delta().is_some()
+        || opts.expand_data()
+        || !opts.preserve_labels().is_empty()
+        || opts.stream_image()
+    {
+        error!("--flash-from-partition-image can not be combined with --flash-partitions, --delta, --expand-data, --preserve-labels or --stream-image");
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    }
+
+    let (name, path) = if let Some((name, path)) = part_image.split_once('=') {
+        (name.trim(), Path::new(path.trim()))
+    } else {
+        error!(
+            "Invalid --flash-from-partition-image '{}', expected PARTITION=IMAGE",
+            part_image
+        );
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    };
+
+    let index = if let Some((_, index)) = BALENA_PART_NAMES
+        .iter()
+        .find(|(part_name, _)| *part_name == name)
+    {
+        *index
+    } else {
+        error!(
+            "Unknown partition '{}' in --flash-from-partition-image, expected one of {}",
+            name,
+            BALENA_PART_NAMES
+                .iter()
+                .map(|(part_name, _)| *part_name)
+                .collect::<Vec<&str>>()
+                .join(", ")
+        );
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    };
+    if index == BALENA_DATA_PART_IDX && opts.keep_data_partition() {
+        error!("The data partition can not be written with --flash-from-partition-image and kept with --keep-data-partition");
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    }
+
+    // stage2 copies the image from the old root
+    let path = match path.canonicalize() {
+        Ok(path) => path,
+        Err(why) => {
+            error!(
+                "The partition image '{}' can not be accessed, error: {}",
+                path.display(),
+                why
+            );
+            return Err(Error::displayed_with(ErrorKind::NotFound));
+        }
+    };
+
+    let compressed = is_gzip_file(&path)?;
+    let (size, image_fs) = if compressed {
+        (
+            get_gzip_size(&path)?,
+            Disk::from_gzip_img(&path)?.get_fs_type(0)?,
+        )
+    } else {
+        (
+            path.metadata()
+                .upstream_with_context(&format!(
+                    "Failed to retrieve metadata for '{}'",
+                    path.display()
+                ))?
+                .len(),
+            Disk::from_drive_file(&path, None)?.get_fs_type(0)?,
+        )
+    };
+
+    // the partition table tells the sector size its start and size are given in
+    let dev_path = flash_dev.get_dev_path();
+    let table = Disk::from_drive_file(&dev_path, None)?.get_part_table()?;
+    let part = if let Some(part) = table.parts.iter().find(|part| part.index == index) {
+        part
+    } else {
+        error!(
+            "Partition {} was not found on '{}'",
+            name,
+            dev_path.display()
+        );
+        return Err(Error::displayed_with(ErrorKind::NotFound));
+    };
+
+    check_part_image_size(
+        &path,
+        size,
+        name,
+        part.num_sectors.saturating_mul(table.sector_size),
+        &dev_path,
+    )?;
+    let part_fs =
+        Disk::from_drive_file(&dev_path, Some(table.sector_size))?.get_fs_type(part.start_lba)?;
+    let image_fs = check_part_image_fs(opts, &path, image_fs, name, part_fs, &dev_path)?;
+
+    info!(
+        "Writing the {} partition image '{}' of {} to partition {} at sector {}, {} sectors",
+        image_fs,
+        path.display(),
+        format_size_with_unit(size),
+        name,
+        part.start_lba,
+        part.num_sectors
+    );
+    Ok(PartImage {
+        name: name.to_string(),
+        path,
+        compressed,
+        size,
+        start_lba: part.start_lba,
+        num_sectors: part.num_sectors,
+        sector_size: table.sector_size,
+    })
+}
+
+// the partition image has to fit the partition it is written to
+fn check_part_image_size(
+    path: &Path,
+    size: u64,
+    name: &str,
+    part_size: u64,
+    dev_path: &Path,
+) -> Result<()> {
+    if size > part_size {
+        error!(
+            "The partition image '{}' of {} does not fit partition {} of {} on '{}'",
+            path.display(),
+            format_size_with_unit(size),
+            name,
+            format_size_with_unit(part_size),
+            dev_path.display()
+        );
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    }
     Ok(())
 }
 
-fn get_umount_parts(
-    flash_dev: &Rc<dyn BlockDevice>,
-    block_dev_info: &BlockDeviceInfo,
-) -> Result<Vec<UmountPart>> {
-    let mut umount_parts: Vec<UmountPart> = Vec::new();
+// the partition image has to contain the filesystem the partition holds now unless --force is given
+fn check_part_image_fs(
+    opts: &Options,
+    path: &Path,
+    image_fs: Option<&'static str>,
+    name: &str,
+    part_fs: Option<&'static str>,
+    dev_path: &Path,
+) -> Result<&'static str> {
+    let image_fs = if let Some(image_fs) = image_fs {
+        image_fs
+    } else {
+        error!(
+            "The partition image '{}' does not contain a known filesystem",
+            path.display()
+        );
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    };
+    if part_fs != Some(image_fs) {
+        let message = format!(
+            "The partition image '{}' contains a {} filesystem, partition {} on '{}' contains {}",
+            path.display(),
+            image_fs,
+            name,
+            dev_path.display(),
+            part_fs.unwrap_or("no known filesystem")
+        );
+        if opts.force() {
+            warn!("{}", message);
+        } else {
+            error!("{}, use --force to write it anyway", message);
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+    }
+    Ok(image_fs)
+}
+
+// read a numeric attribute of a partition from sysfs, eg. its number or its start or size in 512 byte sectors
+fn read_part_sectors(name: &str, value: &str) -> Result<u64> {
+    let path = format!("/sys/class/block/{}/{}", name, value);
+    read_to_string(&path)
+        .upstream_with_context(&format!("Failed to read '{}'", path))?
+        .trim()
+        .parse::<u64>()
+        .upstream_with_context(&format!("Failed to parse the contents of '{}'", path))
+}
+
+/******************************************************************
+ * Resolve --preserve-labels to the labeled partitions of the flash
+ * device. Stage2 lists them again in the flashed partition table as
+ * logical partitions, so they have to be located behind the image
+ * layout and the kept data partition, each preceded by a free
+ * sector for its EBR. Labels not found on the device are skipped.
+ ******************************************************************/
+
+fn get_preserve_parts(
+    opts: &Options,
+    flash_dev: &Rc<dyn BlockDevice>,
+    block_dev_info: &BlockDeviceInfo,
+    image_path: &Path,
+    keep_data_part: Option<&KeepDataPart>,
+) -> Result<Vec<PreservePart>> {
+    if !opts.flash_partitions().is_empty() || opts.delta().is_some() || opts.expand_data() {
+        error!("--preserve-labels can not be combined with --flash-partitions, --delta or --expand-data");
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    }
+
+    let mut parts: Vec<PreservePart> = Vec::new();
+    for label in opts.preserve_labels() {
+        let label = label.trim();
+        if label == BALENA_DATA_PART {
+            error!(
+                "The data partition can not be preserved with --preserve-labels, use --keep-data-partition"
+            );
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+        if parts.iter().any(|part| part.label == label) {
+            continue;
+        }
+
+        let devices: Vec<&Rc<dyn BlockDevice>> = block_dev_info
+            .get_devices()
+            .values()
+            .filter(|device| {
+                if let (Some(parent), Some(partition_info)) =
+                    (device.get_parent(), device.get_partition_info())
+                {
+                    parent.get_name() == flash_dev.get_name()
+                        && partition_info.fs_label() == Some(label)
+                } else {
+                    false
+                }
+            })
+            .collect();
+
+        if devices.is_empty() {
+            warn!(
+                "No partition labeled '{}' was found on '{}', it is not preserved",
+                label,
+                flash_dev.get_dev_path().display()
+            );
+            continue;
+        }
+
+        for device in devices {
+            parts.push(PreservePart {
+                label: label.to_string(),
+                start_lba: read_part_sectors(device.get_name(), "start")?,
+                num_sectors: read_part_sectors(device.get_name(), "size")?,
+            });
+        }
+    }
+
+    if parts.is_empty() {
+        return Ok(parts);
+    }
+
+    let mut layout_end = match get_image_layout_end(image_path) {
+        Ok(layout_end) => layout_end,
+        Err(why) => {
+            error!(
+                "Cannot preserve partitions on '{}': {}",
+                flash_dev.get_dev_path().display(),
+                why
+            );
+            return Err(Error::displayed_with(ErrorKind::InvState));
+        }
+    };
+    if let Some(keep_data_part) = keep_data_part {
+        layout_end = layout_end.max(keep_data_part.start_lba + keep_data_part.num_sectors);
+    }
+
+    parts.sort_by_key(|part| part.start_lba);
+    for part in &parts {
+        // the EBR in front of the partition must not overwrite the image or a preserved partition
+        if part.start_lba <= layout_end {
+            error!(
+                "Cannot preserve partition labeled '{}' at sector {}: it collides with the partitions of the image ending at sector {}",
+                part.label, part.start_lba, layout_end
+            );
+            return Err(Error::displayed_with(ErrorKind::InvState));
+        }
+        info!(
+            "Preserving partition labeled '{}' at sector {}, {} sectors",
+            part.label, part.start_lba, part.num_sectors
+        );
+        layout_end = part.start_lba + part.num_sectors;
+    }
+
+    Ok(parts)
+}
+
+/******************************************************************
+ * Resolve --target-partition-scheme to the scheme stage2 converts
+ * the flashed partition table to, None if the image already has
+ * it.
+ ******************************************************************/
+
+fn get_part_table_conversion(
+    opts: &Options,
+    flash_dev: &Rc<dyn BlockDevice>,
+    image_path: &Path,
+) -> Result<Option<PartitionScheme>> {
+    if opts.target_partition_scheme() == PartitionScheme::Image {
+        return Ok(None);
+    }
+
+    let table = match Disk::from_gzip_img(image_path).and_then(|mut disk| disk.get_part_table()) {
+        Ok(table) => table,
+        Err(why) => {
+            error!(
+                "Cannot convert the partition table: failed to read the partition table of image '{}', error: {}",
+                image_path.display(),
+                why
+            );
+            return Err(Error::displayed_with(ErrorKind::InvState));
+        }
+    };
+    let device = flash_dev.get_dev_path();
+    let device_sectors = get_device_size(&device)? / DEF_BLOCK_SIZE as u64;
+
+    check_part_table_conversion(
+        opts,
+        &table,
+        image_path,
+        &device,
+        device_sectors,
+        || match opts.boot_mode() {
+            BootMode::Auto => get_boot_mode(),
+            boot_mode => Ok(boot_mode),
+        },
+    )
+}
+
+/******************************************************************
+ * Check the image partition table can be converted to the requested
+ * scheme. The options writing into the existing partition table or
+ * relying on its MBR layout can not be combined with a conversion.
+ * A MBR can only hold the image partitions as primary partitions,
+ * a GPT is only converted to when booting with EFI, unless forced,
+ * and needs room for its header in front of the first partition
+ * and for its backup behind the last one.
+ ******************************************************************/
+
+fn check_part_table_conversion(
+    opts: &Options,
+    table: &PartTable,
+    image_path: &Path,
+    device: &Path,
+    device_sectors: u64,
+    boot_mode: impl FnOnce() -> Result<BootMode>,
+) -> Result<Option<PartitionScheme>> {
+    let scheme = opts.target_partition_scheme();
+    let image_scheme = if table.usable.is_some() {
+        PartitionScheme::Gpt
+    } else {
+        PartitionScheme::Mbr
+    };
+    if scheme == PartitionScheme::Image || image_scheme == scheme {
+        info!(
+            "The image '{}' has a {} partition table, no conversion needed",
+            image_path.display(),
+            image_scheme
+        );
+        return Ok(None);
+    }
+
+    if scheme == PartitionScheme::Gpt {
+        // a GPT converted from the MBR of the image only boots with EFI
+        let boot_mode = boot_mode()?;
+        if boot_mode != BootMode::Efi {
+            if opts.force() {
+                warn!(
+                    "Converting the partition table to GPT in {} boot mode, the device might not boot balena-os",
+                    boot_mode
+                );
+            } else {
+                error!("Cannot convert the partition table to GPT in {} boot mode, balena-os only boots from a GPT with EFI, use --force to convert anyway", boot_mode);
+                return Err(Error::displayed_with(ErrorKind::InvState));
+            }
+        }
+    }
+
+    if !opts.flash_partitions().is_empty()
+        || opts.flash_from_partition_image().is_some()
+        || opts.delta().is_some()
+    {
+        error!("--target-partition-scheme can not be combined with --flash-partitions, --flash-from-partition-image or --delta, they keep the partition table of '{}'", device.display());
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    }
+    if opts.keep_data_partition() || !opts.preserve_labels().is_empty() {
+        error!("--target-partition-scheme can not be combined with --keep-data-partition or --preserve-labels, kept partitions rely on the partition layout of '{}'", device.display());
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    }
+    if table.sector_size != DEF_BLOCK_SIZE as u64 {
+        error!(
+            "Cannot convert the partition table: the image '{}' uses {} byte sectors, expected {} byte sectors",
+            image_path.display(),
+            table.sector_size,
+            DEF_BLOCK_SIZE
+        );
+        return Err(Error::displayed_with(ErrorKind::InvState));
+    }
+
+    let image_end = table
+        .parts
+        .iter()
+        .map(|part| part.start_lba.saturating_add(part.num_sectors))
+        .max()
+        .unwrap_or(0);
+
+    if scheme == PartitionScheme::Mbr {
+        if table.parts.len() > MBR_PRIMARY_PARTS {
+            error!(
+                "Cannot convert the partition table to MBR: the image '{}' has {} partitions, a MBR holds {} primary partitions",
+                image_path.display(),
+                table.parts.len(),
+                MBR_PRIMARY_PARTS
+            );
+            return Err(Error::displayed_with(ErrorKind::InvState));
+        }
+        if image_end > u64::from(u32::MAX) {
+            error!(
+                "Cannot convert the partition table to MBR: the partitions of image '{}' end at sector {}, beyond what a MBR can address",
+                image_path.display(),
+                image_end
+            );
+            return Err(Error::displayed_with(ErrorKind::InvState));
+        }
+    } else {
+        // the data partition would fill the room the backup GPT needs
+        if opts.expand_data() {
+            error!("--target-partition-scheme gpt can not be combined with --expand-data");
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+        if let Some(first) = table.parts.iter().map(|part| part.start_lba).min() {
+            if first < GPT_HEADER_SECTORS {
+                error!(
+                    "Cannot convert the partition table to GPT: the first partition of image '{}' starts at sector {}, the GPT needs {} sectors in front of it",
+                    image_path.display(),
+                    first,
+                    GPT_HEADER_SECTORS
+                );
+                return Err(Error::displayed_with(ErrorKind::InvState));
+            }
+        }
+        if image_end.saturating_add(GPT_BACKUP_SECTORS) > device_sectors {
+            error!(
+                "Cannot convert the partition table to GPT: the partitions of image '{}' end at sector {}, leaving no room for the backup GPT on '{}'",
+                image_path.display(),
+                image_end,
+                device.display()
+            );
+            return Err(Error::displayed_with(ErrorKind::InvState));
+        }
+    }
+
+    info!(
+        "Stage2 will convert the {} partition table of the image to {}",
+        image_scheme, scheme
+    );
+    Ok(Some(scheme))
+}
+
+// path of the takeover binary in the new root, has to exist after copying executables
+fn get_init_binary(opts: &Options, takeover_dir: &Path) -> Result<PathBuf> {
+    let init_binary = if let Some(init_binary) = opts.init_binary() {
+        init_binary.to_path_buf()
+    } else {
+        // ExeCopy copies this executable to /bin using its current name
+        let this_exe = read_link("/proc/self/exe")
+            .upstream_with_context("Failed to read link to this executable")?;
+        if let Some(file_name) = this_exe.file_name() {
+            Path::new("/bin").join(file_name)
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!("Invalid executable path '{}'", this_exe.display()),
+            ));
+        }
+    };
+
+    if !init_binary.is_absolute() {
+        error!(
+            "The init binary '{}' must be an absolute path within the new root",
+            init_binary.display()
+        );
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    }
+
+    let curr_path = path_append(takeover_dir, &init_binary);
+    match curr_path.metadata() {
+        Ok(metadata) if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 => {
+            debug!("Using '{}' as init binary", init_binary.display());
+            Ok(init_binary)
+        }
+        _ => {
+            error!(
+                "The init binary '{}' could not be found as an executable in the new root",
+                init_binary.display()
+            );
+            Err(Error::displayed_with(ErrorKind::InvParam))
+        }
+    }
+}
+
+// the old root has to be a directory inside the new root, returns its path after pivot_root
+fn check_old_root(old_root: &Path) -> Result<PathBuf> {
+    if old_root.as_os_str().is_empty()
+        || !old_root
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        error!(
+            "The old root directory '{}' must be a relative path within the new root, eg. 'mnt/old_root'",
+            old_root.display()
+        );
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    }
+    Ok(Path::new("/").join(old_root))
+}
+
+// resolve the address here, stage2 might not be able to resolve host names
+fn resolve_netlog(netlog: &str) -> Result<SocketAddr> {
+    let mut addrs = netlog.to_socket_addrs().upstream_with_context(&format!(
+        "Failed to resolve network log address '{}', expected HOST:PORT",
+        netlog
+    ))?;
+    if let Some(addr) = addrs.next() {
+        info!("Stage2 log will be sent to '{}'", addr);
+        Ok(addr)
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!("No address found for network log address '{}'", netlog),
+        ))
+    }
+}
+
+// the tty stage2 will send its output to, None when running headless.
+// A tty given with --tty has to exist and be writable, stage2 would silently
+// skip the redirect otherwise
+fn get_tty(opts: &Options) -> Result<Option<PathBuf>> {
+    if let Some(tty) = opts.tty() {
+        match stat(tty) {
+            Ok(stat) if !is_chr(&stat) => warn!(
+                "The tty '{}' is not a character device, stage2 output might not show up",
+                tty.display()
+            ),
+            Ok(_) => (),
+            Err(why) => {
+                error!(
+                    "The tty '{}' given with --tty does not exist, error: {}",
+                    tty.display(),
+                    why
+                );
+                return Err(Error::displayed_with(ErrorKind::FileNotFound));
+            }
+        }
+        // a serial line without carrier would block the open without O_NONBLOCK
+        if let Err(why) = OpenOptions::new()
+            .write(true)
+            .custom_flags(O_NOCTTY | O_NONBLOCK)
+            .open(tty)
+        {
+            error!(
+                "The tty '{}' given with --tty is not writable, error: {}",
+                tty.display(),
+                why
+            );
+            return Err(Error::displayed_with(ErrorKind::Permission));
+        }
+        info!("stage2 output goes to tty '{}'", tty.display());
+        return Ok(Some(tty.to_path_buf()));
+    }
+
+    if let Ok(true) = isatty(STDOUT_FILENO) {
+        match read_link("/proc/self/fd/1") {
+            Ok(tty) => return Ok(Some(tty)),
+            Err(why) => warn!("Failed to read tty from '/proc/self/fd/1', error: {}", why),
+        }
+    }
+    warn!("No tty found on stdout, stage2 output will only be logged to files");
+    Ok(None)
+}
+
+// mount data for the takeover tmpfs, None leaves the kernel defaults
+fn get_tmpfs_options(opts: &Options) -> Option<String> {
+    let mut options: Vec<String> = Vec::new();
+    if let Some(tmpfs_size) = opts.tmpfs_size() {
+        options.push(format!("size={}", tmpfs_size));
+    }
+    if let Some(tmpfs_options) = opts.tmpfs_options() {
+        options.push(tmpfs_options.to_string());
+    }
+    if options.is_empty() {
+        None
+    } else {
+        Some(options.join(","))
+    }
+}
+
+// the tmpfs has to hold the staged files and should not claim more than the device has
+fn check_tmpfs_size(opts: &Options, req_space: u64, mem_tot: u64, mem_free: u64) -> Result<()> {
+    if let Some(tmpfs_options) = opts.tmpfs_options() {
+        if tmpfs_options
+            .split(',')
+            .any(|option| option.trim().starts_with("size="))
+        {
+            error!("Please use --tmpfs-size instead of a size in --tmpfs-options");
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+    }
 
-    for device in block_dev_info.get_devices().values() {
-        if let Some(parent) = device.get_parent() {
-            // this is a partition rather than a device
-            if parent.get_name() == flash_dev.get_name() {
-                // it is a partition of the flash device
-                if let Some(mount) = device.get_mountpoint() {
-                    let mut inserted = false;
-                    for (idx, mpoint) in umount_parts.iter().enumerate() {
-                        if mpoint.mountpoint.starts_with(mount.get_mountpoint()) {
-                            umount_parts.insert(
-                                idx,
-                                UmountPart {
-                                    dev_name: device.get_dev_path().to_path_buf(),
-                                    mountpoint: PathBuf::from(mount.get_mountpoint()),
-                                    fs_type: mount.get_fs_type().to_string(),
-                                },
-                            );
-                            inserted = true;
-                            break;
-                        }
-                    }
-                    if !inserted {
-                        umount_parts.push(UmountPart {
-                            dev_name: device.get_dev_path().to_path_buf(),
-                            mountpoint: PathBuf::from(mount.get_mountpoint()),
-                            fs_type: mount.get_fs_type().to_string(),
-                        });
-                    }
-                }
-            }
+    if let Some(tmpfs_size) = opts.tmpfs_size() {
+        if tmpfs_size < req_space {
+            error!(
+                "The tmpfs size of {} is too small, {} are required for the files copied to the RAMFS",
+                format_size_with_unit(tmpfs_size),
+                format_size_with_unit(req_space)
+            );
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+        if tmpfs_size > mem_tot {
+            error!(
+                "The tmpfs size of {} exceeds the total memory of {}",
+                format_size_with_unit(tmpfs_size),
+                format_size_with_unit(mem_tot)
+            );
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+        if tmpfs_size > mem_free {
+            warn!(
+                "The tmpfs size of {} exceeds the free memory of {}",
+                format_size_with_unit(tmpfs_size),
+                format_size_with_unit(mem_free)
+            );
         }
     }
-    umount_parts.reverse();
-    Ok(umount_parts)
+    Ok(())
 }
 
 fn mount_sys_filesystems(
@@ -150,7 +1617,14 @@ fn mount_sys_filesystems(
     // *********************************************************
     // mount tmpfs
 
-    mount_fs(&takeover_dir, "tmpfs", "tmpfs", None)?;
+    let tmpfs_options = get_tmpfs_options(opts);
+    mount_fs_with_options(
+        &takeover_dir,
+        "tmpfs",
+        "tmpfs",
+        tmpfs_options.as_deref(),
+        None,
+    )?;
 
     let curr_path = takeover_dir.join("etc");
     create_dir(&curr_path).upstream_with_context(&format!(
@@ -214,23 +1688,104 @@ fn mount_sys_filesystems(
     Ok(())
 }
 
-fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
+fn prepare(
+    opts: &Options,
+    mig_info: &mut MigrateInfo,
+    timeout: &Stage1Timeout,
+    reporter: &StatusReporter,
+) -> Result<()> {
     info!("Preparing for takeover..");
 
+    let tty = get_tty(opts)?;
+
     // *********************************************************
     // turn off swap
+    set_swap_disabled();
     call_command!(SWAPOFF_CMD, &["-a"], "Failed to disable SWAP")?;
 
     // *********************************************************
     // calculate required memory
 
+    let assets = if let Some(assets_dir) = opts.assets_dir() {
+        Some(Assets::new(
+            assets_dir,
+            &get_os_arch(opts)?,
+            opts.asset_checksums(),
+        )?)
+    } else if !opts.asset_checksums().is_empty() {
+        error!("--asset-sha256 requires --assets-dir");
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    } else {
+        None
+    };
+
     let mut req_space: u64 = 0;
     let mut copy_commands = vec![DD_CMD];
     if mig_info.is_x86() && !opts.no_efi_setup() && dir_exists(SYS_EFI_DIR)? {
         copy_commands.push(EFIBOOTMGR_CMD)
     }
 
-    let commands = match ExeCopy::new(copy_commands) {
+    if opts.expand_data() {
+        if assets.is_some() || (whereis(RESIZE2FS_CMD).is_ok() && whereis(E2FSCK_CMD).is_ok()) {
+            copy_commands.push(E2FSCK_CMD);
+            copy_commands.push(RESIZE2FS_CMD);
+        } else {
+            warn!(
+                "Could not locate '{}' and '{}', the data partition will not be expanded",
+                E2FSCK_CMD, RESIZE2FS_CMD
+            );
+        }
+    }
+
+    // sgdisk is needed unless the image turns out to have the partition scheme already
+    if opts.target_partition_scheme() != PartitionScheme::Image {
+        if assets.is_some() || whereis(SGDISK_CMD).is_ok() {
+            copy_commands.push(SGDISK_CMD);
+        } else {
+            error!(
+                "Could not locate '{}', it is required for --target-partition-scheme",
+                SGDISK_CMD
+            );
+            return Err(Error::displayed_with(ErrorKind::FileNotFound));
+        }
+    }
+
+    let reboot_method = if let Some(reboot_method) = opts.reboot_command() {
+        reboot_method.clone()
+    } else {
+        mig_info.reboot_method()
+    };
+    info!("Stage2 will reboot using {}", reboot_method);
+    if let Some(program) = reboot_method.program() {
+        copy_commands.push(program);
+    }
+
+    // busybox brings its applets to the inspection shell
+    let pause_shell = if opts.pause_after_pivot() {
+        if tty.is_none() {
+            error!("--pause-after-pivot needs a tty for the shell, use --tty");
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+        let program = if whereis(BUSYBOX_CMD).is_ok() {
+            BUSYBOX_CMD
+        } else {
+            SH_CMD
+        };
+        info!(
+            "Stage2 will pause for up to {} seconds, running '{}' before flashing",
+            opts.pause_timeout(),
+            program
+        );
+        copy_commands.push(program);
+        Some(PauseShell {
+            program: program.to_string(),
+            timeout_secs: opts.pause_timeout(),
+        })
+    } else {
+        None
+    };
+
+    let commands = match ExeCopy::new(copy_commands, assets.as_ref()) {
         Ok(commands) => {
             let cmd_space = commands.get_req_space();
             debug!(
@@ -264,6 +1819,10 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         )));
     }
 
+    check_tmpfs_size(opts, req_space + S1_XTRA_FS_SIZE, mem_tot, mem_free)?;
+
+    timeout.check()?;
+
     // *********************************************************
     // make mountpoint for tmpfs
     let takeover_dir = PathBuf::from(TAKEOVER_DIR);
@@ -276,27 +1835,29 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
                 ))?;
                 if read_dir.count() > 0 {
                     error!(
-                        "Found a non-empty directory '{}' - please remove or rename this directory",
+                        "Found a non-empty directory '{}' - please remove or rename this directory or run with --abort to clean up or --resume to continue after a failed migration",
                         takeover_dir.display()
                     );
-                    return Err(Error::displayed());
+                    return Err(Error::displayed_with(ErrorKind::FileExists));
                 } else {
                     warn!(
                         "Directory '{}' exists. Reusing directory",
                         takeover_dir.display()
                     );
+                    set_takeover_dir_used(false);
                 }
             } else {
                 error!(
                     "Found a file '{}' - please remove or rename this file",
                     takeover_dir.display()
                 );
-                return Err(Error::displayed());
+                return Err(Error::displayed_with(ErrorKind::FileExists));
             }
         }
         Err(why) => {
             if why.kind() == ErrorKind::FileNotFound {
                 mkdir(&takeover_dir, 0o755)?;
+                set_takeover_dir_used(true);
             } else {
                 return Err(Error::from_upstream(
                     Box::new(why),
@@ -315,7 +1876,8 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
     // *********************************************************
     // create mountpoint for old root
 
-    let curr_path = path_append(&takeover_dir, OLD_ROOT_MP);
+    let old_root = check_old_root(opts.old_root())?;
+    let curr_path = path_append(&takeover_dir, &old_root);
 
     create_dir_all(&curr_path).upstream_with_context(&format!(
         "Failed to create directory '{}'",
@@ -324,10 +1886,25 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
 
     info!("Created directory '{}'", curr_path.display());
 
-    commands.copy_files(&takeover_dir)?;
+    // executables on the tmpfs might not be allowed to run with SELinux enforcing
+    let relabel = if selinux::is_enforcing()? {
+        if opts.selinux_permissive() {
+            selinux::set_permissive()?;
+            false
+        } else {
+            info!("SELinux is enforcing, copying SELinux contexts to the takeover directory");
+            true
+        }
+    } else {
+        false
+    };
+
+    commands.copy_files(&takeover_dir, relabel)?;
 
     prepare_configs(opts.work_dir(), mig_info)?;
 
+    timeout.check()?;
+
     // *********************************************************
     // setup new init
 
@@ -335,36 +1912,27 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         read_link("/proc/1/exe").upstream_with_context("Failed to read link for /proc/1/exe")?;
 
     // TODO: make new_init_path point to /$takeover_dir/bin/takeover directly
-    let new_init_path = path_append(&takeover_dir, &format!("/bin/{}", env!("CARGO_PKG_NAME")));
+    let init_binary = get_init_binary(opts, &takeover_dir)?;
+    let new_init_path = path_append(&takeover_dir, &init_binary);
     // Assets::write_stage2_script(&takeover_dir, &new_init_path, &tty, opts.get_s2_log_level())?;
 
-    let block_dev_info = BlockDeviceInfo::new()?;
+    // detached on failure, stage2 detaches it after flashing
+    let mut flash_loop = attach_flash_file(opts)?;
 
-    let flash_dev = if let Some(flash_dev) = opts.flash_to() {
-        if let Some(flash_dev) = block_dev_info.get_devices().get(flash_dev) {
-            flash_dev
-        } else {
-            return Err(Error::with_context(
-                ErrorKind::InvState,
-                &format!(
-                    "Could not find configured flash device '{}'",
-                    flash_dev.display()
-                ),
-            ));
-        }
+    let block_dev_info = BlockDeviceInfo::new(
+        flash_loop.as_ref().map(|loop_dev| loop_dev.get_path()),
+        opts.source_root_device().as_deref(),
+    )?;
+
+    let flash_dev_path = if let Some(loop_device) = &flash_loop {
+        Some(loop_device.get_path().to_path_buf())
     } else {
-        block_dev_info.get_root_device()
+        opts.flash_to().clone()
     };
 
-    if !file_exists(&flash_dev.as_ref().get_dev_path()) {
-        return Err(Error::with_context(
-            ErrorKind::DeviceNotFound,
-            &format!(
-                "The device could not be found: '{}'",
-                flash_dev.get_dev_path().display()
-            ),
-        ));
-    }
+    let flash_dev = get_flash_dev(flash_dev_path.as_deref(), mig_info, &block_dev_info)?;
+
+    check_flash_dev_mounts(opts, flash_dev, &block_dev_info)?;
 
     let log_device = if let Some(log_dev_path) = opts.log_to() {
         if let Some(log_dev) = block_dev_info.get_devices().get(log_dev_path) {
@@ -402,14 +1970,130 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         None
     };
 
+    let stage2_netlog = if let Some(netlog) = opts.stage2_netlog() {
+        Some(resolve_netlog(netlog)?)
+    } else {
+        None
+    };
+
+    check_boot_mode(opts, mig_info)?;
+
+    check_image_part_table(mig_info.image_path(), &flash_dev.get_dev_path())?;
+
+    // the image is made for a media geometry, a mismatch can keep the device from booting
+    check_geometry(opts, mig_info.image_path(), &flash_dev.get_dev_path())?;
+
+    if opts.stream_image() {
+        check_stream_image(mig_info.image_path(), flash_dev, &block_dev_info)?;
+    }
+
+    if let Some(backup_path) = opts.partition_table_backup() {
+        save_partition_table(backup_path, flash_dev, &block_dev_info)?;
+    }
+
+    let delta_path = if let Some(delta) = opts.delta() {
+        if opts.keep_data_partition() {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                "The options --delta and --keep-data-partition can not be used together",
+            ));
+        }
+        let delta_path = fetch_delta(delta, &opts.work_dir())?;
+        let (header, _) = open_delta(&delta_path)?;
+        info!(
+            "Applying delta '{}' with {} byte blocks if the base partitions {:?} match",
+            delta_path.display(),
+            header.block_size,
+            header
+                .bases
+                .iter()
+                .map(|base| base.part_index)
+                .collect::<Vec<usize>>()
+        );
+        Some(delta_path)
+    } else {
+        None
+    };
+
+    let keep_data_part = if opts.keep_data_partition() {
+        Some(get_keep_data_part(
+            flash_dev,
+            &block_dev_info,
+            mig_info.image_path(),
+        )?)
+    } else {
+        None
+    };
+
+    let flash_parts = if opts.flash_partitions().is_empty() {
+        None
+    } else {
+        Some(get_flash_parts(opts, flash_dev, mig_info.image_path())?)
+    };
+
+    let part_image = if let Some(part_image) = opts.flash_from_partition_image() {
+        Some(get_part_image(opts, flash_dev, part_image)?)
+    } else {
+        None
+    };
+
+    let preserve_parts = if opts.preserve_labels().is_empty() {
+        None
+    } else {
+        Some(get_preserve_parts(
+            opts,
+            flash_dev,
+            &block_dev_info,
+            mig_info.image_path(),
+            keep_data_part.as_ref(),
+        )?)
+    };
+
+    let convert_part_table = get_part_table_conversion(opts, flash_dev, mig_info.image_path())?;
+
+    let report_file = get_report_file(opts, flash_dev, &block_dev_info)?;
+
+    // stage2 reads the stage1 log from the old root, so the path has to be absolute
+    let stage1_log = match opts.log_file() {
+        Some(log_file) if opts.keep_logs_on_target() => {
+            Some(log_file.canonicalize().upstream_with_context(&format!(
+                "Failed to canonicalize log file '{}'",
+                log_file.display()
+            ))?)
+        }
+        None if opts.keep_logs_on_target() => {
+            warn!("No --log-file was given, only the stage2 log is kept on the target");
+            None
+        }
+        _ => None,
+    };
+
     // collect partitions that need to be unmounted
+    let umount_parts = get_umount_parts(flash_dev, &block_dev_info)?;
+    let old_root_cleanup = opts.old_root_cleanup();
+    if old_root_cleanup == OldRootCleanup::Keep && !umount_parts.is_empty() {
+        error!(
+            "--old-root-cleanup keep can not be used while partitions of '{}' are mounted: {}",
+            flash_dev.get_dev_path().display(),
+            umount_parts
+                .iter()
+                .map(|part| format!("'{}'", part.mountpoint.display()))
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    }
+
+    timeout.check()?;
 
     let s2_cfg = Stage2Config {
         log_dev: log_device,
         log_level: opts.s2_log_level().to_string(),
+        console_log_level: opts.s2_console_log_level().to_string(),
         flash_dev: flash_dev.get_dev_path(),
         pretend: opts.pretend(),
-        umount_parts: get_umount_parts(flash_dev, &block_dev_info)?,
+        umount_parts,
+        old_root_cleanup,
         work_dir: opts
             .work_dir()
             .canonicalize()
@@ -424,10 +2108,43 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
         } else {
             None
         },
-        tty: read_link("/proc/self/fd/1")
-            .upstream_with_context("Failed to read tty from '/proc/self/fd/1'")?,
+        tty,
+        keep_data_part,
+        expand_data: opts.expand_data(),
+        reboot_mode: opts.reboot_mode(),
+        reboot_method,
+        stage2_timeout: opts.stage2_timeout(),
+        pause_shell,
+        stage2_netlog,
+        old_root,
+        init_binary,
+        pretty_config: opts.pretty_config(),
+        stream_image: opts.stream_image(),
+        progress_json: opts.progress_json(),
+        min_free_mem: opts.min_free_mem(),
+        boot_config: mig_info.boot_config().is_some(),
+        delta_path,
+        flash_parts,
+        part_image,
+        preserve_parts,
+        convert_part_table,
+        flash_loop: flash_loop.is_some(),
+        device_type: mig_info.device_type(),
+        keep_logs: opts.keep_logs_on_target(),
+        stage1_log,
+        flash_pause: opts.flash_pause(),
+        min_write_speed: opts.min_disk_write_speed(),
+        report_file,
     };
 
+    // the config is all stage2 acts on, printing it shows what runs after the pivot
+    if opts.print_stage2_config() {
+        let s2_cfg_txt = s2_cfg.serialize()?;
+        println!("{}", s2_cfg_txt);
+        info!("Printed the stage2 config due to --print-stage2-config, not writing it and not restarting init");
+        return Ok(());
+    }
+
     let s2_cfg_path = takeover_dir.join(STAGE2_CONFIG_NAME);
     let mut s2_cfg_file = OpenOptions::new()
         .create(true)
@@ -450,6 +2167,42 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
 
     info!("Wrote stage2 config to '{}'", s2_cfg_path.display());
 
+    mig_info.balena_cfg().log_changes();
+
+    if opts.no_flash() {
+        timeout.commit()?;
+        info!(
+            "Not restarting init due to --no-flash, the migration is prepared in '{}'",
+            takeover_dir.display()
+        );
+        info!(
+            "To complete the migration run: cd {} && mount --bind {} {} && {} u",
+            takeover_dir.display(),
+            new_init_path.display(),
+            old_init_path.display(),
+            TELINIT_CMD
+        );
+        info!(
+            "To undo the preparation run: {} --abort",
+            env!("CARGO_PKG_NAME")
+        );
+        if let Some(loop_device) = flash_loop.as_mut() {
+            loop_device.set_auto_unset(false);
+        }
+        return Ok(());
+    }
+
+    timeout.check()?;
+    stop_services(opts, mig_info.init_system())?;
+
+    // the files copied to the RAMFS are in memory now, init re-checks before it pivots
+    check_free_mem(opts.min_free_mem(), "Before restarting init")?;
+
+    timeout.commit()?;
+
+    // last chance to report, stage2 kills all processes using the network
+    reporter.report(MigrationStatus::Flashing, None);
+
     set_current_dir(&takeover_dir).upstream_with_context(&format!(
         "Failed to change current dir to '{}'",
         takeover_dir.display()
@@ -481,6 +2234,10 @@ fn prepare(opts: &Options, mig_info: &mut MigrateInfo) -> Result<()> {
 
     info!("Restarted init");
 
+    if let Some(loop_device) = flash_loop.as_mut() {
+        loop_device.set_auto_unset(false);
+    }
+
     Ok(())
 }
 
@@ -498,6 +2255,16 @@ pub fn stage1(opts: &Options) -> Result<()> {
     */
 
     if let Some(s1_log_path) = opts.log_file() {
+        // eg. a directory on a writable volume when the working directory is read-only
+        match s1_log_path.parent() {
+            Some(log_dir) if !log_dir.as_os_str().is_empty() && !dir_exists(log_dir)? => {
+                create_dir_all(log_dir).upstream_with_context(&format!(
+                    "Failed to create the directory '{}' for the stage1 log file",
+                    log_dir.display()
+                ))?
+            }
+            _ => (),
+        }
         Logger::set_log_file(&LogDestination::StreamStderr, &s1_log_path, true)
             .upstream_with_context(&format!(
                 "Failed to set logging to '{}'",
@@ -508,6 +2275,73 @@ pub fn stage1(opts: &Options) -> Result<()> {
             .upstream_with_context("Failed to set up logging")?;
     }
 
+    if opts.self_test() {
+        return self_test(opts);
+    }
+
+    if opts.probe_only() {
+        return probe(opts);
+    }
+
+    if opts.detect_only() {
+        return detect_only(opts);
+    }
+
+    if let Some(report_path) = opts.report_device_info() {
+        return report_device_info(opts, report_path);
+    }
+
+    if opts.verify_boot() {
+        return verify_boot(opts);
+    }
+
+    if let Some(plan_path) = opts.plan_in() {
+        if opts.plan_out().is_some() {
+            error!("The options --plan-in and --plan-out can not be combined");
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+        let plan = MigrationPlan::read(plan_path)?;
+        info!(
+            "Migrating with the plan '{}' made by {} {}",
+            plan_path.display(),
+            env!("CARGO_PKG_NAME"),
+            plan.takeover_version
+        );
+        let plan_opts = plan.options()?;
+        return run_stage1(&plan_opts, Some(&plan));
+    }
+
+    if opts.plan_out().is_some() && !opts.migrate() {
+        error!("The option --plan-out can not be combined with --download-only");
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    }
+
+    run_stage1(opts, None)
+}
+
+// stage1 with the options given or those of a migration plan, which is checked before anything is changed
+fn run_stage1(opts: &Options, plan: Option<&MigrationPlan>) -> Result<()> {
+    // downloading an image works without root unless it has to be extracted from a flasher image
+    if opts.migrate() || opts.abort() {
+        check_privileges()?;
+    }
+
+    if let Some(report_file) = opts.report_to_file() {
+        add_observer(Box::new(FileObserver::new(report_file)?));
+    }
+
+    emit(ProgressEvent::Stage(Stage::Stage1));
+
+    if opts.abort() {
+        return cleanup_staged(opts);
+    }
+
+    if opts.resume() && opts.migrate() {
+        clean_staged(opts)?;
+    }
+
+    let timeout = Stage1Timeout::new();
+
     let mut mig_info = match MigrateInfo::new(&opts) {
         Ok(mig_info) => mig_info,
         Err(why) => {
@@ -522,9 +2356,78 @@ pub fn stage1(opts: &Options) -> Result<()> {
         }
     };
 
-    if !is_admin()? {
-        error!("please run this program as root");
-        return Err(Error::displayed());
+    let reporter = StatusReporter::new(opts, mig_info.balena_cfg());
+
+    if opts.migrate() {
+        reporter.report(MigrationStatus::Started, None);
+        emit(ProgressEvent::CheckStarted(
+            REQUIRED_COMMANDS_CHECK.to_string(),
+        ));
+        let missing = find_missing_commands(&required_commands(opts, mig_info.is_x86()));
+        if !missing.is_empty() {
+            let message = format!(
+                "The following commands required for migration could not be found: {}",
+                missing.join(", ")
+            );
+            error!("{}", message);
+            reporter.report(MigrationStatus::Failed, Some(&message));
+            emit(ProgressEvent::CheckFailed {
+                name: REQUIRED_COMMANDS_CHECK.to_string(),
+                error: message,
+            });
+            emit(ProgressEvent::Stage(Stage::Failed));
+            return Err(Error::displayed_with(ErrorKind::FileNotFound));
+        }
+        emit(ProgressEvent::CheckPassed(
+            REQUIRED_COMMANDS_CHECK.to_string(),
+        ));
+
+        emit(ProgressEvent::CheckStarted(
+            KERNEL_SUPPORT_CHECK.to_string(),
+        ));
+        match check_kernel_support(mig_info.image_path()) {
+            Ok(message) => {
+                info!("Kernel support checked: {}", message);
+                emit(ProgressEvent::CheckPassed(KERNEL_SUPPORT_CHECK.to_string()));
+            }
+            Err(why) => {
+                let message = why.to_string();
+                error!(
+                    "The running kernel can not perform the migration: {}",
+                    message
+                );
+                reporter.report(MigrationStatus::Failed, Some(&message));
+                emit(ProgressEvent::CheckFailed {
+                    name: KERNEL_SUPPORT_CHECK.to_string(),
+                    error: message,
+                });
+                emit(ProgressEvent::Stage(Stage::Failed));
+                return Err(Error::displayed_with(why.kind()));
+            }
+        }
+        reporter.report(MigrationStatus::ChecksPassed, None);
+    }
+
+    if let Some(plan_path) = opts.plan_out() {
+        MigrationPlan::new(opts, &mig_info)?.write(plan_path)?;
+        info!(
+            "Wrote the migration plan to '{}', migrate with --plan-in {} after reviewing it",
+            plan_path.display(),
+            plan_path.display()
+        );
+        emit(ProgressEvent::Stage(Stage::Finished));
+        return Ok(());
+    }
+
+    if let Some(plan) = plan {
+        if let Err(why) = plan.check(opts, &mig_info) {
+            reporter.report(
+                MigrationStatus::Failed,
+                Some("The device does not match the migration plan"),
+            );
+            emit(ProgressEvent::Stage(Stage::Failed));
+            return Err(why);
+        }
     }
 
     if !opts.no_ack() {
@@ -556,7 +2459,18 @@ pub fn stage1(opts: &Options) -> Result<()> {
     }
 
     if opts.migrate() {
-        match prepare(&opts, &mut mig_info) {
+        // waiting for the confirmation does not count against the timeout
+        timeout.start(opts);
+        emit(ProgressEvent::Stage(Stage::Prepare));
+        match prepare(&opts, &mut mig_info, &timeout, &reporter) {
+            Ok(_) if opts.no_flash() => {
+                emit(ProgressEvent::Stage(Stage::Finished));
+                Ok(())
+            }
+            Ok(_) if opts.print_stage2_config() => {
+                emit(ProgressEvent::Stage(Stage::Finished));
+                cleanup(opts)
+            }
             Ok(_) => {
                 info!("Takeover initiated successfully, please wait for the device to be reflashed and reboot");
                 Logger::flush();
@@ -565,9 +2479,19 @@ pub fn stage1(opts: &Options) -> Result<()> {
                 Ok(())
             }
             Err(why) => {
+                emit(ProgressEvent::Stage(Stage::Failed));
+                if why.kind() == ErrorKind::Displayed {
+                    reporter.report(MigrationStatus::Failed, None);
+                } else {
+                    reporter.report(MigrationStatus::Failed, Some(&why.to_string()));
+                }
                 if opts.cleanup() {
-                    mig_info.umount_all();
+                    if let Err(cleanup_err) = cleanup(opts) {
+                        warn!("Cleanup failed, error: {}", cleanup_err);
+                    }
                 }
+                // also with --no-cleanup or a failed cleanup
+                selinux::restore_enforcing();
                 Err(why)
             }
         }
@@ -575,3 +2499,136 @@ pub fn stage1(opts: &Options) -> Result<()> {
         Ok(())
     }
 }
+
+// run stage1 reporting progress events to observer
+pub fn run_with_observer(opts: &Options, observer: Box<dyn ProgressObserver>) -> Result<()> {
+    set_observer(observer);
+    stage1(opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::disk_util::PartExtent;
+
+    fn opts(args: &[&str]) -> Options {
+        let mut all_args = vec!["takeover".to_string()];
+        all_args.extend(args.iter().map(|arg| arg.to_string()));
+        Options::from_plan_args(&all_args).unwrap()
+    }
+
+    fn table(gpt: bool, parts: &[(u64, u64)]) -> PartTable {
+        PartTable {
+            sector_size: DEF_BLOCK_SIZE as u64,
+            parts: parts
+                .iter()
+                .enumerate()
+                .map(|(idx, (start_lba, num_sectors))| PartExtent {
+                    index: idx + 1,
+                    start_lba: *start_lba,
+                    num_sectors: *num_sectors,
+                })
+                .collect(),
+            usable: if gpt { Some((34, 1_000_000)) } else { None },
+            extended: None,
+        }
+    }
+
+    fn convert(
+        opts: &Options,
+        table: &PartTable,
+        boot_mode: BootMode,
+    ) -> Result<Option<PartitionScheme>> {
+        check_part_table_conversion(
+            opts,
+            table,
+            Path::new("image.img.gz"),
+            Path::new("/dev/sda"),
+            1_000_000,
+            || Ok(boot_mode),
+        )
+    }
+
+    #[test]
+    fn test_check_part_table_conversion() {
+        let mbr = table(false, &[(2048, 80000), (83968, 300000)]);
+        let gpt = table(true, &[(2048, 80000), (83968, 300000)]);
+        // the GPT images of balena-os have more partitions than a MBR holds
+        let gpt_five = table(
+            true,
+            &[
+                (2048, 1000),
+                (4096, 1000),
+                (8192, 1000),
+                (12288, 1000),
+                (16384, 1000),
+            ],
+        );
+
+        let keep = opts(&[]);
+        assert_eq!(convert(&keep, &mbr, BootMode::Efi).unwrap(), None);
+
+        let to_gpt = opts(&["--target-partition-scheme", "gpt"]);
+        assert_eq!(
+            convert(&to_gpt, &mbr, BootMode::Efi).unwrap(),
+            Some(PartitionScheme::Gpt)
+        );
+        assert_eq!(convert(&to_gpt, &gpt, BootMode::Legacy).unwrap(), None);
+        assert!(convert(&to_gpt, &mbr, BootMode::Legacy).is_err());
+        let forced = opts(&["--target-partition-scheme", "gpt", "--force"]);
+        assert_eq!(
+            convert(&forced, &mbr, BootMode::Legacy).unwrap(),
+            Some(PartitionScheme::Gpt)
+        );
+        // no room for the GPT header or the backup GPT
+        assert!(convert(&to_gpt, &table(false, &[(1, 1000)]), BootMode::Efi).is_err());
+        assert!(convert(&to_gpt, &table(false, &[(2048, 997_952)]), BootMode::Efi).is_err());
+        let expand = opts(&["--target-partition-scheme", "gpt", "--expand-data"]);
+        assert!(convert(&expand, &mbr, BootMode::Efi).is_err());
+
+        let to_mbr = opts(&["--target-partition-scheme", "mbr"]);
+        assert_eq!(
+            convert(&to_mbr, &gpt, BootMode::Legacy).unwrap(),
+            Some(PartitionScheme::Mbr)
+        );
+        assert_eq!(convert(&to_mbr, &mbr, BootMode::Legacy).unwrap(), None);
+        assert!(convert(&to_mbr, &gpt_five, BootMode::Legacy).is_err());
+        let delta = opts(&[
+            "--target-partition-scheme",
+            "mbr",
+            "--delta",
+            "update.delta.gz",
+        ]);
+        assert!(convert(&delta, &gpt, BootMode::Legacy).is_err());
+    }
+
+    #[test]
+    fn test_check_part_image() {
+        let image = Path::new("root.img");
+        let device = Path::new("/dev/sda");
+        let part_size = 1000 * DEF_BLOCK_SIZE as u64;
+
+        assert!(check_part_image_size(image, part_size, "root", part_size, device).is_ok());
+        assert!(check_part_image_size(image, part_size + 1, "root", part_size, device).is_err());
+
+        let no_force = opts(&[]);
+        assert_eq!(
+            check_part_image_fs(&no_force, image, Some("ext4"), "root", Some("ext4"), device)
+                .unwrap(),
+            "ext4"
+        );
+        assert!(
+            check_part_image_fs(&no_force, image, Some("ext4"), "root", Some("vfat"), device)
+                .is_err()
+        );
+        assert!(check_part_image_fs(&no_force, image, Some("ext4"), "root", None, device).is_err());
+        assert!(check_part_image_fs(&no_force, image, None, "root", Some("ext4"), device).is_err());
+        let forced = opts(&["--force"]);
+        assert_eq!(
+            check_part_image_fs(&forced, image, Some("ext4"), "root", Some("vfat"), device)
+                .unwrap(),
+            "ext4"
+        );
+        assert!(check_part_image_fs(&forced, image, None, "root", Some("ext4"), device).is_err());
+    }
+}
@@ -1,9 +1,12 @@
 use std::io::Read;
+use std::time::Duration;
 
 use log::debug;
 
 use reqwest::{blocking::Client, header};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
 
 use crate::common::{Error, ErrorKind, Result, ToError};
 
@@ -12,6 +15,8 @@ const OS_VERSION_URL_P2: &str = "/images";
 
 const OS_IMG_URL: &str = "/download";
 
+const APPLICATION_URL: &str = "/v6/application";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Versions {
     pub versions: Vec<String>,
@@ -27,6 +32,59 @@ struct ImageRequestData {
     file_type: String,
 }
 
+pub(crate) fn get_fleet_id(api_endpoint: &str, api_key: &str, fleet: &str) -> Result<u64> {
+    let mut headers = header::HeaderMap::new();
+    headers.insert(
+        header::AUTHORIZATION,
+        header::HeaderValue::from_str(&format!("Bearer {}", api_key))
+            .upstream_with_context("Failed to create auth header")?,
+    );
+
+    let mut request_url =
+        Url::parse(&format!("{}{}", api_endpoint, APPLICATION_URL)).upstream_with_context(
+            &format!("Failed to parse balena api url '{}'", api_endpoint),
+        )?;
+    request_url
+        .query_pairs_mut()
+        .append_pair("$filter", &format!("slug eq '{}'", fleet.to_lowercase()))
+        .append_pair("$select", "id");
+
+    debug!("get_fleet_id: request_url: '{}'", request_url);
+
+    let res = Client::builder()
+        .default_headers(headers)
+        .build()
+        .upstream_with_context("Failed to create https client")?
+        .get(request_url.as_str())
+        .send()
+        .upstream_with_context(&format!(
+            "Failed to send https request url: '{}'",
+            request_url
+        ))?;
+
+    debug!("Result = {:?}", res);
+
+    let status = res.status();
+    if status == 200 {
+        let apps = res
+            .json::<Value>()
+            .upstream_with_context("Failed to parse request results")?;
+        if let Some(app_id) = apps["d"][0]["id"].as_u64() {
+            Ok(app_id)
+        } else {
+            Err(Error::with_context(
+                ErrorKind::NotFound,
+                &format!("No fleet found for slug '{}'", fleet),
+            ))
+        }
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!("Balena API request failed with status: {}", status),
+        ))
+    }
+}
+
 pub(crate) fn get_os_versions(api_endpoint: &str, api_key: &str, device: &str) -> Result<Versions> {
     let mut headers = header::HeaderMap::new();
     headers.insert(
@@ -108,3 +166,46 @@ pub(crate) fn get_os_image(
 
     Ok(Box::new(res))
 }
+
+pub(crate) fn post_status(
+    request_url: &Url,
+    api_key: Option<&str>,
+    status: &Value,
+    timeout: Duration,
+) -> Result<()> {
+    let mut headers = header::HeaderMap::new();
+    if let Some(api_key) = api_key {
+        headers.insert(
+            header::AUTHORIZATION,
+            header::HeaderValue::from_str(&format!("Bearer {}", api_key))
+                .upstream_with_context("Failed to create auth header")?,
+        );
+    }
+
+    debug!("post_status: request_url: '{}'", request_url);
+
+    let res = Client::builder()
+        .default_headers(headers)
+        .timeout(timeout)
+        .build()
+        .upstream_with_context("Failed to create https client")?
+        .post(request_url.as_str())
+        .json(status)
+        .send()
+        .upstream_with_context(&format!(
+            "Failed to send https request url: '{}'",
+            request_url
+        ))?;
+
+    debug!("Result = {:?}", res);
+
+    let status = res.status();
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!("Status report request failed with status: {}", status),
+        ))
+    }
+}
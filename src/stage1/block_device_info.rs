@@ -1,8 +1,8 @@
 use crate::common::{path_append, Error, Result, ToError};
 
 use lazy_static::lazy_static;
-use log::{debug, trace};
-use nix::sys::stat::{major, minor, stat};
+use log::{debug, info, trace, warn};
+use nix::sys::stat::{major, minor, stat, SFlag};
 use regex::Regex;
 use std::collections::HashMap;
 use std::fmt;
@@ -32,6 +32,16 @@ const BLOC_DEV_SUPP_MAJ_NUMBERS: [u64; 45] = [
     70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, 84, 85, 86, 87, 179, 180, 259,
 ];
 
+const PROC_MOUNTS: &str = "/proc/mounts";
+const SYS_DEV_BLOCK_DIR: &str = "/sys/dev/block";
+const SYS_BLOCK_DIR: &str = "/sys/block";
+
+// dm-crypt mappings, eg. of LUKS, have a device mapper uuid like CRYPT-LUKS2-<uuid>-<name>
+const DM_CRYPT_UUID_PREFIX: &str = "CRYPT-";
+
+// loop devices are only listed when migrating to an image file
+const LOOP_DEV_MAJ_NUMBER: u64 = 7;
+
 type DeviceMap = HashMap<PathBuf, Rc<dyn BlockDevice>>;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -112,7 +122,12 @@ pub(crate) struct BlockDeviceInfo {
 }
 
 impl BlockDeviceInfo {
-    pub fn new() -> Result<BlockDeviceInfo> {
+    // loop_device is listed besides the supported block devices if given,
+    // root_override replaces the detected root device
+    pub fn new(
+        loop_device: Option<&Path>,
+        root_override: Option<&Path>,
+    ) -> Result<BlockDeviceInfo> {
         let stat_res = stat("/").upstream_with_context("Failed to stat root")?;
         let root_number = DeviceNum::new(stat_res.st_dev);
         let mounts = Mount::from_mtab()?;
@@ -143,7 +158,12 @@ impl BlockDeviceInfo {
                         curr_number,
                     );
 
-                    if !BLOC_DEV_SUPP_MAJ_NUMBERS.contains(&curr_number.major()) {
+                    let dev_path = path_append("/dev", &curr_dev);
+                    let is_loop_device = curr_number.major() == LOOP_DEV_MAJ_NUMBER
+                        && loop_device == Some(dev_path.as_path());
+
+                    if !BLOC_DEV_SUPP_MAJ_NUMBERS.contains(&curr_number.major()) && !is_loop_device
+                    {
                         trace!(
                             "Skipping device '{}' with block device major {}",
                             curr_dev,
@@ -152,7 +172,6 @@ impl BlockDeviceInfo {
                         continue;
                     }
 
-                    let dev_path = path_append("/dev", &curr_dev);
                     if !dev_path.exists() {
                         return Err(Error::with_context(
                             ErrorKind::DeviceNotFound,
@@ -221,6 +240,37 @@ impl BlockDeviceInfo {
             }
         }
 
+        // the root filesystem on a dm-crypt mapping resides on the partition below it
+        if root_device.is_none() {
+            if let Some(partition) =
+                BlockDeviceInfo::resolve_crypt_mapping(&device_map, &root_number)?
+            {
+                info!(
+                    "The root filesystem is on a dm-crypt mapping on '{}'",
+                    partition.get_dev_path().display()
+                );
+                if let Some(parent) = partition.get_parent() {
+                    root_device = Some(parent.clone());
+                    root_partition = Some(partition);
+                } else {
+                    root_device = Some(partition);
+                }
+            }
+        }
+
+        if let Some(root_override) = root_override {
+            let detected = match (&root_device, &root_partition) {
+                (Some(root_device), Some(_)) => root_device.get_dev_path().display().to_string(),
+                _ => "<none>".to_string(),
+            };
+            return BlockDeviceInfo::override_root(
+                root_override,
+                &detected,
+                &root_number,
+                device_map,
+            );
+        }
+
         if let Some(root_device) = root_device {
             if let Some(root_partition) = root_partition {
                 return Ok(BlockDeviceInfo {
@@ -237,6 +287,222 @@ impl BlockDeviceInfo {
         ))
     }
 
+    // use the given device as root device after checking the running OS resides on it
+    fn override_root(
+        root_override: &Path,
+        detected: &str,
+        root_number: &DeviceNum,
+        device_map: DeviceMap,
+    ) -> Result<BlockDeviceInfo> {
+        let device = if let Some(device) = device_map.get(root_override) {
+            device.clone()
+        } else {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "The source root device '{}' could not be found",
+                    root_override.display()
+                ),
+            ));
+        };
+
+        let mount_devices = BlockDeviceInfo::get_root_mount_devices(root_number)?;
+        if mount_devices.is_empty() {
+            warn!(
+                "Could not determine the device / is mounted from, the source root device '{}' can not be validated",
+                root_override.display()
+            );
+        } else if !mount_devices.iter().any(|name| name == device.get_name()) {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "The source root device '{}' does not host the running OS, / resides on {}",
+                    root_override.display(),
+                    mount_devices.join(", ")
+                ),
+            ));
+        }
+
+        info!(
+            "Detected source root device: {}, overridden by --source-root-device: {}",
+            detected,
+            root_override.display()
+        );
+
+        let (root_device, root_partition) = if let Some(parent) = device.get_parent() {
+            (parent.clone(), Some(device))
+        } else {
+            (device, None)
+        };
+
+        Ok(BlockDeviceInfo {
+            root_device,
+            root_partition,
+            devices: device_map,
+        })
+    }
+
+    /******************************************************************
+     * Names of the block devices the filesystem mounted on / resides
+     * on, including the disks of partitions and the devices below
+     * device mapper targets like LVM. Empty if they can not be found.
+     ******************************************************************/
+
+    fn get_root_mount_devices(root_number: &DeviceNum) -> Result<Vec<String>> {
+        let root_number = if root_number.major() != 0 {
+            root_number.clone()
+        } else {
+            // btrfs subvolumes and overlays have anonymous device numbers, try the mount source
+            let mounts = read_to_string(PROC_MOUNTS)
+                .upstream_with_context(&format!("Failed to read '{}'", PROC_MOUNTS))?;
+            let source = mounts
+                .lines()
+                .rev()
+                .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+                .find(|columns| columns.len() > 1 && columns[1] == "/")
+                .map(|columns| columns[0].to_string());
+            match source.map(|source| stat(source.as_str())) {
+                Some(Ok(stat_res))
+                    if SFlag::from_bits_truncate(stat_res.st_mode).contains(SFlag::S_IFBLK) =>
+                {
+                    DeviceNum::new(stat_res.st_rdev)
+                }
+                _ => return Ok(Vec::new()),
+            }
+        };
+
+        let mut names: Vec<String> = Vec::new();
+        BlockDeviceInfo::collect_sys_devices(
+            &PathBuf::from(format!("{}/{}", SYS_DEV_BLOCK_DIR, root_number)),
+            &mut names,
+        )?;
+        Ok(names)
+    }
+
+    fn collect_sys_devices(sys_path: &Path, names: &mut Vec<String>) -> Result<()> {
+        let sys_path = sys_path.canonicalize().upstream_with_context(&format!(
+            "Failed to canonicalize path '{}'",
+            sys_path.display()
+        ))?;
+        names.push(BlockDeviceInfo::path_filename_as_string(&sys_path)?);
+
+        if sys_path.join("partition").exists() {
+            if let Some(parent) = sys_path.parent() {
+                names.push(BlockDeviceInfo::path_filename_as_string(parent)?);
+            }
+        }
+
+        let slaves_path = sys_path.join("slaves");
+        if slaves_path.is_dir() {
+            for entry in read_dir(&slaves_path).upstream_with_context(&format!(
+                "Failed to read directory '{}'",
+                slaves_path.display()
+            ))? {
+                let entry = entry.upstream_with_context(&format!(
+                    "Failed to read directory entry from '{}'",
+                    slaves_path.display()
+                ))?;
+                BlockDeviceInfo::collect_sys_devices(&entry.path(), names)?;
+            }
+        }
+        Ok(())
+    }
+
+    // true if the block device name, eg. dm-0, is a dm-crypt mapping
+    fn is_crypt_mapping(name: &str) -> bool {
+        let uuid_path = PathBuf::from(format!("{}/{}/dm/uuid", SYS_BLOCK_DIR, name));
+        matches!(read_to_string(uuid_path), Ok(uuid) if uuid.starts_with(DM_CRYPT_UUID_PREFIX))
+    }
+
+    // the name of the dm-crypt mapping dev_path is, eg. /dev/mapper/root or /dev/dm-0
+    pub fn get_crypt_mapping_name(dev_path: &Path) -> Option<String> {
+        let dev_name =
+            BlockDeviceInfo::path_filename_as_string(dev_path.canonicalize().ok()?).ok()?;
+        if BlockDeviceInfo::is_crypt_mapping(&dev_name) {
+            let name_path = PathBuf::from(format!("{}/{}/dm/name", SYS_BLOCK_DIR, dev_name));
+            Some(
+                read_to_string(name_path)
+                    .map(|name| name.trim().to_string())
+                    .unwrap_or(dev_name),
+            )
+        } else {
+            None
+        }
+    }
+
+    /******************************************************************
+     * The partition (or disk) below device_num if it is a dm-crypt
+     * mapping or a device mapper target stacked on one, eg. LVM on
+     * LUKS. Ok(None) for devices without dm-crypt. Mappings over more
+     * than one device are refused, the device to flash is ambiguous.
+     ******************************************************************/
+
+    fn resolve_crypt_mapping(
+        device_map: &DeviceMap,
+        device_num: &DeviceNum,
+    ) -> Result<Option<Rc<dyn BlockDevice>>> {
+        let sys_path = PathBuf::from(format!("{}/{}", SYS_DEV_BLOCK_DIR, device_num));
+        if !sys_path.join("dm").exists() {
+            return Ok(None);
+        }
+
+        let mut names: Vec<String> = Vec::new();
+        BlockDeviceInfo::collect_sys_devices(&sys_path, &mut names)?;
+        let mapping = if let Some(name) = names
+            .iter()
+            .find(|name| BlockDeviceInfo::is_crypt_mapping(name))
+        {
+            name.clone()
+        } else {
+            return Ok(None);
+        };
+
+        let below: Vec<&Rc<dyn BlockDevice>> = device_map
+            .values()
+            .filter(|device| names.iter().any(|name| name == device.get_name()))
+            .collect();
+        let partitions: Vec<&Rc<dyn BlockDevice>> = below
+            .iter()
+            .filter(|device| device.get_parent().is_some())
+            .copied()
+            .collect();
+        let candidates = if partitions.is_empty() {
+            below
+        } else {
+            partitions
+        };
+
+        match candidates.as_slice() {
+            [device] => {
+                debug!(
+                    "resolve_crypt_mapping: dm-crypt mapping '{}' resides on '{}'",
+                    mapping,
+                    device.get_dev_path().display()
+                );
+                Ok(Some((*device).clone()))
+            }
+            [] => Err(Error::with_context(
+                ErrorKind::NotSupported,
+                &format!(
+                    "The dm-crypt mapping '{}' does not reside on a supported block device",
+                    mapping
+                ),
+            )),
+            _ => Err(Error::with_context(
+                ErrorKind::NotSupported,
+                &format!(
+                    "The dm-crypt mapping '{}' spans the devices {}, takeover only supports a mapping on a single device",
+                    mapping,
+                    candidates
+                        .iter()
+                        .map(|device| device.get_dev_path().display().to_string())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+            )),
+        }
+    }
+
     fn read_partitions<P: AsRef<Path>>(
         device: &Rc<dyn BlockDevice>,
         mounts: &MountTab,
@@ -338,6 +604,34 @@ impl BlockDeviceInfo {
         &self.devices
     }
 
+    // the block device holding the filesystem path resides on, None for eg. tmpfs or nfs
+    pub fn get_device_for_path<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<Option<&Rc<dyn BlockDevice>>> {
+        let path = path.as_ref();
+        let stat_res =
+            stat(path).upstream_with_context(&format!("Failed to stat '{}'", path.display()))?;
+        let device_num = DeviceNum::new(stat_res.st_dev);
+        if let Some(device) = self
+            .devices
+            .values()
+            .find(|device| *device.get_device_num() == device_num)
+        {
+            return Ok(Some(device));
+        }
+
+        // a filesystem on a dm-crypt mapping resides on the partition below it
+        if let Some(partition) = BlockDeviceInfo::resolve_crypt_mapping(&self.devices, &device_num)?
+        {
+            return Ok(self
+                .devices
+                .values()
+                .find(|device| device.get_name() == partition.get_name()));
+        }
+        Ok(None)
+    }
+
     fn get_maj_minor<P: AsRef<Path>>(dev_path: P) -> Result<DeviceNum> {
         let dev_info_path = path_append(dev_path.as_ref(), "dev");
         let dev_info = read_to_string(&dev_info_path).upstream_with_context(&format!(
@@ -21,6 +21,7 @@ pub(crate) struct PartitionInfo {
     block_size: Option<u32>,
     fs_type: Option<String>,
     label: Option<String>,
+    fs_label: Option<String>,
     part_uuid: Option<String>,
 }
 
@@ -45,6 +46,7 @@ impl PartitionInfo {
             let mut block_size: Option<u32> = None;
             let mut fs_type: Option<String> = None;
             let mut label: Option<String> = None;
+            let mut fs_label: Option<String> = None;
             let mut part_uuid: Option<String> = None;
 
             while let Some(params) = next_params {
@@ -69,6 +71,9 @@ impl PartitionInfo {
                         "TYPE" => {
                             fs_type = Some(param_value.to_owned());
                         }
+                        "LABEL" => {
+                            fs_label = Some(param_value.to_owned());
+                        }
                         "PARTLABEL" => {
                             label = Some(param_value.to_owned());
                         }
@@ -91,6 +96,7 @@ impl PartitionInfo {
                 part_uuid,
                 fs_type,
                 label,
+                fs_label,
             };
             debug!(
                 "PartitionInfo::new: for {} got {:?}",
@@ -112,6 +118,14 @@ impl PartitionInfo {
             None
         }
     }
+
+    pub fn fs_label(&self) -> Option<&str> {
+        if let Some(fs_label) = &self.fs_label {
+            Some(fs_label)
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Clone)]
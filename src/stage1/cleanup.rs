@@ -0,0 +1,199 @@
+use std::env::{current_dir, set_current_dir};
+use std::fs::{read_link, read_to_string, remove_dir_all};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use log::{info, warn};
+use mod_logger::{LogDestination, Logger, NO_STREAM};
+use nix::mount::{umount, umount2, MntFlags};
+
+use crate::{
+    common::{
+        call,
+        defs::{SWAPON_CMD, TAKEOVER_DIR},
+        dir_exists, whereis, Error, ErrorKind, Options, Result, ToError,
+    },
+    stage1::selinux::restore_enforcing,
+};
+
+const PROC_MOUNTS: &str = "/proc/mounts";
+
+// what this run did to the takeover directory, cleanup leaves a directory it did not set up alone
+const DIR_UNTOUCHED: u8 = 0;
+const DIR_REUSED: u8 = 1;
+const DIR_CREATED: u8 = 2;
+static TAKEOVER_DIR_STATE: AtomicU8 = AtomicU8::new(DIR_UNTOUCHED);
+// cleanup only turns swap back on if this run turned it off
+static SWAP_DISABLED: AtomicBool = AtomicBool::new(false);
+
+// called before anything is mounted in the takeover directory, created is false for an existing empty directory
+pub(crate) fn set_takeover_dir_used(created: bool) {
+    let state = if created { DIR_CREATED } else { DIR_REUSED };
+    TAKEOVER_DIR_STATE.store(state, Ordering::SeqCst);
+}
+
+// called before stage1 disables swap
+pub(crate) fn set_swap_disabled() {
+    SWAP_DISABLED.store(true, Ordering::SeqCst);
+}
+
+// mountpoints in mount order, octal escapes in /proc/mounts are not decoded
+fn get_mountpoints() -> Result<Vec<PathBuf>> {
+    let mounts = read_to_string(PROC_MOUNTS)
+        .upstream_with_context(&format!("Failed to read '{}'", PROC_MOUNTS))?;
+    Ok(mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(PathBuf::from)
+        .collect())
+}
+
+fn takeover_mounts(takeover_dir: &Path) -> Result<Vec<PathBuf>> {
+    Ok(get_mountpoints()?
+        .into_iter()
+        .filter(|mountpoint| mountpoint.starts_with(takeover_dir))
+        .collect())
+}
+
+fn unmount(mountpoint: &Path) {
+    if let Err(why) = umount(mountpoint) {
+        warn!(
+            "Failed to unmount '{}', error: {:?}, trying lazy unmount",
+            mountpoint.display(),
+            why
+        );
+        if let Err(why) = umount2(mountpoint, MntFlags::MNT_DETACH) {
+            warn!(
+                "Failed to lazily unmount '{}', error: {:?}",
+                mountpoint.display(),
+                why
+            );
+        }
+    } else {
+        info!("Unmounted '{}'", mountpoint.display());
+    }
+}
+
+/******************************************************************
+ * Undo what stage1 set up before pivoting:
+ * unmount the bind-mounted init and everything mounted in the
+ * takeover directory, remove the takeover directory, re-enable
+ * swap and switch SELinux back to enforcing. cleanup only touches the takeover directory if this run
+ * set it up and only removes it if this run created it, a
+ * directory left by a previous run is kept. It only re-enables
+ * swap if this run disabled it. cleanup_staged, used by --abort
+ * and --resume, relies on the current system state only, so it
+ * also removes what a previous run left behind.
+ ******************************************************************/
+
+pub(crate) fn cleanup(opts: &Options) -> Result<()> {
+    match TAKEOVER_DIR_STATE.load(Ordering::SeqCst) {
+        DIR_UNTOUCHED => {
+            info!(
+                "Cleaning up after stage1, '{}' was not set up by this run and is left as it is",
+                TAKEOVER_DIR
+            );
+        }
+        state => cleanup_takeover_dir(opts, state == DIR_CREATED)?,
+    }
+    finish_cleanup(SWAP_DISABLED.load(Ordering::SeqCst));
+    Ok(())
+}
+
+pub(crate) fn cleanup_staged(opts: &Options) -> Result<()> {
+    cleanup_takeover_dir(opts, true)?;
+    finish_cleanup(true);
+    Ok(())
+}
+
+fn finish_cleanup(swap_disabled: bool) {
+    if swap_disabled {
+        reenable_swap();
+    }
+    restore_enforcing();
+    info!("Cleanup finished");
+}
+
+fn cleanup_takeover_dir(opts: &Options, remove_dir: bool) -> Result<()> {
+    info!("Cleaning up after stage1");
+    let takeover_dir = Path::new(TAKEOVER_DIR);
+
+    // don't keep the log file open on a filesystem that is about to be unmounted
+    if let Some(log_file) = opts.log_file() {
+        if log_file.starts_with(takeover_dir) {
+            Logger::set_log_dest(&LogDestination::Stderr, NO_STREAM)
+                .upstream_with_context("Failed to restore logging to stderr")?;
+            warn!(
+                "Stopped logging to '{}', it is located in '{}'",
+                log_file.display(),
+                takeover_dir.display()
+            );
+        }
+    }
+    Logger::flush();
+
+    if let Ok(curr_dir) = current_dir() {
+        if curr_dir.starts_with(takeover_dir) {
+            set_current_dir("/").upstream_with_context("Failed to change current dir to '/'")?;
+        }
+    }
+
+    // the new init is bind-mounted on top of the original init
+    match read_link("/proc/1/exe") {
+        Ok(init_path) => {
+            if get_mountpoints()?.contains(&init_path) {
+                unmount(&init_path);
+            }
+        }
+        Err(why) => warn!("Failed to read link for /proc/1/exe, error: {}", why),
+    }
+
+    for mountpoint in takeover_mounts(takeover_dir)?.iter().rev() {
+        unmount(mountpoint);
+    }
+
+    if dir_exists(takeover_dir)? {
+        let remaining = takeover_mounts(takeover_dir)?;
+        if !remaining.is_empty() {
+            // removing the directory would descend into the remaining mounts, eg. /dev
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "Not removing '{}', filesystems are still mounted in it: {:?}",
+                    takeover_dir.display(),
+                    remaining
+                ),
+            ));
+        } else if remove_dir {
+            remove_dir_all(takeover_dir).upstream_with_context(&format!(
+                "Failed to remove directory '{}'",
+                takeover_dir.display()
+            ))?;
+            info!("Removed '{}'", takeover_dir.display());
+        } else {
+            info!(
+                "Kept '{}', it existed before this run",
+                takeover_dir.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+// stage1 disables swap before copying files to the RAMFS
+fn reenable_swap() {
+    match whereis(SWAPON_CMD) {
+        Ok(swapon_cmd) => match call(&swapon_cmd, &["-a"], true) {
+            Ok(cmd_res) => {
+                if !cmd_res.status.success() {
+                    warn!(
+                        "Failed to re-enable swap, stderr: {}",
+                        cmd_res.stderr.trim()
+                    );
+                }
+            }
+            Err(why) => warn!("Failed to re-enable swap, error: {}", why),
+        },
+        Err(why) => warn!("Failed to locate '{}', error: {}", SWAPON_CMD, why),
+    }
+}
@@ -1,4 +1,7 @@
 use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
 
 pub const DEV_TYPE_INTEL_NUC: &str = "intel-nuc";
 pub const DEV_TYPE_GEN_X86_64: &str = "genericx86-64-ext";
@@ -13,7 +16,7 @@ pub const DEV_TYPE_BBXM: &str = "beagleboard-xm";
 pub const MAX_CONFIG_JSON: usize = 2048;
 pub const GZIP_MAGIC_COOKIE: u16 = 0x1f8b;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub(crate) enum DeviceType {
     BeagleboneGreen,
     BeagleboneBlack,
@@ -29,9 +32,9 @@ impl Display for DeviceType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{},",
+            "{}",
             match self {
-                Self::IntelNuc => "X68_64/Intel Nuc",
+                Self::IntelNuc => "X86_64/Intel Nuc",
                 Self::BeagleboneGreen => "Beaglebone Green",
                 Self::BeagleboneBlack => "Beaglebone Black",
                 Self::BeagleboardXM => "Beagleboard XM",
@@ -44,10 +47,11 @@ impl Display for DeviceType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum OSArch {
     AMD64,
     ARMHF,
+    ARMV6,
     I386,
     ARM64,
     /*
@@ -59,3 +63,151 @@ pub(crate) enum OSArch {
         S390EX,
     */
 }
+
+impl OSArch {
+    // the architecture this binary was compiled for
+    pub fn compiled() -> Option<OSArch> {
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "x86_64")] {
+                Some(OSArch::AMD64)
+            } else if #[cfg(target_arch = "x86")] {
+                Some(OSArch::I386)
+            } else if #[cfg(all(target_arch = "arm", target_feature = "v7"))] {
+                Some(OSArch::ARMHF)
+            } else if #[cfg(target_arch = "arm")] {
+                Some(OSArch::ARMV6)
+            } else if #[cfg(target_arch = "aarch64")] {
+                Some(OSArch::ARM64)
+            } else {
+                None
+            }
+        }
+    }
+
+    // an ARMv6 build also runs on ARMv7 CPUs
+    pub fn runs_on(&self, arch: &OSArch) -> bool {
+        self == arch || (*self == OSArch::ARMV6 && *arch == OSArch::ARMHF)
+    }
+}
+
+impl Display for OSArch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let output = match self {
+            OSArch::AMD64 => "amd64",
+            OSArch::ARMHF => "armhf",
+            OSArch::ARMV6 => "armv6",
+            OSArch::I386 => "i386",
+            OSArch::ARM64 => "arm64",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+impl FromStr for OSArch {
+    type Err = String;
+
+    fn from_str(arch: &str) -> std::result::Result<Self, Self::Err> {
+        match arch {
+            "amd64" => Ok(OSArch::AMD64),
+            "armhf" => Ok(OSArch::ARMHF),
+            "armv6" => Ok(OSArch::ARMV6),
+            "arm64" => Ok(OSArch::ARM64),
+            _ => Err(format!(
+                "Invalid architecture '{}', expected one of amd64, armhf, armv6, arm64",
+                arch
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BootMode {
+    Auto,
+    Efi,
+    Legacy,
+}
+
+impl Display for BootMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let output = match self {
+            BootMode::Auto => "auto",
+            BootMode::Efi => "efi",
+            BootMode::Legacy => "legacy",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+impl FromStr for BootMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> std::result::Result<Self, Self::Err> {
+        match mode {
+            "auto" => Ok(BootMode::Auto),
+            "efi" => Ok(BootMode::Efi),
+            "legacy" => Ok(BootMode::Legacy),
+            _ => Err(format!(
+                "Invalid boot mode '{}', expected one of efi, legacy, auto",
+                mode
+            )),
+        }
+    }
+}
+
+// where migrated wifi networks are written to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum NetworkVia {
+    // the network section of config.json
+    Config,
+    // NetworkManager profiles in system-connections on the boot partition
+    SystemConnections,
+}
+
+impl Display for NetworkVia {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let output = match self {
+            NetworkVia::Config => "config",
+            NetworkVia::SystemConnections => "system-connections",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+impl FromStr for NetworkVia {
+    type Err = String;
+
+    fn from_str(via: &str) -> std::result::Result<Self, Self::Err> {
+        match via {
+            "config" => Ok(NetworkVia::Config),
+            "system-connections" => Ok(NetworkVia::SystemConnections),
+            _ => Err(format!(
+                "Invalid network target '{}', expected one of config, system-connections",
+                via
+            )),
+        }
+    }
+}
+
+// what to do about keys in config.json that are not known to balena-os or takeover
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum UnknownKeys {
+    Warn,
+    Error,
+    Ignore,
+}
+
+impl FromStr for UnknownKeys {
+    type Err = String;
+
+    fn from_str(mode: &str) -> std::result::Result<Self, Self::Err> {
+        match mode {
+            "warn" => Ok(UnknownKeys::Warn),
+            "error" => Ok(UnknownKeys::Error),
+            "ignore" => Ok(UnknownKeys::Ignore),
+            _ => Err(format!(
+                "Invalid unknown keys mode '{}', expected one of warn, error, ignore",
+                mode
+            )),
+        }
+    }
+}
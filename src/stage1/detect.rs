@@ -0,0 +1,98 @@
+use std::fs::read_to_string;
+
+use log::error;
+
+use crate::{
+    common::{Error, ErrorKind, Options, Result},
+    stage1::{
+        device_impl::{get_device, read_compatible, DEVICE_TREE_MODEL},
+        utils::{detect_os_arch, get_os_arch},
+    },
+};
+
+// the DMI values x86 boards identify themselves with
+const DMI_FILES: [(&str, &str); 3] = [
+    ("vendor", "/sys/class/dmi/id/sys_vendor"),
+    ("product", "/sys/class/dmi/id/product_name"),
+    ("board", "/sys/class/dmi/id/board_name"),
+];
+
+fn read_id(path: &str) -> Option<String> {
+    read_to_string(path)
+        .ok()
+        .map(|value| value.trim_end_matches('\0').trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+// what takeover detects the device as, the device type fails to resolve on unsupported devices
+pub(crate) fn get_detected(opts: &Options) -> (Vec<(&'static str, String)>, Result<()>) {
+    let mut report: Vec<(&'static str, String)> = Vec::new();
+
+    report.push((
+        "architecture",
+        match detect_os_arch() {
+            Ok(arch) => arch.to_string(),
+            Err(why) => format!("unknown ({})", why),
+        },
+    ));
+    if let Some(arch) = opts.arch() {
+        report.push(("requested arch", arch.to_string()));
+    }
+
+    if let Some(model) = read_id(DEVICE_TREE_MODEL) {
+        report.push(("device-tree model", model));
+    }
+    let compatible = read_compatible();
+    if !compatible.is_empty() {
+        report.push(("device-tree compatible", compatible.join(", ")));
+    }
+    for (name, path) in &DMI_FILES {
+        if let Some(value) = read_id(path) {
+            report.push((name, value));
+        }
+    }
+
+    let res = get_os_arch(opts).and_then(|_| get_device(opts));
+    match &res {
+        Ok(device) => {
+            report.push(("device type", device.get_device_type().to_string()));
+            let target = if let Some(flash_to) = opts.flash_to() {
+                format!("{} (--flash-to)", flash_to.display())
+            } else if let Some(default_dev) = device.default_target_device() {
+                format!("{} (install device of the board)", default_dev.display())
+            } else {
+                "the device the running OS resides on".to_string()
+            };
+            report.push(("target device", target));
+        }
+        Err(why) if why.kind() == ErrorKind::Displayed => {
+            report.push(("device type", "not supported, see log".to_string()))
+        }
+        Err(why) => report.push(("device type", format!("not supported ({})", why))),
+    }
+
+    (report, res.map(|_| ()))
+}
+
+/******************************************************************
+ * Print what takeover detects the device as: the architecture, the
+ * hardware identification it goes by and the resulting device type
+ * and target device, then exit. Nothing is modified, so this runs
+ * without root.
+ ******************************************************************/
+
+pub(crate) fn detect_only(opts: &Options) -> Result<()> {
+    let (report, res) = get_detected(opts);
+    for (name, value) in &report {
+        println!("{:<18} {}", format!("{}:", name), value);
+    }
+
+    match res {
+        Ok(_) => Ok(()),
+        Err(why) if why.kind() == ErrorKind::Displayed => Err(why),
+        Err(why) => {
+            error!("The device was not identified: {}", why);
+            Err(Error::displayed_with(ErrorKind::NotSupported))
+        }
+    }
+}
@@ -1,12 +1,37 @@
 use std::fmt::{self, Display, Debug};
+use std::path::{Path, PathBuf};
 
 use crate::{
+    common::{stage2_config::RebootMethod, Result},
     stage1::{defs::DeviceType, },
 };
 
+// handed to Device::post_flash by stage2 while the flashed boot partition is mounted
+pub(crate) struct PostFlashContext<'a> {
+    pub boot_mountpoint: &'a Path,
+    // the boot settings collected by get_boot_config, if any
+    pub boot_config: Option<&'a Path>,
+}
+
 pub(crate) trait Device {
     fn supports_device_type(&self, dev_type: &str) -> bool;
     fn get_device_type(&self) -> DeviceType;
+    // boot settings of the running system that the flashed boot partition should keep
+    fn get_boot_config(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+    // device specific touch-ups of the flashed image, called by stage2 after writing it
+    fn post_flash(&self, _ctx: &PostFlashContext) -> Result<()> {
+        Ok(())
+    }
+    // the conventional install device of the board, flashed unless --flash-to is given
+    fn default_target_device(&self) -> Option<PathBuf> {
+        None
+    }
+    // how stage2 reboots into the flashed OS unless --reboot-command is given
+    fn reboot_method(&self) -> RebootMethod {
+        RebootMethod::Restart
+    }
 }
 
 impl Display for dyn Device {
@@ -1,10 +1,16 @@
-use log::{error, info, warn};
-use std::fs::read_to_string;
+use log::{debug, error, info, warn};
+use std::fs::{read, read_to_string};
 
-use crate::common::ToError;
 use crate::{
     common::{get_os_name, Error, ErrorKind, Options, Result},
-    stage1::{defs::OSArch, device::Device, utils::get_os_arch},
+    stage1::{
+        defs::{
+            DeviceType, OSArch, DEV_TYPE_BBB, DEV_TYPE_BBG, DEV_TYPE_BBXM, DEV_TYPE_RPI1,
+            DEV_TYPE_RPI2, DEV_TYPE_RPI3, DEV_TYPE_RPI4_64,
+        },
+        device::Device,
+        utils::get_os_arch,
+    },
 };
 
 // mod beaglebone;
@@ -12,7 +18,48 @@ mod beaglebone;
 mod intel_nuc;
 mod raspberrypi;
 
-const DEVICE_TREE_MODEL: &str = "/proc/device-tree/model";
+pub(crate) const DEVICE_TREE_MODEL: &str = "/proc/device-tree/model";
+// a list of NUL terminated strings, the most specific first
+pub(crate) const DEVICE_TREE_COMPATIBLE: &str = "/proc/device-tree/compatible";
+
+// balena device types of boards by their device tree compatible, used when the model is not recognized
+const COMPATIBLE_DEVICE_TYPES: [(&str, &str); 22] = [
+    ("raspberrypi,model-a", DEV_TYPE_RPI1),
+    ("raspberrypi,model-a-plus", DEV_TYPE_RPI1),
+    ("raspberrypi,model-b", DEV_TYPE_RPI1),
+    ("raspberrypi,model-b-plus", DEV_TYPE_RPI1),
+    ("raspberrypi,model-b-rev2", DEV_TYPE_RPI1),
+    ("raspberrypi,model-zero", DEV_TYPE_RPI1),
+    ("raspberrypi,model-zero-w", DEV_TYPE_RPI1),
+    ("raspberrypi,compute-module", DEV_TYPE_RPI1),
+    ("raspberrypi,2-model-b", DEV_TYPE_RPI2),
+    ("raspberrypi,3-model-b", DEV_TYPE_RPI3),
+    ("raspberrypi,3-model-b-plus", DEV_TYPE_RPI3),
+    ("raspberrypi,3-compute-module", DEV_TYPE_RPI3),
+    ("raspberrypi,4-model-b", DEV_TYPE_RPI4_64),
+    ("ti,am335x-bone-black", DEV_TYPE_BBB),
+    ("ti,am335x-bone-green", DEV_TYPE_BBG),
+    // the plain BeagleBone is handled like the green one, see beaglebone::is_bb
+    ("ti,am335x-bone", DEV_TYPE_BBG),
+    ("ti,omap3-beagle-xm", DEV_TYPE_BBXM),
+    // recognized to name them, takeover does not support these
+    ("nvidia,p3450-0000", "jetson-nano"),
+    ("nvidia,p3541-0000", "jetson-nano-2gb-devkit"),
+    ("nvidia,p3509-0000+p3668-0000", "jetson-xavier-nx-devkit"),
+    ("nvidia,p2972-0000", "jetson-xavier"),
+    ("nvidia,p2771-0000", "jetson-tx2"),
+];
+
+const DEVICE_TYPES: [DeviceType; 8] = [
+    DeviceType::BeagleboneGreen,
+    DeviceType::BeagleboneBlack,
+    DeviceType::BeagleboardXM,
+    DeviceType::IntelNuc,
+    DeviceType::RaspberryPi1,
+    DeviceType::RaspberryPi2,
+    DeviceType::RaspberryPi3,
+    DeviceType::RaspberryPi4,
+];
 
 pub(crate) fn check_os(supported: &[&str], opts: &Options, dev_type: &str) -> Result<bool> {
     let os_name = get_os_name()?;
@@ -39,22 +86,69 @@ pub(crate) fn check_os(supported: &[&str], opts: &Options, dev_type: &str) -> Re
     }
 }
 
+// the entries of the device tree compatible, empty if the device has none
+pub(crate) fn read_compatible() -> Vec<String> {
+    read(DEVICE_TREE_COMPATIBLE)
+        .map(|compatible| {
+            compatible
+                .split(|byte| *byte == 0)
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| String::from_utf8_lossy(entry).to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// the balena device type of the most specific compatible entry that has one
+fn get_compatible_slug(compatible: &[String]) -> Option<(&str, &'static str)> {
+    compatible.iter().find_map(|entry| {
+        COMPATIBLE_DEVICE_TYPES
+            .iter()
+            .find(|(dt_compatible, _)| dt_compatible == entry)
+            .map(|(_, slug)| (entry.as_str(), *slug))
+    })
+}
+
+fn get_device_for_slug(opts: &Options, slug: &str) -> Result<Option<Box<dyn Device>>> {
+    Ok(Some(match slug {
+        DEV_TYPE_RPI1 => Box::new(raspberrypi::RaspberryPi1::from_config(opts)?),
+        DEV_TYPE_RPI2 => Box::new(raspberrypi::RaspberryPi2::from_config(opts)?),
+        DEV_TYPE_RPI3 => Box::new(raspberrypi::RaspberryPi3::from_config(opts)?),
+        DEV_TYPE_RPI4_64 => Box::new(raspberrypi::RaspberryPi4_64::from_config(opts)?),
+        DEV_TYPE_BBG => Box::new(beaglebone::BeagleboneGreen::from_config(opts)?),
+        DEV_TYPE_BBB => Box::new(beaglebone::BeagleboneBlack::from_config(opts)?),
+        DEV_TYPE_BBXM => Box::new(beaglebone::BeagleboardXM::from_config(opts)?),
+        _ => return Ok(None),
+    }))
+}
+
 pub(crate) fn get_device(opts: &Options) -> Result<Box<dyn Device>> {
-    let os_arch = get_os_arch()?;
+    let os_arch = get_os_arch(opts)?;
     info!("Detected OS Architecture is {:?}", os_arch);
 
     match os_arch {
-        OSArch::ARMHF | OSArch::ARM64 => {
-            let dev_tree_model = String::from(
-                read_to_string(DEVICE_TREE_MODEL)
-                    .upstream_with_context(&format!(
-                        "get_device: unable to determine model due to inaccessible file '{}'",
-                        DEVICE_TREE_MODEL
-                    ))?
-                    .trim_end_matches('\0')
-                    .trim_end(),
+        OSArch::ARMHF | OSArch::ARMV6 | OSArch::ARM64 => {
+            let compatible = read_compatible();
+            debug!(
+                "get_device: device tree compatible: '{}'",
+                compatible.join("', '")
             );
 
+            let dev_tree_model = match read_to_string(DEVICE_TREE_MODEL) {
+                Ok(model) => String::from(model.trim_end_matches('\0').trim_end()),
+                Err(why) if compatible.is_empty() => {
+                    return Err(Error::from_upstream(
+                        Box::new(why),
+                        &format!(
+                            "get_device: unable to determine model due to inaccessible file '{}'",
+                            DEVICE_TREE_MODEL
+                        ),
+                    ));
+                }
+                Err(_) => String::new(),
+            };
+            debug!("get_device: device tree model: '{}'", dev_tree_model);
+
             if let Some(device) = raspberrypi::is_rpi(opts, &dev_tree_model)? {
                 return Ok(device);
             }
@@ -63,6 +157,22 @@ pub(crate) fn get_device(opts: &Options) -> Result<Box<dyn Device>> {
                 return Ok(device);
             }
 
+            if let Some((dt_compatible, slug)) = get_compatible_slug(&compatible) {
+                if let Some(device) = get_device_for_slug(opts, slug)? {
+                    info!(
+                        "Identified device type {} by device tree compatible '{}'",
+                        slug, dt_compatible
+                    );
+                    return Ok(device);
+                }
+                let message = format!(
+                    "Your device was identified as balena device type '{}' by device tree compatible '{}', which is not supported by takeover.",
+                    slug, dt_compatible
+                );
+                error!("{}", message);
+                return Err(Error::with_context(ErrorKind::NotSupported, &message));
+            }
+
             let message = format!(
                 "Your device type: '{}' is not supported by balena-migrate.",
                 dev_tree_model
@@ -81,3 +191,57 @@ pub(crate) fn get_device(opts: &Options) -> Result<Box<dyn Device>> {
         )),
     }
 }
+
+// stage2 can not probe the device, it gets the one stage1 detected
+pub(crate) fn get_device_for_type(device_type: DeviceType) -> Box<dyn Device> {
+    match device_type {
+        DeviceType::BeagleboneGreen => Box::new(beaglebone::BeagleboneGreen {}),
+        DeviceType::BeagleboneBlack => Box::new(beaglebone::BeagleboneBlack {}),
+        DeviceType::BeagleboardXM => Box::new(beaglebone::BeagleboardXM {}),
+        DeviceType::IntelNuc => Box::new(intel_nuc::IntelNuc),
+        DeviceType::RaspberryPi1 => Box::new(raspberrypi::RaspberryPi1),
+        DeviceType::RaspberryPi2 => Box::new(raspberrypi::RaspberryPi2),
+        DeviceType::RaspberryPi3 => Box::new(raspberrypi::RaspberryPi3),
+        DeviceType::RaspberryPi4 => Box::new(raspberrypi::RaspberryPi4_64),
+    }
+}
+
+// true if one of the supported devices accepts the device type slug
+pub(crate) fn is_known_device_type(slug: &str) -> bool {
+    DEVICE_TYPES
+        .iter()
+        .any(|device_type| get_device_for_type(*device_type).supports_device_type(slug))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compatible_slug() {
+        let compatible = |entries: &[&str]| -> Vec<String> {
+            entries.iter().map(|entry| entry.to_string()).collect()
+        };
+
+        assert_eq!(
+            get_compatible_slug(&compatible(&["raspberrypi,3-model-b-plus", "brcm,bcm2837"])),
+            Some(("raspberrypi,3-model-b-plus", DEV_TYPE_RPI3))
+        );
+        // the most specific entry wins over the generic board
+        assert_eq!(
+            get_compatible_slug(&compatible(&[
+                "ti,am335x-bone-black",
+                "ti,am335x-bone",
+                "ti,am33xx"
+            ])),
+            Some(("ti,am335x-bone-black", DEV_TYPE_BBB))
+        );
+        // unknown entries are skipped
+        assert_eq!(
+            get_compatible_slug(&compatible(&["vendor,custom-board", "nvidia,p3450-0000"])),
+            Some(("nvidia,p3450-0000", "jetson-nano"))
+        );
+        assert_eq!(get_compatible_slug(&compatible(&["brcm,bcm2711"])), None);
+        assert_eq!(get_compatible_slug(&[]), None);
+    }
+}
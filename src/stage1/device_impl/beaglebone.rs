@@ -74,9 +74,9 @@ pub(crate) struct BeagleboneGreen {}
 
 impl BeagleboneGreen {
     // this is used in stage1
-    fn from_config(opts: &Options) -> Result<BeagleboneGreen> {
+    pub fn from_config(opts: &Options) -> Result<BeagleboneGreen> {
         if !check_os(&SUPPORTED_OSSES, opts, "Beaglebone Green")? {
-            return Err(Error::displayed());
+            return Err(Error::displayed_with(ErrorKind::NotSupported));
         }
 
         Ok(BeagleboneGreen {})
@@ -97,9 +97,9 @@ pub(crate) struct BeagleboneBlack {}
 
 impl BeagleboneBlack {
     // this is used in stage1
-    fn from_config(opts: &Options) -> Result<BeagleboneBlack> {
+    pub fn from_config(opts: &Options) -> Result<BeagleboneBlack> {
         if !check_os(&SUPPORTED_OSSES, opts, "Beaglebone Black")? {
-            return Err(Error::displayed());
+            return Err(Error::displayed_with(ErrorKind::NotSupported));
         }
 
         Ok(BeagleboneBlack {})
@@ -120,9 +120,9 @@ pub(crate) struct BeagleboardXM {}
 
 impl BeagleboardXM {
     // this is used in stage1
-    fn from_config(opts: &Options) -> Result<BeagleboardXM> {
+    pub fn from_config(opts: &Options) -> Result<BeagleboardXM> {
         if opts.migrate() && !check_os(&SUPPORTED_OSSES, opts, "Beagleboard XM")? {
-            return Err(Error::displayed());
+            return Err(Error::displayed_with(ErrorKind::NotSupported));
         }
 
         Ok(BeagleboardXM {})
@@ -1,8 +1,9 @@
 use log::{error, info};
+use std::path::PathBuf;
 
 use crate::stage1::device_impl::check_os;
 use crate::{
-    common::{Error, Options, Result},
+    common::{Error, ErrorKind, Options, Result},
     // linux_common::is_secure_boot,
     stage1::{
         defs::{DeviceType, DEV_TYPE_GEN_X86_64, DEV_TYPE_INTEL_NUC},
@@ -12,6 +13,8 @@ use crate::{
 };
 
 const X86_SLUGS: [&str; 2] = [DEV_TYPE_INTEL_NUC, DEV_TYPE_GEN_X86_64];
+// NUCs come with an NVMe or a SATA drive, the NVMe drive is preferred if both are present
+const NUC_TARGET_DEVICES: [&str; 2] = ["/dev/nvme0n1", "/dev/sda"];
 
 pub(crate) struct IntelNuc;
 
@@ -32,7 +35,7 @@ impl IntelNuc {
 
         if opts.migrate() {
             if !check_os(SUPPORTED_OSSES, opts, "Generic x86_64/Intel Nuc")? {
-                return Err(Error::displayed());
+                return Err(Error::displayed_with(ErrorKind::NotSupported));
             }
 
             // **********************************************************************
@@ -50,7 +53,7 @@ impl IntelNuc {
                     "{} does not currently support systems with secure boot enabled.",
                     env!("CARGO_PKG_NAME")
                 );
-                return Err(Error::displayed());
+                return Err(Error::displayed_with(ErrorKind::NotSupported));
             }
         }
         Ok(IntelNuc)
@@ -64,4 +67,10 @@ impl<'a> Device for IntelNuc {
     fn get_device_type(&self) -> DeviceType {
         DeviceType::IntelNuc
     }
+    fn default_target_device(&self) -> Option<PathBuf> {
+        NUC_TARGET_DEVICES
+            .iter()
+            .map(PathBuf::from)
+            .find(|device| device.exists())
+    }
 }
@@ -1,12 +1,16 @@
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use nix::unistd::sync;
 use regex::Regex;
+use std::fs::{read_to_string, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use crate::stage1::device_impl::check_os;
 use crate::{
-    common::{options::Options, Error, ErrorKind, Result},
+    common::{file_exists, options::Options, path_append, Error, ErrorKind, Result, ToError},
     stage1::{
         defs::{DeviceType, DEV_TYPE_RPI1, DEV_TYPE_RPI2, DEV_TYPE_RPI3, DEV_TYPE_RPI4_64},
-        device::Device,
+        device::{Device, PostFlashContext},
     },
 };
 
@@ -20,6 +24,8 @@ const RPI1_SLUGS: [&str; 1] = [DEV_TYPE_RPI1];
 const RPI2_SLUGS: [&str; 1] = [DEV_TYPE_RPI2];
 const RPI3_SLUGS: [&str; 1] = [DEV_TYPE_RPI3];
 const RPI4_64_SLUGS: [&str; 1] = [DEV_TYPE_RPI4_64];
+// the SD card or the eMMC of compute modules
+const RPI_TARGET_DEVICE: &str = "/dev/mmcblk0";
 
 const SUPPORTED_OSSES: [&str; 4] = [
     "Raspbian GNU/Linux 8 (jessie)",
@@ -28,6 +34,167 @@ const SUPPORTED_OSSES: [&str; 4] = [
     "Ubuntu 20.04 LTS",
 ];
 
+// config.txt locations of Raspberry Pi OS and Ubuntu
+const CONFIG_TXT_PATHS: [&str; 2] = ["/boot/config.txt", "/boot/firmware/config.txt"];
+// settings that configure attached hardware like displays and HATs
+const BOOT_CONFIG_REGEX: &str = r#"^\s*(dtoverlay|dtparam|device_tree_overlay|device_tree_param|hdmi_\w+|display_\w+|lcd_\w+|framebuffer_\w+|overscan_\w+|disable_overscan|enable_uart|gpu_mem\w*)\s*="#;
+// Ubuntu spreads its settings over included files
+const MAX_INCLUDE_DEPTH: usize = 2;
+
+// config.txt on the balena boot partition
+const BALENA_CONFIG_TXT: &str = "/config.txt";
+const BOOT_CONFIG_MARKER: &str = "# boot settings migrated by takeover";
+
+fn read_config_txt(path: &Path, depth: usize, lines: &mut Vec<String>) -> Result<()> {
+    let content = read_to_string(path)
+        .upstream_with_context(&format!("Failed to read '{}'", path.display()))?;
+    for line in content.lines() {
+        let line = line.trim();
+        if let Some(include) = line.strip_prefix("include ") {
+            let include_path = path
+                .parent()
+                .unwrap_or_else(|| Path::new("/"))
+                .join(include.trim());
+            if depth < MAX_INCLUDE_DEPTH && file_exists(&include_path) {
+                read_config_txt(&include_path, depth + 1, lines)?;
+            } else {
+                warn!(
+                    "Not following include of '{}' in '{}'",
+                    include_path.display(),
+                    path.display()
+                );
+            }
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+    Ok(())
+}
+
+/******************************************************************
+ * Collect the hardware related settings from config.txt of the
+ * running system, keeping the conditional sections they appear in
+ ******************************************************************/
+
+fn get_rpi_boot_config() -> Result<Option<String>> {
+    let config_path = if let Some(config_path) = CONFIG_TXT_PATHS
+        .iter()
+        .map(Path::new)
+        .find(|path| file_exists(path))
+    {
+        config_path
+    } else {
+        info!("No config.txt found, not migrating boot settings");
+        return Ok(None);
+    };
+
+    let mut lines: Vec<String> = Vec::new();
+    read_config_txt(config_path, 0, &mut lines)?;
+
+    let setting_regex = Regex::new(BOOT_CONFIG_REGEX).unwrap();
+    let mut section = String::from("[all]");
+    let mut output_section = section.clone();
+    let mut boot_config: Vec<String> = Vec::new();
+
+    for line in lines {
+        if line.starts_with('[') {
+            section = line;
+        } else if setting_regex.is_match(&line) {
+            if section != output_section {
+                boot_config.push(section.clone());
+                output_section = section.clone();
+            }
+            debug!("Migrating boot setting '{}'", line);
+            boot_config.push(line);
+        }
+    }
+
+    if boot_config.is_empty() {
+        info!(
+            "No boot settings to migrate found in '{}'",
+            config_path.display()
+        );
+        Ok(None)
+    } else {
+        if output_section != "[all]" {
+            boot_config.push(String::from("[all]"));
+        }
+        info!(
+            "Migrating {} boot settings from '{}'",
+            boot_config.len(),
+            config_path.display()
+        );
+        Ok(Some(boot_config.join("\n") + "\n"))
+    }
+}
+
+// append the boot settings collected by stage1 that the image does not contain yet
+fn merge_boot_config(ctx: &PostFlashContext) -> Result<()> {
+    let src_path = if let Some(src_path) = ctx.boot_config {
+        src_path
+    } else {
+        return Ok(());
+    };
+
+    let boot_config = read_to_string(src_path)
+        .upstream_with_context(&format!("Failed to read '{}'", src_path.display()))?;
+
+    let target_path = path_append(ctx.boot_mountpoint, BALENA_CONFIG_TXT);
+    let curr_config = if file_exists(&target_path) {
+        read_to_string(&target_path)
+            .upstream_with_context(&format!("Failed to read '{}'", target_path.display()))?
+    } else {
+        String::new()
+    };
+
+    let mut merged = 0;
+    let mut new_config = curr_config.clone();
+    if !new_config.is_empty() && !new_config.ends_with('\n') {
+        new_config.push('\n');
+    }
+    new_config.push_str(BOOT_CONFIG_MARKER);
+    new_config.push('\n');
+    for line in boot_config.lines() {
+        // section headers are kept so settings stay conditional
+        if line.starts_with('[') || !curr_config.lines().any(|curr| curr.trim() == line) {
+            new_config.push_str(line);
+            new_config.push('\n');
+            if !line.starts_with('[') {
+                merged += 1;
+            }
+        }
+    }
+
+    if merged == 0 {
+        info!(
+            "The boot settings are already present in '{}'",
+            target_path.display()
+        );
+        return Ok(());
+    }
+
+    let mut target_file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&target_path)
+        .upstream_with_context(&format!(
+            "Failed to open '{}' for writing",
+            target_path.display()
+        ))?;
+    target_file
+        .write_all(new_config.as_bytes())
+        .upstream_with_context(&format!("Failed to write '{}'", target_path.display()))?;
+    sync();
+
+    info!(
+        "Merged {} boot settings into '{}'",
+        merged,
+        target_path.display()
+    );
+    Ok(())
+}
+
 pub(crate) fn is_rpi(opts: &Options, model_string: &str) -> Result<Option<Box<dyn Device>>> {
     debug!(
         "raspberrypi::is_rpi: entered with model string: '{}'",
@@ -91,7 +258,7 @@ pub(crate) struct RaspberryPi1;
 impl RaspberryPi1 {
     pub fn from_config(opts: &Options) -> Result<RaspberryPi1> {
         if opts.migrate() && !check_os(&SUPPORTED_OSSES, opts, "Raspberry PI 1")? {
-            return Err(Error::displayed());
+            return Err(Error::displayed_with(ErrorKind::NotSupported));
         }
 
         Ok(RaspberryPi1 {})
@@ -106,13 +273,25 @@ impl Device for RaspberryPi1 {
     fn get_device_type(&self) -> DeviceType {
         DeviceType::RaspberryPi1
     }
+
+    fn get_boot_config(&self) -> Result<Option<String>> {
+        get_rpi_boot_config()
+    }
+
+    fn post_flash(&self, ctx: &PostFlashContext) -> Result<()> {
+        merge_boot_config(ctx)
+    }
+
+    fn default_target_device(&self) -> Option<PathBuf> {
+        Some(PathBuf::from(RPI_TARGET_DEVICE))
+    }
 }
 
 pub(crate) struct RaspberryPi2;
 impl RaspberryPi2 {
     pub fn from_config(opts: &Options) -> Result<RaspberryPi2> {
         if opts.migrate() && !check_os(&SUPPORTED_OSSES, opts, "Raspberry PI 2")? {
-            return Err(Error::displayed());
+            return Err(Error::displayed_with(ErrorKind::NotSupported));
         }
 
         Ok(RaspberryPi2 {})
@@ -127,6 +306,18 @@ impl Device for RaspberryPi2 {
     fn get_device_type(&self) -> DeviceType {
         DeviceType::RaspberryPi2
     }
+
+    fn get_boot_config(&self) -> Result<Option<String>> {
+        get_rpi_boot_config()
+    }
+
+    fn post_flash(&self, ctx: &PostFlashContext) -> Result<()> {
+        merge_boot_config(ctx)
+    }
+
+    fn default_target_device(&self) -> Option<PathBuf> {
+        Some(PathBuf::from(RPI_TARGET_DEVICE))
+    }
 }
 
 pub(crate) struct RaspberryPi3;
@@ -134,7 +325,7 @@ pub(crate) struct RaspberryPi3;
 impl RaspberryPi3 {
     pub fn from_config(opts: &Options) -> Result<RaspberryPi3> {
         if opts.migrate() && !check_os(&SUPPORTED_OSSES, opts, "Raspberry PI 3")? {
-            return Err(Error::displayed());
+            return Err(Error::displayed_with(ErrorKind::NotSupported));
         }
 
         Ok(RaspberryPi3)
@@ -149,6 +340,18 @@ impl Device for RaspberryPi3 {
     fn get_device_type(&self) -> DeviceType {
         DeviceType::RaspberryPi3
     }
+
+    fn get_boot_config(&self) -> Result<Option<String>> {
+        get_rpi_boot_config()
+    }
+
+    fn post_flash(&self, ctx: &PostFlashContext) -> Result<()> {
+        merge_boot_config(ctx)
+    }
+
+    fn default_target_device(&self) -> Option<PathBuf> {
+        Some(PathBuf::from(RPI_TARGET_DEVICE))
+    }
 }
 
 pub(crate) struct RaspberryPi4_64;
@@ -156,7 +359,7 @@ pub(crate) struct RaspberryPi4_64;
 impl RaspberryPi4_64 {
     pub fn from_config(opts: &Options) -> Result<RaspberryPi4_64> {
         if opts.migrate() && !check_os(&SUPPORTED_OSSES, opts, "Raspberry PI 4")? {
-            return Err(Error::displayed());
+            return Err(Error::displayed_with(ErrorKind::NotSupported));
         }
 
         Ok(RaspberryPi4_64)
@@ -171,4 +374,16 @@ impl Device for RaspberryPi4_64 {
     fn get_device_type(&self) -> DeviceType {
         DeviceType::RaspberryPi4
     }
+
+    fn get_boot_config(&self) -> Result<Option<String>> {
+        get_rpi_boot_config()
+    }
+
+    fn post_flash(&self, ctx: &PostFlashContext) -> Result<()> {
+        merge_boot_config(ctx)
+    }
+
+    fn default_target_device(&self) -> Option<PathBuf> {
+        Some(PathBuf::from(RPI_TARGET_DEVICE))
+    }
 }
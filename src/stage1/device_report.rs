@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::fs::{read_dir, read_link, read_to_string, write};
+use std::path::Path;
+use std::time::Duration;
+
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    common::{
+        defs::DISK_BY_LABEL_PATH, get_mem_info, get_os_name, system::uname, Options, Result,
+        ToError,
+    },
+    stage1::{
+        detect::get_detected,
+        migrate_info::{apply_endpoint_options, MigrateInfo},
+        probe::probe_endpoints,
+    },
+};
+
+const SYS_BLOCK_DIR: &str = "/sys/block";
+// sysfs gives sizes and offsets in 512 byte sectors
+const SYSFS_SECTOR_SIZE: u64 = 512;
+
+fn read_sys_value(path: &Path) -> Option<String> {
+    read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn read_sys_sectors(path: &Path) -> Option<u64> {
+    read_sys_value(path)
+        .and_then(|value| value.parse::<u64>().ok())
+        .map(|sectors| sectors * SYSFS_SECTOR_SIZE)
+}
+
+// device names by filesystem label, the links in /dev/disk/by-label are readable without root
+fn get_labels() -> HashMap<String, String> {
+    let mut labels = HashMap::new();
+    if let Ok(entries) = read_dir(DISK_BY_LABEL_PATH) {
+        for entry in entries.flatten() {
+            if let Ok(target) = read_link(entry.path()) {
+                if let Some(name) = target.file_name() {
+                    labels.insert(
+                        name.to_string_lossy().to_string(),
+                        entry.file_name().to_string_lossy().to_string(),
+                    );
+                }
+            }
+        }
+    }
+    labels
+}
+
+// the block devices with their partitions as sysfs lists them, empty devices are left out
+fn get_block_devices() -> Result<Value> {
+    let labels = get_labels();
+    let mut devices: Vec<Value> = Vec::new();
+    for entry in read_dir(SYS_BLOCK_DIR)
+        .upstream_with_context(&format!("Failed to read directory '{}'", SYS_BLOCK_DIR))?
+    {
+        let entry =
+            entry.upstream_with_context(&format!("Failed to read entry of '{}'", SYS_BLOCK_DIR))?;
+        let dev_dir = entry.path();
+        let size = read_sys_sectors(&dev_dir.join("size")).unwrap_or(0);
+        if size == 0 {
+            continue;
+        }
+
+        let mut partitions: Vec<Value> = Vec::new();
+        if let Ok(part_entries) = read_dir(&dev_dir) {
+            for part_entry in part_entries.flatten() {
+                let part_dir = part_entry.path();
+                if !part_dir.join("partition").exists() {
+                    continue;
+                }
+                let name = part_entry.file_name().to_string_lossy().to_string();
+                partitions.push(json!({
+                    "label": labels.get(&name),
+                    "start": read_sys_sectors(&part_dir.join("start")),
+                    "size": read_sys_sectors(&part_dir.join("size")),
+                    "name": name,
+                }));
+            }
+        }
+        partitions.sort_by_key(|part| part["name"].as_str().unwrap_or_default().to_string());
+
+        devices.push(json!({
+            "name": entry.file_name().to_string_lossy(),
+            "size": size,
+            "model": read_sys_value(&dev_dir.join("device/model")),
+            "removable": read_sys_value(&dev_dir.join("removable")).as_deref() == Some("1"),
+            "read_only": read_sys_value(&dev_dir.join("ro")).as_deref() == Some("1"),
+            "partitions": partitions,
+        }));
+    }
+    devices.sort_by_key(|device| device["name"].as_str().unwrap_or_default().to_string());
+    Ok(Value::Array(devices))
+}
+
+// the config.json given with --config with its secrets hidden and the results of probing its endpoints
+fn get_config_report(opts: &Options) -> (Value, Value) {
+    let config_path = if let Some(config_path) = opts.config().first() {
+        config_path
+    } else {
+        let not_given = json!({ "error": "no config.json given with --config" });
+        return (not_given.clone(), not_given);
+    };
+
+    let res = MigrateInfo::read_cfg_json(config_path).and_then(|mut config| {
+        apply_endpoint_options(&mut config, opts)?;
+        Ok(config)
+    });
+    let config = match res {
+        Ok(config) => config,
+        Err(why) => {
+            let failed = json!({ "error": format!("failed to read config.json: {}", why) });
+            return (failed.clone(), failed);
+        }
+    };
+
+    let probe = match probe_endpoints(&config, Duration::from_secs(opts.check_timeout())) {
+        Ok(results) => Value::Array(results.iter().map(|result| result.to_json()).collect()),
+        Err(why) => json!({ "error": format!("failed to read the endpoints: {}", why) }),
+    };
+    // secrets can show up in error messages, eg. a key in an endpoint url
+    let probe = match serde_json::from_str(&config.redact(&probe.to_string())) {
+        Ok(probe) => probe,
+        Err(_) => json!({ "error": "failed to redact the probe results" }),
+    };
+
+    (config.to_redacted(), probe)
+}
+
+fn unknown(why: impl std::fmt::Display) -> Value {
+    json!({ "error": why.to_string() })
+}
+
+/******************************************************************
+ * Collect what support needs to look into a failed migration into
+ * one JSON file: what the device is detected as, kernel, memory,
+ * block devices, the config.json with its secrets hidden and a
+ * probe of its endpoints. Nothing is modified, so this runs without
+ * root and without a migration. Parts that can not be determined
+ * are reported as errors instead of failing the report.
+ ******************************************************************/
+
+pub(crate) fn report_device_info(opts: &Options, report_path: &Path) -> Result<()> {
+    let mut report = Map::new();
+    report.insert("takeover".to_string(), json!(env!("CARGO_PKG_VERSION")));
+
+    let (detected, _) = get_detected(opts);
+    report.insert(
+        "detected".to_string(),
+        Value::Object(
+            detected
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), Value::from(value)))
+                .collect(),
+        ),
+    );
+
+    report.insert(
+        "os".to_string(),
+        get_os_name().map_or_else(unknown, Value::from),
+    );
+    report.insert(
+        "kernel".to_string(),
+        match uname() {
+            Ok(uts_name) => json!({
+                "release": uts_name.get_release(),
+                "version": uts_name.get_version(),
+                "machine": uts_name.get_machine(),
+            }),
+            Err(why) => unknown(why),
+        },
+    );
+    report.insert(
+        "memory".to_string(),
+        match get_mem_info() {
+            Ok((total, free)) => json!({ "total": total, "free": free }),
+            Err(why) => unknown(why),
+        },
+    );
+    report.insert(
+        "block_devices".to_string(),
+        get_block_devices().unwrap_or_else(unknown),
+    );
+
+    let (config, probe) = get_config_report(opts);
+    report.insert("config".to_string(), config);
+    report.insert("probe".to_string(), probe);
+
+    let report = serde_json::to_string_pretty(&Value::Object(report))
+        .upstream_with_context("Failed to serialize the device report")?;
+    write(report_path, format!("{}\n", report)).upstream_with_context(&format!(
+        "Failed to write the device report to '{}'",
+        report_path.display()
+    ))?;
+
+    if opts.config().is_empty() {
+        warn!(
+            "No config.json given with --config, the report does not contain a connectivity probe"
+        );
+    }
+    info!("Wrote the device report to '{}'", report_path.display());
+    Ok(())
+}
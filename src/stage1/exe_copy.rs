@@ -1,11 +1,16 @@
+use crate::common::defs::BUSYBOX_CMD;
 use crate::common::system::stat;
-use crate::common::{call, dir_exists, path_append, whereis, Error, ErrorKind, Result, ToError};
+use crate::common::{
+    call, dir_exists, path_append, sha256_file, whereis, Error, ErrorKind, Result, ToError,
+};
+use crate::stage1::{defs::OSArch, selinux::copy_context};
 
 use lazy_static::lazy_static;
 use log::{debug, info, trace, warn};
 use regex::Regex;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs::{copy, create_dir, create_dir_all, read_link};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 pub(crate) struct ExeCopy {
@@ -14,8 +19,171 @@ pub(crate) struct ExeCopy {
     executables: HashSet<String>,
 }
 
+// executables supplied in an assets directory instead of the ones installed on the system
+pub(crate) struct Assets {
+    dir: PathBuf,
+    checksums: HashMap<String, String>,
+}
+
+impl Assets {
+    // assets are kept in a subdirectory per architecture, eg. <assets dir>/armhf/dd
+    pub fn new(assets_dir: &Path, os_arch: &OSArch, checksums: &[String]) -> Result<Assets> {
+        let dir = path_append(assets_dir, os_arch.to_string());
+        if !dir_exists(&dir)? {
+            return Err(Error::with_context(
+                ErrorKind::FileNotFound,
+                &format!(
+                    "The assets directory '{}' does not contain a '{}' directory",
+                    assets_dir.display(),
+                    os_arch
+                ),
+            ));
+        }
+        info!("Using assets from '{}'", dir.display());
+
+        let mut assets = Assets {
+            dir,
+            checksums: HashMap::new(),
+        };
+
+        for checksum in checksums {
+            let mut parts = checksum.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(sha256))
+                    if !name.is_empty()
+                        && sha256.len() == 64
+                        && sha256.chars().all(|c| c.is_ascii_hexdigit()) =>
+                {
+                    assets
+                        .checksums
+                        .insert(name.to_string(), sha256.to_ascii_lowercase());
+                }
+                _ => {
+                    return Err(Error::with_context(
+                        ErrorKind::InvParam,
+                        &format!(
+                            "Invalid asset checksum '{}', expected NAME=SHA256 with a hex encoded SHA-256",
+                            checksum
+                        ),
+                    ));
+                }
+            }
+        }
+
+        Ok(assets)
+    }
+
+    fn get(&self, command: &str) -> Result<String> {
+        let asset_path = path_append(&self.dir, command);
+        match asset_path.metadata() {
+            Ok(metadata) if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 => (),
+            Ok(_) => {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "The asset '{}' is not an executable file",
+                        asset_path.display()
+                    ),
+                ))
+            }
+            Err(why) => {
+                return Err(Error::with_all(
+                    ErrorKind::FileNotFound,
+                    &format!("The asset '{}' could not be found", asset_path.display()),
+                    Box::new(why),
+                ))
+            }
+        }
+
+        // stage2 executes the assets after the pivot, refuse anything that does not match
+        if let Some(expected) = self.checksums.get(command) {
+            let found: String = sha256_file(&asset_path)?
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            if &found != expected {
+                return Err(Error::with_context(
+                    ErrorKind::Validation,
+                    &format!(
+                        "The SHA-256 of asset '{}' is {}, expected {}",
+                        asset_path.display(),
+                        found,
+                        expected
+                    ),
+                ));
+            }
+            info!("Verified SHA-256 of asset '{}'", asset_path.display());
+        } else {
+            warn!(
+                "No SHA-256 given for asset '{}', it is not verified",
+                asset_path.display()
+            );
+        }
+
+        Ok(asset_path.to_string_lossy().to_string())
+    }
+}
+
+/******************************************************************
+ * Commands that link to busybox are copied as busybox under the
+ * command name, which only works in stage2 if that busybox was
+ * built with the applet. Fails naming the missing applets.
+ ******************************************************************/
+
+fn check_busybox_applets(executables: &HashSet<String>) -> Result<()> {
+    let mut busyboxes: HashMap<PathBuf, Vec<String>> = HashMap::new();
+    for file in executables {
+        let path = Path::new(file);
+        match (path.canonicalize(), path.file_name()) {
+            (Ok(target), Some(applet))
+                if target.file_name() == Some(BUSYBOX_CMD.as_ref()) && applet != BUSYBOX_CMD =>
+            {
+                busyboxes
+                    .entry(target)
+                    .or_default()
+                    .push(applet.to_string_lossy().to_string())
+            }
+            _ => (),
+        }
+    }
+
+    for (busybox, mut applets) in busyboxes {
+        let cmd_res = call(&busybox.to_string_lossy(), &["--list"], true)?;
+        if !cmd_res.status.success() {
+            // busybox builds without --list can not be checked
+            warn!(
+                "Failed to list the applets of '{}', not checking them, error: {}",
+                busybox.display(),
+                cmd_res.stderr.trim()
+            );
+            continue;
+        }
+
+        let available: HashSet<&str> = cmd_res.stdout.lines().map(|line| line.trim()).collect();
+        applets.retain(|applet| !available.contains(applet.as_str()));
+        if !applets.is_empty() {
+            applets.sort();
+            return Err(Error::with_context(
+                ErrorKind::NotFound,
+                &format!(
+                    "The busybox '{}' does not provide the applets needed by stage2: {}",
+                    busybox.display(),
+                    applets.join(", ")
+                ),
+            ));
+        }
+        debug!(
+            "The busybox '{}' provides all applets needed by stage2",
+            busybox.display()
+        );
+    }
+
+    Ok(())
+}
+
 impl ExeCopy {
-    pub fn new(cmd_list: Vec<&str>) -> Result<ExeCopy> {
+    // assets: take the commands from the assets directory rather than from the system
+    pub fn new(cmd_list: Vec<&str>, assets: Option<&Assets>) -> Result<ExeCopy> {
         trace!("new: entered with {:?}", cmd_list);
 
         let mut executables: HashSet<String> = HashSet::new();
@@ -28,12 +196,18 @@ impl ExeCopy {
         );
 
         for command in cmd_list {
-            executables.insert(whereis(&command).error_with_all(
-                ErrorKind::FileNotFound,
-                &format!("Command '{}' could not be located", command),
-            )?);
+            if let Some(assets) = assets {
+                executables.insert(assets.get(command)?);
+            } else {
+                executables.insert(whereis(&command).error_with_all(
+                    ErrorKind::FileNotFound,
+                    &format!("Command '{}' could not be located", command),
+                )?);
+            }
         }
 
+        check_busybox_applets(&executables)?;
+
         let mut efi_files = ExeCopy {
             req_space: 0,
             libraries: HashSet::new(),
@@ -159,7 +333,49 @@ impl ExeCopy {
         Ok(())
     }
 
-    fn copy_file<P1: AsRef<Path>, P2: AsRef<Path>>(src_path: P1, takeover_dir: P2) -> Result<()> {
+    // make sure the copy is complete, stage2 depends on these files
+    fn verify_copy(src_path: &Path, dest_path: &Path) -> Result<()> {
+        let src_len = src_path
+            .metadata()
+            .upstream_with_context(&format!("Failed to stat '{}'", src_path.display()))?
+            .len();
+        let dest_len = dest_path
+            .metadata()
+            .upstream_with_context(&format!("Failed to stat '{}'", dest_path.display()))?
+            .len();
+
+        if src_len != dest_len {
+            return Err(Error::with_context(
+                ErrorKind::Validation,
+                &format!(
+                    "Copy of '{}' to '{}' is incomplete: {} of {} bytes",
+                    src_path.display(),
+                    dest_path.display(),
+                    dest_len,
+                    src_len
+                ),
+            ));
+        }
+
+        if sha256_file(src_path)? != sha256_file(dest_path)? {
+            return Err(Error::with_context(
+                ErrorKind::Validation,
+                &format!(
+                    "Copy of '{}' to '{}' does not match the original",
+                    src_path.display(),
+                    dest_path.display()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn copy_file<P1: AsRef<Path>, P2: AsRef<Path>>(
+        src_path: P1,
+        takeover_dir: P2,
+        relabel: bool,
+    ) -> Result<()> {
         trace!(
             "copy_file: entered with '{}'",
             takeover_dir.as_ref().display()
@@ -202,10 +418,15 @@ impl ExeCopy {
             dest_path.display()
         ))?;
 
+        ExeCopy::verify_copy(src_path, &dest_path)?;
+        if relabel {
+            copy_context(src_path, &dest_path)?;
+        }
         Ok(())
     }
 
-    pub fn copy_files<P: AsRef<Path>>(&self, takeover_dir: P) -> Result<()> {
+    // relabel: copy the SELinux context of the source files
+    pub fn copy_files<P: AsRef<Path>>(&self, takeover_dir: P, relabel: bool) -> Result<()> {
         trace!(
             "copy_files: entered with '{}'",
             takeover_dir.as_ref().display()
@@ -213,7 +434,7 @@ impl ExeCopy {
         let takeover_dir = takeover_dir.as_ref();
 
         for src_path in &self.libraries {
-            ExeCopy::copy_file(src_path, takeover_dir)?;
+            ExeCopy::copy_file(src_path, takeover_dir, relabel)?;
         }
 
         let dest_path = path_append(takeover_dir, "/bin");
@@ -237,6 +458,10 @@ impl ExeCopy {
                     file,
                     dest_path.display()
                 ))?;
+                ExeCopy::verify_copy(Path::new(file), &dest_path)?;
+                if relabel {
+                    copy_context(Path::new(file), &dest_path)?;
+                }
                 info!("Copied '{}' to '{}'", &file, dest_path.display());
             } else {
                 return Err(Error::with_context(
@@ -249,3 +474,39 @@ impl ExeCopy {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::path_append;
+    use std::env::temp_dir;
+    use std::fs::{remove_dir_all, write};
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_check_busybox_applets() {
+        let dir = path_append(
+            temp_dir(),
+            format!("takeover-busybox-{}", std::process::id()),
+        );
+        create_dir_all(&dir).unwrap();
+        let busybox = path_append(&dir, BUSYBOX_CMD);
+        write(&busybox, "#!/bin/sh\nprintf 'dd\\nsh\\n'\n").unwrap();
+        std::fs::set_permissions(&busybox, std::fs::Permissions::from_mode(0o755)).unwrap();
+        for applet in &["dd", "resize2fs"] {
+            symlink(&busybox, path_append(&dir, applet)).unwrap();
+        }
+        let executable = |name: &str| path_append(&dir, name).to_string_lossy().to_string();
+
+        let executables: HashSet<String> = vec![executable("dd")].into_iter().collect();
+        assert!(check_busybox_applets(&executables).is_ok());
+
+        let executables: HashSet<String> = vec![executable("dd"), executable("resize2fs")]
+            .into_iter()
+            .collect();
+        let why = check_busybox_applets(&executables).unwrap_err();
+        assert!(why.to_string().contains("needed by stage2: resize2fs"));
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
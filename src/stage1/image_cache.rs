@@ -0,0 +1,380 @@
+use std::fs::{
+    copy, create_dir_all, hard_link, read_dir, read_to_string, remove_file, rename, write,
+};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libc::EXDEV;
+use log::{debug, info, warn};
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+
+use crate::common::{path_append, sha256_file, Error, Options, Result, ToError};
+
+// the metadata of a cached image is kept next to it in '<id>.json'
+const ENTRY_EXT: &str = "json";
+
+/******************************************************************
+ * A cached image and what it was downloaded as. key identifies the
+ * download, eg. the URL and expected checksum, sha256 is the digest
+ * of the cached file so a corrupted copy is never reused.
+ ******************************************************************/
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    key: String,
+    image_name: String,
+    sha256: String,
+    size: u64,
+    // Last-Modified of the download in seconds since the epoch, for --max-image-age
+    modified: Option<u64>,
+    last_used: u64,
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+// cache key of an image downloaded from url, an expected checksum is part of the key
+pub(crate) fn url_cache_key(url: &str, sha256: Option<&str>) -> String {
+    format!(
+        "{} {}",
+        url,
+        sha256
+            .map(|sha256| sha256.to_ascii_lowercase())
+            .unwrap_or_else(|| "-".to_string())
+    )
+}
+
+// cache key of an image downloaded from the balena API, version is the resolved version
+pub(crate) fn api_cache_key(api_endpoint: &str, device_type: &str, version: &str) -> String {
+    format!("api:{}:{}:{}", api_endpoint, device_type, version)
+}
+
+pub(crate) struct ImageCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl ImageCache {
+    // None if no cache dir is configured, --no-cache was given or the dir can not be created
+    pub fn new(opts: &Options) -> Option<ImageCache> {
+        let dir = opts.image_cache_dir()?;
+        if let Err(why) = create_dir_all(dir) {
+            warn!(
+                "Not using the image cache, failed to create '{}', error: {}",
+                dir.display(),
+                why
+            );
+            return None;
+        }
+        Some(ImageCache {
+            dir: dir.to_path_buf(),
+            max_size: opts.image_cache_size(),
+        })
+    }
+
+    fn entry_id(key: &str) -> String {
+        hex(&sha256(key.as_bytes())[0..8])
+    }
+
+    fn entry_path(&self, id: &str) -> PathBuf {
+        path_append(&self.dir, format!("{}.{}", id, ENTRY_EXT))
+    }
+
+    fn image_path(&self, id: &str, image_name: &str) -> PathBuf {
+        path_append(&self.dir, format!("{}-{}", id, image_name))
+    }
+
+    fn read_entry(entry_path: &Path) -> Result<CacheEntry> {
+        let entry_txt = read_to_string(entry_path).upstream_with_context(&format!(
+            "Failed to read cache entry '{}'",
+            entry_path.display()
+        ))?;
+        serde_json::from_str(&entry_txt).upstream_with_context(&format!(
+            "Failed to parse cache entry '{}'",
+            entry_path.display()
+        ))
+    }
+
+    fn write_entry(entry_path: &Path, entry: &CacheEntry) -> Result<()> {
+        let entry_txt = serde_json::to_string_pretty(entry)
+            .upstream_with_context("Failed to serialize cache entry")?;
+        write(entry_path, entry_txt).upstream_with_context(&format!(
+            "Failed to write cache entry '{}'",
+            entry_path.display()
+        ))
+    }
+
+    fn remove(&self, id: &str, image_name: &str) {
+        let _res = remove_file(self.image_path(id, image_name));
+        let _res = remove_file(self.entry_path(id));
+    }
+
+    /******************************************************************
+     * Look up the image cached for key. A copy whose size or SHA-256
+     * changed since it was cached is dropped. Returns the path of the
+     * cached image and its Last-Modified time if known.
+     ******************************************************************/
+
+    pub fn get(&self, key: &str) -> Option<(PathBuf, Option<SystemTime>)> {
+        let id = ImageCache::entry_id(key);
+        let entry_path = self.entry_path(&id);
+        if !entry_path.exists() {
+            debug!("No cached image for '{}'", key);
+            return None;
+        }
+
+        let mut entry = match ImageCache::read_entry(&entry_path) {
+            Ok(entry) if entry.key == key => entry,
+            Ok(_) => return None,
+            Err(why) => {
+                warn!("Ignoring the cached image for '{}', error: {}", key, why);
+                return None;
+            }
+        };
+
+        let image_path = self.image_path(&id, &entry.image_name);
+        let intact = image_path
+            .metadata()
+            .map(|metadata| metadata.len() == entry.size)
+            .unwrap_or(false)
+            && sha256_file(&image_path)
+                .map(|digest| hex(&digest) == entry.sha256)
+                .unwrap_or(false);
+        if !intact {
+            info!(
+                "Dropping the cached image '{}', it changed since it was cached",
+                image_path.display()
+            );
+            self.remove(&id, &entry.image_name);
+            return None;
+        }
+
+        entry.last_used = now_secs();
+        if let Err(why) = ImageCache::write_entry(&entry_path, &entry) {
+            warn!("Failed to update the cache entry for '{}': {}", key, why);
+        }
+
+        info!("Using the cached image '{}'", image_path.display());
+        Some((
+            image_path,
+            entry
+                .modified
+                .map(|modified| UNIX_EPOCH + Duration::from_secs(modified)),
+        ))
+    }
+
+    // a checksum or age check failed on a cached image, do not offer it again
+    pub fn discard(&self, key: &str) {
+        let id = ImageCache::entry_id(key);
+        if let Ok(entry) = ImageCache::read_entry(&self.entry_path(&id)) {
+            self.remove(&id, &entry.image_name);
+        }
+    }
+
+    /******************************************************************
+     * Add a downloaded image to the cache, then evict the least
+     * recently used images until the cache fits max_size. Failures
+     * are only reported, the image in the work dir is still used.
+     ******************************************************************/
+
+    pub fn put(&self, key: &str, image_path: &Path, modified: Option<SystemTime>) {
+        if let Err(why) = self.try_put(key, image_path, modified) {
+            warn!(
+                "Failed to add '{}' to the image cache, error: {}",
+                image_path.display(),
+                why
+            );
+        }
+    }
+
+    fn try_put(&self, key: &str, image_path: &Path, modified: Option<SystemTime>) -> Result<()> {
+        let size = image_path
+            .metadata()
+            .upstream_with_context(&format!(
+                "Failed to retrieve metadata for '{}'",
+                image_path.display()
+            ))?
+            .len();
+        if size > self.max_size {
+            warn!(
+                "Not caching '{}', it is larger than the image cache size of {} MiB",
+                image_path.display(),
+                self.max_size / (1024 * 1024)
+            );
+            return Ok(());
+        }
+
+        let id = ImageCache::entry_id(key);
+        let image_name = image_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "image".to_string());
+        let cached_path = self.image_path(&id, &image_name);
+        let tmp_path = path_append(&self.dir, format!("{}.tmp", id));
+
+        // a stale link left by an interrupted run would make hard_link fail
+        if tmp_path.exists() {
+            remove_file(&tmp_path).upstream_with_context(&format!(
+                "Failed to remove stale file '{}'",
+                tmp_path.display()
+            ))?;
+        }
+
+        // a hard link needs no space if the work dir is on the same filesystem, only a
+        // different filesystem falls back to a copy
+        match hard_link(image_path, &tmp_path) {
+            Ok(_) => (),
+            Err(why) if why.raw_os_error() == Some(EXDEV) => {
+                copy(image_path, &tmp_path).upstream_with_context(&format!(
+                    "Failed to copy '{}' to '{}'",
+                    image_path.display(),
+                    tmp_path.display()
+                ))?;
+            }
+            Err(why) => {
+                return Err(Error::from_upstream(
+                    Box::new(why),
+                    &format!(
+                        "Failed to link '{}' to '{}'",
+                        image_path.display(),
+                        tmp_path.display()
+                    ),
+                ))
+            }
+        }
+        rename(&tmp_path, &cached_path).upstream_with_context(&format!(
+            "Failed to rename '{}' to '{}'",
+            tmp_path.display(),
+            cached_path.display()
+        ))?;
+
+        let entry = CacheEntry {
+            key: key.to_string(),
+            image_name,
+            sha256: hex(&sha256_file(&cached_path)?),
+            size,
+            modified: modified
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_secs()),
+            last_used: now_secs(),
+        };
+        ImageCache::write_entry(&self.entry_path(&id), &entry)?;
+        info!("Added '{}' to the image cache", image_path.display());
+
+        self.evict(&id)
+    }
+
+    fn evict(&self, keep_id: &str) -> Result<()> {
+        let mut entries: Vec<(String, CacheEntry)> = Vec::new();
+        for dir_entry in read_dir(&self.dir).upstream_with_context(&format!(
+            "Failed to read directory '{}'",
+            self.dir.display()
+        ))? {
+            let path = dir_entry
+                .upstream_with_context(&format!(
+                    "Failed to read directory '{}'",
+                    self.dir.display()
+                ))?
+                .path();
+            if path.extension().is_none_or(|ext| ext != ENTRY_EXT) {
+                continue;
+            }
+            // files that are not cache entries are left alone
+            if let (Some(id), Ok(entry)) = (
+                path.file_stem().map(|id| id.to_string_lossy().to_string()),
+                ImageCache::read_entry(&path),
+            ) {
+                entries.push((id, entry));
+            }
+        }
+
+        let mut total: u64 = entries.iter().map(|(_, entry)| entry.size).sum();
+        entries.sort_by_key(|(_, entry)| entry.last_used);
+        for (id, entry) in &entries {
+            if total <= self.max_size {
+                break;
+            }
+            if id == keep_id {
+                continue;
+            }
+            info!(
+                "Evicting the cached image for '{}' to stay within the image cache size",
+                entry.key
+            );
+            self.remove(id, &entry.image_name);
+            total -= entry.size;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn test_image_cache() {
+        let dir = path_append(temp_dir(), format!("takeover-cache-{}", std::process::id()));
+        let cache_dir = path_append(&dir, "cache");
+        create_dir_all(&cache_dir).unwrap();
+        let cache = ImageCache {
+            dir: cache_dir,
+            max_size: 10,
+        };
+
+        let image_a = path_append(&dir, "a.img.gz");
+        let image_b = path_append(&dir, "b.img.gz");
+        write(&image_a, b"aaaaaa").unwrap();
+        write(&image_b, b"bbbbbb").unwrap();
+
+        let key_a = url_cache_key("https://mirror/a.img.gz", None);
+        assert!(cache.get(&key_a).is_none());
+        cache.put(
+            &key_a,
+            &image_a,
+            Some(UNIX_EPOCH + Duration::from_secs(1000)),
+        );
+        let (cached_a, modified) = cache.get(&key_a).unwrap();
+        assert_eq!(read_to_string(&cached_a).unwrap(), "aaaaaa");
+        assert_eq!(modified, Some(UNIX_EPOCH + Duration::from_secs(1000)));
+        assert!(cache
+            .get(&url_cache_key("https://mirror/a.img.gz", Some("AB")))
+            .is_none());
+
+        // a modified copy is dropped
+        write(&cached_a, b"corrupt").unwrap();
+        assert!(cache.get(&key_a).is_none());
+        assert!(!cached_a.exists());
+
+        // a stale link of an interrupted run is replaced, not written through
+        let stale_tmp = path_append(&cache.dir, format!("{}.tmp", ImageCache::entry_id(&key_a)));
+        hard_link(&image_b, &stale_tmp).unwrap();
+        write(&image_a, b"aaaaaa").unwrap();
+        cache.put(&key_a, &image_a, None);
+        assert_eq!(read_to_string(&image_b).unwrap(), "bbbbbb");
+        assert_eq!(
+            read_to_string(cache.get(&key_a).unwrap().0).unwrap(),
+            "aaaaaa"
+        );
+
+        // b does not fit next to a and evicts it
+        let key_b = url_cache_key("https://mirror/b.img.gz", None);
+        cache.put(&key_b, &image_b, None);
+        assert!(cache.get(&key_a).is_none());
+        assert!(cache.get(&key_b).is_some());
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
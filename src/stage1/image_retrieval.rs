@@ -1,18 +1,20 @@
 use std::fs::{self, create_dir, remove_dir, File, OpenOptions};
 use std::io::{copy, Read};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use chrono::DateTime;
 use log::{debug, error, info, warn, Level};
 
 use semver::{Identifier, Version, VersionReq};
 
 use crate::{
     common::{
-        defs::NIX_NONE,
+        defs::{BALENA_DELTA_NAME, BALENA_IMAGE_NAME, NIX_NONE},
         disk_util::{Disk, PartitionIterator, PartitionReader},
-        is_admin,
+        file_exists, is_admin,
         loop_device::LoopDevice,
-        path_append,
+        path_append, sha256_file,
         stream_progress::StreamProgress,
         Error, Result, ToError,
     },
@@ -22,6 +24,7 @@ use crate::{
             DEV_TYPE_BBB, DEV_TYPE_BBG, DEV_TYPE_GEN_X86_64, DEV_TYPE_INTEL_NUC, DEV_TYPE_RPI1,
             DEV_TYPE_RPI2, DEV_TYPE_RPI3, DEV_TYPE_RPI4_64,
         },
+        image_cache::{api_cache_key, url_cache_key, ImageCache},
         migrate_info::balena_cfg_json::BalenaCfgJson,
     },
     ErrorKind,
@@ -29,6 +32,15 @@ use crate::{
 
 use flate2::{Compression, GzBuilder};
 use nix::mount::{mount, umount, MsFlags};
+use openssl::{
+    hash::MessageDigest,
+    pkey::{Id, PKey},
+    sign::Verifier,
+};
+use reqwest::header::LAST_MODIFIED;
+use url::Url;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
 
 const FLASHER_DEVICES: [&str; 4] = [
     DEV_TYPE_INTEL_NUC,
@@ -173,7 +185,7 @@ fn extract_image<P1: AsRef<Path>, P2: AsRef<Path>>(
     work_dir: P2,
 ) -> Result<()> {
     let work_dir = work_dir.as_ref();
-    let progress = StreamProgress::new(stream, 10, Level::Info, None);
+    let progress = StreamProgress::new(stream, 10, Level::Info, None).report_download();
     let mut disk = Disk::from_gzip_stream(progress)?;
     let mut part_iterator = PartitionIterator::new(&mut disk)?;
     if let Some(part_info) = part_iterator.nth(1) {
@@ -321,6 +333,7 @@ pub(crate) fn download_image(
     work_dir: &Path,
     device_type: &str,
     version: &str,
+    cache: Option<&ImageCache>,
 ) -> Result<PathBuf> {
     if !SUPPORTED_DEVICES.contains(&device_type) {
         return Err(Error::with_context(
@@ -344,6 +357,11 @@ pub(crate) fn download_image(
 
     let version = determine_version(version, &versions)?;
 
+    let key = api_cache_key(&api_endpoint, device_type, &version.to_string());
+    if let Some((image_path, _modified)) = cache.and_then(|cache| cache.get(&key)) {
+        return Ok(image_path);
+    }
+
     info!(
         "Downloading Balena OS image, selected version is: '{}'",
         version.to_string()
@@ -365,7 +383,7 @@ pub(crate) fn download_image(
     if FLASHER_DEVICES.contains(&device_type) {
         if !is_admin()? {
             error!("please run this program as root");
-            return Err(Error::displayed());
+            return Err(Error::displayed_with(ErrorKind::Permission));
         }
         extract_image(stream, &img_file_name, device_type, work_dir)?;
     } else {
@@ -376,7 +394,7 @@ pub(crate) fn download_image(
         ))?;
 
         // TODO: show progress
-        let mut progress = StreamProgress::new(stream, 10, Level::Info, None);
+        let mut progress = StreamProgress::new(stream, 10, Level::Info, None).report_download();
         copy(&mut progress, &mut file).upstream_with_context(&format!(
             "Failed to write downloaded data to '{}'",
             img_file_name.display()
@@ -387,5 +405,418 @@ pub(crate) fn download_image(
         );
     }
 
+    if let Some(cache) = cache {
+        cache.put(&key, &img_file_name, None);
+    }
+
     Ok(img_file_name)
 }
+
+fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+// the Last-Modified header is formatted as in RFC 2822, eg. 'Wed, 21 Oct 2015 07:28:00 GMT'
+fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let date = DateTime::parse_from_rfc2822(date).ok()?;
+    if date.timestamp() < 0 {
+        None
+    } else {
+        Some(UNIX_EPOCH + Duration::from_secs(date.timestamp() as u64))
+    }
+}
+
+// the image is refused if modified is more than max_age days ago, an unknown age is only reported
+fn check_image_age(source: &str, modified: Option<SystemTime>, max_age: u64) -> Result<()> {
+    let modified = if let Some(modified) = modified {
+        modified
+    } else {
+        warn!(
+            "The age of image '{}' is unknown, it is used without checking --max-image-age",
+            source
+        );
+        return Ok(());
+    };
+
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_else(|_| Duration::from_secs(0))
+        .as_secs();
+    if age > max_age.saturating_mul(SECS_PER_DAY) {
+        Err(Error::with_context(
+            ErrorKind::Validation,
+            &format!(
+                "The image '{}' was last modified {} days ago, more than the maximum image age of {} days",
+                source,
+                age / SECS_PER_DAY,
+                max_age
+            ),
+        ))
+    } else {
+        info!(
+            "The image '{}' was last modified {} days ago",
+            source,
+            age / SECS_PER_DAY
+        );
+        Ok(())
+    }
+}
+
+// the age is checked before the body is downloaded if max_age is given, returns the Last-Modified time
+fn download_file(
+    url: &str,
+    target_path: &Path,
+    max_age: Option<u64>,
+) -> Result<Option<SystemTime>> {
+    let response = reqwest::blocking::get(url)
+        .upstream_with_context(&format!("Failed to request '{}'", url))?;
+    if !response.status().is_success() {
+        return Err(Error::with_context(
+            ErrorKind::Connectivity,
+            &format!(
+                "Failed to download '{}', status: {}",
+                url,
+                response.status()
+            ),
+        ));
+    }
+
+    let modified = response
+        .headers()
+        .get(LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_http_date);
+    if let Some(max_age) = max_age {
+        check_image_age(url, modified, max_age)?;
+    }
+
+    let size = response.content_length();
+    let mut file = File::create(target_path).upstream_with_context(&format!(
+        "Failed to create file: '{}'",
+        target_path.display()
+    ))?;
+    let mut progress = StreamProgress::new(response, 10, Level::Info, size).report_download();
+    copy(&mut progress, &mut file).upstream_with_context(&format!(
+        "Failed to write downloaded data to '{}'",
+        target_path.display()
+    ))?;
+    Ok(modified)
+}
+
+fn parse_sha256(sha256: &str) -> Result<String> {
+    if sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(sha256.to_ascii_lowercase())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Invalid image checksum '{}', expected a hex encoded SHA-256",
+                sha256
+            ),
+        ))
+    }
+}
+
+pub(crate) fn check_image_sha256(image_path: &Path, sha256: &str) -> Result<()> {
+    let expected = parse_sha256(sha256)?;
+    let found: String = sha256_file(image_path)?
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if found == expected {
+        info!("The SHA-256 of image '{}' matches", image_path.display());
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::Validation,
+            &format!(
+                "The SHA-256 of image '{}' is {}, expected {}",
+                image_path.display(),
+                found,
+                expected
+            ),
+        ))
+    }
+}
+
+/******************************************************************
+ * Verify the detached signature sig_path of the image with the PEM
+ * encoded public key pubkey_path. RSA and EC signatures are made
+ * over the SHA-256 of the image, as by openssl dgst -sha256 -sign.
+ * Ed25519 can not hash a stream, so Ed25519 signatures are made
+ * over the binary SHA-256 digest of the image, as by openssl pkeyutl
+ * -sign -rawin on the output of openssl dgst -sha256 -binary.
+ ******************************************************************/
+
+pub(crate) fn check_image_signature(
+    image_path: &Path,
+    pubkey_path: &Path,
+    sig_path: &Path,
+) -> Result<()> {
+    let pubkey = fs::read(pubkey_path).upstream_with_context(&format!(
+        "Failed to read public key '{}'",
+        pubkey_path.display()
+    ))?;
+    let pubkey = PKey::public_key_from_pem(&pubkey).upstream_with_context(&format!(
+        "Failed to parse public key '{}', expected a PEM encoded RSA, EC or Ed25519 key",
+        pubkey_path.display()
+    ))?;
+    let signature = fs::read(sig_path).upstream_with_context(&format!(
+        "Failed to read image signature '{}'",
+        sig_path.display()
+    ))?;
+
+    let verified = if pubkey.id() == Id::ED25519 {
+        let digest = sha256_file(image_path)?;
+        Verifier::new_without_digest(&pubkey)
+            .and_then(|mut verifier| verifier.verify_oneshot(&signature, &digest))
+    } else {
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &pubkey)
+            .upstream_with_context("Failed to create a signature verifier")?;
+        let mut image = File::open(image_path)
+            .upstream_with_context(&format!("Failed to open image '{}'", image_path.display()))?;
+        copy(&mut image, &mut verifier)
+            .upstream_with_context(&format!("Failed to read image '{}'", image_path.display()))?;
+        verifier.verify(&signature)
+    };
+
+    // a malformed signature is reported as an error instead of a mismatch
+    if let Ok(true) = verified {
+        info!(
+            "Signature verified: image '{}' is signed by the key '{}'",
+            image_path.display(),
+            pubkey_path.display()
+        );
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::Validation,
+            &format!(
+                "The signature '{}' of image '{}' does not validate with the key '{}'",
+                sig_path.display(),
+                image_path.display(),
+                pubkey_path.display()
+            ),
+        ))
+    }
+}
+
+// a cached image older than max_age is downloaded again, the source may have been updated since
+fn get_cached(
+    cache: &ImageCache,
+    key: &str,
+    source: &str,
+    max_age: Option<u64>,
+) -> Option<PathBuf> {
+    let (image_path, modified) = cache.get(key)?;
+    if let Some(max_age) = max_age {
+        if let Err(why) = check_image_age(source, modified, max_age) {
+            info!("Downloading '{}' again, {}", source, why);
+            cache.discard(key);
+            return None;
+        }
+    }
+    Some(image_path)
+}
+
+fn fetch_image_source(
+    source: &str,
+    sha256: Option<&str>,
+    max_age: Option<u64>,
+    work_dir: &Path,
+    cache: Option<&ImageCache>,
+) -> Result<PathBuf> {
+    let key = url_cache_key(source, sha256);
+    let cached = if is_url(source) {
+        cache.and_then(|cache| get_cached(cache, &key, source, max_age))
+    } else {
+        None
+    };
+    // the Last-Modified time of a download that is added to the cache
+    let mut downloaded = None;
+
+    let image_path = if let Some(image_path) = &cached {
+        image_path.clone()
+    } else if is_url(source) {
+        let file_name = Url::parse(source)
+            .ok()
+            .and_then(|url| {
+                url.path_segments()
+                    .and_then(|mut segments| segments.next_back().map(String::from))
+            })
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| BALENA_IMAGE_NAME.to_string());
+        let image_path = path_append(work_dir, &file_name);
+        info!("Downloading image from '{}'", source);
+        match download_file(source, &image_path, max_age) {
+            Ok(modified) => downloaded = Some(modified),
+            Err(why) => {
+                let _res = fs::remove_file(&image_path);
+                return Err(why);
+            }
+        }
+        image_path
+    } else {
+        let image_path = Path::new(source);
+        if !file_exists(image_path) {
+            return Err(Error::with_context(
+                ErrorKind::FileNotFound,
+                &format!("The image '{}' could not be found", source),
+            ));
+        }
+        if let Some(max_age) = max_age {
+            let modified = image_path
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            check_image_age(source, modified, max_age)?;
+        }
+        image_path.to_path_buf()
+    };
+
+    if let Some(sha256) = sha256 {
+        if let Err(why) = check_image_sha256(&image_path, sha256) {
+            // do not leave a corrupt download behind
+            if cached.is_some() {
+                if let Some(cache) = cache {
+                    cache.discard(&key);
+                }
+            } else if is_url(source) {
+                let _res = fs::remove_file(&image_path);
+            }
+            return Err(why);
+        }
+    }
+
+    if let (Some(cache), Some(modified)) = (cache, downloaded) {
+        cache.put(&key, &image_path, modified);
+    }
+
+    image_path.canonicalize().upstream_with_context(&format!(
+        "Failed to canonicalize path '{}'",
+        image_path.display()
+    ))
+}
+
+/******************************************************************
+ * Try the configured image sources in order, the first one that can
+ * be found or downloaded, matches the checksum and is not older
+ * than max_age days is used.
+ ******************************************************************/
+
+pub(crate) fn fetch_image(
+    sources: &[String],
+    sha256: Option<&str>,
+    max_age: Option<u64>,
+    work_dir: &Path,
+    cache: Option<&ImageCache>,
+) -> Result<PathBuf> {
+    if let Some(sha256) = sha256 {
+        parse_sha256(sha256)?;
+    }
+
+    for source in sources {
+        match fetch_image_source(source, sha256, max_age, work_dir, cache) {
+            Ok(image_path) => {
+                if sources.len() > 1 {
+                    info!("Using image from '{}'", source);
+                }
+                return Ok(image_path);
+            }
+            Err(why) => warn!("Image source '{}' failed: {}", source, why),
+        }
+    }
+
+    error!(
+        "None of the configured images could be used: {}",
+        sources.join(", ")
+    );
+    Err(Error::displayed_with(ErrorKind::FileNotFound))
+}
+
+// download a delta given as http(s) url to work_dir, local deltas are used in place
+pub(crate) fn fetch_delta(source: &str, work_dir: &Path) -> Result<PathBuf> {
+    if is_url(source) {
+        info!("Downloading delta from '{}'", source);
+        let delta_path = path_append(work_dir, BALENA_DELTA_NAME);
+        download_file(source, &delta_path, None)?;
+        info!("The delta was written to '{}'", delta_path.display());
+        Ok(delta_path)
+    } else {
+        let delta_path = Path::new(source);
+        delta_path.canonicalize().upstream_with_context(&format!(
+            "Failed to canonicalize delta path '{}'",
+            delta_path.display()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_http_date() {
+        assert_eq!(
+            parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(1445412480))
+        );
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_check_image_age() {
+        let modified = Some(SystemTime::now() - Duration::from_secs(10 * SECS_PER_DAY));
+        assert!(check_image_age("image.img", modified, 11).is_ok());
+        assert!(check_image_age("image.img", modified, 9).is_err());
+        // does not overflow
+        assert!(check_image_age("image.img", modified, u64::MAX).is_ok());
+        assert!(check_image_age("image.img", None, 0).is_ok());
+    }
+
+    #[test]
+    fn test_check_image_signature() {
+        use openssl::{ec::EcGroup, ec::EcKey, nid::Nid, sha::sha256, sign::Signer};
+        use std::env::temp_dir;
+
+        let dir = temp_dir().join(format!("takeover-signature-test-{}", std::process::id()));
+        create_dir(&dir).unwrap();
+        let image_path = dir.join("balena.img.gz");
+        let pubkey_path = dir.join("key.pem");
+        let sig_path = dir.join("balena.img.gz.sig");
+        let image = b"not really an image".to_vec();
+        fs::write(&image_path, &image).unwrap();
+
+        let ed25519 = PKey::generate_ed25519().unwrap();
+        let ec = PKey::from_ec_key(
+            EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap(),
+        )
+        .unwrap();
+
+        // Ed25519 signs the SHA-256 digest of the image
+        let signature = Signer::new_without_digest(&ed25519)
+            .unwrap()
+            .sign_oneshot_to_vec(&sha256(&image))
+            .unwrap();
+        fs::write(&pubkey_path, ed25519.public_key_to_pem().unwrap()).unwrap();
+        fs::write(&sig_path, &signature).unwrap();
+        check_image_signature(&image_path, &pubkey_path, &sig_path).unwrap();
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &ec).unwrap();
+        signer.update(&image).unwrap();
+        fs::write(&pubkey_path, ec.public_key_to_pem().unwrap()).unwrap();
+        fs::write(&sig_path, signer.sign_to_vec().unwrap()).unwrap();
+        check_image_signature(&image_path, &pubkey_path, &sig_path).unwrap();
+
+        // a signature made by another key or over other content does not validate
+        fs::write(&sig_path, &signature).unwrap();
+        let err = check_image_signature(&image_path, &pubkey_path, &sig_path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Validation);
+        fs::write(&pubkey_path, ed25519.public_key_to_pem().unwrap()).unwrap();
+        fs::write(&image_path, b"another image").unwrap();
+        let err = check_image_signature(&image_path, &pubkey_path, &sig_path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Validation);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
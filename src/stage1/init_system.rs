@@ -0,0 +1,136 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::canonicalize;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+use crate::common::{
+    call, defs::SYSTEMCTL_CMD, dir_exists, file_exists, path_append, pidof, Error, ErrorKind,
+    Result,
+};
+
+const SYSTEMD_RUN_DIR: &str = "/run/systemd/system";
+const OPENRC_RUN_DIR: &str = "/run/openrc";
+const INIT_PATH: &str = "/sbin/init";
+const INIT_SCRIPT_DIR: &str = "/etc/init.d";
+const RC_SERVICE_CMD: &str = "rc-service";
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum InitSystem {
+    Systemd,
+    OpenRC,
+    BusyBox,
+    SysVInit,
+    Unknown,
+}
+
+impl InitSystem {
+    pub fn detect() -> Result<InitSystem> {
+        if dir_exists(SYSTEMD_RUN_DIR)? {
+            return Ok(InitSystem::Systemd);
+        }
+
+        if dir_exists(OPENRC_RUN_DIR)? {
+            return Ok(InitSystem::OpenRC);
+        }
+
+        if file_exists(INIT_PATH) {
+            // /sbin/init is typically a link to the actual init binary
+            if let Ok(init_path) = canonicalize(INIT_PATH) {
+                debug!("{} resolves to '{}'", INIT_PATH, init_path.display());
+                let init_path = init_path.to_string_lossy();
+                if init_path.contains("systemd") {
+                    return Ok(InitSystem::Systemd);
+                } else if init_path.contains("busybox") {
+                    return Ok(InitSystem::BusyBox);
+                }
+            }
+            Ok(InitSystem::SysVInit)
+        } else {
+            Ok(InitSystem::Unknown)
+        }
+    }
+
+    // check if a service / daemon is running
+    pub fn is_running(&self, name: &str) -> Result<bool> {
+        if let InitSystem::Systemd = self {
+            if call(SYSTEMCTL_CMD, &["is-active", "--quiet", name], true)?
+                .status
+                .success()
+            {
+                return Ok(true);
+            }
+        }
+        Ok(!pidof(name)?.is_empty())
+    }
+
+    /******************************************************************
+     * Stop a service / daemon using the init system and wait up to
+     * timeout for it to exit
+     ******************************************************************/
+
+    pub fn stop(&self, name: &str, timeout: Duration) -> Result<()> {
+        let init_script = path_append(INIT_SCRIPT_DIR, name);
+        let cmd_res = match self {
+            InitSystem::Systemd => call(SYSTEMCTL_CMD, &["stop", name], true)?,
+            InitSystem::OpenRC => call(RC_SERVICE_CMD, &[name, "stop"], true)?,
+            InitSystem::BusyBox | InitSystem::SysVInit if file_exists(&init_script) => {
+                call(&init_script.to_string_lossy(), &["stop"], true)?
+            }
+            _ => {
+                return Err(Error::with_context(
+                    ErrorKind::NotSupported,
+                    &format!("Don't know how to stop service '{}' using {}", name, self),
+                ));
+            }
+        };
+
+        if !cmd_res.status.success() {
+            return Err(Error::with_context(
+                ErrorKind::ExecProcess,
+                &format!(
+                    "Failed to stop service '{}', stderr: {}",
+                    name,
+                    cmd_res.stderr.trim()
+                ),
+            ));
+        }
+
+        let start = Instant::now();
+        while self.is_running(name)? {
+            if start.elapsed() > timeout {
+                return Err(Error::with_context(
+                    ErrorKind::Timeout,
+                    &format!(
+                        "Service '{}' did not exit within {} seconds",
+                        name,
+                        timeout.as_secs()
+                    ),
+                ));
+            }
+            sleep(STOP_POLL_INTERVAL);
+        }
+
+        info!(
+            "Stopped service '{}' after {} ms",
+            name,
+            start.elapsed().as_millis()
+        );
+        Ok(())
+    }
+}
+
+impl Display for InitSystem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let output = match self {
+            InitSystem::Systemd => "systemd",
+            InitSystem::OpenRC => "OpenRC",
+            InitSystem::BusyBox => "BusyBox init",
+            InitSystem::SysVInit => "SysV init",
+            InitSystem::Unknown => "unknown",
+        };
+        write!(f, "{}", output)
+    }
+}
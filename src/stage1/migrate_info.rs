@@ -1,34 +1,656 @@
 use log::{debug, error, info, warn};
-use nix::mount::umount;
-use std::fs::{read_to_string, remove_dir_all, OpenOptions};
+use std::fs::{read_to_string, set_permissions, OpenOptions, Permissions};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::ptr::read_volatile;
 
-use crate::common::defs::BACKUP_ARCH_NAME;
-use crate::common::path_append;
+use crate::common::defs::{BACKUP_ARCH_NAME, BALENA_BOOT_PART, DISK_BY_LABEL_PATH};
+use crate::common::disk_util::Disk;
+use crate::common::{file_exists, path_append};
 use crate::{
-    common::{file_exists, get_os_name, options::Options, Error, ErrorKind, Result, ToError},
+    common::{
+        get_os_name,
+        migration_marker::{get_os_release_value, get_os_version, parse_os_version},
+        options::Options,
+        progress::{emit, ProgressEvent},
+        stage2_config::RebootMethod,
+        Error, ErrorKind, Result, ToError, OS_RELEASE_FILE,
+    },
     stage1::{
+        api_calls::get_fleet_id,
         backup::config::backup_cfg_from_file,
         backup::{create, create_ext},
-        defs::{DEV_TYPE_GEN_X86_64, GZIP_MAGIC_COOKIE, MAX_CONFIG_JSON},
+        defs::{
+            DeviceType, NetworkVia, UnknownKeys, DEV_TYPE_GEN_X86_64, GZIP_MAGIC_COOKIE,
+            MAX_CONFIG_JSON,
+        },
         device::Device,
         device_impl::get_device,
-        image_retrieval::download_image,
-        migrate_info::balena_cfg_json::BalenaCfgJson,
-        utils::mktemp,
+        image_cache::ImageCache,
+        image_retrieval::{check_image_sha256, check_image_signature, download_image, fetch_image},
+        init_system::InitSystem,
+        migrate_info::balena_cfg_json::{
+            is_valid_uuid, redacted, value_type, BalenaCfgJson, ConfigCheck, Connectivity,
+            DEFAULT_MODE, STDIN_CONFIG,
+        },
+        resume::{image_sources, resumed_image, save_image_state},
+        utils::{mktemp, wait_for_network},
         wifi_config::WifiConfig,
     },
 };
 
 use crate::stage1::utils::ReadBuffer;
 use flate2::read::GzDecoder;
+use semver::{Version, VersionReq};
+use serde_json::Value;
 use std::io::copy;
 
 #[link_section = ".config_json_section"]
 static CONFIG_JSON: [u8; MAX_CONFIG_JSON] = [0; MAX_CONFIG_JSON];
 
 pub(crate) mod balena_cfg_json;
+mod config_schema;
+
+// written by the balena-os build to the boot partition, the device type is its slug
+const IMAGE_DEVICE_TYPE_FILE: &str = "device-type.json";
+const IMAGE_OS_RELEASE_FILE: &str = "os-release";
+
+// searched for config.json without --config, older balena-os versions label the boot partition resin-boot
+const BOOT_PART_LABELS: [&str; 2] = [BALENA_BOOT_PART, "balena-boot"];
+const CONFIG_SEARCH_PATHS: [&str; 2] = ["/mnt/boot/config.json", "/boot/config.json"];
+const CONFIG_JSON_NAME: &str = "config.json";
+const PROC_MOUNTS: &str = "/proc/mounts";
+// the os-release ID of balena-os, older versions call it resin-os
+const BALENA_OS_IDS: [&str; 2] = ["balena-os", "resin-os"];
+
+// where the labeled boot partition of a balena like device is mounted
+fn get_boot_part_mounts() -> Vec<PathBuf> {
+    let boot_devs: Vec<PathBuf> = BOOT_PART_LABELS
+        .iter()
+        .filter_map(|label| path_append(DISK_BY_LABEL_PATH, label).canonicalize().ok())
+        .collect();
+    if boot_devs.is_empty() {
+        return Vec::new();
+    }
+
+    read_to_string(PROC_MOUNTS)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            let source = Path::new(columns.next()?).canonicalize().ok()?;
+            let mountpoint = columns.next()?;
+            if boot_devs.contains(&source) {
+                Some(PathBuf::from(mountpoint))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+// custom keys are carried to the device as they are, typos of known keys are not noticed otherwise
+fn check_unknown_keys(config: &BalenaCfgJson, mode: UnknownKeys) -> Result<()> {
+    if mode == UnknownKeys::Ignore {
+        return Ok(());
+    }
+    let unknown = config.get_unknown_keys();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+
+    if mode == UnknownKeys::Error {
+        error!(
+            "config.json contains unknown keys: {}, use --unknown-keys warn or ignore to accept them",
+            unknown.join(", ")
+        );
+        Err(Error::displayed_with(ErrorKind::InvParam))
+    } else {
+        warn!(
+            "config.json contains unknown keys: {}, they are kept as they are",
+            unknown.join(", ")
+        );
+        Ok(())
+    }
+}
+
+/******************************************************************
+ * Look for config.json in the conventional locations when neither
+ * --config is given nor one is embedded: the mounted boot partition
+ * of a balena like device, /mnt/boot and /boot. The first file that
+ * parses and validates is used, the others are not looked at.
+ ******************************************************************/
+
+fn find_cfg_json() -> Result<BalenaCfgJson> {
+    let mut search_paths: Vec<PathBuf> = get_boot_part_mounts()
+        .iter()
+        .map(|mountpoint| path_append(mountpoint, CONFIG_JSON_NAME))
+        .collect();
+    for path in CONFIG_SEARCH_PATHS.iter().map(PathBuf::from) {
+        if !search_paths.contains(&path) {
+            search_paths.push(path);
+        }
+    }
+
+    for path in &search_paths {
+        if !file_exists(path) {
+            debug!("find_cfg_json: no config.json at '{}'", path.display());
+            continue;
+        }
+        match BalenaCfgJson::new(path).and_then(|config| {
+            config.validate()?;
+            Ok(config)
+        }) {
+            Ok(config) => {
+                info!(
+                    "No --config given, using config.json found at '{}'",
+                    path.display()
+                );
+                return Ok(config);
+            }
+            Err(why) => warn!(
+                "Not using '{}', it is not a valid config.json, error: {}",
+                path.display(),
+                why
+            ),
+        }
+    }
+
+    error!(
+        "The required parameter --config/-c was not provided, no internal config.json was found and none was found in {}",
+        search_paths
+            .iter()
+            .map(|path| format!("'{}'", path.display()))
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+    Err(Error::displayed_with(ErrorKind::InvParam))
+}
+
+// copy the allowlisted keys from the source config, other keys of the source are ignored
+fn carry_config_keys(config: &mut BalenaCfgJson, source: &Path, keys: &[String]) -> Result<()> {
+    let source_cfg = BalenaCfgJson::new(source)?;
+    for key in keys {
+        if let Some(value) = source_cfg.get_value(key) {
+            info!(
+                "Carrying config.json value '{}' from '{}': {}",
+                key,
+                source.display(),
+                redacted(key, value)
+            );
+            if let Err(why) = config.set_value(key, value.clone()) {
+                error!("Failed to carry config.json value '{}': {}", key, why);
+                return Err(Error::displayed_with(ErrorKind::InvParam));
+            }
+        } else {
+            warn!(
+                "The config.json value '{}' was not found in '{}', not carrying it",
+                key,
+                source.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+// whether the running version is the requested one, None if the version is only decided at download
+fn is_requested_version(requested: &str, running: &str) -> Option<bool> {
+    let running = Version::parse(running).ok()?;
+    match requested {
+        "default" | "latest" => None,
+        requested if requested.starts_with('^') || requested.starts_with('~') => {
+            VersionReq::parse(requested)
+                .ok()
+                .map(|ver_req| ver_req.matches(&running))
+        }
+        requested => Version::parse(requested)
+            .ok()
+            .map(|version| version == running),
+    }
+}
+
+// the balena-os version of the image from the os-release of its boot partition, None if it has none
+fn get_image_os_version(image_path: &Path) -> Result<Option<String>> {
+    Ok(Disk::from_gzip_img(image_path)?
+        .read_boot_file(IMAGE_OS_RELEASE_FILE)?
+        .and_then(|os_release| parse_os_version(&String::from_utf8_lossy(&os_release))))
+}
+
+/******************************************************************
+ * Refuse to migrate a device that already runs balena-os at the
+ * version of the image, eg. when it was forgotten in a batch that
+ * it was migrated before. The version is read from the os-release
+ * in the boot partition of the resolved image, an image without one
+ * is compared by the requested version. When neither tells the
+ * version, as for default or latest, the device is migrated. A
+ * device with a different uuid in its config.json than the one it
+ * is migrated with is assumed to be re-provisioned on purpose.
+ * --force migrates the device again.
+ ******************************************************************/
+
+fn check_already_migrated(config: &BalenaCfgJson, opts: &Options, image_path: &Path) -> Result<()> {
+    let os_release = Path::new(OS_RELEASE_FILE);
+    match get_os_release_value(os_release, "ID") {
+        Ok(os_id) if BALENA_OS_IDS.contains(&os_id.as_str()) => (),
+        _ => return Ok(()),
+    }
+    let os_version = match get_os_version(os_release) {
+        Ok(os_version) => os_version,
+        Err(why) => {
+            warn!(
+                "The device runs balena-os but its version is unknown, error: {}",
+                why
+            );
+            return Ok(());
+        }
+    };
+
+    let image_version = match get_image_os_version(image_path) {
+        Ok(image_version) => image_version,
+        Err(why) => {
+            warn!(
+                "Failed to read the balena-os version of the image '{}', error: {}",
+                image_path.display(),
+                why
+            );
+            None
+        }
+    };
+    // an image without an os-release is compared by the version it was requested with
+    let requested = if opts.images().is_empty() {
+        is_requested_version(opts.version(), &os_version)
+    } else {
+        None
+    };
+    let same_version = match (&image_version, requested) {
+        (Some(image_version), _) => image_version == &os_version,
+        (None, Some(same_version)) => same_version,
+        (None, None) => {
+            info!(
+                "The version of the image is unknown, not checking whether the device already runs it, the device runs balena-os {}",
+                os_version
+            );
+            return Ok(());
+        }
+    };
+
+    if !same_version {
+        info!(
+            "The device runs balena-os {}, migrating to version '{}'",
+            os_version,
+            image_version.as_deref().unwrap_or_else(|| opts.version())
+        );
+        return Ok(());
+    }
+
+    let running_uuid = CONFIG_SEARCH_PATHS
+        .iter()
+        .map(Path::new)
+        .find(|path| path.exists())
+        .and_then(|path| BalenaCfgJson::new(path).ok())
+        .and_then(|running| running.get_uuid().ok().flatten());
+    let uuid = match (running_uuid, config.get_uuid()?) {
+        (Some(running_uuid), Some(uuid)) if running_uuid != uuid => {
+            info!(
+                "The device runs balena-os {} as device {}, migrating it as device {}",
+                os_version, running_uuid, uuid
+            );
+            return Ok(());
+        }
+        (Some(uuid), _) | (None, Some(uuid)) => format!(" as device {}", uuid),
+        (None, None) => String::new(),
+    };
+
+    if opts.force() {
+        warn!(
+            "Already migrated: the device runs balena-os {}{}, migrating it again due to --force",
+            os_version, uuid
+        );
+        Ok(())
+    } else {
+        error!(
+            "Already migrated: the device runs balena-os {}{}, use --force to migrate it again",
+            os_version, uuid
+        );
+        Err(Error::displayed_with(ErrorKind::InvState))
+    }
+}
+
+// checked after all changes to config.json, before anything is written
+fn check_config_schema(config: &BalenaCfgJson, schema_path: &Path) -> Result<()> {
+    let schema = match read_to_string(schema_path) {
+        Ok(schema) => schema,
+        Err(why) => {
+            error!(
+                "Failed to read the schema '{}', error: {}",
+                schema_path.display(),
+                why
+            );
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+    };
+    let schema = match serde_json::from_str::<Value>(&schema) {
+        Ok(schema) => schema,
+        Err(why) => {
+            error!(
+                "Failed to parse the schema '{}' as JSON, error: {}",
+                schema_path.display(),
+                why
+            );
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+    };
+
+    let violations = match config_schema::validate_schema(&schema, &config.to_value()) {
+        Ok(violations) => violations,
+        Err(why) => {
+            error!("{}", why);
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+    };
+    if violations.is_empty() {
+        info!(
+            "config.json conforms to the schema '{}'",
+            schema_path.display()
+        );
+        return Ok(());
+    }
+
+    for violation in &violations {
+        error!("config.json violates the schema: {}", violation);
+    }
+    error!(
+        "config.json has {} schema violations, the migration was not started",
+        violations.len()
+    );
+    Err(Error::displayed_with(ErrorKind::InvParam))
+}
+
+fn apply_config_overlays(config: &mut BalenaCfgJson, overlays: &[String]) -> Result<()> {
+    for overlay in overlays {
+        // the overlay is not logged, it might contain secrets
+        let overlay = match serde_json::from_str::<Value>(overlay) {
+            Ok(Value::Object(overlay)) => overlay,
+            Ok(value) => {
+                error!(
+                    "Invalid --config-overlay, expected a JSON object, found {}",
+                    value_type(&value)
+                );
+                return Err(Error::displayed_with(ErrorKind::InvParam));
+            }
+            Err(why) => {
+                error!("Failed to parse --config-overlay as JSON, error: {}", why);
+                return Err(Error::displayed_with(ErrorKind::InvParam));
+            }
+        };
+
+        let changed = config.merge_overlay(overlay);
+        if changed.is_empty() {
+            info!("The config.json overlay did not change any values");
+        } else {
+            info!(
+                "The config.json overlay changed {} values: {}",
+                changed.len(),
+                changed.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+fn apply_config_overrides(config: &mut BalenaCfgJson, overrides: &[String]) -> Result<()> {
+    for config_override in overrides {
+        let mut parts = config_override.splitn(2, '=');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) if !name.is_empty() => (name, value),
+            _ => {
+                error!(
+                    "Invalid config.json override '{}', expected KEY=VALUE",
+                    config_override
+                );
+                return Err(Error::displayed_with(ErrorKind::InvParam));
+            }
+        };
+
+        let value = serde_json::from_str::<Value>(value)
+            .unwrap_or_else(|_| Value::String(value.to_string()));
+        info!(
+            "Overriding config.json value '{}' with {}",
+            name,
+            redacted(name, &value)
+        );
+        if name == "apiEndpoint" {
+            if let Some(url) = value.as_str() {
+                if let Err(why) = config.set_api_endpoint(url) {
+                    error!("{}", why);
+                    return Err(Error::displayed_with(ErrorKind::InvParam));
+                }
+                continue;
+            }
+        }
+        if let Err(why) = config.set_value(name, value) {
+            error!(
+                "Invalid config.json override '{}': {}",
+                config_override, why
+            );
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+    }
+    Ok(())
+}
+
+/******************************************************************
+ * Decide which device the migrated device registers as. The uuid
+ * of config.json is kept if it has one, including one carried with
+ * --carry-keys uuid,deviceApiKey so the device keeps its dashboard
+ * entry. --new-uuid drops both so the supervisor registers a new
+ * device.
+ ******************************************************************/
+
+fn apply_device_uuid(config: &mut BalenaCfgJson, opts: &Options) -> Result<()> {
+    if opts.new_uuid() {
+        if let Some(uuid) = config.remove_value("uuid") {
+            info!("--new-uuid: dropping the config.json uuid {}", uuid);
+        }
+        if config.remove_value("deviceApiKey").is_some() {
+            info!("--new-uuid: dropping the config.json deviceApiKey");
+        }
+        info!("The device registers as a new device");
+        return Ok(());
+    }
+
+    if let Some(uuid) = config.get_uuid()? {
+        if !is_valid_uuid(&uuid) {
+            error!(
+                "Invalid config.json uuid '{}', expected 32 or 62 lowercase hex digits",
+                uuid
+            );
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+        info!("The device keeps the uuid {} of config.json", uuid);
+        return Ok(());
+    }
+
+    info!("config.json has no uuid, the device registers as a new device");
+    Ok(())
+}
+
+// --registry-mirror and --delta-endpoint, applied after --set so they take precedence
+pub(crate) fn apply_endpoint_options(config: &mut BalenaCfgJson, opts: &Options) -> Result<()> {
+    if let Some(mirror) = opts.registry_mirror() {
+        info!(
+            "Setting the config.json registryEndpoint to the mirror '{}'",
+            mirror
+        );
+        if let Err(why) = config.set_registry_endpoint(mirror) {
+            error!("{}", why);
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+    }
+    if let Some(delta_endpoint) = opts.delta_endpoint() {
+        info!(
+            "Setting the config.json deltaEndpoint to '{}'",
+            delta_endpoint
+        );
+        if let Err(why) = config.set_delta_endpoint(delta_endpoint) {
+            error!("{}", why);
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+    }
+    Ok(())
+}
+
+fn set_fleet(config: &mut BalenaCfgJson, fleet: &str) -> Result<()> {
+    let api_key = config.get_api_key().upstream_with_context(
+        "Failed to retrieve api-key from config.json - unable to resolve fleet",
+    )?;
+    let api_endpoint = config.get_api_endpoint().upstream_with_context(
+        "Failed to retrieve api-endpoint from config.json - unable to resolve fleet",
+    )?;
+
+    let fleet_id = get_fleet_id(&api_endpoint, &api_key, fleet)?;
+    info!("Resolved fleet '{}' to application id {}", fleet, fleet_id);
+
+    match config.get_app_id() {
+        Ok(app_id) => {
+            if app_id != fleet_id {
+                error!(
+                    "The fleet '{}' (application id {}) does not match the applicationId {} found in config.json",
+                    fleet, fleet_id, app_id
+                );
+                return Err(Error::displayed_with(ErrorKind::InvParam));
+            }
+        }
+        Err(why) => {
+            if why.kind() != ErrorKind::NotFound {
+                return Err(why);
+            }
+        }
+    }
+
+    config.set_value("applicationId", Value::from(fleet_id))?;
+    Ok(())
+}
+
+// the device type the image was built for, None if the image carries no device-type.json
+fn get_image_device_type(image_path: &Path) -> Result<Option<String>> {
+    let device_type_json = if let Some(device_type_json) =
+        Disk::from_gzip_img(image_path)?.read_boot_file(IMAGE_DEVICE_TYPE_FILE)?
+    {
+        device_type_json
+    } else {
+        return Ok(None);
+    };
+
+    let device_type: Value =
+        serde_json::from_slice(&device_type_json).upstream_with_context(&format!(
+            "Failed to parse {} from the image '{}'",
+            IMAGE_DEVICE_TYPE_FILE,
+            image_path.display()
+        ))?;
+
+    if let Some(slug) = device_type.get("slug").and_then(Value::as_str) {
+        Ok(Some(slug.to_string()))
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "No slug found in {} from the image '{}'",
+                IMAGE_DEVICE_TYPE_FILE,
+                image_path.display()
+            ),
+        ))
+    }
+}
+
+// the image has to match config.json and the device, config.json alone can match the device
+fn check_image_device_type(
+    image_path: &Path,
+    config: &BalenaCfgJson,
+    device: &dyn Device,
+) -> Result<()> {
+    let image_type = match get_image_device_type(image_path) {
+        Ok(Some(image_type)) => image_type,
+        Ok(None) => {
+            warn!(
+                "No {} found in the image '{}', the device type of the image can not be checked",
+                IMAGE_DEVICE_TYPE_FILE,
+                image_path.display()
+            );
+            return Ok(());
+        }
+        Err(why) => {
+            warn!(
+                "Failed to read the device type of the image '{}', error: {}",
+                image_path.display(),
+                why
+            );
+            return Ok(());
+        }
+    };
+
+    let config_type = config.get_device_type()?;
+    if image_type == config_type && device.supports_device_type(&image_type) {
+        info!(
+            "The image '{}' is for device type {}",
+            image_path.display(),
+            image_type
+        );
+        Ok(())
+    } else {
+        error!(
+            "The device types do not match, the image '{}' is for {}, config.json is for {}, the detected device is {}",
+            image_path.display(),
+            image_type,
+            config_type,
+            device.get_device_type()
+        );
+        Err(Error::displayed_with(ErrorKind::InvParam))
+    }
+}
+
+// wifis that config.json can describe are listed in its network section as well. balenaOS
+// does not set up networks from it, so every wifi is still written as a network manager file
+fn add_config_wifis(config: &mut BalenaCfgJson, wifis: &[WifiConfig]) -> Result<()> {
+    for wifi in wifis {
+        if let Some((ssid, psk)) = wifi.get_params() {
+            if config.add_wifi(ssid, psk)? {
+                info!(
+                    "Added wifi '{}' to the network section of config.json",
+                    ssid
+                );
+            } else {
+                info!(
+                    "Wifi '{}' is already configured in config.json, keeping its settings",
+                    ssid
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/******************************************************************
+ * The config.json and connectivity checks of stage1 on their own,
+ * without reading files or preparing the migration, so they can be
+ * run against fixture configs and mock devices. Invalid settings are
+ * returned as errors, the outcome of every check in ConfigCheck.
+ ******************************************************************/
+
+pub(crate) fn run_checks(
+    config: &BalenaCfgJson,
+    opts: &Options,
+    device: &dyn Device,
+) -> Result<ConfigCheck> {
+    config.validate()?;
+    let checks = config.get_enabled_checks(opts)?;
+    if let Some(timeout) = opts.wait_for_network() {
+        if checks.api || checks.vpn {
+            wait_for_network(timeout);
+        }
+    }
+    config.check(opts, &checks, device)
+}
 
 #[derive(Debug)]
 pub(crate) struct MigrateInfo {
@@ -39,10 +661,14 @@ pub(crate) struct MigrateInfo {
     image_path: PathBuf,
     device: Box<dyn Device>,
     config: BalenaCfgJson,
+    // write config.json to the work dir even if it is unchanged
+    always_write_config: bool,
     work_dir: PathBuf,
     wifis: Vec<WifiConfig>,
     nwmgr_files: Vec<PathBuf>,
     backup: Option<PathBuf>,
+    init_system: InitSystem,
+    boot_config: Option<String>,
 }
 
 #[allow(dead_code)]
@@ -51,15 +677,59 @@ impl MigrateInfo {
         let device = get_device(opts)?;
         info!("Detected device type: {}", device.get_device_type());
 
-        let mut config = if let Some(balena_cfg) = opts.config() {
-            BalenaCfgJson::new(balena_cfg)?
+        let init_system = InitSystem::detect()?;
+        info!("Detected init system: {}", init_system);
+
+        // losing the boot settings is not worth failing the migration for
+        let boot_config = match device.get_boot_config() {
+            Ok(boot_config) => boot_config,
+            Err(why) => {
+                warn!("Failed to collect boot settings, error: {}", why);
+                None
+            }
+        };
+
+        let stdin_configs = opts
+            .config()
+            .iter()
+            .filter(|path| path.as_path() == Path::new(STDIN_CONFIG))
+            .count();
+        if stdin_configs > 1 {
+            error!("config.json can be read from stdin only once");
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+        // the confirmation prompt would read from the exhausted stdin
+        if stdin_configs > 0 && !opts.no_ack() {
+            error!("Reading config.json from stdin requires --no-ack");
+            return Err(Error::displayed_with(ErrorKind::InvParam));
+        }
+        // the watchdog has to see progress between the flash pauses
+        if let (Some(flash_pause), Some(timeout)) = (opts.flash_pause(), opts.stage2_timeout()) {
+            if flash_pause.pause_ms >= timeout.saturating_mul(1000) {
+                error!(
+                    "The --flash-pause of {} ms has to be shorter than the --stage2-timeout of {} seconds",
+                    flash_pause.pause_ms, timeout
+                );
+                return Err(Error::displayed_with(ErrorKind::InvParam));
+            }
+        }
+
+        let mut config = if let Some((first, others)) = opts.config().split_first() {
+            let mut config = MigrateInfo::read_cfg_json(first)?;
+            for other in others {
+                info!("Merging config.json values from '{}'", other.display());
+                if let Err(why) = config.merge(MigrateInfo::read_cfg_json(other)?) {
+                    error!("Failed to merge '{}', error: {}", other.display(), why);
+                    return Err(Error::displayed_with(ErrorKind::InvParam));
+                }
+            }
+            config
         } else {
             match MigrateInfo::get_internal_cfg_json(&opts.work_dir()) {
                 Ok(balena_cfg_json) => balena_cfg_json,
                 Err(why) => {
                     if why.kind() == ErrorKind::NotFound {
-                        error!("The required parameter --config/-c was not provided and no internal config.json was found");
-                        return Err(Error::displayed());
+                        find_cfg_json()?
                     } else {
                         return Err(why);
                     }
@@ -67,8 +737,75 @@ impl MigrateInfo {
             }
         };
 
-        if opts.migrate() {
-            config.check(opts, &*device)?;
+        // the device uuid and deviceApiKey are only carried if listed, see apply_device_uuid
+        match (opts.carry_from(), opts.carry_keys().is_empty()) {
+            (Some(source), false) => carry_config_keys(&mut config, source, opts.carry_keys())?,
+            (None, true) => (),
+            (Some(_), true) => {
+                error!("The option --carry-from needs --carry-keys, eg. --carry-keys uuid,deviceApiKey to keep the device");
+                return Err(Error::displayed_with(ErrorKind::InvParam));
+            }
+            (None, false) => {
+                error!("The option --carry-keys needs --carry-from");
+                return Err(Error::displayed_with(ErrorKind::InvParam));
+            }
+        }
+
+        apply_config_overlays(&mut config, opts.config_overlays())?;
+        apply_config_overrides(&mut config, opts.config_overrides())?;
+        apply_endpoint_options(&mut config, opts)?;
+        apply_device_uuid(&mut config, opts)?;
+        config.set_pretty(opts.pretty_config());
+
+        if let Some(fleet) = opts.fleet() {
+            set_fleet(&mut config, fleet)?;
+        }
+
+        check_unknown_keys(&config, opts.unknown_keys())?;
+        let check = if opts.migrate() {
+            run_checks(&config, opts, &*device).map(Some)
+        } else {
+            config.validate().map(|_| None)
+        };
+        let check = match check {
+            Ok(check) => check,
+            Err(why) => {
+                error!("{}", why);
+                return Err(Error::displayed_with(why.kind()));
+            }
+        };
+
+        if let Some(check) = check {
+            for (name, reason) in check.failures() {
+                error!("The {} check failed: {}", name, reason);
+            }
+            if let Some(kind) = check.error_kind() {
+                return Err(Error::displayed_with(kind));
+            }
+            for latency in check.slow() {
+                warn!(
+                    "Connecting to the {} server took {} ms, more than {} ms, your device might struggle to stay online",
+                    latency.server,
+                    latency.latency.as_millis(),
+                    opts.latency_warning().as_millis()
+                );
+            }
+
+            let connectivity = check.connectivity();
+            if connectivity == Connectivity::Verified {
+                info!("Connectivity: {}", connectivity);
+            } else {
+                warn!("Connectivity: {}", connectivity);
+            }
+            emit(ProgressEvent::Connectivity {
+                verdict: connectivity.verdict().to_string(),
+                skipped: connectivity
+                    .skipped()
+                    .iter()
+                    .map(|check| check.to_string())
+                    .collect(),
+                summary: connectivity.to_string(),
+            });
         }
 
         info!(
@@ -84,32 +821,59 @@ impl MigrateInfo {
                 opts.work_dir().display()
             ))?;
 
-        let image_path = if let Some(image_path) = opts.image() {
-            if file_exists(&image_path) {
-                image_path.canonicalize().upstream_with_context(&format!(
-                    "Failed to canonicalize path '{}'",
-                    image_path.display()
-                ))?
-            } else {
-                error!(
-                    "The balena-os image configured as '{}' could not be found",
-                    image_path.display()
-                );
-                return Err(Error::displayed());
-            }
+        let sources = image_sources(opts, config.get_device_type()?.as_str());
+        let resumed = if opts.resume() {
+            resumed_image(&work_dir, &sources, opts.image_sha256())
+        } else {
+            None
+        };
+
+        let cache = ImageCache::new(opts);
+        let image_path = if let Some(image_path) = &resumed {
+            image_path.clone()
+        } else if !opts.images().is_empty() {
+            fetch_image(
+                opts.images(),
+                opts.image_sha256(),
+                opts.max_image_age(),
+                &work_dir,
+                cache.as_ref(),
+            )?
         } else {
             let image_path = download_image(
                 &config,
                 &work_dir,
                 config.get_device_type()?.as_str(),
                 opts.version(),
+                cache.as_ref(),
             )?;
+            if let Some(sha256) = opts.image_sha256() {
+                check_image_sha256(&image_path, sha256)?;
+            }
             image_path.canonicalize().upstream_with_context(&format!(
                 "Failed to canonicalize path '{}'",
                 image_path.display()
             ))?
         };
 
+        if let Some(pubkey) = opts.verify_image_signature() {
+            let sig_path = if let Some(sig_path) = opts.image_signature() {
+                sig_path.to_path_buf()
+            } else {
+                PathBuf::from(format!("{}.sig", image_path.display()))
+            };
+            if let Err(why) = check_image_signature(&image_path, pubkey, &sig_path) {
+                error!("Image signature check failed: {}", why);
+                return Err(Error::displayed_with(ErrorKind::Validation));
+            }
+        } else {
+            info!("No image signature checked, use --verify-image-signature to verify the image");
+        }
+
+        if resumed.is_none() {
+            save_image_state(&work_dir, &sources, &image_path);
+        }
+
         if !opts.migrate() {
             return Err(Error::with_context(
                 ErrorKind::ImageDownloaded,
@@ -119,16 +883,23 @@ impl MigrateInfo {
 
         debug!("image path: '{}'", image_path.display());
 
+        check_image_device_type(&image_path, &config, &*device)?;
+        check_already_migrated(&config, opts, &image_path)?;
+
         let wifi_ssids = opts.wifis();
 
         let wifis: Vec<WifiConfig> = if !wifi_ssids.is_empty() || !opts.no_wifis() {
-            WifiConfig::scan(wifi_ssids)?
+            WifiConfig::scan(wifi_ssids, init_system)?
         } else {
             Vec::new()
         };
+        if opts.network_via() == NetworkVia::Config {
+            add_config_wifis(&mut config, &wifis)?;
+        }
 
         let nwmgr_files = Vec::from(opts.nwmgr_cfg());
 
+        // the network section of config.json does not bring the device online
         if nwmgr_files.is_empty() && wifis.is_empty() {
             if opts.no_nwmgr_check() {
                 warn!(
@@ -138,7 +909,7 @@ impl MigrateInfo {
                 error!(
                     "No Network manager files were found, the device might not be able to come online"
                 );
-                return Err(Error::displayed());
+                return Err(Error::displayed_with(ErrorKind::InvParam));
             }
         }
 
@@ -168,23 +939,42 @@ impl MigrateInfo {
             config.set_host_name(&hostname);
         }
 
+        // validate the config.json as it is written, after all changes
+        if let Some(schema_path) = opts.config_validate_schema() {
+            check_config_schema(&config, schema_path)?;
+        }
+
         Ok(MigrateInfo {
             // assets: Assets::new(),
             os_name: get_os_name()?,
             to_dir: None,
             mounts: Vec::new(),
             config,
+            always_write_config: opts.always_write_config(),
             image_path,
             device,
             work_dir,
             wifis,
             nwmgr_files,
             backup,
+            init_system,
+            boot_config,
         })
     }
 
+    pub(crate) fn read_cfg_json(path: &Path) -> Result<BalenaCfgJson> {
+        if path == Path::new(STDIN_CONFIG) {
+            BalenaCfgJson::from_stdin()
+        } else {
+            BalenaCfgJson::new(path)
+        }
+    }
+
     pub fn update_config(&mut self) -> Result<()> {
-        if self.config.is_modified() {
+        if self.config.is_modified() || self.always_write_config {
+            if !self.config.is_modified() {
+                info!("config.json is unchanged, writing it due to --always-write-config");
+            }
             let target_path = mktemp(false, Some("config."), Some(".json"), Some(&self.work_dir))?;
             self.config.write(&target_path)?;
             info!("Copied config.json to '{}'", target_path.display());
@@ -200,6 +990,22 @@ impl MigrateInfo {
         &self.to_dir
     }
 
+    pub fn init_system(&self) -> InitSystem {
+        self.init_system
+    }
+
+    pub fn device_type(&self) -> DeviceType {
+        self.device.get_device_type()
+    }
+
+    pub fn default_target_device(&self) -> Option<PathBuf> {
+        self.device.default_target_device()
+    }
+
+    pub fn reboot_method(&self) -> RebootMethod {
+        self.device.reboot_method()
+    }
+
     pub fn is_x86(&self) -> bool {
         self.device.supports_device_type(DEV_TYPE_GEN_X86_64)
     }
@@ -236,26 +1042,8 @@ impl MigrateInfo {
         &self.wifis
     }
 
-    pub fn umount_all(&mut self) {
-        while let Some(mountpoint) = self.mounts.pop() {
-            if let Err(why) = umount(&mountpoint) {
-                warn!(
-                    "Failed to unmount mountpoint: '{}', error : {:?}",
-                    mountpoint.display(),
-                    why
-                );
-            }
-        }
-
-        if let Some(takeover_dir) = &self.to_dir {
-            if let Err(why) = remove_dir_all(takeover_dir) {
-                warn!(
-                    "Failed to remove takeover directory: '{}', error : {:?}",
-                    takeover_dir.display(),
-                    why
-                );
-            }
-        }
+    pub fn boot_config(&self) -> Option<&str> {
+        self.boot_config.as_deref()
     }
 
     fn get_internal_cfg_json(work_dir: &Path) -> Result<BalenaCfgJson> {
@@ -285,6 +1073,11 @@ impl MigrateInfo {
             Err(Error::new(ErrorKind::NotFound))
         } else if size < CONFIG_JSON.len() - SIZE_LEN {
             let target_path = mktemp(false, Some("config."), Some(".json"), Some(work_dir))?;
+            set_permissions(&target_path, Permissions::from_mode(DEFAULT_MODE))
+                .upstream_with_context(&format!(
+                    "Failed to set permissions on '{}'",
+                    target_path.display()
+                ))?;
 
             {
                 let mut file = OpenOptions::new()
@@ -333,3 +1126,106 @@ impl MigrateInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stage1::migrate_info::balena_cfg_json::CheckResult;
+    use structopt::StructOpt;
+
+    struct MockDevice {
+        slug: &'static str,
+        device_type: DeviceType,
+    }
+
+    impl Device for MockDevice {
+        fn supports_device_type(&self, dev_type: &str) -> bool {
+            dev_type == self.slug
+        }
+
+        fn get_device_type(&self) -> DeviceType {
+            self.device_type
+        }
+    }
+
+    #[test]
+    fn test_run_checks() {
+        let config = BalenaCfgJson::new(path_append(
+            env!("CARGO_MANIFEST_DIR"),
+            "test_data/config.json",
+        ))
+        .unwrap();
+        let opts = Options::from_iter(&["takeover", "--no-api-check", "--no-vpn-check"]);
+
+        let rpi3 = MockDevice {
+            slug: "raspberrypi3",
+            device_type: DeviceType::RaspberryPi3,
+        };
+        let check = run_checks(&config, &opts, &rpi3).unwrap();
+        assert!(check.failures().is_empty());
+        assert_eq!(check.api, CheckResult::Skipped);
+        assert_eq!(check.vpn, CheckResult::Skipped);
+        assert_eq!(check.connectivity(), Connectivity::Unverified);
+
+        let bbg = MockDevice {
+            slug: "beaglebone-green",
+            device_type: DeviceType::BeagleboneGreen,
+        };
+        let check = run_checks(&config, &opts, &bbg).unwrap();
+        assert_eq!(check.failures().len(), 1);
+        assert_eq!(check.error_kind(), Some(ErrorKind::InvParam));
+
+        let opts = Options::from_iter(&[
+            "takeover",
+            "--no-api-check",
+            "--no-vpn-check",
+            "--expect-api-fingerprint",
+            "00",
+        ]);
+        assert!(run_checks(&config, &opts, &rpi3).is_err());
+    }
+
+    #[test]
+    fn test_apply_device_uuid() {
+        let source = path_append(env!("CARGO_MANIFEST_DIR"), "test_data/config.json");
+        let source_arg = source.to_string_lossy().to_string();
+        let mut config = BalenaCfgJson::new(&source).unwrap();
+        config.remove_value("uuid").unwrap();
+
+        let opts = Options::from_iter(&["takeover"]);
+        apply_device_uuid(&mut config, &opts).unwrap();
+        assert_eq!(config.get_uuid().unwrap(), None);
+
+        // the uuid is only carried when listed in --carry-keys
+        let keys = ["uuid".to_string(), "deviceApiKey".to_string()];
+        carry_config_keys(&mut config, &source, &keys).unwrap();
+        let opts = Options::from_iter(&[
+            "takeover",
+            "--carry-from",
+            &source_arg,
+            "--carry-keys",
+            "uuid,deviceApiKey",
+        ]);
+        apply_device_uuid(&mut config, &opts).unwrap();
+        assert_eq!(
+            config.get_uuid().unwrap().as_deref(),
+            Some("0123456789abcdef0123456789abcdef")
+        );
+
+        config.set_value("uuid", Value::from("ABCD")).unwrap();
+        assert!(apply_device_uuid(&mut config, &opts).is_err());
+
+        let opts = Options::from_iter(&["takeover", "--carry-from", &source_arg, "--new-uuid"]);
+        apply_device_uuid(&mut config, &opts).unwrap();
+        assert_eq!(config.get_uuid().unwrap(), None);
+        assert_eq!(config.get_device_api_key().unwrap(), None);
+    }
+
+    #[test]
+    fn test_is_requested_version() {
+        assert_eq!(is_requested_version("2.50.1", "2.50.1+rev1"), Some(true));
+        assert_eq!(is_requested_version("2.51.0", "2.50.1"), Some(false));
+        assert_eq!(is_requested_version("^2.50", "2.50.1"), Some(true));
+        assert_eq!(is_requested_version("default", "2.50.1"), None);
+    }
+}
@@ -1,66 +1,814 @@
 use crate::{
     common::{Error, ErrorKind, Options, Result, ToError},
-    stage1::{device::Device, utils::check_tcp_connect},
+    stage1::{
+        defs::DEV_TYPE_RPI4_64,
+        device::Device,
+        device_impl::is_known_device_type,
+        utils::{check_http_get, check_tcp_connect, get_tls_fingerprint, parse_fingerprint},
+    },
 };
 
-use log::{error, info};
-use serde_json::Value;
+use log::{debug, info, warn};
+use nix::unistd::{chown, getgid, getuid, Gid, Uid};
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::BufReader;
+use std::convert::TryFrom;
+use std::env::current_dir;
+use std::fmt::{self, Display};
+use std::fs::{read_to_string, symlink_metadata, File, OpenOptions, Permissions};
+use std::io::{self, Read, Write};
+use std::mem::replace;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::thread::{spawn, JoinHandle};
+use std::time::Duration;
 use url::Url;
 
 pub const BALENA_API_PORT: u16 = 80;
 
+// used for config files created from scratch
+pub(crate) const DEFAULT_MODE: u32 = 0o600;
+
+// given as --config path to read config.json from stdin, also stored as its path until it is written
+pub(crate) const STDIN_CONFIG: &str = "-";
+// the path of a config built in memory by a test, it is only written to a path given to write
+#[cfg(test)]
+const IN_MEMORY_CONFIG: &str = "<in-memory>";
+
+const UTF8_BOM: char = '\u{feff}';
+
+const SECRET_KEYS: [&str; 2] = ["apiKey", "deviceApiKey"];
+// secrets nested in objects, eg. the psk of a network.wifi entry
+const NESTED_SECRET_KEYS: [&str; 1] = ["psk"];
+// wifi networks listed in config.json, a list of {"ssid": .., "psk": ..}, balenaOS itself
+// only sets up networks from the network manager files in system-connections
+const NETWORK_KEY: &str = "network";
+const NETWORK_WIFI_KEY: &str = "network.wifi";
+const REDACTED: &str = "<redacted>";
+// balena API keys are random strings of letters and digits, usually 32 long
+const API_KEY_MIN_LEN: usize = 16;
+const API_KEY_MAX_LEN: usize = 128;
+
+const REQUIRED_KEYS: [&str; 2] = ["applicationId", "deviceType"];
+const STRING_KEYS: [&str; 6] = [
+    "apiKey",
+    "deviceApiKey",
+    "apiEndpoint",
+    "deviceType",
+    "vpnEndpoint",
+    "hostname",
+];
+// an empty deviceType is usually a template variable that was not substituted,
+// not a device that is not supported
+const EMPTY_DEVICE_TYPE: &str = "'deviceType' is empty in config.json, check for a template variable that was not substituted when config.json was generated";
+const UINT_KEYS: [&str; 2] = ["applicationId", "vpnPort"];
+// uint values that are used as ports and must fit a u16
+const PORT_KEYS: [&str; 1] = ["vpnPort"];
+// top level keys written by the balena dashboard, the supervisor or takeover and the
+// balenaOS settings of the config.json reference
+const KNOWN_KEYS: [&str; 38] = [
+    "apiEndpoint",
+    "apiKey",
+    "appUpdatePollInterval",
+    "applicationId",
+    "applicationName",
+    "balenaRootCA",
+    "connectivity",
+    "country",
+    "dashboardUrl",
+    "deltaEndpoint",
+    "developmentMode",
+    "deviceApiKey",
+    "deviceApiKeys",
+    "deviceId",
+    "deviceType",
+    "dnsServers",
+    "hostname",
+    "initialDeviceName",
+    "installer",
+    "listenPort",
+    "localMode",
+    "logsEndpoint",
+    "mixpanelToken",
+    "network",
+    "ntpServers",
+    "os",
+    "persistentLogging",
+    "pubnubPublishKey",
+    "pubnubSubscribeKey",
+    "randomMacAddressScan",
+    "registered_at",
+    "registryEndpoint",
+    "supervisorPort",
+    "userId",
+    "username",
+    "uuid",
+    "vpnEndpoint",
+    "vpnPort",
+];
+// booleans enabling or disabling the checks, overridden by the --no-*-check options
+const INSTALLER_CHECKS_KEY: &str = "installer.checks";
+// an unknown key this close to a known one is reported as a likely typo
+const MAX_TYPO_DISTANCE: usize = 2;
+
+// the Levenshtein distance of a and b, case is ignored
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.to_lowercase().chars().collect();
+    let b: Vec<char> = b.to_lowercase().chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.iter().enumerate() {
+        let mut curr = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let cost = if a_char == b_char { 0 } else { 1 };
+            curr[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(curr[j] + 1);
+        }
+        prev = curr;
+    }
+    prev[b.len()]
+}
+
+pub(crate) fn value_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn redact_nested(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| {
+                    if NESTED_SECRET_KEYS.contains(&key.as_str()) {
+                        (key.clone(), Value::from(REDACTED))
+                    } else {
+                        (key.clone(), redact_nested(value))
+                    }
+                })
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.iter().map(redact_nested).collect()),
+        _ => value.clone(),
+    }
+}
+
+fn is_secret(name: &str) -> bool {
+    SECRET_KEYS.contains(&name) || NESTED_SECRET_KEYS.contains(&name)
+}
+
+// whether setting the value, eg. with --set or --config-overlay, writes a secret
+pub(crate) fn holds_secret(name: &str, value: &Value) -> bool {
+    // the last part of a dotted path like network.wifi.0.psk
+    let key = name.rsplit('.').next().unwrap_or(name);
+    is_secret(key)
+        || match value {
+            Value::Object(map) => map.iter().any(|(key, value)| holds_secret(key, value)),
+            Value::Array(values) => values.iter().any(|value| holds_secret("", value)),
+            _ => false,
+        }
+}
+
+// render a config value for logging, hiding secrets
+pub(crate) fn redacted(name: &str, value: &Value) -> String {
+    if is_secret(name) {
+        REDACTED.to_string()
+    } else {
+        redact_nested(value).to_string()
+    }
+}
+
+// a WPA passphrase has 8 to 63 printable characters, a raw key 64 hex digits
+fn is_valid_psk(psk: &str) -> bool {
+    (psk.len() >= 8 && psk.len() <= 63 && psk.chars().all(|c| (' '..='~').contains(&c)))
+        || (psk.len() == 64 && psk.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+// path relative to the current dir if it can be determined, symlinks and '..' are kept
+fn absolute_path(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else if let Ok(curr_dir) = current_dir() {
+        curr_dir.join(path)
+    } else {
+        path.to_path_buf()
+    }
+}
+
+// balena device uuids are 32 lowercase hex digits, devices registered long ago have 62
+pub(crate) fn is_valid_uuid(uuid: &str) -> bool {
+    (uuid.len() == 32 || uuid.len() == 62)
+        && uuid
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+// what is wrong with the network section of config.json, the psks are never part of a message
+fn check_network(network: &Value) -> Vec<String> {
+    let network = if let Some(network) = network.as_object() {
+        network
+    } else {
+        return vec![format!(
+            "'{}' is {}, expected an object",
+            NETWORK_KEY,
+            value_type(network)
+        )];
+    };
+    let wifis = match network.get("wifi") {
+        Some(Value::Array(wifis)) => wifis,
+        Some(wifis) => {
+            return vec![format!(
+                "'{}' is {}, expected a list of wifi networks",
+                NETWORK_WIFI_KEY,
+                value_type(wifis)
+            )]
+        }
+        None => return Vec::new(),
+    };
+
+    let mut errors = Vec::new();
+    for (index, wifi) in wifis.iter().enumerate() {
+        let name = format!("{}.{}", NETWORK_WIFI_KEY, index);
+        match wifi.get("ssid").and_then(|ssid| ssid.as_str()) {
+            Some(ssid) if !ssid.is_empty() && ssid.len() <= 32 => (),
+            _ => errors.push(format!(
+                "'{}.ssid' is missing or not a string of 1 to 32 characters",
+                name
+            )),
+        }
+        match wifi.get("psk") {
+            None => (),
+            Some(Value::String(psk)) if is_valid_psk(psk) => (),
+            Some(_) => errors.push(format!(
+                "'{}.psk' is not a passphrase of 8 to 63 characters or a key of 64 hex digits",
+                name
+            )),
+        }
+    }
+    errors
+}
+
+// what is wrong with the API key in name, the key itself is never part of the message
+fn check_api_key(name: &str, key: &str) -> Option<String> {
+    if key.is_empty() {
+        Some(format!(
+            "'{}' is empty, download a new config.json for the fleet",
+            name
+        ))
+    } else if key.len() < API_KEY_MIN_LEN
+        || key.len() > API_KEY_MAX_LEN
+        || !key.chars().all(|c| c.is_ascii_alphanumeric())
+    {
+        Some(format!(
+            "'{}' does not look like a balena API key, expected {} to {} letters and digits, found {} characters, check for a truncated or placeholder key",
+            name,
+            API_KEY_MIN_LEN,
+            API_KEY_MAX_LEN,
+            key.chars().count()
+        ))
+    } else {
+        None
+    }
+}
+
+// merge overlay into curr, changed values are collected by their dotted path
+fn overlay_value(curr: &mut Value, overlay: Value, path: &str, changes: &mut Vec<ConfigChange>) {
+    match overlay {
+        Value::Object(overlay) if curr.is_object() => {
+            let curr = curr.as_object_mut().unwrap();
+            for (name, value) in overlay {
+                let path = format!("{}.{}", path, name);
+                if let Some(curr_value) = curr.get_mut(&name) {
+                    overlay_value(curr_value, value, &path, changes);
+                } else {
+                    changes.push(ConfigChange {
+                        key: path,
+                        old: None,
+                        new: value.clone(),
+                    });
+                    curr.insert(name, value);
+                }
+            }
+        }
+        overlay => {
+            if *curr != overlay {
+                changes.push(ConfigChange {
+                    key: path.to_string(),
+                    old: Some(replace(curr, overlay.clone())),
+                    new: overlay,
+                });
+            }
+        }
+    }
+}
+
+// set the value at the dotted path components below curr, missing objects are created
+fn set_path(
+    curr: &mut Value,
+    components: &[&str],
+    value: Value,
+    path: &str,
+) -> Result<Option<Value>> {
+    let (component, rest) = if let Some(split) = components.split_first() {
+        split
+    } else {
+        return Ok(Some(replace(curr, value)));
+    };
+
+    match curr {
+        Value::Object(map) => {
+            if rest.is_empty() {
+                Ok(map.insert(component.to_string(), value))
+            } else {
+                set_path(
+                    map.entry(component.to_string())
+                        .or_insert_with(|| Value::Object(Map::new())),
+                    rest,
+                    value,
+                    path,
+                )
+            }
+        }
+        Value::Array(array) => {
+            let length = array.len();
+            let index = component.parse::<usize>().map_err(|_| {
+                Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!("Invalid array index '{}' in '{}'", component, path),
+                )
+            })?;
+            if let Some(element) = array.get_mut(index) {
+                set_path(element, rest, value, path)
+            } else {
+                Err(Error::with_context(
+                    ErrorKind::NotFound,
+                    &format!(
+                        "Array index {} in '{}' is out of range, the array has {} elements",
+                        index, path, length
+                    ),
+                ))
+            }
+        }
+        other => Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Cannot set '{}', found a {} where an object or array was expected",
+                path,
+                value_type(other)
+            ),
+        )),
+    }
+}
+
+// byte offset of a 1 based line / column position as reported by serde_json
+fn byte_offset(text: &str, line: usize, column: usize) -> usize {
+    let line_start: usize = text
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(|line| line.len())
+        .sum();
+    line_start + column.saturating_sub(1)
+}
+
+// parse config.json leniently, files edited on windows or created by templating tools
+// may start with a BOM or carry trailing data after the json object
+fn parse_config(cfg_txt: &str) -> Result<HashMap<String, Value>> {
+    let cfg_txt = cfg_txt.strip_prefix(UTF8_BOM).unwrap_or(cfg_txt);
+
+    let mut stream =
+        serde_json::Deserializer::from_str(cfg_txt).into_iter::<HashMap<String, Value>>();
+    match stream.next() {
+        Some(Ok(config)) => {
+            let trailing = cfg_txt[stream.byte_offset()..].trim();
+            if !trailing.is_empty() {
+                warn!(
+                    "Ignoring {} bytes of trailing data after the json object in config.json",
+                    trailing.len()
+                );
+            }
+            Ok(config)
+        }
+        Some(Err(why)) => Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Invalid json at line {} column {} (byte offset {}): {}",
+                why.line(),
+                why.column(),
+                byte_offset(cfg_txt, why.line(), why.column()),
+                why
+            ),
+        )),
+        None => Err(Error::with_context(
+            ErrorKind::InvParam,
+            "No json object found",
+        )),
+    }
+}
+
+// a key changed in config.json, old is the value before the first change
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ConfigChange {
+    pub key: String,
+    pub old: Option<Value>,
+    pub new: Value,
+}
+
+// outcome of a single check run by BalenaCfgJson::check
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum CheckResult {
+    Ok,
+    Skipped,
+    Failed(ErrorKind, String),
+}
+
+// what the passed connectivity checks of a ConfigCheck tell about the device coming online
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Connectivity {
+    // the api and the vpn checks passed
+    Verified,
+    // the connectivity checks that did not run
+    Partial(Vec<&'static str>),
+    // no connectivity check ran
+    Unverified,
+}
+
+impl Connectivity {
+    // the verdict reported in progress events
+    pub fn verdict(&self) -> &'static str {
+        match self {
+            Connectivity::Verified => "verified",
+            Connectivity::Partial(_) => "partial",
+            Connectivity::Unverified => "unverified",
+        }
+    }
+
+    pub fn skipped(&self) -> &[&'static str] {
+        match self {
+            Connectivity::Partial(skipped) => skipped.as_slice(),
+            _ => &[],
+        }
+    }
+}
+
+impl Display for Connectivity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Connectivity::Verified => write!(f, "device verified reachable to API and VPN"),
+            Connectivity::Partial(skipped) => {
+                write!(f, "partial verification ({} skipped)", skipped.join(", "))
+            }
+            Connectivity::Unverified => write!(f, "no connectivity verified (offline mode)"),
+        }
+    }
+}
+
+// the checks BalenaCfgJson::check runs, see BalenaCfgJson::get_enabled_checks
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EnabledChecks {
+    pub device_type: bool,
+    pub api: bool,
+    pub vpn: bool,
+}
+
+// connect latency of a server that could be reached, slow when above --latency-warning
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Latency {
+    pub server: &'static str,
+    pub latency: Duration,
+    pub slow: bool,
+}
+
+// results of BalenaCfgJson::check, the caller decides how to report failures
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ConfigCheck {
+    pub device_type: CheckResult,
+    pub api: CheckResult,
+    pub api_fingerprint: CheckResult,
+    pub vpn: CheckResult,
+    pub latencies: Vec<Latency>,
+}
+
+impl ConfigCheck {
+    // (check, reason) of all failed checks in check order
+    pub fn failures(&self) -> Vec<(&'static str, &str)> {
+        self.results()
+            .iter()
+            .filter_map(|(check, result)| match result {
+                CheckResult::Failed(_, reason) => Some((*check, reason.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // the error kind of the first failed check
+    pub fn error_kind(&self) -> Option<ErrorKind> {
+        self.results().iter().find_map(|(_, result)| match result {
+            CheckResult::Failed(kind, _) => Some(*kind),
+            _ => None,
+        })
+    }
+
+    // servers that could be reached but took longer than the warning threshold
+    pub fn slow(&self) -> Vec<&Latency> {
+        self.latencies
+            .iter()
+            .filter(|latency| latency.slow)
+            .collect()
+    }
+
+    // the verdict of the api and vpn checks, meaningful once no check failed
+    pub fn connectivity(&self) -> Connectivity {
+        let skipped: Vec<&'static str> = [("api", &self.api), ("vpn", &self.vpn)]
+            .iter()
+            .filter(|(_, result)| **result != CheckResult::Ok)
+            .map(|(check, _)| *check)
+            .collect();
+        match skipped.len() {
+            0 => Connectivity::Verified,
+            2 => Connectivity::Unverified,
+            _ => Connectivity::Partial(skipped),
+        }
+    }
+
+    fn results(&self) -> [(&'static str, &CheckResult); 4] {
+        [
+            ("device type", &self.device_type),
+            ("api", &self.api),
+            ("api fingerprint", &self.api_fingerprint),
+            ("vpn", &self.vpn),
+        ]
+    }
+}
+
+type ConnectCheck = (String, u16, JoinHandle<Result<Duration>>);
+
+fn start_connect_check(host: String, port: u16, timeout: u64) -> ConnectCheck {
+    let check_host = host.clone();
+    let handle = spawn(move || check_tcp_connect(&check_host, port, timeout));
+    (host, port, handle)
+}
+
+// local mode or on-prem fleets are probed at the service they actually use
+// a http or https url with a host, name is what the url is used as in error messages
+fn parse_http_url(name: &str, url: &str) -> Result<Url> {
+    match Url::parse(url) {
+        Ok(parsed)
+            if (parsed.scheme() == "http" || parsed.scheme() == "https") && parsed.has_host() =>
+        {
+            Ok(parsed)
+        }
+        _ => Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!("Invalid {} '{}', expected a http or https url", name, url),
+        )),
+    }
+}
+
+fn parse_health_endpoint(endpoint: &str) -> Result<Url> {
+    parse_http_url("health endpoint", endpoint)
+}
+
+fn start_health_check(url: Url, timeout: u64) -> ConnectCheck {
+    let host = url.host_str().unwrap_or_default().to_string();
+    let port = url.port_or_known_default().unwrap_or(BALENA_API_PORT);
+    let handle = spawn(move || check_http_get(url.as_str(), timeout));
+    (host, port, handle)
+}
+
+// a barely reachable server predicts a device that struggles to stay online,
+// so connections slower than latency_warning are flagged even if they succeed
+fn join_connect_check(
+    server: &'static str,
+    check: Option<ConnectCheck>,
+    latency_warning: Duration,
+    latencies: &mut Vec<Latency>,
+) -> CheckResult {
+    let (host, port, handle) = if let Some(check) = check {
+        check
+    } else {
+        return CheckResult::Skipped;
+    };
+
+    match handle.join() {
+        Ok(Ok(latency)) => {
+            info!(
+                "connection to {}: {}:{} is ok, took {} ms",
+                server,
+                host,
+                port,
+                latency.as_millis()
+            );
+            latencies.push(Latency {
+                server,
+                latency,
+                slow: latency > latency_warning,
+            });
+            CheckResult::Ok
+        }
+        Ok(Err(why)) => CheckResult::Failed(
+            ErrorKind::Connectivity,
+            format!(
+                "failed to connect to {} server @ {}:{}, your device might not come online, error: {}",
+                server, host, port, why
+            ),
+        ),
+        Err(_) => CheckResult::Failed(
+            ErrorKind::Connectivity,
+            format!(
+                "The connection check for {} server @ {}:{} panicked",
+                server, host, port
+            ),
+        ),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct BalenaCfgJson {
     config: HashMap<String, Value>,
     file: PathBuf,
     modified: bool,
+    changes: Vec<ConfigChange>,
+    pretty: bool,
+    mode: u32,
+    owner: (u32, u32),
 }
 
 impl BalenaCfgJson {
+    // symlinks are followed, the config is read from and its path, permissions and
+    // owner are taken from the file the link points to
     pub fn new<P: AsRef<Path>>(cfg_file: P) -> Result<BalenaCfgJson> {
-        let cfg_file = cfg_file
-            .as_ref()
-            .canonicalize()
-            .upstream_with_context(&format!(
-                "Failed to canonicalize path: '{}'",
-                cfg_file.as_ref().display()
-            ))?;
+        let given = cfg_file.as_ref();
+        let cfg_file = match given.canonicalize() {
+            Ok(cfg_file) => cfg_file,
+            // a directory on the way could not be searched, eg. in a container, the file might still be readable
+            Err(why) if File::open(given).is_ok() => {
+                let cfg_file = absolute_path(given);
+                warn!(
+                    "Failed to canonicalize path '{}', using '{}', error: {}",
+                    given.display(),
+                    cfg_file.display(),
+                    why
+                );
+                cfg_file
+            }
+            Err(why) => {
+                return Err(why).upstream_with_context(&format!(
+                    "Failed to canonicalize path: '{}'",
+                    given.display()
+                ))
+            }
+        };
+
+        // config.json contains secrets, keep its permissions when writing it
+        let metadata = cfg_file.metadata().upstream_with_context(&format!(
+            "Failed to retrieve metadata for '{}'",
+            cfg_file.display()
+        ))?;
+
+        let cfg_txt = read_to_string(&cfg_file)
+            .upstream_with_context(&format!("new: cannot read file '{}'", cfg_file.display()))?;
 
         Ok(BalenaCfgJson {
-            config: serde_json::from_reader(BufReader::new(
-                File::open(&cfg_file).upstream_with_context(&format!(
-                    "new: cannot open file '{}'",
-                    cfg_file.display()
-                ))?,
-            ))
-            .upstream_with_context(&format!(
+            config: parse_config(&cfg_txt).upstream_with_context(&format!(
                 "Failed to parse json from file '{}'",
                 cfg_file.display()
             ))?,
             file: cfg_file,
             modified: false,
+            changes: Vec::new(),
+            pretty: false,
+            mode: metadata.mode() & 0o7777,
+            owner: (metadata.uid(), metadata.gid()),
         })
     }
 
-    pub fn write<P: AsRef<Path>>(&mut self, target_path: P) -> Result<()> {
+    // read config.json from stdin, it is marked modified so it gets written to a real file for stage2
+    pub fn from_stdin() -> Result<BalenaCfgJson> {
+        let mut cfg_txt = String::new();
+        io::stdin()
+            .read_to_string(&mut cfg_txt)
+            .upstream_with_context("Failed to read config.json from stdin")?;
+
+        Ok(BalenaCfgJson {
+            config: parse_config(&cfg_txt)
+                .upstream_with_context("Failed to parse json from stdin")?,
+            file: PathBuf::from(STDIN_CONFIG),
+            modified: true,
+            changes: Vec::new(),
+            pretty: false,
+            mode: DEFAULT_MODE,
+            owner: (getuid().as_raw(), getgid().as_raw()),
+        })
+    }
+
+    // an in-memory config for tests, get_path returns IN_MEMORY_CONFIG until it is written
+    #[cfg(test)]
+    pub fn from_map(config: HashMap<String, Value>) -> BalenaCfgJson {
+        BalenaCfgJson {
+            config,
+            file: PathBuf::from(IN_MEMORY_CONFIG),
+            modified: false,
+            changes: Vec::new(),
+            pretty: false,
+            mode: DEFAULT_MODE,
+            owner: (getuid().as_raw(), getgid().as_raw()),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn from_json(cfg_txt: &str) -> Result<BalenaCfgJson> {
+        Ok(BalenaCfgJson::from_map(parse_config(cfg_txt)?))
+    }
+
+    // write indented json instead of balena's compact format
+    pub fn set_pretty(&mut self, pretty: bool) {
+        self.pretty = pretty;
+    }
+
+    // returns the hex encoded SHA-256 of the bytes written, only the digest is logged.
+    // The file is truncated and written in place, never renamed over, so bind mounted
+    // files keep working. A symlink at target_path is followed and stays in place,
+    // afterwards get_path returns the path of the file that was written.
+    pub fn write<P: AsRef<Path>>(&mut self, target_path: P) -> Result<String> {
         let target_path = target_path.as_ref();
-        let out_file = OpenOptions::new()
+
+        let is_link = symlink_metadata(target_path)
+            .map(|metadata| metadata.file_type().is_symlink())
+            .unwrap_or(false);
+        if is_link {
+            debug!(
+                "Writing config.json through symlink '{}'",
+                target_path.display()
+            );
+        }
+
+        let mut cfg_bytes = if self.pretty {
+            serde_json::to_vec_pretty(&self.config)
+        } else {
+            serde_json::to_vec(&self.config)
+        }
+        .upstream_with_context("Failed to serialize config.json")?;
+        if self.pretty {
+            cfg_bytes.push(b'\n');
+        }
+        let digest: String = sha256(&cfg_bytes)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        let mut out_file = OpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
+            .mode(DEFAULT_MODE)
             .open(target_path)
             .upstream_with_context(&format!(
                 "Failed to open file for writing: '{}'",
                 target_path.display()
             ))?;
 
-        serde_json::to_writer(out_file, &self.config).upstream_with_context(&format!(
-            "Failed save modified config.json to '{}'",
-            target_path.display()
-        ))?;
+        // filesystems like vfat do not support ownership and permissions,
+        // new files are still created with DEFAULT_MODE
+        if let Err(why) = out_file.set_permissions(Permissions::from_mode(self.mode)) {
+            debug!(
+                "Failed to set permissions {:o} on '{}', error: {}",
+                self.mode,
+                target_path.display(),
+                why
+            );
+        }
+        if let Err(why) = chown(
+            target_path,
+            Some(Uid::from_raw(self.owner.0)),
+            Some(Gid::from_raw(self.owner.1)),
+        ) {
+            debug!(
+                "Failed to set owner {}:{} on '{}', error: {}",
+                self.owner.0,
+                self.owner.1,
+                target_path.display(),
+                why
+            );
+        }
+
+        out_file
+            .write_all(&cfg_bytes)
+            .upstream_with_context(&format!(
+                "Failed save modified config.json to '{}'",
+                target_path.display()
+            ))?;
+
+        info!(
+            "Wrote config.json to '{}', sha256: {}",
+            target_path.display(),
+            digest
+        );
 
         self.modified = false;
         self.file = target_path.canonicalize().upstream_with_context(&format!(
@@ -68,143 +816,709 @@ impl BalenaCfgJson {
             target_path.display()
         ))?;
 
+        Ok(digest)
+    }
+
+    // merge values from other into self, values from other take precedence
+    pub fn merge(&mut self, other: BalenaCfgJson) -> Result<()> {
+        let conflicts: Vec<String> = other
+            .config
+            .iter()
+            .filter_map(|(name, value)| {
+                if let Some(curr_value) = self.config.get(name) {
+                    if value_type(curr_value) != value_type(value) {
+                        return Some(format!(
+                            "'{}': {} in '{}', {} in '{}'",
+                            name,
+                            value_type(curr_value),
+                            self.file.display(),
+                            value_type(value),
+                            other.file.display()
+                        ));
+                    }
+                }
+                None
+            })
+            .collect();
+
+        if !conflicts.is_empty() {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Conflicting types found merging config files: {}",
+                    conflicts.join(", ")
+                ),
+            ));
+        }
+
+        // keys are merged literally, they are not paths
+        for (name, value) in other.config {
+            if self.config.get(&name) != Some(&value) {
+                self.modified = true;
+                let prev = self.config.insert(name.clone(), value.clone());
+                self.record_change(&name, prev, value);
+            }
+        }
         Ok(())
     }
 
-    pub fn check(&self, opts: &Options, device: &dyn Device) -> Result<()> {
-        info!("Configured for application id: {}", self.get_app_id()?);
+    // deep merge overlay into the config, returns the dotted paths of the changed values
+    pub fn merge_overlay(&mut self, overlay: Map<String, Value>) -> Vec<String> {
+        let mut changes: Vec<ConfigChange> = Vec::new();
+        for (name, value) in overlay {
+            if let Some(curr_value) = self.config.get_mut(&name) {
+                overlay_value(curr_value, value, &name, &mut changes);
+            } else {
+                changes.push(ConfigChange {
+                    key: name.clone(),
+                    old: None,
+                    new: value.clone(),
+                });
+                self.config.insert(name, value);
+            }
+        }
 
-        let device_type = self.get_device_type()?;
-        if !device.supports_device_type(device_type.as_str()) {
-            error!("The devicetype configured in config.json ({}) is not supported by the detected device type {:?}",
-                   device_type, device.get_device_type());
-            return Err(Error::displayed());
+        let keys = changes.iter().map(|change| change.key.clone()).collect();
+        for change in changes {
+            self.modified = true;
+            self.record_change(&change.key, change.old, change.new);
         }
+        keys
+    }
 
-        if opts.api_check() {
-            let api_endpoint = &self.get_api_endpoint()?;
+    // the top level keys that are not known, noting the known key an unknown key is a likely typo of
+    pub fn get_unknown_keys(&self) -> Vec<String> {
+        let mut unknown: Vec<String> = self
+            .config
+            .keys()
+            .filter(|key| !KNOWN_KEYS.contains(&key.as_str()))
+            .map(|key| {
+                let closest = KNOWN_KEYS
+                    .iter()
+                    .map(|known| (edit_distance(key, known), known))
+                    .min();
+                match closest {
+                    Some((distance, known)) if distance <= MAX_TYPO_DISTANCE => {
+                        format!("'{}' (did you mean '{}'?)", key, known)
+                    }
+                    _ => format!("'{}'", key),
+                }
+            })
+            .collect();
+        unknown.sort();
+        unknown
+    }
 
-            let api_url = Url::parse(&api_endpoint).upstream_with_context(&format!(
-                "Failed to parse balena api url '{}'",
-                api_endpoint
-            ))?;
+    // check that required values are present and values have the expected types
+    pub fn validate(&self) -> Result<()> {
+        let mut errors: Vec<String> = Vec::new();
 
-            if let Some(api_host) = api_url.host() {
-                let api_host = api_host.to_string();
-                let api_port = if let Some(api_port) = api_url.port() {
-                    api_port
-                } else {
-                    BALENA_API_PORT
-                };
+        for name in REQUIRED_KEYS.iter() {
+            if !self.config.contains_key(*name) {
+                errors.push(format!("'{}' is missing", name));
+            }
+        }
 
-                if let Ok(_v) = check_tcp_connect(&api_host, api_port, opts.check_timeout()) {
-                    info!("connection to api: {}:{} is ok", api_host, api_port);
-                } else {
-                    return Err(Error::with_context(
-                        ErrorKind::InvState,
-                        &format!(
-                        "failed to connect to api server @ {}:{} your device might not come online",
-                        api_endpoint, api_port
-                    ),
-                    ));
+        for name in STRING_KEYS.iter() {
+            if let Err(why) = self.get_str_val(name) {
+                if why.kind() != ErrorKind::NotFound {
+                    errors.push(format!("'{}' is not a string", name));
                 }
-            } else {
-                return Err(Error::with_context(
-                    ErrorKind::InvParam,
-                    &format!(
-                        "failed to parse api server url from config.json: {}",
-                        api_endpoint
-                    ),
+            }
+        }
+
+        for name in UINT_KEYS.iter() {
+            if let Err(why) = self.get_uint_val(name) {
+                if why.kind() != ErrorKind::NotFound {
+                    errors.push(format!("'{}' is not an unsigned integer", name));
+                }
+            }
+        }
+
+        for name in PORT_KEYS.iter() {
+            if let (Ok(value), Err(_)) = (self.get_uint_val(name), self.get_port_val(name)) {
+                errors.push(format!(
+                    "'{}' is {}, expected a port up to {}",
+                    name,
+                    value,
+                    u16::MAX
+                ));
+            }
+        }
+
+        if let Ok(0) = self.get_uint_val("applicationId") {
+            errors.push(
+                "'applicationId' is 0, expected the id of the fleet the device joins".to_string(),
+            );
+        }
+
+        if let Ok(device_type) = self.get_str_val("deviceType") {
+            if device_type.trim().is_empty() {
+                errors.push(EMPTY_DEVICE_TYPE.to_string());
+            } else if device_type.chars().all(|c| c.is_ascii_digit()) {
+                errors.push(format!(
+                    "'deviceType' is '{}', expected a device type slug like '{}', not a numeric id",
+                    device_type, DEV_TYPE_RPI4_64
+                ));
+            } else if !is_known_device_type(&device_type) {
+                errors.push(format!(
+                    "'deviceType' '{}' is not a device type supported by {}, expected the slug of a supported device like '{}'",
+                    device_type,
+                    env!("CARGO_PKG_NAME"),
+                    DEV_TYPE_RPI4_64
                 ));
             }
         }
 
-        if opts.vpn_check() {
-            let vpn_endpoint = self.get_vpn_endpoint()?;
-            let vpn_port = self.get_vpn_port()? as u16;
-            if let Ok(_v) = check_tcp_connect(&vpn_endpoint, vpn_port, opts.check_timeout()) {
-                // TODO: call a command on API instead of just connecting
-                info!("connection to vpn: {}:{} is ok", vpn_endpoint, vpn_port);
+        if let Some(network) = self.config.get(NETWORK_KEY) {
+            errors.extend(check_network(network));
+        }
+
+        match self.get_str_val("uuid") {
+            Ok(uuid) if !is_valid_uuid(&uuid) => errors.push(format!(
+                "'uuid' is '{}', expected 32 or 62 lowercase hex digits",
+                uuid
+            )),
+            Err(why) if why.kind() != ErrorKind::NotFound => {
+                errors.push("'uuid' is not a string".to_string())
+            }
+            _ => (),
+        }
+
+        for name in SECRET_KEYS.iter() {
+            if let Ok(key) = self.get_str_val(name) {
+                if let Some(error) = check_api_key(name, &key) {
+                    errors.push(error);
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("Invalid config.json: {}", errors.join(", ")),
+            ))
+        }
+    }
+
+    /******************************************************************
+     * Decide which checks run. A --no-*-check option disables its check,
+     * otherwise a boolean in the installer.checks section of config.json
+     * enables or disables it, eg. {"installer": {"checks": {"vpn": false}}}.
+     * Checks neither disables are enabled. The source that decided is
+     * logged for every check.
+     ******************************************************************/
+
+    pub fn get_enabled_checks(&self, opts: &Options) -> Result<EnabledChecks> {
+        let decide = |name: &str, key: &str, option: &str, enabled: bool| -> Result<bool> {
+            let path = format!("{}.{}", INSTALLER_CHECKS_KEY, key);
+            let (enabled, source) = if !enabled {
+                (false, format!("the option {}", option))
             } else {
+                match self.get_value(&path) {
+                    Some(Value::Bool(enabled)) => (*enabled, format!("'{}' in config.json", path)),
+                    Some(value) => {
+                        return Err(Error::with_context(
+                            ErrorKind::InvParam,
+                            &format!("'{}' in config.json is not a boolean: {}", path, value),
+                        ))
+                    }
+                    None => (true, "default".to_string()),
+                }
+            };
+            info!(
+                "The {} check is {}, decided by {}",
+                name,
+                if enabled { "enabled" } else { "disabled" },
+                source
+            );
+            Ok(enabled)
+        };
+
+        Ok(EnabledChecks {
+            device_type: decide(
+                "device type",
+                "deviceType",
+                "--no-device-type-check",
+                opts.device_type_check(),
+            )?,
+            api: decide("api", "api", "--no-api-check", opts.api_check())?,
+            vpn: decide("vpn", "vpn", "--no-vpn-check", opts.vpn_check())?,
+        })
+    }
+
+    // errors are returned for invalid settings only, the outcome of the checks is
+    // returned in ConfigCheck for the caller to report
+    pub fn check(
+        &self,
+        opts: &Options,
+        checks: &EnabledChecks,
+        device: &dyn Device,
+    ) -> Result<ConfigCheck> {
+        info!("Configured for application id: {}", self.get_app_id()?);
+
+        let device_type = self.get_device_type()?;
+        let device_type = if !checks.device_type {
+            CheckResult::Skipped
+        } else if device_type.trim().is_empty() {
+            CheckResult::Failed(ErrorKind::InvParam, EMPTY_DEVICE_TYPE.to_string())
+        } else if device.supports_device_type(device_type.as_str()) {
+            CheckResult::Ok
+        } else {
+            CheckResult::Failed(
+                ErrorKind::InvParam,
+                format!(
+                    "The devicetype configured in config.json ({}) is not supported by the detected device type {:?}",
+                    device_type,
+                    device.get_device_type()
+                ),
+            )
+        };
+
+        let api_fingerprint = match opts.expect_api_fingerprint() {
+            Some(_) if !checks.api => {
                 return Err(Error::with_context(
-                    ErrorKind::InvState,
-                    &format!(
-                        "failed to connect to vpn server @ {}:{} your device might not come online",
-                        vpn_endpoint, vpn_port
-                    ),
-                ));
+                    ErrorKind::InvParam,
+                    "--expect-api-fingerprint can not be checked with the api check disabled",
+                ))
+            }
+            Some(fingerprint) => Some(parse_fingerprint(fingerprint)?),
+            None => None,
+        };
+
+        let health = match opts.health_endpoint() {
+            Some(_) if !checks.api => {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    "--health-endpoint can not be checked with the api check disabled",
+                ))
+            }
+            Some(endpoint) => Some(parse_health_endpoint(endpoint)?),
+            None => None,
+        };
+
+        let api = if checks.api && health.is_none() {
+            Some(self.get_api_host_port()?)
+        } else {
+            None
+        };
+
+        let vpn = match (checks.vpn, self.get_vpn_settings()?) {
+            // TODO: call a command on API instead of just connecting
+            (true, Some(vpn_host_port)) => Ok(Some(vpn_host_port)),
+            (true, None) => Err(CheckResult::Failed(
+                ErrorKind::InvParam,
+                "config.json contains no vpnEndpoint and vpnPort, use --no-vpn-check to migrate without checking the VPN"
+                    .to_string(),
+            )),
+            (false, None) => {
+                info!("config.json contains no VPN settings");
+                Ok(None)
+            }
+            (false, Some(_)) => Ok(None),
+        };
+
+        // connect concurrently so a slow link costs one timeout, not one per server
+        let check_timeout = opts.check_timeout();
+        let (api_server, api_handle) = if let Some(url) = health {
+            info!(
+                "Checking the health endpoint '{}' instead of the apiEndpoint",
+                url
+            );
+            ("health", Some(start_health_check(url, check_timeout)))
+        } else {
+            (
+                "api",
+                api.map(|(host, port)| start_connect_check(host, port, check_timeout)),
+            )
+        };
+        let vpn_handle = match vpn {
+            Ok(vpn) => Ok(vpn.map(|(host, port)| start_connect_check(host, port, check_timeout))),
+            Err(result) => Err(result),
+        };
+
+        let latency_warning = opts.latency_warning();
+        let mut latencies = Vec::new();
+        let api = join_connect_check(api_server, api_handle, latency_warning, &mut latencies);
+        let vpn = match vpn_handle {
+            Ok(vpn_handle) => {
+                join_connect_check("vpn", vpn_handle, latency_warning, &mut latencies)
             }
+            Err(result) => result,
+        };
+
+        // a handshake with a server that can not be reached is pointless
+        let api_fingerprint = match (api_fingerprint, &api) {
+            (Some(expected), CheckResult::Ok) => {
+                self.check_api_fingerprint(&expected, check_timeout)
+            }
+            _ => CheckResult::Skipped,
+        };
+
+        Ok(ConfigCheck {
+            device_type,
+            api,
+            api_fingerprint,
+            vpn,
+            latencies,
+        })
+    }
+
+    // guards against a config.json for the wrong or a spoofed backend
+    fn check_api_fingerprint(&self, expected: &str, timeout: u64) -> CheckResult {
+        let api_endpoint = match self.get_api_endpoint() {
+            Ok(api_endpoint) => api_endpoint,
+            Err(why) => return CheckResult::Failed(why.kind(), why.to_string()),
+        };
+        if !api_endpoint.starts_with("https:") {
+            return CheckResult::Failed(
+                ErrorKind::InvParam,
+                format!(
+                    "--expect-api-fingerprint requires a https apiEndpoint, found '{}'",
+                    api_endpoint
+                ),
+            );
         }
 
-        Ok(())
+        let (api_host, api_port) = match self.get_api_host_port() {
+            Ok(host_port) => host_port,
+            Err(why) => return CheckResult::Failed(why.kind(), why.to_string()),
+        };
+        match get_tls_fingerprint(&api_host, api_port, timeout) {
+            Ok(found) if found == expected => {
+                info!(
+                    "The certificate of api server @ {}:{} matches the expected fingerprint",
+                    api_host, api_port
+                );
+                CheckResult::Ok
+            }
+            Ok(found) => CheckResult::Failed(
+                ErrorKind::Validation,
+                format!(
+                    "The certificate of api server @ {}:{} has the SHA-256 fingerprint {}, expected {}",
+                    api_host, api_port, found, expected
+                ),
+            ),
+            Err(why) => CheckResult::Failed(why.kind(), why.to_string()),
+        }
     }
 
     pub fn is_modified(&self) -> bool {
         self.modified
     }
 
-    fn get_str_val(&self, name: &str) -> Result<String> {
-        if let Some(value) = self.config.get(name) {
-            if let Some(value) = value.as_str() {
-                Ok(value.to_string())
+    // keep the original value of a key changed more than once, drop keys changed back to it
+    fn record_change(&mut self, key: &str, old: Option<Value>, new: Value) {
+        if let Some(change) = self.changes.iter_mut().find(|change| change.key == key) {
+            change.new = new;
+        } else {
+            self.changes.push(ConfigChange {
+                key: key.to_string(),
+                old,
+                new,
+            });
+        }
+        self.changes
+            .retain(|change| change.old.as_ref() != Some(&change.new));
+    }
+
+    pub fn changes(&self) -> &[ConfigChange] {
+        &self.changes
+    }
+
+    // log a diff style summary of all changes, secrets are redacted
+    pub fn log_changes(&self) {
+        if self.changes.is_empty() {
+            info!("No changes were made to config.json");
+            return;
+        }
+
+        info!("Changes made to config.json:");
+        for change in self.changes() {
+            let name = change.key.rsplit('.').next().unwrap_or(&change.key);
+            if let Some(old) = &change.old {
+                info!(
+                    "  ~ {}: {} -> {}",
+                    change.key,
+                    redacted(name, old),
+                    redacted(name, &change.new)
+                );
             } else {
-                Err(Error::with_context(
-                    ErrorKind::InvParam,
-                    &format!(
-                        "Invalid type encountered for '{}', expected String, found {:?} in config.json",
-                        name, value
-                    ),
-                ))
+                info!("  + {}: {}", change.key, redacted(name, &change.new));
             }
+        }
+    }
+
+    // look up a value by a dotted path like 'os.sshKeys.0', numeric components index arrays
+    fn lookup(&self, path: &str) -> Result<&Value> {
+        // keys containing dots are matched literally
+        if let Some(value) = self.config.get(path) {
+            return Ok(value);
+        }
+
+        let not_found = |found: &str, component: &str| {
+            Error::with_context(
+                ErrorKind::NotFound,
+                &format!(
+                    "Key could not be found in config.json: '{}', '{}' has no '{}'",
+                    path, found, component
+                ),
+            )
+        };
+
+        let mut components = path.split('.');
+        let first = components.next().unwrap_or(path);
+        let mut curr = if let Some(value) = self.config.get(first) {
+            value
         } else {
-            Err(Error::with_context(
+            return Err(Error::with_context(
                 ErrorKind::NotFound,
-                &format!("Key could not be found in config.json: '{}'", name),
+                &format!("Key could not be found in config.json: '{}'", path),
+            ));
+        };
+
+        let mut found = first.to_string();
+        for component in components {
+            curr = match curr {
+                Value::Object(map) => map.get(component),
+                Value::Array(array) => {
+                    if let Ok(index) = component.parse::<usize>() {
+                        array.get(index)
+                    } else {
+                        return Err(Error::with_context(
+                            ErrorKind::InvParam,
+                            &format!(
+                                "Invalid array index '{}' in '{}', '{}' is an array",
+                                component, path, found
+                            ),
+                        ));
+                    }
+                }
+                other => {
+                    return Err(Error::with_context(
+                        ErrorKind::InvParam,
+                        &format!(
+                            "Cannot look up '{}' in config.json, '{}' is a {}",
+                            path,
+                            found,
+                            value_type(other)
+                        ),
+                    ))
+                }
+            }
+            .ok_or_else(|| not_found(&found, component))?;
+            found.push('.');
+            found.push_str(component);
+        }
+
+        Ok(curr)
+    }
+
+    fn get_str_val(&self, name: &str) -> Result<String> {
+        let value = self.lookup(name)?;
+        if let Some(value) = value.as_str() {
+            Ok(value.to_string())
+        } else {
+            Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid type encountered for '{}', expected String, found {:?} in config.json",
+                    name, value
+                ),
             ))
         }
     }
 
     fn get_uint_val(&self, name: &str) -> Result<u64> {
-        if let Some(value) = self.config.get(name) {
-            if let Some(value) = value.as_u64() {
-                Ok(value)
-            } else if let Some(str_val) = value.as_str() {
-                Ok(str_val.parse::<u64>().upstream_with_context(&format!(
-                    "Failed to parse uint value for '{}' from config.json",
-                    name
-                ))?)
-            } else {
-                Err(Error::with_context(
-                    ErrorKind::InvParam,
-                    &format!(
-                        "Invalid type encountered for '{}', expected uint, found {:?}",
-                        name, value
-                    ),
-                ))
-            }
+        let value = self.lookup(name)?;
+        if let Some(value) = value.as_u64() {
+            Ok(value)
+        } else if let Some(str_val) = value.as_str() {
+            Ok(str_val.parse::<u64>().upstream_with_context(&format!(
+                "Failed to parse uint value for '{}' from config.json",
+                name
+            ))?)
         } else {
             Err(Error::with_context(
-                ErrorKind::NotFound,
-                &format!("Key could not be found in config.json: '{}'", name),
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid type encountered for '{}', expected uint, found {:?}",
+                    name, value
+                ),
             ))
         }
     }
 
+    // checked conversion, an out of range port must not wrap to a different one
+    fn get_port_val(&self, name: &str) -> Result<u16> {
+        let value = self.get_uint_val(name)?;
+        u16::try_from(value).map_err(|_| {
+            Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid port {} for '{}' in config.json, expected a value up to {}",
+                    value,
+                    name,
+                    u16::MAX
+                ),
+            )
+        })
+    }
+
+    // name can be a dotted path, see lookup
+    pub fn set_value(&mut self, name: &str, value: Value) -> Result<Option<Value>> {
+        if let Ok(curr_value) = self.lookup(name) {
+            if *curr_value == value {
+                return Ok(Some(value));
+            }
+        }
+
+        if !name.contains('.') || self.config.contains_key(name) {
+            self.modified = true;
+            let prev = self.config.insert(name.to_string(), value.clone());
+            self.record_change(name, prev.clone(), value);
+            return Ok(prev);
+        }
+
+        // work on a copy so a failure leaves no partially created objects behind
+        let components: Vec<&str> = name.split('.').collect();
+        let mut root = self
+            .config
+            .get(components[0])
+            .cloned()
+            .unwrap_or_else(|| Value::Object(Map::new()));
+        let prev = set_path(&mut root, &components[1..], value.clone(), name)?;
+        self.config.insert(components[0].to_string(), root);
+        self.modified = true;
+        self.record_change(name, prev.clone(), value);
+        Ok(prev)
+    }
+
     /*pub fn get_hostname(&self) -> Result<String, Error> {
         self.get_str_val("hostname")
     }*/
 
+    // removes a top level key, the change summary shows it as null
+    pub fn remove_value(&mut self, name: &str) -> Option<Value> {
+        let prev = self.config.remove(name)?;
+        self.modified = true;
+        self.record_change(name, Some(prev.clone()), Value::Null);
+        Some(prev)
+    }
+
+    pub fn get_uuid(&self) -> Result<Option<String>> {
+        self.get_optional_str_val("uuid")
+    }
+
     pub fn set_host_name(&mut self, hostname: &str) -> Option<String> {
         self.modified = true;
 
-        if let Some(value) = self
-            .config
-            .insert("hostname".to_string(), Value::String(hostname.to_string()))
+        let value = Value::String(hostname.to_string());
+        let prev = self.config.insert("hostname".to_string(), value.clone());
+        self.record_change("hostname", prev.clone(), value);
+        prev.map(|value| value.to_string())
+    }
+
+    pub fn get_value(&self, name: &str) -> Option<&Value> {
+        self.lookup(name).ok()
+    }
+
+    // replace the secrets of this config.json wherever they appear in text, e.g. a log
+    pub fn redact(&self, text: &str) -> String {
+        let psks = self.get_wifis();
+        SECRET_KEYS
+            .iter()
+            .filter_map(|name| self.config.get(*name).and_then(|value| value.as_str()))
+            .chain(psks.iter().filter_map(|(_, psk)| psk.as_deref()))
+            .filter(|secret| !secret.is_empty())
+            .fold(text.to_string(), |text, secret| {
+                text.replace(secret, REDACTED)
+            })
+    }
+
+    // the whole config as a JSON object, eg. to validate it against a schema
+    pub fn to_value(&self) -> Value {
+        Value::Object(
+            self.config
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+        )
+    }
+
+    // the whole config with its secrets hidden, eg. for a diagnostics report
+    pub fn to_redacted(&self) -> Value {
+        Value::Object(
+            self.config
+                .iter()
+                .map(|(name, value)| {
+                    let value = if is_secret(name) {
+                        Value::from(REDACTED)
+                    } else {
+                        redact_nested(value)
+                    };
+                    (name.clone(), value)
+                })
+                .collect(),
+        )
+    }
+
+    // (ssid, psk) of the wifi networks in the network section, invalid entries are skipped
+    pub fn get_wifis(&self) -> Vec<(String, Option<String>)> {
+        self.lookup(NETWORK_WIFI_KEY)
+            .ok()
+            .and_then(|wifis| wifis.as_array())
+            .map(|wifis| {
+                wifis
+                    .iter()
+                    .filter_map(|wifi| {
+                        let ssid = wifi.get("ssid")?.as_str()?.to_string();
+                        let psk = wifi
+                            .get("psk")
+                            .and_then(|psk| psk.as_str())
+                            .map(String::from);
+                        Some((ssid, psk))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // add a wifi network to the network section, returns false if the ssid is already configured
+    pub fn add_wifi(&mut self, ssid: &str, psk: Option<&str>) -> Result<bool> {
+        let mut wifis = match self.lookup(NETWORK_WIFI_KEY) {
+            Ok(Value::Array(wifis)) => wifis.clone(),
+            Ok(wifis) => {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "'{}' is {}, expected a list of wifi networks",
+                        NETWORK_WIFI_KEY,
+                        value_type(wifis)
+                    ),
+                ))
+            }
+            Err(why) if why.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(why) => return Err(why),
+        };
+
+        if wifis
+            .iter()
+            .any(|wifi| wifi.get("ssid").and_then(|value| value.as_str()) == Some(ssid))
         {
-            Some(value.to_string())
-        } else {
-            None
+            return Ok(false);
         }
+
+        let mut wifi = Map::new();
+        wifi.insert("ssid".to_string(), Value::from(ssid));
+        if let Some(psk) = psk {
+            wifi.insert("psk".to_string(), Value::from(psk));
+        }
+        wifis.push(Value::Object(wifi));
+        self.set_value(NETWORK_WIFI_KEY, Value::Array(wifis))?;
+        Ok(true)
     }
 
     pub fn get_app_id(&self) -> Result<u64> {
@@ -215,16 +1529,150 @@ impl BalenaCfgJson {
         self.get_str_val("apiKey")
     }
 
+    // not all configs contain a deviceApiKey
+    pub fn get_device_api_key(&self) -> Result<Option<String>> {
+        match self.get_str_val("deviceApiKey") {
+            Ok(device_api_key) => Ok(Some(device_api_key)),
+            Err(why) => {
+                if why.kind() == ErrorKind::NotFound {
+                    Ok(None)
+                } else {
+                    Err(why)
+                }
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn set_device_api_key(&mut self, device_api_key: &str) -> Option<String> {
+        self.modified = true;
+
+        let value = Value::String(device_api_key.to_string());
+        let prev = self
+            .config
+            .insert("deviceApiKey".to_string(), value.clone());
+        self.record_change("deviceApiKey", prev.clone(), value);
+        prev.map(|value| value.to_string())
+    }
+
     pub fn get_api_endpoint(&self) -> Result<String> {
         self.get_str_val("apiEndpoint")
     }
 
+    // host and port of the apiEndpoint, the port defaults to the one of the url scheme
+    pub fn get_api_host_port(&self) -> Result<(String, u16)> {
+        let api_endpoint = self.get_api_endpoint()?;
+
+        let api_url = Url::parse(&api_endpoint).upstream_with_context(&format!(
+            "Failed to parse balena api url '{}'",
+            api_endpoint
+        ))?;
+
+        if let Some(api_host) = api_url.host_str() {
+            Ok((
+                api_host.to_string(),
+                api_url.port_or_known_default().unwrap_or(BALENA_API_PORT),
+            ))
+        } else {
+            Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "failed to parse api server url from config.json: {}",
+                    api_endpoint
+                ),
+            ))
+        }
+    }
+
+    // reject endpoints that would only fail after the device rebooted into balena OS
+    pub fn set_api_endpoint(&mut self, url: &str) -> Result<Option<String>> {
+        match Url::parse(url) {
+            Ok(api_url) if api_url.scheme() == "http" || api_url.scheme() == "https" => (),
+            _ => {
+                return Err(Error::with_context(
+                    ErrorKind::InvParam,
+                    &format!(
+                        "Invalid apiEndpoint '{}', expected a http or https url",
+                        url
+                    ),
+                ))
+            }
+        }
+
+        self.modified = true;
+        let value = Value::String(url.to_string());
+        let prev = self.config.insert("apiEndpoint".to_string(), value.clone());
+        self.record_change("apiEndpoint", prev.clone(), value);
+        Ok(prev.map(|value| value.to_string()))
+    }
+
     fn get_vpn_endpoint(&self) -> Result<String> {
         self.get_str_val("vpnEndpoint")
     }
 
-    fn get_vpn_port(&self) -> Result<u64> {
-        self.get_uint_val("vpnPort")
+    fn get_vpn_port(&self) -> Result<u16> {
+        self.get_port_val("vpnPort")
+    }
+
+    // configs of fleets without VPN, eg. of public devices, contain no VPN settings
+    pub fn get_vpn_settings(&self) -> Result<Option<(String, u16)>> {
+        let vpn_endpoint = match self.get_vpn_endpoint() {
+            Ok(vpn_endpoint) => vpn_endpoint,
+            Err(why) if why.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(why) => return Err(why),
+        };
+        match self.get_vpn_port() {
+            Ok(vpn_port) => Ok(Some((vpn_endpoint, vpn_port))),
+            Err(why) if why.kind() == ErrorKind::NotFound => Ok(None),
+            Err(why) => Err(why),
+        }
+    }
+
+    fn get_optional_str_val(&self, name: &str) -> Result<Option<String>> {
+        match self.get_str_val(name) {
+            Ok(value) => Ok(Some(value)),
+            Err(why) if why.kind() == ErrorKind::NotFound => Ok(None),
+            Err(why) => Err(why),
+        }
+    }
+
+    pub fn get_delta_endpoint(&self) -> Result<Option<String>> {
+        self.get_optional_str_val("deltaEndpoint")
+    }
+
+    pub fn set_delta_endpoint(&mut self, url: &str) -> Result<Option<Value>> {
+        parse_http_url("delta endpoint", url)?;
+        self.set_value("deltaEndpoint", Value::from(url))
+    }
+
+    pub fn get_registry_endpoint(&self) -> Result<Option<String>> {
+        self.get_optional_str_val("registryEndpoint")
+    }
+
+    // the supervisor expects host[:port] in registryEndpoint, a url is reduced to that
+    pub fn set_registry_endpoint(&mut self, mirror: &str) -> Result<Option<Value>> {
+        let url = if mirror.contains("://") {
+            mirror.to_string()
+        } else {
+            format!("https://{}", mirror)
+        };
+        let parsed = parse_http_url("registry mirror", &url)?;
+        if parsed.path() != "/" || parsed.query().is_some() {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!(
+                    "Invalid registry mirror '{}', expected a host name with an optional port",
+                    mirror
+                ),
+            ));
+        }
+
+        let host = parsed.host_str().unwrap_or_default();
+        let endpoint = match parsed.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        self.set_value("registryEndpoint", Value::from(endpoint))
     }
 
     pub fn get_device_type(&self) -> Result<String> {
@@ -235,3 +1683,420 @@ impl BalenaCfgJson {
         &self.file
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::env::temp_dir;
+    use std::fs::remove_file;
+
+    #[test]
+    fn test_parse_config() {
+        let config = parse_config("\u{feff}{\"uuid\": \"1234\"}\n\0\0").unwrap();
+        assert_eq!(config.get("uuid"), Some(&Value::from("1234")));
+
+        let err = parse_config("{\n  \"uuid\": 1234,\n  }").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvParam);
+        assert!(err.to_string().contains("byte offset"));
+    }
+
+    #[test]
+    fn test_get_unknown_keys() {
+        let config = BalenaCfgJson::from_json(
+            r#"{
+                "applicationId": 1234,
+                "deviceType": "raspberrypi3",
+                "hostname": "my-device",
+                "persistentLogging": true,
+                "country": "DE",
+                "ntpServers": "0.pool.ntp.org 1.pool.ntp.org",
+                "dnsServers": "8.8.8.8 1.1.1.1",
+                "randomMacAddressScan": false,
+                "connectivity": {"uri": "https://api.balena-cloud.com/connectivity-check"},
+                "os": {"sshKeys": []},
+                "installer": {"secureboot": false},
+                "developmentMode": false,
+                "ntpServer": "pool.ntp.org",
+                "customSetting": "value"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.get_unknown_keys(),
+            vec![
+                "'customSetting'",
+                "'ntpServer' (did you mean 'ntpServers'?)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_value_paths() {
+        let mut config =
+            BalenaCfgJson::from_json(r#"{"os": {"sshKeys": ["key0"]}, "vpnPort": 443}"#).unwrap();
+
+        assert_eq!(config.get_str_val("os.sshKeys.0").unwrap(), "key0");
+        assert_eq!(
+            config.get_str_val("os.sshKeys.1").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            config.get_uint_val("vpnPort.0").unwrap_err().kind(),
+            ErrorKind::InvParam
+        );
+
+        config
+            .set_value("os.udevRules.56", Value::from("rule"))
+            .unwrap();
+        assert_eq!(config.get_str_val("os.udevRules.56").unwrap(), "rule");
+        assert!(config.set_value("vpnPort.port", Value::from(1)).is_err());
+        assert_eq!(config.get_uint_val("vpnPort").unwrap(), 443);
+        assert_eq!(config.get_vpn_settings().unwrap(), None);
+
+        config.set_value("vpnPort", Value::from(1194)).unwrap();
+        config.set_value("vpnPort", Value::from(443)).unwrap();
+        assert_eq!(
+            config.changes(),
+            &[ConfigChange {
+                key: "os.udevRules.56".to_string(),
+                old: None,
+                new: Value::from("rule"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_merge_overlay() {
+        let mut config =
+            BalenaCfgJson::from_json(r#"{"os": {"sshKeys": ["key0"], "network": {"wifi": true}}}"#)
+                .unwrap();
+
+        let overlay = serde_json::from_str::<Value>(
+            r#"{"os": {"sshKeys": ["key1"], "network": {"wifi": true, "dns": "1.1.1.1"}}, "hostname": "dev"}"#,
+        )
+        .unwrap();
+        let mut keys = config.merge_overlay(overlay.as_object().unwrap().clone());
+        keys.sort();
+        assert_eq!(keys, vec!["hostname", "os.network.dns", "os.sshKeys"]);
+        assert!(config.modified);
+        assert_eq!(config.get_str_val("os.sshKeys.0").unwrap(), "key1");
+        assert_eq!(
+            config.get_value("os.network.wifi"),
+            Some(&Value::from(true))
+        );
+    }
+
+    #[test]
+    fn test_api_host_port() {
+        let mut config = BalenaCfgJson::from_map(HashMap::new());
+
+        config
+            .set_api_endpoint("https://api.balena-cloud.com")
+            .unwrap();
+        assert_eq!(
+            config.get_api_host_port().unwrap(),
+            ("api.balena-cloud.com".to_string(), 443)
+        );
+        config.set_api_endpoint("http://10.0.0.1:8080/").unwrap();
+        assert_eq!(
+            config.get_api_host_port().unwrap(),
+            ("10.0.0.1".to_string(), 8080)
+        );
+    }
+
+    #[test]
+    fn test_set_endpoints() {
+        let mut config = BalenaCfgJson::new("test_data/config.json").unwrap();
+        assert_eq!(config.get_delta_endpoint().unwrap(), None);
+        assert_eq!(config.get_registry_endpoint().unwrap(), None);
+
+        config
+            .set_delta_endpoint("https://delta.mirror.local")
+            .unwrap();
+        assert_eq!(
+            config.get_delta_endpoint().unwrap().as_deref(),
+            Some("https://delta.mirror.local")
+        );
+        assert!(config.set_delta_endpoint("delta.mirror.local").is_err());
+
+        config
+            .set_registry_endpoint("registry.mirror.local")
+            .unwrap();
+        assert_eq!(
+            config.get_registry_endpoint().unwrap().as_deref(),
+            Some("registry.mirror.local")
+        );
+        config
+            .set_registry_endpoint("http://10.0.0.1:5000/")
+            .unwrap();
+        assert_eq!(
+            config.get_registry_endpoint().unwrap().as_deref(),
+            Some("10.0.0.1:5000")
+        );
+        assert!(config
+            .set_registry_endpoint("https://registry.mirror.local/v2/")
+            .is_err());
+        assert!(config
+            .set_registry_endpoint("ftp://registry.local")
+            .is_err());
+        assert!(config.is_modified());
+    }
+
+    #[test]
+    fn test_parse_health_endpoint() {
+        let url = parse_health_endpoint("https://balena.local:8443/ping").unwrap();
+        assert_eq!(url.host_str(), Some("balena.local"));
+        assert_eq!(url.port_or_known_default(), Some(8443));
+        assert_eq!(url.path(), "/ping");
+
+        assert!(parse_health_endpoint("balena.local:8443").is_err());
+        assert!(parse_health_endpoint("ftp://balena.local/ping").is_err());
+    }
+
+    #[test]
+    fn test_redact() {
+        let config =
+            BalenaCfgJson::from_json(r#"{"apiKey": "secret0", "deviceApiKey": ""}"#).unwrap();
+
+        assert_eq!(
+            config.redact("apiKey: secret0, again secret0\n"),
+            "apiKey: <redacted>, again <redacted>\n"
+        );
+    }
+
+    #[test]
+    fn test_to_redacted() {
+        let config = BalenaCfgJson::from_json(r#"{"apiKey": "secret0", "applicationId": 1234, "network": {"wifi": [{"ssid": "home", "psk": "secret1"}]}}"#).unwrap();
+
+        assert_eq!(
+            config.to_redacted(),
+            json!({
+                "apiKey": "<redacted>",
+                "applicationId": 1234,
+                "network": {"wifi": [{"ssid": "home", "psk": "<redacted>"}]}
+            })
+        );
+    }
+
+    #[test]
+    fn test_check_api_key() {
+        assert!(check_api_key("apiKey", "Ab3dEf6hIj9lMn2pQr5tUv8xYz1bCd4f").is_none());
+        assert!(check_api_key("apiKey", "").is_some());
+        assert!(check_api_key("apiKey", "<your api key>").is_some());
+        assert!(check_api_key("deviceApiKey", "Ab3dEf6h").is_some());
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let config = BalenaCfgJson::from_json(
+            r#"{"applicationId": 1, "aplicationId": 1, "deviceType": "raspberrypi4-64", "myCustomKey": true}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.get_unknown_keys(),
+            vec![
+                "'aplicationId' (did you mean 'applicationId'?)".to_string(),
+                "'myCustomKey'".to_string()
+            ]
+        );
+        assert!(BalenaCfgJson::from_json(r#"{"uuid": "1234", "os": {}}"#)
+            .unwrap()
+            .get_unknown_keys()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_enabled_checks() {
+        use structopt::StructOpt;
+
+        let config = BalenaCfgJson::from_json(
+            r#"{"installer": {"checks": {"vpn": false, "deviceType": true}}}"#,
+        )
+        .unwrap();
+        let opts = Options::from_iter(&["takeover"]);
+        assert_eq!(
+            config.get_enabled_checks(&opts).unwrap(),
+            EnabledChecks {
+                device_type: true,
+                api: true,
+                vpn: false
+            }
+        );
+        let opts = Options::from_iter(&["takeover", "--no-device-type-check"]);
+        assert!(!config.get_enabled_checks(&opts).unwrap().device_type);
+
+        let config =
+            BalenaCfgJson::from_json(r#"{"installer": {"checks": {"api": "no"}}}"#).unwrap();
+        let err = config.get_enabled_checks(&opts).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvParam);
+    }
+
+    #[test]
+    fn test_validate_app_and_device_type() {
+        let mut config =
+            BalenaCfgJson::from_json(r#"{"applicationId": 0, "deviceType": "1234"}"#).unwrap();
+        let why = config.validate().unwrap_err().to_string();
+        assert!(why.contains("'applicationId' is 0"));
+        assert!(why.contains("not a numeric id"));
+
+        config.config = parse_config(r#"{"applicationId": 1, "deviceType": "nuc"}"#).unwrap();
+        assert!(config.validate().is_err());
+
+        config.config = parse_config(r#"{"applicationId": 1, "deviceType": " "}"#).unwrap();
+        let why = config.validate().unwrap_err().to_string();
+        assert!(why.contains("'deviceType' is empty"));
+        assert!(!why.contains("not a device type supported"));
+
+        config.config =
+            parse_config(r#"{"applicationId": 1, "deviceType": "raspberrypi3"}"#).unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_network_section() {
+        let mut config = BalenaCfgJson::from_json(r#"{"applicationId": 1, "deviceType": "raspberrypi3", "network": {"wifi": [{"ssid": "home", "psk": "short"}, {"psk": "long enough"}]}}"#).unwrap();
+        let why = config.validate().unwrap_err().to_string();
+        assert!(why.contains("'network.wifi.0.psk' is not a passphrase"));
+        assert!(why.contains("'network.wifi.1.ssid' is missing"));
+        assert!(!why.contains("short"));
+
+        config.config.remove(NETWORK_KEY);
+        assert!(config.add_wifi("home", Some("secret passphrase")).unwrap());
+        assert!(!config.add_wifi("home", None).unwrap());
+        assert!(config.add_wifi("guest", None).unwrap());
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.get_wifis(),
+            vec![
+                ("home".to_string(), Some("secret passphrase".to_string())),
+                ("guest".to_string(), None)
+            ]
+        );
+
+        let change = config.changes().last().unwrap();
+        assert_eq!(change.key, NETWORK_WIFI_KEY);
+        assert!(!redacted("wifi", &change.new).contains("secret"));
+        assert_eq!(config.redact("psk=secret passphrase"), "psk=<redacted>");
+    }
+
+    #[test]
+    fn test_port_overflow() {
+        let mut config = BalenaCfgJson::from_json(r#"{"applicationId": 1, "deviceType": "raspberrypi3", "vpnEndpoint": "vpn.balena-cloud.com", "vpnPort": 65535}"#).unwrap();
+        assert_eq!(
+            config.get_vpn_settings().unwrap(),
+            Some(("vpn.balena-cloud.com".to_string(), 65535))
+        );
+        assert!(config.validate().is_ok());
+
+        // 65979 wraps to 443 when truncated
+        for value in &[
+            Value::from(65979),
+            Value::from("65536"),
+            Value::from(u64::MAX),
+        ] {
+            config.config.insert("vpnPort".to_string(), value.clone());
+            assert_eq!(
+                config.get_port_val("vpnPort").unwrap_err().kind(),
+                ErrorKind::InvParam
+            );
+            assert!(config.get_vpn_settings().is_err());
+            assert!(config
+                .validate()
+                .unwrap_err()
+                .to_string()
+                .contains("expected a port up to 65535"));
+        }
+    }
+
+    #[test]
+    fn test_config_check_failures() {
+        let mut check = ConfigCheck {
+            device_type: CheckResult::Ok,
+            api: CheckResult::Ok,
+            api_fingerprint: CheckResult::Skipped,
+            vpn: CheckResult::Skipped,
+            latencies: vec![Latency {
+                server: "api",
+                latency: Duration::from_millis(1500),
+                slow: true,
+            }],
+        };
+        assert!(check.failures().is_empty());
+        assert_eq!(check.slow()[0].server, "api");
+        assert_eq!(check.error_kind(), None);
+
+        check.api = CheckResult::Failed(ErrorKind::Connectivity, "no route".to_string());
+        check.vpn = CheckResult::Failed(ErrorKind::InvParam, "no vpnEndpoint".to_string());
+        assert_eq!(
+            check.failures(),
+            vec![("api", "no route"), ("vpn", "no vpnEndpoint")]
+        );
+        assert_eq!(check.error_kind(), Some(ErrorKind::Connectivity));
+    }
+
+    #[test]
+    fn test_absolute_path() {
+        assert_eq!(
+            absolute_path(Path::new("/boot/config.json")),
+            PathBuf::from("/boot/config.json")
+        );
+        assert_eq!(
+            absolute_path(Path::new("../config.json")),
+            current_dir().unwrap().join("../config.json")
+        );
+    }
+
+    #[test]
+    fn test_symlinked_config() {
+        use std::fs::{create_dir_all, read_link, remove_dir_all, write};
+        use std::os::unix::fs::symlink;
+
+        let dir = temp_dir().join(format!("takeover-config-test-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let target = dir.join("target.json");
+        let link = dir.join("config.json");
+        write(&target, r#"{"applicationId": 1}"#).unwrap();
+        symlink("target.json", &link).unwrap();
+
+        // read through the link, the path is the one of its target
+        let mut config = BalenaCfgJson::new(&link).unwrap();
+        assert_eq!(config.get_app_id().unwrap(), 1);
+        assert_eq!(config.get_path(), target.as_path());
+
+        // written through the link
+        config
+            .config
+            .insert("applicationId".to_string(), Value::from(2));
+        config.write(&link).unwrap();
+        assert_eq!(read_link(&link).unwrap(), PathBuf::from("target.json"));
+        assert_eq!(
+            BalenaCfgJson::new(&target).unwrap().get_app_id().unwrap(),
+            2
+        );
+        assert_eq!(config.get_path(), target.as_path());
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_in_memory_config() {
+        let mut config = BalenaCfgJson::from_json(r#"{"applicationId": 1}"#).unwrap();
+        assert_eq!(config.get_path(), Path::new(IN_MEMORY_CONFIG));
+        assert!(!config.is_modified());
+        config.set_host_name("dev");
+        assert!(config.is_modified());
+
+        // only ever written to the path given
+        let target = temp_dir().join(format!("takeover-memory-test-{}.json", std::process::id()));
+        config.write(&target).unwrap();
+        assert_eq!(config.get_path(), target.as_path());
+        let written = BalenaCfgJson::new(&target).unwrap();
+        assert_eq!(written.get_app_id().unwrap(), 1);
+        assert_eq!(written.get_value("hostname"), Some(&Value::from("dev")));
+        assert!(!Path::new(IN_MEMORY_CONFIG).exists());
+
+        remove_file(&target).unwrap();
+    }
+}
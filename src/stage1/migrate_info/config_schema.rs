@@ -0,0 +1,352 @@
+use regex::Regex;
+use serde_json::{Map, Value};
+
+use crate::common::{Error, ErrorKind, Result};
+
+// keywords that do not constrain the value
+const ANNOTATION_KEYWORDS: [&str; 8] = [
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "definitions",
+];
+
+fn invalid_schema(path: &str, reason: &str) -> Error {
+    Error::with_context(
+        ErrorKind::InvParam,
+        &format!("Invalid schema at '{}': {}", path, reason),
+    )
+}
+
+fn type_matches(type_name: &str, value: &Value) -> bool {
+    match type_name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => false,
+    }
+}
+
+fn get_f64(schema: &Map<String, Value>, keyword: &str, schema_path: &str) -> Result<f64> {
+    schema
+        .get(keyword)
+        .and_then(Value::as_f64)
+        .ok_or_else(|| invalid_schema(schema_path, &format!("'{}' is not a number", keyword)))
+}
+
+fn get_usize(schema: &Map<String, Value>, keyword: &str, schema_path: &str) -> Result<usize> {
+    schema
+        .get(keyword)
+        .and_then(Value::as_u64)
+        .map(|value| value as usize)
+        .ok_or_else(|| {
+            invalid_schema(
+                schema_path,
+                &format!("'{}' is not a non-negative integer", keyword),
+            )
+        })
+}
+
+fn get_schemas<'a>(
+    schema: &'a Map<String, Value>,
+    keyword: &str,
+    schema_path: &str,
+) -> Result<&'a Vec<Value>> {
+    match schema.get(keyword) {
+        Some(Value::Array(schemas)) if !schemas.is_empty() => Ok(schemas),
+        _ => Err(invalid_schema(
+            schema_path,
+            &format!("'{}' is not a non-empty array of schemas", keyword),
+        )),
+    }
+}
+
+// a key of an object as a JSON path component
+fn key_path(path: &str, key: &str) -> String {
+    if key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        format!("{}.{}", path, key)
+    } else {
+        format!("{}[{:?}]", path, key)
+    }
+}
+
+// the violations of value, the values themselves are not reported as they might be secrets
+fn check_value(
+    schema: &Value,
+    schema_path: &str,
+    value: &Value,
+    path: &str,
+    violations: &mut Vec<String>,
+) -> Result<()> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => {
+            violations.push(format!("{}: no value is allowed", path));
+            return Ok(());
+        }
+        Value::Object(schema) => schema,
+        _ => return Err(invalid_schema(schema_path, "not an object or a boolean")),
+    };
+
+    for (keyword, arg) in schema {
+        let keyword_path = format!("{}/{}", schema_path, keyword);
+        match keyword.as_str() {
+            "type" => {
+                let types: Vec<&str> = match arg {
+                    Value::String(type_name) => vec![type_name.as_str()],
+                    Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                    _ => return Err(invalid_schema(&keyword_path, "not a string or an array")),
+                };
+                if !types.iter().any(|type_name| type_matches(type_name, value)) {
+                    violations.push(format!("{}: expected type {}", path, types.join(" or ")));
+                }
+            }
+            "enum" => {
+                let allowed = arg
+                    .as_array()
+                    .ok_or_else(|| invalid_schema(&keyword_path, "not an array"))?;
+                if !allowed.contains(value) {
+                    violations.push(format!("{}: not one of the allowed values", path));
+                }
+            }
+            "const" => {
+                if arg != value {
+                    violations.push(format!("{}: not the required value", path));
+                }
+            }
+            "minimum" | "maximum" | "exclusiveMinimum" | "exclusiveMaximum" => {
+                let limit = get_f64(schema, keyword, schema_path)?;
+                if let Some(number) = value.as_f64() {
+                    let (fails, relation) = match keyword.as_str() {
+                        "minimum" => (number < limit, "at least"),
+                        "maximum" => (number > limit, "at most"),
+                        "exclusiveMinimum" => (number <= limit, "more than"),
+                        _ => (number >= limit, "less than"),
+                    };
+                    if fails {
+                        violations.push(format!("{}: expected {} {}", path, relation, limit));
+                    }
+                }
+            }
+            "minLength" | "maxLength" => {
+                let limit = get_usize(schema, keyword, schema_path)?;
+                if let Some(string) = value.as_str() {
+                    let len = string.chars().count();
+                    if keyword == "minLength" && len < limit {
+                        violations.push(format!("{}: shorter than {} characters", path, limit));
+                    } else if keyword == "maxLength" && len > limit {
+                        violations.push(format!("{}: longer than {} characters", path, limit));
+                    }
+                }
+            }
+            "pattern" => {
+                let pattern = arg
+                    .as_str()
+                    .ok_or_else(|| invalid_schema(&keyword_path, "not a string"))?;
+                let regex = Regex::new(pattern).map_err(|why| {
+                    invalid_schema(&keyword_path, &format!("invalid pattern: {}", why))
+                })?;
+                if let Some(string) = value.as_str() {
+                    if !regex.is_match(string) {
+                        violations.push(format!("{}: does not match '{}'", path, pattern));
+                    }
+                }
+            }
+            "required" => {
+                let required = arg
+                    .as_array()
+                    .ok_or_else(|| invalid_schema(&keyword_path, "not an array"))?;
+                if let Value::Object(object) = value {
+                    for key in required.iter().filter_map(Value::as_str) {
+                        if !object.contains_key(key) {
+                            violations.push(format!("{}: missing", key_path(path, key)));
+                        }
+                    }
+                }
+            }
+            "properties" => {
+                let properties = arg
+                    .as_object()
+                    .ok_or_else(|| invalid_schema(&keyword_path, "not an object"))?;
+                if let Value::Object(object) = value {
+                    for (key, property_schema) in properties {
+                        if let Some(property) = object.get(key) {
+                            check_value(
+                                property_schema,
+                                &format!("{}/{}", keyword_path, key),
+                                property,
+                                &key_path(path, key),
+                                violations,
+                            )?;
+                        }
+                    }
+                }
+            }
+            "additionalProperties" => {
+                let properties = schema.get("properties").and_then(Value::as_object);
+                if let Value::Object(object) = value {
+                    for (key, property) in object {
+                        if properties.is_some_and(|properties| properties.contains_key(key)) {
+                            continue;
+                        }
+                        check_value(
+                            arg,
+                            &keyword_path,
+                            property,
+                            &key_path(path, key),
+                            violations,
+                        )?;
+                    }
+                }
+            }
+            "items" => {
+                if let Value::Array(items) = value {
+                    for (idx, item) in items.iter().enumerate() {
+                        check_value(
+                            arg,
+                            &keyword_path,
+                            item,
+                            &format!("{}[{}]", path, idx),
+                            violations,
+                        )?;
+                    }
+                }
+            }
+            "minItems" | "maxItems" => {
+                let limit = get_usize(schema, keyword, schema_path)?;
+                if let Some(items) = value.as_array() {
+                    if keyword == "minItems" && items.len() < limit {
+                        violations.push(format!("{}: fewer than {} items", path, limit));
+                    } else if keyword == "maxItems" && items.len() > limit {
+                        violations.push(format!("{}: more than {} items", path, limit));
+                    }
+                }
+            }
+            "allOf" => {
+                for (idx, sub_schema) in get_schemas(schema, keyword, schema_path)?
+                    .iter()
+                    .enumerate()
+                {
+                    check_value(
+                        sub_schema,
+                        &format!("{}/{}", keyword_path, idx),
+                        value,
+                        path,
+                        violations,
+                    )?;
+                }
+            }
+            "anyOf" | "oneOf" | "not" => {
+                let sub_schemas = if keyword == "not" {
+                    vec![arg.clone()]
+                } else {
+                    get_schemas(schema, keyword, schema_path)?.clone()
+                };
+                let mut matching = 0;
+                for (idx, sub_schema) in sub_schemas.iter().enumerate() {
+                    let mut sub_violations = Vec::new();
+                    check_value(
+                        sub_schema,
+                        &format!("{}/{}", keyword_path, idx),
+                        value,
+                        path,
+                        &mut sub_violations,
+                    )?;
+                    if sub_violations.is_empty() {
+                        matching += 1;
+                    }
+                }
+                match keyword.as_str() {
+                    "anyOf" if matching == 0 => {
+                        violations.push(format!("{}: matches none of the anyOf schemas", path))
+                    }
+                    "oneOf" if matching != 1 => violations.push(format!(
+                        "{}: matches {} of the oneOf schemas, expected exactly one",
+                        path, matching
+                    )),
+                    "not" if matching > 0 => {
+                        violations.push(format!("{}: matches the schema of not", path))
+                    }
+                    _ => (),
+                }
+            }
+            keyword if ANNOTATION_KEYWORDS.contains(&keyword) => (),
+            // an ignored keyword would silently not enforce a policy
+            keyword => {
+                return Err(invalid_schema(
+                    schema_path,
+                    &format!("the keyword '{}' is not supported", keyword),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/******************************************************************
+ * Validate config.json against a JSON Schema, returning every
+ * violation with the JSON path of the offending value, eg.
+ * "$.network.wifi[0].ssid: missing". The validation keywords for
+ * types, values, strings, numbers, objects, arrays and combining
+ * schemas are supported, a schema using other keywords, eg. $ref,
+ * is rejected rather than partially enforced.
+ ******************************************************************/
+
+pub(crate) fn validate_schema(schema: &Value, config: &Value) -> Result<Vec<String>> {
+    let mut violations = Vec::new();
+    check_value(schema, "#", config, "$", &mut violations)?;
+    Ok(violations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate_schema() {
+        let schema = json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "type": "object",
+            "required": ["applicationId", "fleetOwner"],
+            "properties": {
+                "applicationId": {"type": "integer", "minimum": 1},
+                "hostname": {"type": "string", "pattern": "^site-[0-9]+$"},
+                "network": {
+                    "properties": {
+                        "wifi": {"items": {"required": ["ssid"]}, "maxItems": 1}
+                    }
+                }
+            }
+        });
+        let config = json!({
+            "applicationId": 0,
+            "hostname": "site-12",
+            "network": {"wifi": [{"psk": "secret"}, {"ssid": "b"}]}
+        });
+        assert_eq!(
+            validate_schema(&schema, &config).unwrap(),
+            vec![
+                "$.applicationId: expected at least 1",
+                "$.network.wifi[0].ssid: missing",
+                "$.network.wifi: more than 1 items",
+                "$.fleetOwner: missing",
+            ]
+        );
+
+        assert!(
+            validate_schema(&json!({"$ref": "#/definitions/a"}), &config)
+                .unwrap_err()
+                .to_string()
+                .contains("'$ref' is not supported")
+        );
+    }
+}
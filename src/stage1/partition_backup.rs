@@ -0,0 +1,323 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use log::{error, info, warn};
+
+use crate::common::{
+    disk_util::{Disk, LabelType, PartitionIterator, DEF_BLOCK_SIZE},
+    format_size_with_unit, Error, ErrorKind, Result, ToError,
+};
+
+// the text header is padded to a fixed size so the sectors can be restored with dd skip=
+const HEADER_SIZE: usize = 8 * DEF_BLOCK_SIZE;
+// MBR, GPT header and 128 partition entries
+const PRIMARY_SECTORS: u64 = 34;
+// backup GPT entries and header at the end of the device
+const GPT_BACKUP_SECTORS: u64 = 33;
+// the disk GUID in the GPT header at LBA 1
+const GPT_DISK_GUID_OFFSET: usize = DEF_BLOCK_SIZE + 56;
+
+fn read_sectors(device: &mut File, device_path: &Path, lba: u64, count: u64) -> Result<Vec<u8>> {
+    let mut buffer = vec![0u8; (count as usize) * DEF_BLOCK_SIZE];
+    device
+        .seek(SeekFrom::Start(lba * DEF_BLOCK_SIZE as u64))
+        .and_then(|_| device.read_exact(&mut buffer))
+        .upstream_with_context(&format!(
+            "Failed to read {} sectors at LBA {} from '{}'",
+            count,
+            lba,
+            device_path.display()
+        ))?;
+    Ok(buffer)
+}
+
+fn format_guid(guid: &[u8]) -> String {
+    format!(
+        "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+        guid[3], guid[2], guid[1], guid[0], guid[5], guid[4], guid[7], guid[6],
+        guid[8], guid[9], guid[10], guid[11], guid[12], guid[13], guid[14], guid[15]
+    )
+}
+
+// the device a backup was taken from as described by its header
+#[derive(Debug, Clone, PartialEq)]
+struct BackupDevice {
+    device: PathBuf,
+    num_sectors: u64,
+    label: String,
+    disk_id: String,
+}
+
+impl BackupDevice {
+    fn describe(&self) -> String {
+        format!(
+            "'{}', {} sectors, {} label, disk identifier {}",
+            self.device.display(),
+            self.num_sectors,
+            self.label,
+            self.disk_id
+        )
+    }
+}
+
+fn parse_backup_header(header: &str) -> Option<BackupDevice> {
+    if !header.starts_with("# takeover partition table backup\n") {
+        return None;
+    }
+    let value = |name: &str| {
+        header
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("# {}: ", name)))
+            .map(|value| value.trim().to_string())
+    };
+
+    Some(BackupDevice {
+        device: PathBuf::from(value("device")?),
+        num_sectors: value("size")?
+            .split(", ")
+            .nth(1)?
+            .split(' ')
+            .next()?
+            .parse()
+            .ok()?,
+        label: value("label")?,
+        disk_id: value("disk identifier")?,
+    })
+}
+
+fn read_backup_device(backup_path: &Path) -> Result<Option<BackupDevice>> {
+    let mut header = vec![0u8; HEADER_SIZE];
+    let read = File::open(backup_path)
+        .and_then(|mut file| file.read(&mut header))
+        .upstream_with_context(&format!(
+            "Failed to read partition table backup '{}'",
+            backup_path.display()
+        ))?;
+    Ok(parse_backup_header(&String::from_utf8_lossy(
+        &header[..read],
+    )))
+}
+
+// a backup of another device or of the same device in another state must not be replaced,
+// it might be the only way to restore the device it was taken from
+fn check_backup_target(previous: &BackupDevice, target: &BackupDevice) -> Result<()> {
+    let reason = if target.num_sectors < previous.num_sectors {
+        Some(
+            "the target is smaller than the backed up device, the backup can not be restored to it",
+        )
+    } else if target.device != previous.device || target.num_sectors != previous.num_sectors {
+        Some("the backup was taken from a different device")
+    } else if target.label != previous.label || target.disk_id != previous.disk_id {
+        Some("the partition table of the target changed since the backup was taken")
+    } else {
+        None
+    };
+
+    if let Some(reason) = reason {
+        error!(
+            "The existing partition table backup does not match the target device, {}",
+            reason
+        );
+        error!("  backed up device: {}", previous.describe());
+        error!("  target device:    {}", target.describe());
+        error!("Move the existing backup away or flash the device it was taken from");
+        Err(Error::displayed_with(ErrorKind::InvParam))
+    } else {
+        Ok(())
+    }
+}
+
+/******************************************************************
+ * Save the partition table of device to backup_path: a text header
+ * padded to HEADER_SIZE describing the device, followed by the
+ * first PRIMARY_SECTORS sectors and for GPT the last
+ * GPT_BACKUP_SECTORS sectors of the device. An existing backup is
+ * only replaced if it was taken from the same, unchanged device.
+ ******************************************************************/
+
+pub(crate) fn backup_partition_table(device_path: &Path, backup_path: &Path) -> Result<()> {
+    let mut device = File::open(device_path)
+        .upstream_with_context(&format!("Failed to open '{}'", device_path.display()))?;
+    let device_size = device
+        .seek(SeekFrom::End(0))
+        .upstream_with_context(&format!(
+            "Failed to determine the size of '{}'",
+            device_path.display()
+        ))?;
+    let num_sectors = device_size / DEF_BLOCK_SIZE as u64;
+
+    if num_sectors < PRIMARY_SECTORS + GPT_BACKUP_SECTORS {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "The device '{}' is too small to hold a partition table: {} sectors",
+                device_path.display(),
+                num_sectors
+            ),
+        ));
+    }
+
+    let primary = read_sectors(&mut device, device_path, 0, PRIMARY_SECTORS)?;
+
+    let mut disk = Disk::from_drive_file(device_path, None)?;
+    let (label, disk_id, gpt_backup) = match disk.get_label()? {
+        LabelType::GPT => {
+            let backup_lba = num_sectors - GPT_BACKUP_SECTORS;
+            (
+                "gpt",
+                format_guid(&primary[GPT_DISK_GUID_OFFSET..GPT_DISK_GUID_OFFSET + 16]),
+                Some((
+                    backup_lba,
+                    read_sectors(&mut device, device_path, backup_lba, GPT_BACKUP_SECTORS)?,
+                )),
+            )
+        }
+        LabelType::Dos => {
+            let disk_id = if let Some(disk_id) = PartitionIterator::new(&mut disk)?.get_disk_id() {
+                format!("0x{:08x}", disk_id)
+            } else {
+                "none".to_string()
+            };
+            ("dos", disk_id, None)
+        }
+        LabelType::Other => ("none", "none".to_string(), None),
+    };
+
+    let target = BackupDevice {
+        device: device_path.to_path_buf(),
+        num_sectors,
+        label: label.to_string(),
+        disk_id: disk_id.clone(),
+    };
+    if backup_path.exists() {
+        match read_backup_device(backup_path)? {
+            Some(previous) => {
+                check_backup_target(&previous, &target)?;
+                info!(
+                    "Replacing the partition table backup '{}' of the same device",
+                    backup_path.display()
+                );
+            }
+            None => warn!(
+                "Replacing '{}', it is not a partition table backup",
+                backup_path.display()
+            ),
+        }
+    }
+
+    let mut header = format!(
+        "# takeover partition table backup\n\
+         # device: {}\n\
+         # size: {} bytes, {} sectors of {} bytes ({})\n\
+         # label: {}\n\
+         # disk identifier: {}\n\
+         # header: {} bytes\n\
+         # restore: dd if=<this file> of={} bs={} skip={} count={} conv=notrunc\n",
+        device_path.display(),
+        device_size,
+        num_sectors,
+        DEF_BLOCK_SIZE,
+        format_size_with_unit(device_size),
+        label,
+        disk_id,
+        HEADER_SIZE,
+        device_path.display(),
+        DEF_BLOCK_SIZE,
+        HEADER_SIZE / DEF_BLOCK_SIZE,
+        PRIMARY_SECTORS,
+    );
+    if let Some((backup_lba, _)) = &gpt_backup {
+        header.push_str(&format!(
+            "# restore gpt backup: dd if=<this file> of={} bs={} skip={} seek={} count={} conv=notrunc\n",
+            device_path.display(),
+            DEF_BLOCK_SIZE,
+            HEADER_SIZE as u64 / DEF_BLOCK_SIZE as u64 + PRIMARY_SECTORS,
+            backup_lba,
+            GPT_BACKUP_SECTORS
+        ));
+    }
+
+    let mut header = header.into_bytes();
+    if header.len() > HEADER_SIZE {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            "The partition table backup header exceeds its size",
+        ));
+    }
+    header.resize(HEADER_SIZE, b'\n');
+
+    let mut backup_file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(backup_path)
+        .upstream_with_context(&format!(
+            "Failed to create partition table backup '{}'",
+            backup_path.display()
+        ))?;
+
+    backup_file
+        .write_all(&header)
+        .and_then(|_| backup_file.write_all(&primary))
+        .and_then(|_| {
+            if let Some((_, sectors)) = &gpt_backup {
+                backup_file.write_all(sectors)
+            } else {
+                Ok(())
+            }
+        })
+        .and_then(|_| backup_file.sync_all())
+        .upstream_with_context(&format!(
+            "Failed to write partition table backup '{}'",
+            backup_path.display()
+        ))?;
+
+    info!(
+        "Saved the {} partition table of '{}' to '{}'",
+        label,
+        device_path.display(),
+        backup_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_backup_target() {
+        let header = "# takeover partition table backup\n\
+                      # device: /dev/sda\n\
+                      # size: 32017047552 bytes, 62533296 sectors of 512 bytes (29.82 GiB)\n\
+                      # label: dos\n\
+                      # disk identifier: 0x5a3e4c21\n\
+                      # header: 4096 bytes\n";
+        let previous = parse_backup_header(header).unwrap();
+        assert_eq!(
+            previous,
+            BackupDevice {
+                device: PathBuf::from("/dev/sda"),
+                num_sectors: 62533296,
+                label: "dos".to_string(),
+                disk_id: "0x5a3e4c21".to_string(),
+            }
+        );
+        assert!(parse_backup_header("# some other file\n").is_none());
+
+        assert!(check_backup_target(&previous, &previous).is_ok());
+
+        let mut target = previous.clone();
+        target.num_sectors -= 1;
+        assert!(check_backup_target(&previous, &target).is_err());
+
+        let mut target = previous.clone();
+        target.device = PathBuf::from("/dev/sdb");
+        assert!(check_backup_target(&previous, &target).is_err());
+
+        let mut target = previous.clone();
+        target.disk_id = "0x00000000".to_string();
+        assert!(check_backup_target(&previous, &target).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{read_to_string, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use log::{error, info};
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    common::{
+        disk_util::get_device_size, options::Options, sha256_file, Error, ErrorKind, Result,
+        ToError,
+    },
+    stage1::{
+        block_device_info::BlockDeviceInfo,
+        defs::DeviceType,
+        get_flash_dev,
+        migrate_info::{
+            balena_cfg_json::{holds_secret, EnabledChecks},
+            MigrateInfo,
+        },
+    },
+};
+
+const PLAN_OUT_OPTION: &str = "--plan-out";
+const ENV_PREFIX: &str = "TAKEOVER_";
+// the plan options are not part of the plan
+const PLAN_ENV_VARS: [&str; 2] = ["TAKEOVER_PLAN_OUT", "TAKEOVER_PLAN_IN"];
+
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PlanTarget {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PlanImage {
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/******************************************************************
+ * Everything stage1 resolved for a migration, written by
+ * --plan-out for review and executed by --plan-in. The plan holds
+ * the command line and TAKEOVER_* variables it was made with, so
+ * executing it runs stage1 with the same options and refuses to
+ * continue unless the outcome matches the plan.
+ ******************************************************************/
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MigrationPlan {
+    pub takeover_version: String,
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub device_type: DeviceType,
+    pub target: PlanTarget,
+    pub image: PlanImage,
+    pub checks: EnabledChecks,
+    pub keep_data_partition: bool,
+    pub preserve_labels: Vec<String>,
+    pub flash_partitions: Vec<String>,
+    // for review, secrets are redacted, config_sha256 identifies the config.json that is flashed
+    pub config: Value,
+    pub config_sha256: String,
+}
+
+// the command line without the plan options
+fn get_plan_args(args: impl Iterator<Item = String>) -> Vec<String> {
+    let mut plan_args = Vec::new();
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        if arg == PLAN_OUT_OPTION {
+            let _value = args.next();
+        } else if !arg.starts_with(&format!("{}=", PLAN_OUT_OPTION)) {
+            plan_args.push(arg);
+        }
+    }
+    plan_args
+}
+
+fn get_plan_env() -> BTreeMap<String, String> {
+    env::vars()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX) && !PLAN_ENV_VARS.contains(&name.as_str()))
+        .collect()
+}
+
+// the flash device as prepare resolves it, an image file given with --flash-to is not attached
+fn get_target(opts: &Options, mig_info: &MigrateInfo) -> Result<PlanTarget> {
+    let path = match opts.flash_to() {
+        Some(flash_to) if flash_to.is_file() => flash_to.clone(),
+        flash_to => {
+            let block_dev_info = BlockDeviceInfo::new(None, opts.source_root_device().as_deref())?;
+            get_flash_dev(flash_to.as_deref(), mig_info, &block_dev_info)?.get_dev_path()
+        }
+    };
+
+    Ok(PlanTarget {
+        size: get_device_size(&path)?,
+        path,
+    })
+}
+
+/******************************************************************
+ * The plan holds the options it is executed with, so they can not
+ * be redacted. Refuse to write secrets given with --set or
+ * --config-overlay to it, they are better kept in the config.json
+ * given with --config.
+ ******************************************************************/
+
+fn check_no_secrets(opts: &Options) -> Result<()> {
+    let mut secrets: Vec<String> = Vec::new();
+    for config_override in opts.config_overrides() {
+        let mut parts = config_override.splitn(2, '=');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            let value = serde_json::from_str::<Value>(value)
+                .unwrap_or_else(|_| Value::String(value.to_string()));
+            if holds_secret(name, &value) {
+                secrets.push(format!("--set {}", name));
+            }
+        }
+    }
+    for overlay in opts.config_overlays() {
+        // an overlay that can not be parsed was refused before
+        if let Ok(overlay) = serde_json::from_str::<Value>(overlay) {
+            if holds_secret("", &overlay) {
+                secrets.push("--config-overlay".to_string());
+            }
+        }
+    }
+
+    if secrets.is_empty() {
+        Ok(())
+    } else {
+        error!(
+            "The migration plan would hold the secrets given with {}, please put them in the config.json given with --config",
+            secrets.join(", ")
+        );
+        Err(Error::displayed_with(ErrorKind::InvParam))
+    }
+}
+
+impl MigrationPlan {
+    pub fn new(opts: &Options, mig_info: &MigrateInfo) -> Result<MigrationPlan> {
+        check_no_secrets(opts)?;
+        let config = mig_info.balena_cfg();
+        let config_txt = serde_json::to_vec(&config.to_value())
+            .upstream_with_context("Failed to serialize config.json")?;
+        let image_path = mig_info.image_path();
+        let image_size = image_path
+            .metadata()
+            .upstream_with_context(&format!(
+                "Failed to read metadata of image '{}'",
+                image_path.display()
+            ))?
+            .len();
+
+        Ok(MigrationPlan {
+            takeover_version: env!("CARGO_PKG_VERSION").to_string(),
+            args: get_plan_args(env::args()),
+            env: get_plan_env(),
+            device_type: mig_info.device_type(),
+            target: get_target(opts, mig_info)?,
+            image: PlanImage {
+                path: image_path.to_path_buf(),
+                size: image_size,
+                sha256: to_hex(&sha256_file(image_path)?),
+            },
+            checks: config.get_enabled_checks(opts)?,
+            keep_data_partition: opts.keep_data_partition(),
+            preserve_labels: opts.preserve_labels().to_vec(),
+            flash_partitions: opts.flash_partitions().to_vec(),
+            config: config.to_redacted(),
+            config_sha256: to_hex(&sha256(&config_txt)),
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<MigrationPlan> {
+        let plan_txt = read_to_string(path).upstream_with_context(&format!(
+            "Failed to read migration plan '{}'",
+            path.display()
+        ))?;
+        serde_json::from_str(&plan_txt).upstream_with_context(&format!(
+            "Failed to parse migration plan '{}'",
+            path.display()
+        ))
+    }
+
+    // the command line of the plan might hold secrets, eg. in --set
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let plan_txt = serde_json::to_string_pretty(self)
+            .upstream_with_context("Failed to serialize migration plan")?;
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", plan_txt).and_then(|_| file.sync_all()))
+            .upstream_with_context(&format!(
+                "Failed to write migration plan '{}'",
+                path.display()
+            ))
+    }
+
+    // the options the plan was made with, the TAKEOVER_* variables are replaced by those of the plan
+    pub fn options(&self) -> Result<Options> {
+        let current: Vec<String> = env::vars()
+            .map(|(name, _)| name)
+            .filter(|name| name.starts_with(ENV_PREFIX))
+            .collect();
+        for name in current {
+            env::remove_var(name);
+        }
+        for (name, value) in &self.env {
+            env::set_var(name, value);
+        }
+
+        let opts = Options::from_plan_args(&self.args).map_err(|why| {
+            Error::with_context(
+                ErrorKind::InvParam,
+                &format!("Invalid options in the migration plan: {}", why.message),
+            )
+        })?;
+        if opts.plan_in().is_some() || opts.plan_out().is_some() {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                "The options of the migration plan can not contain --plan-in or --plan-out",
+            ));
+        }
+        Ok(opts)
+    }
+
+    /******************************************************************
+     * Compare what stage1 resolved now with the plan and fail unless
+     * they match, listing every difference. The config.json values
+     * are not logged, only whether the config.json differs.
+     ******************************************************************/
+
+    pub fn check(&self, opts: &Options, mig_info: &MigrateInfo) -> Result<()> {
+        let current = MigrationPlan::new(opts, mig_info)?;
+        let mut mismatches: Vec<String> = Vec::new();
+
+        if current.takeover_version != self.takeover_version {
+            mismatches.push(format!(
+                "takeover version is {}, planned {}",
+                current.takeover_version, self.takeover_version
+            ));
+        }
+        if current.device_type != self.device_type {
+            mismatches.push(format!(
+                "device type is {}, planned {}",
+                current.device_type, self.device_type
+            ));
+        }
+        if current.target != self.target {
+            mismatches.push(format!(
+                "flash device is '{}' of {} bytes, planned '{}' of {} bytes",
+                current.target.path.display(),
+                current.target.size,
+                self.target.path.display(),
+                self.target.size
+            ));
+        }
+        if current.image.sha256 != self.image.sha256 || current.image.size != self.image.size {
+            mismatches.push(format!(
+                "image '{}' has SHA-256 {}, planned {}",
+                current.image.path.display(),
+                current.image.sha256,
+                self.image.sha256
+            ));
+        }
+        if current.config_sha256 != self.config_sha256 {
+            mismatches.push("config.json differs from the planned one".to_string());
+        }
+        if current.checks != self.checks {
+            mismatches.push(format!(
+                "checks enabled are {:?}, planned {:?}",
+                current.checks, self.checks
+            ));
+        }
+
+        if mismatches.is_empty() {
+            info!(
+                "The device matches the migration plan, migrating '{}' with image '{}'",
+                self.target.path.display(),
+                current.image.path.display()
+            );
+            Ok(())
+        } else {
+            for mismatch in &mismatches {
+                error!("The device does not match the migration plan: {}", mismatch);
+            }
+            Err(Error::displayed_with(ErrorKind::Validation))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_plan_args() {
+        let args = [
+            "takeover",
+            "--plan-out",
+            "plan.json",
+            "-c",
+            "config.json",
+            "--plan-out=x",
+        ];
+        assert_eq!(
+            get_plan_args(args.iter().map(|arg| arg.to_string())),
+            vec!["takeover", "-c", "config.json"]
+        );
+    }
+
+    #[test]
+    fn test_check_no_secrets() {
+        let opts = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            Options::from_plan_args(&args).unwrap()
+        };
+        assert!(check_no_secrets(&opts(&["takeover", "--set", "hostname=dev"])).is_ok());
+        assert!(check_no_secrets(&opts(&["takeover", "--set", "apiKey=secret"])).is_err());
+        assert!(
+            check_no_secrets(&opts(&["takeover", "--set", "network.wifi.0.psk=secret"])).is_err()
+        );
+        assert!(check_no_secrets(&opts(&[
+            "takeover",
+            "--set",
+            r#"network={"wifi":[{"ssid":"home","psk":"secret"}]}"#
+        ]))
+        .is_err());
+        assert!(check_no_secrets(&opts(&[
+            "takeover",
+            "--config-overlay",
+            r#"{"deviceApiKey":"secret"}"#
+        ]))
+        .is_err());
+    }
+}
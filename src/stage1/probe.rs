@@ -0,0 +1,468 @@
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::{
+    common::{
+        progress::{emit, ProgressEvent},
+        Error, ErrorKind, Options, Result, ToError,
+    },
+    stage1::migrate_info::{apply_endpoint_options, balena_cfg_json::BalenaCfgJson, MigrateInfo},
+};
+
+const HTTPS_PORT: u16 = 443;
+
+/******************************************************************
+ * An endpoint of config.json and how to probe it. http is the url
+ * to request and whether it has to return a success status, for
+ * endpoints without a ping url any status below 500 shows that the
+ * service answers.
+ ******************************************************************/
+
+#[derive(Debug, PartialEq)]
+struct Endpoint {
+    name: &'static str,
+    host: String,
+    port: u16,
+    tls: bool,
+    http: Option<(String, bool)>,
+}
+
+enum Step {
+    Passed(Duration, String),
+    Failed(String),
+    Skipped,
+    NotApplicable,
+}
+
+impl Step {
+    fn cell(&self) -> String {
+        match self {
+            Step::Passed(elapsed, detail) if detail.is_empty() => {
+                format!("{}ms", elapsed.as_millis())
+            }
+            Step::Passed(elapsed, detail) => format!("{}ms {}", elapsed.as_millis(), detail),
+            Step::Failed(_) => "FAIL".to_string(),
+            Step::Skipped => "skipped".to_string(),
+            Step::NotApplicable => "-".to_string(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            Step::Passed(elapsed, detail) => json!({
+                "result": "passed",
+                "ms": elapsed.as_millis() as u64,
+                "detail": detail,
+            }),
+            Step::Failed(why) => json!({ "result": "failed", "error": why }),
+            Step::Skipped => json!({ "result": "skipped" }),
+            Step::NotApplicable => json!({ "result": "not-applicable" }),
+        }
+    }
+}
+
+pub(crate) struct ProbeResult {
+    endpoint: Endpoint,
+    addresses: Vec<IpAddr>,
+    steps: [(&'static str, Step); 4],
+}
+
+impl ProbeResult {
+    fn failures(&self) -> Vec<(&'static str, &str)> {
+        self.steps
+            .iter()
+            .filter_map(|(name, step)| match step {
+                Step::Failed(why) => Some((*name, why.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "endpoint": self.endpoint.name,
+            "host": self.endpoint.host,
+            "port": self.endpoint.port,
+            "addresses": self
+                .addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect::<Vec<_>>(),
+            "steps": self
+                .steps
+                .iter()
+                .map(|(name, step)| (name.to_string(), step.to_json()))
+                .collect::<serde_json::Map<String, Value>>(),
+        })
+    }
+}
+
+fn url_endpoint(
+    name: &'static str,
+    url: &str,
+    path: &str,
+    require_success: bool,
+) -> Result<Endpoint> {
+    let parsed = Url::parse(url)
+        .upstream_with_context(&format!("Failed to parse the {} url '{}'", name, url))?;
+    let host = parsed.host_str().ok_or_else(|| {
+        Error::with_context(
+            ErrorKind::InvParam,
+            &format!("The {} url '{}' has no host", name, url),
+        )
+    })?;
+    Ok(Endpoint {
+        name,
+        host: host.to_string(),
+        port: parsed.port_or_known_default().unwrap_or(HTTPS_PORT),
+        tls: parsed.scheme() == "https",
+        http: Some((
+            format!("{}{}", url.trim_end_matches('/'), path),
+            require_success,
+        )),
+    })
+}
+
+// the endpoints config.json names, the registry is usually given as a bare host name
+fn get_endpoints(config: &BalenaCfgJson) -> Result<Vec<Endpoint>> {
+    let mut endpoints = vec![url_endpoint(
+        "api",
+        &config.get_api_endpoint()?,
+        "/ping",
+        true,
+    )?];
+
+    if let Some((host, port)) = config.get_vpn_settings()? {
+        endpoints.push(Endpoint {
+            name: "vpn",
+            host,
+            port,
+            tls: false,
+            http: None,
+        });
+    }
+
+    if let Some(delta_endpoint) = config.get_delta_endpoint()? {
+        endpoints.push(url_endpoint("delta", &delta_endpoint, "", false)?);
+    }
+
+    if let Some(registry_endpoint) = config.get_registry_endpoint()? {
+        let registry_url = if registry_endpoint.contains("://") {
+            registry_endpoint
+        } else {
+            format!("https://{}", registry_endpoint)
+        };
+        endpoints.push(url_endpoint("registry", &registry_url, "/v2/", false)?);
+    }
+
+    Ok(endpoints)
+}
+
+fn http_status(url: &str, require_success: bool, timeout: Duration) -> Step {
+    use reqwest::blocking::Client;
+
+    let client = match Client::builder().timeout(timeout).build() {
+        Ok(client) => client,
+        Err(why) => return Step::Failed(format!("failed to create http client: {}", why)),
+    };
+    let started = Instant::now();
+    match client.get(url).send() {
+        Ok(response) => {
+            let status = response.status();
+            if status.is_server_error() || (require_success && !status.is_success()) {
+                Step::Failed(format!("'{}' returned status {}", url, status))
+            } else {
+                Step::Passed(started.elapsed(), status.as_u16().to_string())
+            }
+        }
+        Err(why) => Step::Failed(format!("request to '{}' failed: {}", url, why)),
+    }
+}
+
+/******************************************************************
+ * Probe one endpoint step by step: resolve it, connect to the first
+ * address, do a TLS handshake and request its http url. Steps after
+ * a failed one are skipped. The handshake does not verify the
+ * certificate, the http request does.
+ ******************************************************************/
+
+fn probe_endpoint(endpoint: Endpoint, timeout: Duration) -> ProbeResult {
+    use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+
+    let mut dns = Step::Skipped;
+    let mut tcp = Step::Skipped;
+    let mut tls = if endpoint.tls {
+        Step::Skipped
+    } else {
+        Step::NotApplicable
+    };
+    let mut http = if endpoint.http.is_some() {
+        Step::Skipped
+    } else {
+        Step::NotApplicable
+    };
+    let mut addresses: Vec<IpAddr> = Vec::new();
+
+    let started = Instant::now();
+    let sock_addrs = match (endpoint.host.as_str(), endpoint.port).to_socket_addrs() {
+        Ok(sock_addrs) => sock_addrs.collect::<Vec<_>>(),
+        Err(why) => {
+            dns = Step::Failed(format!("failed to resolve '{}': {}", endpoint.host, why));
+            Vec::new()
+        }
+    };
+    for sock_addr in &sock_addrs {
+        if !addresses.contains(&sock_addr.ip()) {
+            addresses.push(sock_addr.ip());
+        }
+    }
+
+    if let Some(sock_addr) = sock_addrs.first() {
+        dns = Step::Passed(started.elapsed(), String::new());
+
+        let started = Instant::now();
+        match TcpStream::connect_timeout(sock_addr, timeout) {
+            Ok(tcp_stream) => {
+                tcp = Step::Passed(started.elapsed(), String::new());
+                if endpoint.tls {
+                    let started = Instant::now();
+                    let res = tcp_stream
+                        .set_read_timeout(Some(timeout))
+                        .and_then(|_| tcp_stream.set_write_timeout(Some(timeout)))
+                        .upstream_with_context("failed to set socket timeouts")
+                        .and_then(|_| {
+                            SslConnector::builder(SslMethod::tls())
+                                .upstream_with_context("failed to create TLS connector")
+                        })
+                        .and_then(|mut builder| {
+                            builder.set_verify(SslVerifyMode::NONE);
+                            builder
+                                .build()
+                                .connect(&endpoint.host, tcp_stream)
+                                .map_err(|why| {
+                                    Error::with_context(
+                                        ErrorKind::Connectivity,
+                                        &format!("TLS handshake failed: {}", why),
+                                    )
+                                })
+                        });
+                    tls = match res {
+                        Ok(stream) => {
+                            Step::Passed(started.elapsed(), stream.ssl().version_str().to_string())
+                        }
+                        Err(why) => Step::Failed(why.to_string()),
+                    };
+                }
+            }
+            Err(why) => {
+                tcp = Step::Failed(format!("failed to connect to {}: {}", sock_addr, why));
+            }
+        }
+    } else if matches!(dns, Step::Skipped) {
+        dns = Step::Failed(format!("no addresses found for '{}'", endpoint.host));
+    }
+
+    let connected = matches!(tcp, Step::Passed(..)) && !matches!(tls, Step::Failed(_));
+    if let (true, Some((url, require_success))) = (connected, &endpoint.http) {
+        http = http_status(url, *require_success, timeout);
+    }
+
+    ProbeResult {
+        endpoint,
+        addresses,
+        steps: [("dns", dns), ("tcp", tcp), ("tls", tls), ("http", http)],
+    }
+}
+
+fn print_table(results: &[ProbeResult]) {
+    let header = [
+        "ENDPOINT",
+        "HOST:PORT",
+        "DNS",
+        "TCP",
+        "TLS",
+        "HTTP",
+        "ADDRESSES",
+    ];
+    let mut rows: Vec<Vec<String>> = vec![header.iter().map(|col| col.to_string()).collect()];
+    for result in results {
+        let mut row = vec![
+            result.endpoint.name.to_string(),
+            format!("{}:{}", result.endpoint.host, result.endpoint.port),
+        ];
+        row.extend(result.steps.iter().map(|(_, step)| step.cell()));
+        row.push(
+            result
+                .addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+        rows.push(row);
+    }
+
+    let mut widths = vec![0; header.len()];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    for row in &rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+// probe the endpoints of config one after the other, emitting a check event for each
+pub(crate) fn probe_endpoints(
+    config: &BalenaCfgJson,
+    timeout: Duration,
+) -> Result<Vec<ProbeResult>> {
+    let endpoints = get_endpoints(config)?;
+
+    info!("Probing {} endpoints of config.json", endpoints.len());
+    let mut results = Vec::new();
+    for endpoint in endpoints {
+        let name = endpoint.name.to_string();
+        emit(ProgressEvent::CheckStarted(name.clone()));
+        let result = probe_endpoint(endpoint, timeout);
+        let failures = result.failures();
+        if failures.is_empty() {
+            emit(ProgressEvent::CheckPassed(name));
+        } else {
+            emit(ProgressEvent::CheckFailed {
+                name,
+                error: failures
+                    .iter()
+                    .map(|(step, why)| format!("{}: {}", step, why))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            });
+        }
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/******************************************************************
+ * Probe all endpoints of the given config.json and print a table
+ * of the results. Nothing is modified, so this runs without root
+ * and without a migration.
+ ******************************************************************/
+
+pub(crate) fn probe(opts: &Options) -> Result<()> {
+    let config_path = if let Some(config_path) = opts.config().first() {
+        config_path
+    } else {
+        error!("--probe-only needs a config.json given with --config");
+        return Err(Error::displayed_with(ErrorKind::InvParam));
+    };
+    let mut config = MigrateInfo::read_cfg_json(config_path)?;
+    // probe the endpoints the migrated device will use
+    apply_endpoint_options(&mut config, opts)?;
+    let results = match probe_endpoints(&config, Duration::from_secs(opts.check_timeout())) {
+        Ok(results) => results,
+        Err(why) => {
+            error!("Failed to read the endpoints from config.json: {}", why);
+            return Err(Error::displayed_with(why.kind()));
+        }
+    };
+
+    print_table(&results);
+
+    let mut failed = 0;
+    for result in &results {
+        let failures = result.failures();
+        if !failures.is_empty() {
+            failed += 1;
+        }
+        for (step, why) in failures {
+            error!(
+                "probe {:<10} {:<5} FAIL: {}",
+                result.endpoint.name, step, why
+            );
+        }
+    }
+
+    if failed == 0 {
+        info!("All {} endpoints are reachable", results.len());
+        Ok(())
+    } else {
+        error!(
+            "Probe failed: {} of {} endpoints are not reachable",
+            failed,
+            results.len()
+        );
+        Err(Error::displayed_with(ErrorKind::Connectivity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_get_endpoints() {
+        let mut config = BalenaCfgJson::new("test_data/config.json").unwrap();
+        let endpoints = get_endpoints(&config).unwrap();
+        assert_eq!(
+            endpoints,
+            vec![
+                Endpoint {
+                    name: "api",
+                    host: "api.balena-cloud.com".to_string(),
+                    port: 443,
+                    tls: true,
+                    http: Some(("https://api.balena-cloud.com/ping".to_string(), true)),
+                },
+                Endpoint {
+                    name: "vpn",
+                    host: "vpn.balena-cloud.com".to_string(),
+                    port: 443,
+                    tls: false,
+                    http: None,
+                },
+            ]
+        );
+
+        config
+            .set_value("deltaEndpoint", json!("http://delta.local:8080/"))
+            .unwrap();
+        config
+            .set_value("registryEndpoint", json!("registry2.balena-cloud.com"))
+            .unwrap();
+        let endpoints = get_endpoints(&config).unwrap();
+        assert_eq!(
+            endpoints[2],
+            Endpoint {
+                name: "delta",
+                host: "delta.local".to_string(),
+                port: 8080,
+                tls: false,
+                http: Some(("http://delta.local:8080".to_string(), false)),
+            }
+        );
+        assert_eq!(
+            endpoints[3],
+            Endpoint {
+                name: "registry",
+                host: "registry2.balena-cloud.com".to_string(),
+                port: 443,
+                tls: true,
+                http: Some(("https://registry2.balena-cloud.com/v2/".to_string(), false)),
+            }
+        );
+    }
+}
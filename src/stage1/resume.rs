@@ -0,0 +1,189 @@
+use std::fs::{read_dir, read_to_string, write};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    common::{
+        defs::TAKEOVER_DIR, dir_exists, file_exists, path_append, sha256_file, Options, Result,
+        ToError,
+    },
+    stage1::cleanup::cleanup_staged,
+};
+
+// written to the work dir once the image was fetched, read by --resume
+const RESUME_STATE_NAME: &str = "takeover-resume.json";
+
+/******************************************************************
+ * What a previous run fetched: the image sources it was given and
+ * the SHA-256 of the image it ended up with. --resume only reuses
+ * the image if the sources match and the file is unchanged.
+ ******************************************************************/
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResumeState {
+    takeover_version: String,
+    sources: Vec<String>,
+    image_path: PathBuf,
+    image_sha256: String,
+}
+
+fn hex_sha256(path: &Path) -> Result<String> {
+    Ok(sha256_file(path)?
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+// the image sources of opts, images downloaded from the API are identified by device type and version
+pub(crate) fn image_sources(opts: &Options, device_type: &str) -> Vec<String> {
+    if opts.images().is_empty() {
+        vec![format!("api:{}:{}", device_type, opts.version())]
+    } else {
+        opts.images().to_vec()
+    }
+}
+
+pub(crate) fn save_image_state(work_dir: &Path, sources: &[String], image_path: &Path) {
+    let state_path = path_append(work_dir, RESUME_STATE_NAME);
+    let res = hex_sha256(image_path).and_then(|image_sha256| {
+        let state = ResumeState {
+            takeover_version: env!("CARGO_PKG_VERSION").to_string(),
+            sources: sources.to_vec(),
+            image_path: image_path.to_path_buf(),
+            image_sha256,
+        };
+        let state_txt = serde_json::to_string_pretty(&state)
+            .upstream_with_context("Failed to serialize resume state")?;
+        write(&state_path, state_txt).upstream_with_context(&format!(
+            "Failed to write resume state '{}'",
+            state_path.display()
+        ))
+    });
+
+    if let Err(why) = res {
+        warn!(
+            "A later --resume will fetch the image again, error: {}",
+            why
+        );
+    }
+}
+
+fn check_image_state(
+    state_path: &Path,
+    sources: &[String],
+    sha256: Option<&str>,
+) -> Result<std::result::Result<PathBuf, String>> {
+    let state_txt = read_to_string(state_path).upstream_with_context(&format!(
+        "Failed to read resume state '{}'",
+        state_path.display()
+    ))?;
+    let state: ResumeState = serde_json::from_str(&state_txt).upstream_with_context(&format!(
+        "Failed to parse resume state '{}'",
+        state_path.display()
+    ))?;
+
+    if state.takeover_version != env!("CARGO_PKG_VERSION") {
+        return Ok(Err(format!(
+            "it was written by takeover {}",
+            state.takeover_version
+        )));
+    }
+    if state.sources != sources {
+        return Ok(Err(format!(
+            "it was fetched from {}",
+            state.sources.join(", ")
+        )));
+    }
+    if !file_exists(&state.image_path) {
+        return Ok(Err(format!(
+            "'{}' no longer exists",
+            state.image_path.display()
+        )));
+    }
+    let image_sha256 = hex_sha256(&state.image_path)?;
+    if image_sha256 != state.image_sha256 {
+        return Ok(Err(format!(
+            "'{}' changed since it was fetched",
+            state.image_path.display()
+        )));
+    }
+    if let Some(sha256) = sha256 {
+        if !image_sha256.eq_ignore_ascii_case(sha256) {
+            return Ok(Err(format!("its SHA-256 is not {}", sha256)));
+        }
+    }
+    Ok(Ok(state.image_path))
+}
+
+// the image fetched by a previous run if it is still intact, else None with the reason logged
+pub(crate) fn resumed_image(
+    work_dir: &Path,
+    sources: &[String],
+    sha256: Option<&str>,
+) -> Option<PathBuf> {
+    let state_path = path_append(work_dir, RESUME_STATE_NAME);
+    if !file_exists(&state_path) {
+        info!(
+            "Not resuming, no image was fetched by a previous run in '{}'",
+            work_dir.display()
+        );
+        return None;
+    }
+
+    match check_image_state(&state_path, sources, sha256) {
+        Ok(Ok(image_path)) => {
+            info!(
+                "Resuming with the image '{}' fetched by a previous run",
+                image_path.display()
+            );
+            Some(image_path)
+        }
+        Ok(Err(reason)) => {
+            info!(
+                "Not reusing the image fetched by a previous run, {}",
+                reason
+            );
+            None
+        }
+        Err(why) => {
+            warn!(
+                "Not reusing the image fetched by a previous run, error: {}",
+                why
+            );
+            None
+        }
+    }
+}
+
+/******************************************************************
+ * The RAMFS staged by an interrupted run is not reused, its copies
+ * and mounts depend on options that may have changed. Clean it up
+ * like --abort so stage1 can stage it again.
+ ******************************************************************/
+
+pub(crate) fn clean_staged(opts: &Options) -> Result<()> {
+    let takeover_dir = Path::new(TAKEOVER_DIR);
+    if !dir_exists(takeover_dir)? {
+        return Ok(());
+    }
+
+    let staged = read_dir(takeover_dir)
+        .upstream_with_context(&format!(
+            "Failed to read directory '{}'",
+            takeover_dir.display()
+        ))?
+        .next()
+        .is_some();
+
+    if staged {
+        info!(
+            "Cleaning up '{}' staged by a previous run, it is staged again",
+            takeover_dir.display()
+        );
+        cleanup_staged(opts)?;
+    }
+    Ok(())
+}
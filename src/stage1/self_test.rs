@@ -0,0 +1,253 @@
+use std::fs::{read_to_string, remove_dir, write};
+use std::path::Path;
+
+use libc::MS_PRIVATE;
+use log::{error, info};
+use nix::{
+    mount::{mount, umount, MsFlags},
+    sched::{unshare, CloneFlags},
+    sys::wait::{waitpid, WaitStatus},
+    unistd::{chroot, fork, pivot_root, ForkResult},
+};
+
+use crate::{
+    common::{
+        call,
+        defs::{DD_CMD, NIX_NONE},
+        is_admin, path_append,
+        progress::{emit, ProgressEvent},
+        whereis, Error, ErrorKind, Options, Result, ToError,
+    },
+    stage1::{
+        defs::OSArch,
+        device_impl::get_device,
+        exe_copy::ExeCopy,
+        utils::{find_missing_commands, get_os_arch, mktemp, required_commands},
+    },
+};
+
+const TEST_FILE_CONTENT: &str = "takeover self-test";
+// the steps of pivot_scratch, its exit code is the number of the failed step
+const PIVOT_STEPS: [&str; 6] = [
+    "create a mount namespace",
+    "make / private recursively",
+    "mount a tmpfs",
+    "create the old root directory",
+    "pivot_root",
+    "chroot",
+];
+
+type SelfTest<'a> = (&'static str, Box<dyn Fn() -> Result<String> + 'a>);
+
+fn test_device_type(opts: &Options) -> Result<String> {
+    let device = get_device(opts)?;
+    Ok(format!("detected device type {}", device.get_device_type()))
+}
+
+fn test_commands(opts: &Options) -> Result<String> {
+    let is_x86 = matches!(get_os_arch(opts)?, OSArch::AMD64 | OSArch::I386);
+    let commands = required_commands(opts, is_x86);
+    let missing = find_missing_commands(&commands);
+    if missing.is_empty() {
+        Ok(format!("found {}", commands.join(", ")))
+    } else {
+        Err(Error::with_context(
+            ErrorKind::FileNotFound,
+            &format!("missing commands: {}", missing.join(", ")),
+        ))
+    }
+}
+
+fn test_executables() -> Result<String> {
+    // stage2 runs on copies of these executables
+    ExeCopy::new(vec![DD_CMD], None)?;
+
+    let dd_path = whereis(DD_CMD)?;
+    let cmd_res = call(
+        &dd_path,
+        &["if=/dev/zero", "of=/dev/null", "bs=512", "count=1"],
+        true,
+    )?;
+    if cmd_res.status.success() {
+        Ok(format!(
+            "'{}' and its libraries found and executed",
+            dd_path
+        ))
+    } else {
+        Err(Error::with_context(
+            ErrorKind::ExecProcess,
+            &format!("'{}' failed, stderr: {}", dd_path, cmd_res.stderr),
+        ))
+    }
+}
+
+// pivot and chroot into scratch_dir in a private mount namespace, only used in a forked child
+fn pivot_scratch(scratch_dir: &Path) -> i32 {
+    if unshare(CloneFlags::CLONE_NEWNS).is_err() {
+        return 1;
+    }
+    if mount(
+        NIX_NONE,
+        "/",
+        NIX_NONE,
+        MsFlags::from_bits(MS_PRIVATE).unwrap() | MsFlags::MS_REC,
+        NIX_NONE,
+    )
+    .is_err()
+    {
+        return 2;
+    }
+    if mount(
+        Some("tmpfs"),
+        scratch_dir,
+        Some("tmpfs"),
+        MsFlags::empty(),
+        NIX_NONE,
+    )
+    .is_err()
+    {
+        return 3;
+    }
+    let old_root = path_append(scratch_dir, "old_root");
+    if nix::unistd::mkdir(&old_root, nix::sys::stat::Mode::S_IRWXU).is_err() {
+        return 4;
+    }
+    if pivot_root(scratch_dir, &old_root).is_err() {
+        return 5;
+    }
+    if chroot("/").is_err() {
+        return 6;
+    }
+    0
+}
+
+// also run as a migration preflight, stage2 depends on all of these working
+pub(crate) fn test_tmpfs() -> Result<String> {
+    if !is_admin()? {
+        return Err(Error::with_context(
+            ErrorKind::Permission,
+            "root privileges are required to mount a tmpfs",
+        ));
+    }
+
+    const NO_PATH: Option<&Path> = None;
+    let scratch_dir = mktemp(true, Some("takeover-self-test."), None, NO_PATH)?;
+
+    let res = test_tmpfs_in(&scratch_dir);
+
+    remove_dir(&scratch_dir).upstream_with_context(&format!(
+        "Failed to remove scratch directory '{}'",
+        scratch_dir.display()
+    ))?;
+
+    res
+}
+
+fn test_tmpfs_in(scratch_dir: &Path) -> Result<String> {
+    mount(
+        Some("tmpfs"),
+        scratch_dir,
+        Some("tmpfs"),
+        MsFlags::empty(),
+        NIX_NONE,
+    )
+    .upstream_with_context(&format!(
+        "Failed to mount tmpfs on '{}'",
+        scratch_dir.display()
+    ))?;
+
+    let test_file = path_append(scratch_dir, "test.txt");
+    let res = write(&test_file, TEST_FILE_CONTENT)
+        .upstream_with_context(&format!("Failed to write '{}'", test_file.display()))
+        .and_then(|_| {
+            read_to_string(&test_file)
+                .upstream_with_context(&format!("Failed to read '{}'", test_file.display()))
+        });
+
+    umount(scratch_dir).upstream_with_context(&format!(
+        "Failed to unmount tmpfs from '{}'",
+        scratch_dir.display()
+    ))?;
+
+    if res? != TEST_FILE_CONTENT {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            "Content read back from tmpfs does not match",
+        ));
+    }
+
+    match fork().upstream_with_context("Failed to fork pivot_root test process")? {
+        ForkResult::Child => unsafe { libc::_exit(pivot_scratch(scratch_dir)) },
+        ForkResult::Parent { child } => {
+            match waitpid(child, None)
+                .upstream_with_context("Failed to wait for pivot_root test process")?
+            {
+                WaitStatus::Exited(_, 0) => {
+                    Ok("wrote to, pivoted and chrooted into a scratch tmpfs".to_string())
+                }
+                WaitStatus::Exited(_, code) if code > 0 && code as usize <= PIVOT_STEPS.len() => {
+                    Err(Error::with_context(
+                        ErrorKind::NotSupported,
+                        &format!(
+                            "The kernel failed to {} in the pivot_root test process",
+                            PIVOT_STEPS[code as usize - 1]
+                        ),
+                    ))
+                }
+                status => Err(Error::with_context(
+                    ErrorKind::InvState,
+                    &format!("pivot_root test process failed: {:?}", status),
+                )),
+            }
+        }
+    }
+}
+
+// Check the environment without touching the system, report pass / fail per item
+pub(crate) fn self_test(opts: &Options) -> Result<()> {
+    info!("Running self-test");
+
+    let tests: Vec<SelfTest> = vec![
+        ("device type", Box::new(|| test_device_type(opts))),
+        ("external commands", Box::new(|| test_commands(opts))),
+        ("stage2 executables", Box::new(test_executables)),
+        ("scratch tmpfs", Box::new(test_tmpfs)),
+    ];
+
+    let mut failed = 0;
+    for (item, test) in &tests {
+        emit(ProgressEvent::CheckStarted(item.to_string()));
+        match test() {
+            Ok(msg) => {
+                info!("self-test {:<20} PASS: {}", item, msg);
+                emit(ProgressEvent::CheckPassed(item.to_string()));
+            }
+            Err(why) => {
+                let error = if why.kind() == ErrorKind::Displayed {
+                    error!("self-test {:<20} FAIL: see above", item);
+                    "see log".to_string()
+                } else {
+                    error!("self-test {:<20} FAIL: {}", item, why);
+                    why.to_string()
+                };
+                emit(ProgressEvent::CheckFailed {
+                    name: item.to_string(),
+                    error,
+                });
+                failed += 1;
+            }
+        }
+    }
+
+    if failed == 0 {
+        info!("Self-test passed");
+        Ok(())
+    } else {
+        error!(
+            "Self-test failed: {} of {} items failed",
+            failed,
+            tests.len()
+        );
+        Err(Error::displayed_with(ErrorKind::Validation))
+    }
+}
@@ -0,0 +1,84 @@
+use std::fs::{read_to_string, write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{error, info, warn};
+
+use crate::common::{
+    call_retry, defs::CHCON_CMD, file_exists, whereis, Error, ErrorKind, Result, ToError,
+};
+
+const SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
+
+// set once this run switched SELinux from enforcing to permissive
+static SWITCHED_TO_PERMISSIVE: AtomicBool = AtomicBool::new(false);
+
+// false if SELinux is not present or not enforcing
+pub(crate) fn is_enforcing() -> Result<bool> {
+    if !file_exists(SELINUX_ENFORCE) {
+        return Ok(false);
+    }
+    let enforce = read_to_string(SELINUX_ENFORCE)
+        .upstream_with_context(&format!("Failed to read '{}'", SELINUX_ENFORCE))?;
+    Ok(enforce.trim() == "1")
+}
+
+// stage1 switches SELinux back to enforcing if it fails or is aborted, see restore_enforcing
+pub(crate) fn set_permissive() -> Result<()> {
+    write(SELINUX_ENFORCE, "0").upstream_with_context(&format!(
+        "Failed to switch SELinux to permissive mode through '{}'",
+        SELINUX_ENFORCE
+    ))?;
+    SWITCHED_TO_PERMISSIVE.store(true, Ordering::SeqCst);
+    warn!("Switched SELinux to permissive mode, it stays permissive for stage2");
+    Ok(())
+}
+
+// switch SELinux back to enforcing if this run made it permissive, does nothing otherwise
+pub(crate) fn restore_enforcing() {
+    if SWITCHED_TO_PERMISSIVE.swap(false, Ordering::SeqCst) {
+        match write(SELINUX_ENFORCE, "1") {
+            Ok(_) => info!("Switched SELinux back to enforcing mode"),
+            Err(why) => error!(
+                "Failed to switch SELinux back to enforcing mode through '{}', error: {}",
+                SELINUX_ENFORCE, why
+            ),
+        }
+    }
+}
+
+// files copied to the tmpfs get the tmpfs label which may not be allowed to execute,
+// label the copy like its source instead
+pub(crate) fn copy_context(src_path: &Path, dest_path: &Path) -> Result<()> {
+    let chcon_path = whereis(CHCON_CMD).error_with_all(
+        ErrorKind::FileNotFound,
+        &format!(
+            "SELinux is enforcing but '{}' could not be located, use --selinux-permissive to migrate anyway",
+            CHCON_CMD
+        ),
+    )?;
+
+    let reference = format!("--reference={}", src_path.display());
+    let cmd_res = call_retry(
+        &chcon_path,
+        &[reference.as_str(), &*dest_path.to_string_lossy()],
+        true,
+    )?;
+    if cmd_res.status.success() {
+        info!(
+            "Set SELinux context of '{}' from '{}'",
+            dest_path.display(),
+            src_path.display()
+        );
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::ExecProcess,
+            &format!(
+                "Failed to set SELinux context of '{}', stderr: {}, use --selinux-permissive to migrate anyway",
+                dest_path.display(),
+                cmd_res.stderr.trim()
+            ),
+        ))
+    }
+}
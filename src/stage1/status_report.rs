@@ -0,0 +1,121 @@
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde_json::json;
+use url::Url;
+
+use crate::{
+    common::Options,
+    stage1::{api_calls::post_status, migrate_info::balena_cfg_json::BalenaCfgJson},
+};
+
+const REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum MigrationStatus {
+    Started,
+    ChecksPassed,
+    Flashing,
+    Failed,
+    Verified,
+    VerifyFailed,
+}
+
+impl Display for MigrationStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let output = match self {
+            MigrationStatus::Started => "started",
+            MigrationStatus::ChecksPassed => "checks-passed",
+            MigrationStatus::Flashing => "flashing",
+            MigrationStatus::Failed => "failed",
+            MigrationStatus::Verified => "verified",
+            MigrationStatus::VerifyFailed => "verify-failed",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+// Best effort reporting of migration status transitions to a remote endpoint
+pub(crate) struct StatusReporter {
+    url: Option<Url>,
+    api_key: Option<String>,
+    uuid: Option<String>,
+    device_type: Option<String>,
+}
+
+impl StatusReporter {
+    pub fn new(opts: &Options, config: &BalenaCfgJson) -> StatusReporter {
+        let mut reporter = StatusReporter {
+            url: None,
+            api_key: None,
+            uuid: config
+                .get_value("uuid")
+                .and_then(|uuid| uuid.as_str())
+                .map(String::from),
+            device_type: config.get_device_type().ok(),
+        };
+
+        if let Some(report_url) = opts.report_url() {
+            // paths are relative to the balena API, the api key is only sent there
+            if report_url.starts_with('/') {
+                match config.get_api_endpoint() {
+                    Ok(api_endpoint) => {
+                        match Url::parse(&api_endpoint).and_then(|url| url.join(report_url)) {
+                            Ok(url) => {
+                                reporter.url = Some(url);
+                                // reports are about this device, prefer its own key
+                                reporter.api_key = match config.get_device_api_key() {
+                                    Ok(Some(device_api_key)) => Some(device_api_key),
+                                    _ => config.get_api_key().ok(),
+                                };
+                            }
+                            Err(why) => warn!(
+                                "Invalid status report url '{}{}', error: {}",
+                                api_endpoint, report_url, why
+                            ),
+                        }
+                    }
+                    Err(why) => warn!(
+                        "Status reports disabled, no apiEndpoint found in config.json: {}",
+                        why
+                    ),
+                }
+            } else {
+                match Url::parse(report_url) {
+                    Ok(url) => reporter.url = Some(url),
+                    Err(why) => warn!("Invalid status report url '{}', error: {}", report_url, why),
+                }
+            }
+        }
+
+        if let Some(url) = &reporter.url {
+            info!("Reporting migration status to '{}'", url);
+        }
+
+        reporter
+    }
+
+    pub fn report(&self, status: MigrationStatus, message: Option<&str>) {
+        if let Some(url) = &self.url {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0);
+            let data = json!({
+                "uuid": self.uuid,
+                "deviceType": self.device_type,
+                "status": status.to_string(),
+                "message": message,
+                "timestamp": timestamp,
+            });
+
+            if let Err(why) = post_status(url, self.api_key.as_deref(), &data, REPORT_TIMEOUT) {
+                warn!(
+                    "Failed to report migration status '{}', error: {}",
+                    status, why
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::process::exit;
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc,
+};
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use log::{error, info, warn};
+use mod_logger::Logger;
+use nix::sys::signal::{SigSet, Signal};
+
+use crate::{
+    common::{Error, ErrorKind, Options, Result},
+    stage1::selinux::restore_enforcing,
+};
+
+// before the migration is confirmed stage1 has nothing to clean up
+const STATE_IDLE: u8 = 0;
+const STATE_RUNNING: u8 = 1;
+const STATE_COMMITTED: u8 = 2;
+const STATE_TIMED_OUT: u8 = 3;
+const STATE_INTERRUPTED: u8 = 4;
+// how long the main thread gets to clean up after the timeout expired or stage1 was interrupted
+const ABORT_GRACE_SECS: u64 = 60;
+
+// exit without cleanup if the main thread does not notice the abort in time
+fn exit_after_grace(reason: &'static str) {
+    spawn(move || {
+        sleep(Duration::from_secs(ABORT_GRACE_SECS));
+        error!(
+            "Stage1 did not abort within {} seconds of being {}, exiting without cleanup",
+            ABORT_GRACE_SECS, reason
+        );
+        restore_enforcing();
+        Logger::flush();
+        exit(1);
+    });
+}
+
+/******************************************************************
+ * SIGINT and SIGTERM abort stage1 before the point of no return and
+ * are ignored after it. Like the timeout, the handler only marks
+ * stage1 as interrupted, the main thread notices in check or commit
+ * and cleans up on its way out. A second signal exits right away
+ * without cleaning up, as does a signal received before the
+ * migration was confirmed.
+ ******************************************************************/
+
+fn handle_signals(state: Arc<AtomicU8>) {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+
+    // threads spawned later inherit the mask, so only the handler thread receives the signals
+    if let Err(why) = signals.thread_block() {
+        warn!(
+            "Failed to block SIGINT and SIGTERM, interrupting stage1 will skip the cleanup, error: {}",
+            why
+        );
+        return;
+    }
+
+    spawn(move || loop {
+        let signal = match signals.wait() {
+            Ok(signal) => signal,
+            Err(why) => {
+                warn!("Failed to wait for signals, error: {}", why);
+                return;
+            }
+        };
+
+        if state
+            .compare_exchange(
+                STATE_IDLE,
+                STATE_INTERRUPTED,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok()
+        {
+            error!("Received {}, terminating", signal.as_str());
+            Logger::flush();
+            exit(1);
+        }
+
+        match state.compare_exchange(
+            STATE_RUNNING,
+            STATE_INTERRUPTED,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => {
+                error!("Received {}, aborting migration", signal.as_str());
+                exit_after_grace("interrupted");
+            }
+            Err(STATE_COMMITTED) => warn!(
+                "Received {}, the migration can not be safely interrupted any more, ignoring it",
+                signal.as_str()
+            ),
+            Err(_) => {
+                error!(
+                    "Received {} while aborting, exiting without cleanup",
+                    signal.as_str()
+                );
+                restore_enforcing();
+                Logger::flush();
+                exit(1);
+            }
+        }
+    });
+}
+
+// Aborts stage1 if it does not reach the point of no return in time or is interrupted
+pub(crate) struct Stage1Timeout {
+    state: Arc<AtomicU8>,
+}
+
+impl Stage1Timeout {
+    pub fn new() -> Stage1Timeout {
+        let state = Arc::new(AtomicU8::new(STATE_IDLE));
+
+        handle_signals(state.clone());
+
+        Stage1Timeout { state }
+    }
+
+    /******************************************************************
+     * Start the stage1 timeout, once the migration was confirmed. The
+     * timer only marks stage1 as timed out, the main thread notices
+     * in check or commit and cleans up on its way out. A main thread
+     * that does not get there within ABORT_GRACE_SECS is stuck, stage1
+     * then exits without cleaning up.
+     ******************************************************************/
+
+    pub fn start(&self, opts: &Options) {
+        // a signal received before this terminated stage1 already
+        let _ = self.state.compare_exchange(
+            STATE_IDLE,
+            STATE_RUNNING,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+
+        if let Some(timeout) = opts.stage1_timeout() {
+            info!("Stage1 will be aborted after {} seconds", timeout);
+            let thread_state = self.state.clone();
+            spawn(move || {
+                sleep(Duration::from_secs(timeout));
+                if thread_state
+                    .compare_exchange(
+                        STATE_RUNNING,
+                        STATE_TIMED_OUT,
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                    )
+                    .is_ok()
+                {
+                    error!(
+                        "Stage1 did not complete within {} seconds, aborting migration",
+                        timeout
+                    );
+                    exit_after_grace("timed out");
+                }
+            });
+        }
+    }
+
+    // fails once the timeout expired or stage1 was interrupted, called between the steps of stage1
+    pub fn check(&self) -> Result<()> {
+        match self.state.load(Ordering::SeqCst) {
+            STATE_TIMED_OUT => Err(Error::with_context(
+                ErrorKind::Timeout,
+                "Stage1 timed out before the new init was installed",
+            )),
+            // the signal handler logged the signal
+            STATE_INTERRUPTED => Err(Error::displayed()),
+            _ => Ok(()),
+        }
+    }
+
+    // called at the point of no return, the timeout can not abort stage1 after this
+    pub fn commit(&self) -> Result<()> {
+        match self.state.compare_exchange(
+            STATE_RUNNING,
+            STATE_COMMITTED,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) | Err(STATE_COMMITTED) => Ok(()),
+            Err(STATE_INTERRUPTED) => Err(Error::displayed()),
+            Err(_) => Err(Error::with_context(
+                ErrorKind::Timeout,
+                "Stage1 timed out before the new init was installed",
+            )),
+        }
+    }
+}
@@ -6,42 +6,224 @@ use rand::{thread_rng, Rng};
 use std::cmp::min;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use crate::{
     common::{
         call,
-        defs::{MOKUTIL_CMD, NIX_NONE, SYS_EFI_DIR},
+        defs::{
+            BLKID_CMD, DD_CMD, EFIBOOTMGR_CMD, MOKUTIL_CMD, MOUNT_CMD, SWAPOFF_CMD, SYS_EFI_DIR,
+            TAR_CMD, TELINIT_CMD,
+        },
         dir_exists,
+        options::Options,
         system::{mkdir, mknod, uname},
         whereis, Error, ErrorKind, Result, ToError,
     },
-    stage1::defs::OSArch,
+    stage1::defs::{BootMode, OSArch},
 };
 
-use log::{error, trace, warn};
+use log::{debug, error, trace, warn};
 use regex::Regex;
 
 use crate::common::path_append;
 use crate::stage1::migrate_info::MigrateInfo;
 
-use std::fs::create_dir_all;
+use std::fs::{create_dir_all, metadata, read_to_string, File};
 use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
 
-pub(crate) fn get_os_arch() -> Result<OSArch> {
+const CPU_INFO_PATH: &str = "/proc/cpuinfo";
+const USERLAND_EXE: &str = "/bin/sh";
+
+// external commands used by stage1, init and stage2
+pub(crate) fn required_commands(opts: &Options, is_x86: bool) -> Vec<&'static str> {
+    let mut commands = vec![SWAPOFF_CMD, TELINIT_CMD, MOUNT_CMD, BLKID_CMD, "ldd"];
+
+    // executables for stage2 are validated in the assets directory instead
+    if opts.assets_dir().is_none() {
+        commands.push(DD_CMD);
+        if is_x86 && !opts.no_efi_setup() && dir_exists(SYS_EFI_DIR).unwrap_or(false) {
+            commands.push(EFIBOOTMGR_CMD);
+        }
+    }
+
+    if opts.backup_config().is_some() && !opts.tar_internal() {
+        commands.push(TAR_CMD);
+    }
+
+    commands
+}
+
+// returns the commands that could not be found or are not executable
+pub(crate) fn find_missing_commands(commands: &[&str]) -> Vec<String> {
+    commands
+        .iter()
+        .filter_map(|command| match whereis(command) {
+            Ok(cmd_path) => match metadata(&cmd_path) {
+                Ok(cmd_meta) => {
+                    if cmd_meta.permissions().mode() & 0o111 == 0 {
+                        Some(format!("{} ('{}' is not executable)", command, cmd_path))
+                    } else {
+                        None
+                    }
+                }
+                Err(_) => Some(format!("{} ('{}' is not accessible)", command, cmd_path)),
+            },
+            Err(_) => Some(command.to_string()),
+        })
+        .collect()
+}
+
+pub(crate) fn get_os_arch(opts: &Options) -> Result<OSArch> {
     trace!("get_os_arch: entered");
 
+    let detected = detect_os_arch();
+    // an ARMv7 build might start on an ARMv6 CPU but fail on the first ARMv7 instruction
+    let armv6_cpu = matches!(detected, Ok(OSArch::ARMV6));
+    let os_arch = if let Some(arch) = opts.arch() {
+        match &detected {
+            Ok(detected) if detected == arch => (),
+            Ok(detected) => warn!(
+                "*** The requested architecture {:?} does not match the detected architecture {:?}, \
+                executables copied for stage2 might fail to execute ***",
+                arch, detected
+            ),
+            Err(why) => warn!(
+                "*** Using requested architecture {:?}, failed to detect the architecture: {} ***",
+                arch, why
+            ),
+        }
+        info!("Using requested architecture {:?}", arch);
+        arch.clone()
+    } else {
+        detected?
+    };
+
+    match OSArch::compiled() {
+        Some(compiled) if compiled.runs_on(&os_arch) => (),
+        Some(OSArch::ARMHF) if armv6_cpu => {
+            error!(
+                "This binary was compiled for {:?} and will not run on an ARMv6 CPU, \
+                please use a build for the arm-unknown-linux-gnueabihf target",
+                OSArch::ARMHF
+            );
+            return Err(Error::displayed_with(ErrorKind::NotSupported));
+        }
+        Some(compiled) => warn!(
+            "*** The architecture {:?} does not match the architecture this binary was compiled for: {:?} ***",
+            os_arch, compiled
+        ),
+        None => warn!(
+            "*** The architecture this binary was compiled for is unknown, using {:?} ***",
+            os_arch
+        ),
+    }
+    Ok(os_arch)
+}
+
+// Raspberry PI 1 / Zero kernels may report armv7l and the ARM1176 reports
+// 'CPU architecture: 7', so look for the v6l processor name in /proc/cpuinfo
+fn is_armv6_cpu() -> Result<bool> {
+    let cpu_info = read_to_string(CPU_INFO_PATH)
+        .upstream_with_context(&format!("Failed to read '{}'", CPU_INFO_PATH))?;
+    Ok(cpu_info.lines().any(|line| {
+        (line.starts_with("model name") || line.starts_with("Processor"))
+            && (line.contains("(v6l)") || line.contains("ARMv6"))
+    }))
+}
+
+fn arm32_arch() -> Result<OSArch> {
+    if is_armv6_cpu()? {
+        Ok(OSArch::ARMV6)
+    } else {
+        Ok(OSArch::ARMHF)
+    }
+}
+
+// architecture of the installed userland taken from the ELF header of USERLAND_EXE,
+// a 64 bit kernel can run a 32 bit userland, eg. in multiarch containers
+fn userland_arch() -> Result<Option<OSArch>> {
+    const EI_CLASS_32: u8 = 1;
+    const EI_CLASS_64: u8 = 2;
+    const EM_386: u16 = 3;
+    const EM_ARM: u16 = 40;
+    const EM_X86_64: u16 = 62;
+    const EM_AARCH64: u16 = 183;
+
+    let mut header = [0u8; 20];
+    File::open(USERLAND_EXE)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .upstream_with_context(&format!("Failed to read ELF header of '{}'", USERLAND_EXE))?;
+
+    if header[0..4] != *b"\x7fELF" {
+        return Ok(None);
+    }
+
+    // only little endian targets are supported
+    let machine = u16::from_le_bytes([header[18], header[19]]);
+    debug!(
+        "userland_arch: '{}' ELF class {} machine {}",
+        USERLAND_EXE, header[4], machine
+    );
+    match (header[4], machine) {
+        (EI_CLASS_64, EM_X86_64) => Ok(Some(OSArch::AMD64)),
+        (EI_CLASS_32, EM_386) => Ok(Some(OSArch::I386)),
+        (EI_CLASS_32, EM_ARM) => Ok(Some(arm32_arch()?)),
+        (EI_CLASS_64, EM_AARCH64) => Ok(Some(OSArch::ARM64)),
+        _ => Ok(None),
+    }
+}
+
+pub(crate) fn detect_os_arch() -> Result<OSArch> {
     let uname_res = uname()?;
     let machine = uname_res.get_machine();
-    match machine {
-        "x86_64" => Ok(OSArch::AMD64),
-        "i386" => Ok(OSArch::I386),
-        "armv7l" => Ok(OSArch::ARMHF),
-        "armv6l" => Ok(OSArch::ARMHF),
-        "aarch64" => Ok(OSArch::ARM64),
-        _ => Err(Error::with_context(
-            ErrorKind::InvParam,
-            &format!("get_os_arch: unsupported architecture '{}'", machine),
-        )),
+    let kernel_arch = match machine {
+        "x86_64" => OSArch::AMD64,
+        "i386" | "i486" | "i586" | "i686" => OSArch::I386,
+        "armv6l" => OSArch::ARMV6,
+        // armv8l is reported for 32 bit processes on 64 bit ARM kernels
+        "armv7l" | "armv8l" => arm32_arch()?,
+        "aarch64" => OSArch::ARM64,
+        _ => {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("get_os_arch: unsupported architecture '{}'", machine),
+            ))
+        }
+    };
+
+    // stage2 runs copies of the installed executables, so their architecture counts
+    match userland_arch() {
+        Ok(Some(userland_arch)) => {
+            if userland_arch != kernel_arch {
+                info!(
+                    "The userland architecture {:?} differs from the kernel architecture {:?} ({}), using {:?}",
+                    userland_arch, kernel_arch, machine, userland_arch
+                );
+            }
+            Ok(userland_arch)
+        }
+        Ok(None) => {
+            warn!(
+                "Could not determine the userland architecture from '{}', using the kernel architecture {:?}",
+                USERLAND_EXE, kernel_arch
+            );
+            Ok(kernel_arch)
+        }
+        Err(why) => {
+            warn!("{}, using the kernel architecture {:?}", why, kernel_arch);
+            Ok(kernel_arch)
+        }
+    }
+}
+
+// the kernel exposes /sys/firmware/efi only when it was started by EFI firmware
+pub(crate) fn get_boot_mode() -> Result<BootMode> {
+    if dir_exists(SYS_EFI_DIR)? {
+        Ok(BootMode::Efi)
+    } else {
+        Ok(BootMode::Legacy)
     }
 }
 
@@ -145,9 +327,10 @@ pub(crate) fn mktemp<P: AsRef<Path>>(
     }
 }
 
-pub(crate) fn check_tcp_connect(host: &str, port: u16, timeout: u64) -> Result<()> {
+// returns the time it took to connect
+pub(crate) fn check_tcp_connect(host: &str, port: u16, timeout: u64) -> Result<Duration> {
     use std::net::{Shutdown, TcpStream, ToSocketAddrs};
-    use std::time::Duration;
+    use std::time::Instant;
     let url = format!("{}:{}", host, port);
     let mut addrs_iter = url.to_socket_addrs().upstream_with_context(&format!(
         "check_tcp_connect: failed to resolve host address: '{}'",
@@ -155,14 +338,16 @@ pub(crate) fn check_tcp_connect(host: &str, port: u16, timeout: u64) -> Result<(
     ))?;
 
     if let Some(ref sock_addr) = addrs_iter.next() {
+        let started = Instant::now();
         let tcp_stream = TcpStream::connect_timeout(sock_addr, Duration::from_secs(timeout))
             .upstream_with_context(&format!(
                 "check_tcp_connect: failed to connect to: '{}' with timeout: {}",
                 url, timeout
             ))?;
+        let latency = started.elapsed();
 
         let _res = tcp_stream.shutdown(Shutdown::Both);
-        Ok(())
+        Ok(latency)
     } else {
         Err(Error::with_context(
             ErrorKind::InvState,
@@ -174,11 +359,263 @@ pub(crate) fn check_tcp_connect(host: &str, port: u16, timeout: u64) -> Result<(
     }
 }
 
+// GET url and require a success status, returns the time it took to receive the response
+pub(crate) fn check_http_get(url: &str, timeout: u64) -> Result<Duration> {
+    use reqwest::blocking::Client;
+    use std::time::Instant;
+    let client = Client::builder()
+        .timeout(Duration::from_secs(timeout))
+        .build()
+        .upstream_with_context("check_http_get: failed to create http client")?;
+
+    let started = Instant::now();
+    let response = client.get(url).send().upstream_with_context(&format!(
+        "check_http_get: failed to request '{}' with timeout: {}",
+        url, timeout
+    ))?;
+    let latency = started.elapsed();
+
+    if response.status().is_success() {
+        Ok(latency)
+    } else {
+        Err(Error::with_context(
+            ErrorKind::Connectivity,
+            &format!(
+                "check_http_get: '{}' returned status {}",
+                url,
+                response.status()
+            ),
+        ))
+    }
+}
+
+// a SHA-256 fingerprint as printed by 'openssl x509 -fingerprint -sha256', colons are optional
+pub(crate) fn parse_fingerprint(fingerprint: &str) -> Result<String> {
+    let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+    if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(hex.to_ascii_lowercase())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::InvParam,
+            &format!(
+                "Invalid certificate fingerprint '{}', expected a hex encoded SHA-256",
+                fingerprint
+            ),
+        ))
+    }
+}
+
+// the SHA-256 fingerprint of the certificate host:port presents, it is deliberately not
+// verified against the CA store so a pinned fingerprint does not depend on it
+pub(crate) fn get_tls_fingerprint(host: &str, port: u16, timeout: u64) -> Result<String> {
+    use openssl::hash::MessageDigest;
+    use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+    use std::net::{TcpStream, ToSocketAddrs};
+
+    let url = format!("{}:{}", host, port);
+    let sock_addr = url
+        .to_socket_addrs()
+        .upstream_with_context(&format!(
+            "get_tls_fingerprint: failed to resolve host address: '{}'",
+            url
+        ))?
+        .next()
+        .ok_or_else(|| {
+            Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "get_tls_fingerprint: no results from name resolution for: '{}'",
+                    url
+                ),
+            )
+        })?;
+
+    let tcp_stream = TcpStream::connect_timeout(&sock_addr, Duration::from_secs(timeout))
+        .upstream_with_context(&format!(
+            "get_tls_fingerprint: failed to connect to: '{}' with timeout: {}",
+            url, timeout
+        ))?;
+    tcp_stream
+        .set_read_timeout(Some(Duration::from_secs(timeout)))
+        .and_then(|_| tcp_stream.set_write_timeout(Some(Duration::from_secs(timeout))))
+        .upstream_with_context("get_tls_fingerprint: failed to set socket timeouts")?;
+
+    let mut builder = SslConnector::builder(SslMethod::tls())
+        .upstream_with_context("get_tls_fingerprint: failed to create TLS connector")?;
+    builder.set_verify(SslVerifyMode::NONE);
+    let stream = builder.build().connect(host, tcp_stream).map_err(|why| {
+        Error::with_context(
+            ErrorKind::Connectivity,
+            &format!(
+                "get_tls_fingerprint: TLS handshake with '{}' failed: {}",
+                url, why
+            ),
+        )
+    })?;
+
+    let cert = stream.ssl().peer_certificate().ok_or_else(|| {
+        Error::with_context(
+            ErrorKind::Connectivity,
+            &format!("get_tls_fingerprint: '{}' presented no certificate", url),
+        )
+    })?;
+    let digest = cert
+        .digest(MessageDigest::sha256())
+        .upstream_with_context("get_tls_fingerprint: failed to hash the server certificate")?;
+    Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+const PROC_NET_ROUTE: &str = "/proc/net/route";
+const PROC_NET_IPV6_ROUTE: &str = "/proc/net/ipv6_route";
+const RTF_UP: u32 = 0x0001;
+const RTF_REJECT: u32 = 0x0200;
+const NETWORK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const NETWORK_LOG_INTERVAL: u64 = 10;
+
+// true if one of the routing tables has a default route that is up, the kernel adds
+// an unreachable IPv6 default route on lo that does not count
+fn has_default_route(route_txt: &str, ipv6_route_txt: &str) -> bool {
+    let is_up = |flags: &str| {
+        u32::from_str_radix(flags, 16)
+            .map(|flags| flags & RTF_UP != 0 && flags & RTF_REJECT == 0)
+            .unwrap_or(false)
+    };
+
+    // Iface Destination Gateway Flags ..
+    let ipv4 = route_txt.lines().skip(1).any(|line| {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        columns.len() > 3 && columns[1] == "00000000" && is_up(columns[3])
+    });
+
+    // Destination PrefixLen Source SourcePrefixLen NextHop Metric RefCnt Use Flags Iface
+    let ipv6 = ipv6_route_txt.lines().any(|line| {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        columns.len() > 9
+            && columns[0].chars().all(|c| c == '0')
+            && columns[1] == "00"
+            && is_up(columns[8])
+            && columns[9] != "lo"
+    });
+
+    ipv4 || ipv6
+}
+
+/******************************************************************
+ * When takeover is started at boot the network might not be up yet.
+ * Wait up to timeout seconds for a default route, the connection
+ * checks that follow report a network that did not come up.
+ ******************************************************************/
+
+pub(crate) fn wait_for_network(timeout: u64) {
+    use std::thread::sleep;
+    use std::time::Instant;
+
+    let started = Instant::now();
+    let mut next_log = 0;
+    loop {
+        let route_txt = read_to_string(PROC_NET_ROUTE).unwrap_or_default();
+        let ipv6_route_txt = read_to_string(PROC_NET_IPV6_ROUTE).unwrap_or_default();
+        if has_default_route(&route_txt, &ipv6_route_txt) {
+            info!(
+                "Found a default route after {} seconds",
+                started.elapsed().as_secs()
+            );
+            return;
+        }
+
+        let waited = started.elapsed().as_secs();
+        if waited >= timeout {
+            warn!(
+                "No default route found within {} seconds, running the connection checks anyway",
+                timeout
+            );
+            return;
+        }
+        if waited >= next_log {
+            info!(
+                "Waiting for the network to come up, {} of {} seconds waited",
+                waited, timeout
+            );
+            next_log = waited + NETWORK_LOG_INTERVAL;
+        }
+        sleep(NETWORK_POLL_INTERVAL);
+    }
+}
+
+const PROC_SELF_STATUS: &str = "/proc/self/status";
+// needed to mount, create device nodes and pivot into the RAMFS
+const REQUIRED_CAPS: [(u32, &str); 2] = [(21, "CAP_SYS_ADMIN"), (27, "CAP_MKNOD")];
+
+fn get_effective_caps() -> Result<u64> {
+    let status = read_to_string(PROC_SELF_STATUS)
+        .upstream_with_context(&format!("Failed to read '{}'", PROC_SELF_STATUS))?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .ok_or_else(|| {
+            Error::with_context(
+                ErrorKind::NotFound,
+                &format!("No effective capabilities found in '{}'", PROC_SELF_STATUS),
+            )
+        })
+}
+
+/******************************************************************
+ * Fail early with a clear message instead of an EPERM from a mount
+ * or an external command somewhere in the migration.
+ ******************************************************************/
+
+pub(crate) fn check_privileges() -> Result<()> {
+    if unsafe { libc::geteuid() } != 0 {
+        error!(
+            "{} must be run as root, eg. using sudo",
+            env!("CARGO_PKG_NAME")
+        );
+        return Err(Error::displayed_with(ErrorKind::NotRoot));
+    }
+
+    match get_effective_caps() {
+        Ok(caps) => {
+            let missing: Vec<&str> = REQUIRED_CAPS
+                .iter()
+                .filter(|(cap, _)| caps & (1 << cap) == 0)
+                .map(|(_, name)| *name)
+                .collect();
+            if !missing.is_empty() {
+                error!(
+                    "{} is running as root but lacks the capabilities {}, it can not migrate the device from a restricted container",
+                    env!("CARGO_PKG_NAME"),
+                    missing.join(", ")
+                );
+                return Err(Error::displayed_with(ErrorKind::NotRoot));
+            }
+        }
+        Err(why) => warn!(
+            "Failed to determine the effective capabilities, error: {}",
+            why
+        ),
+    }
+
+    Ok(())
+}
+
 pub(crate) fn mount_fs<P: AsRef<Path>>(
     mount_dir: P,
     fs: &str,
     fs_type: &str,
     mig_info: Option<&mut MigrateInfo>,
+) -> Result<()> {
+    mount_fs_with_options(mount_dir, fs, fs_type, None, mig_info)
+}
+
+// options are passed to the file system as mount data, eg. size=64m for tmpfs
+pub(crate) fn mount_fs_with_options<P: AsRef<Path>>(
+    mount_dir: P,
+    fs: &str,
+    fs_type: &str,
+    options: Option<&str>,
+    mig_info: Option<&mut MigrateInfo>,
 ) -> Result<()> {
     let mount_dir = mount_dir.as_ref();
     if !dir_exists(mount_dir)? {
@@ -193,20 +630,30 @@ pub(crate) fn mount_fs<P: AsRef<Path>>(
         mount_dir,
         Some(fs_type.as_bytes()),
         MsFlags::empty(),
-        NIX_NONE,
+        options.map(str::as_bytes),
     )
     .upstream_with_context(&format!(
-        "Failed to mount {} on {} with fstype {}",
+        "Failed to mount {} on {} with fstype {}, options '{}'",
         fs,
         mount_dir.display(),
-        fs_type
+        fs_type,
+        options.unwrap_or("")
     ))?;
 
     if let Some(mig_info) = mig_info {
         mig_info.add_mount(mount_dir);
     }
 
-    info!("Mounted {} file system on '{}'", fs, mount_dir.display());
+    if let Some(options) = options {
+        info!(
+            "Mounted {} file system on '{}' with options '{}'",
+            fs,
+            mount_dir.display(),
+            options
+        );
+    } else {
+        info!("Mounted {} file system on '{}'", fs, mount_dir.display());
+    }
 
     Ok(())
 }
@@ -251,4 +698,43 @@ mod tests {
         copy(&mut read_buffer, &mut buffer).unwrap();
         assert_eq!(&BUFFER[..], buffer.as_slice());
     }
+
+    #[test]
+    fn test_has_default_route() {
+        const ROUTE_HEADER: &str =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n";
+        const IPV6_LO_UNREACHABLE: &str = "00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo\n";
+
+        let local_only = format!(
+            "{}eth0\t000200C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n",
+            ROUTE_HEADER
+        );
+        assert!(!has_default_route(&local_only, IPV6_LO_UNREACHABLE));
+
+        let ipv4_default = format!(
+            "{}eth0\t00000000\t010200C0\t0003\t0\t0\t0\t00000000\n",
+            ROUTE_HEADER
+        );
+        assert!(has_default_route(&ipv4_default, ""));
+
+        let ipv6_default = "00000000000000000000000000000000 00 00000000000000000000000000000000 00 fd000000000000000000000000000001 00000400 00000001 00000000 00000003     eth0\n";
+        assert!(has_default_route(ROUTE_HEADER, ipv6_default));
+    }
+
+    #[test]
+    fn test_parse_fingerprint() {
+        const FINGERPRINT: &str =
+            "9c7e1a0f8f1cd220d7cba237d1e14fd040a83ab6f6a8ec07c4fbbd21373b3c5d";
+        assert_eq!(parse_fingerprint(FINGERPRINT).unwrap(), FINGERPRINT);
+        let colons = FINGERPRINT
+            .to_ascii_uppercase()
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| String::from_utf8_lossy(pair).to_string())
+            .collect::<Vec<String>>()
+            .join(":");
+        assert_eq!(parse_fingerprint(&colons).unwrap(), FINGERPRINT);
+        assert!(parse_fingerprint(&FINGERPRINT[1..]).is_err());
+        assert!(parse_fingerprint("not a fingerprint").is_err());
+    }
 }
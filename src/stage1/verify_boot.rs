@@ -0,0 +1,135 @@
+use std::path::Path;
+
+use log::{error, info, warn};
+
+use crate::{
+    common::{
+        defs::{
+            BALENA_CONFIG_PATH, BALENA_HOST_BOOT_MP, BALENA_HOST_DATA_MP, MIGRATION_MARKER_NAME,
+        },
+        migration_marker::{get_os_version, MigrationMarker},
+        path_append, sha256_file, Error, ErrorKind, Options, Result, OS_RELEASE_FILE,
+    },
+    stage1::{
+        migrate_info::balena_cfg_json::BalenaCfgJson,
+        status_report::{MigrationStatus, StatusReporter},
+    },
+};
+
+// reports go through the config.json balena-os booted with unless one was given
+fn get_reporter(opts: &Options) -> Option<StatusReporter> {
+    let config_path = if let Some(config_path) = opts.config().first() {
+        config_path.clone()
+    } else {
+        path_append(BALENA_HOST_BOOT_MP, BALENA_CONFIG_PATH)
+    };
+
+    match BalenaCfgJson::new(&config_path) {
+        Ok(config) => Some(StatusReporter::new(opts, &config)),
+        Err(why) => {
+            if opts.report_url().is_some() {
+                warn!(
+                    "Not reporting the verification result, failed to read '{}', error: {}",
+                    config_path.display(),
+                    why
+                );
+            }
+            None
+        }
+    }
+}
+
+// balena-os updates config.json when the device registers, so a mismatch is only reported
+fn verify_config(expected: &str) {
+    let config_path = path_append(BALENA_HOST_BOOT_MP, BALENA_CONFIG_PATH);
+    match sha256_file(&config_path) {
+        Ok(digest) => {
+            let found: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+            if found == expected {
+                info!("config.json matches the one written by takeover");
+            } else {
+                warn!(
+                    "config.json in '{}' changed since takeover wrote it, expected sha256 {}, found {}",
+                    config_path.display(),
+                    expected,
+                    found
+                );
+            }
+        }
+        Err(why) => warn!(
+            "Failed to compute the sha256 of '{}', error: {}",
+            config_path.display(),
+            why
+        ),
+    }
+}
+
+fn verify(marker_path: &Path) -> Result<String> {
+    let marker = MigrationMarker::read(marker_path)?;
+    info!(
+        "Found migration marker for device {} ({}), migrated at {} by takeover {}",
+        marker.uuid.as_deref().unwrap_or("<unknown uuid>"),
+        marker
+            .device_type
+            .as_deref()
+            .unwrap_or("<unknown device type>"),
+        marker.timestamp,
+        marker.takeover_version
+    );
+
+    if let Some(config_sha256) = &marker.config_sha256 {
+        verify_config(config_sha256);
+    }
+
+    let os_version = get_os_version(Path::new(OS_RELEASE_FILE))?;
+
+    match marker.os_version {
+        Some(expected) if expected == os_version => Ok(format!(
+            "running balena-os {} as flashed by takeover",
+            os_version
+        )),
+        Some(expected) => Err(Error::with_context(
+            ErrorKind::Validation,
+            &format!(
+                "running OS version {} does not match the flashed version {}",
+                os_version, expected
+            ),
+        )),
+        None => {
+            warn!("The flashed balena-os version is unknown, the version can not be compared");
+            Ok(format!(
+                "running OS version {}, the flashed version is unknown",
+                os_version
+            ))
+        }
+    }
+}
+
+/******************************************************************
+ * Run under the new OS after the migration: read the marker stage2
+ * left on the data partition and compare the flashed version with
+ * the running one.
+ ******************************************************************/
+
+pub(crate) fn verify_boot(opts: &Options) -> Result<()> {
+    let marker_path = path_append(BALENA_HOST_DATA_MP, MIGRATION_MARKER_NAME);
+    let reporter = get_reporter(opts);
+
+    match verify(&marker_path) {
+        Ok(message) => {
+            info!("Migration verified: {}", message);
+            if let Some(reporter) = reporter {
+                reporter.report(MigrationStatus::Verified, Some(&message));
+            }
+            Ok(())
+        }
+        Err(why) => {
+            let message = why.to_string();
+            error!("Migration verification failed: {}", message);
+            if let Some(reporter) = reporter {
+                reporter.report(MigrationStatus::VerifyFailed, Some(&message));
+            }
+            Err(Error::displayed_with(why.kind()))
+        }
+    }
+}
@@ -11,7 +11,8 @@ mod nwmgr_parser;
 mod wpa_parser;
 
 use crate::{
-    common::{dir_exists, file_exists, path_append, pidof, Result, ToError},
+    common::{dir_exists, file_exists, path_append, Result, ToError},
+    stage1::init_system::InitSystem,
     stage1::wifi_config::{
         connmgr_parser::{parse_connmgr_config, CONNMGR_CONFIG_DIR},
         nwmgr_parser::NWMGR_CONFIG_DIR,
@@ -68,13 +69,13 @@ pub(crate) enum WifiConfig {
 }
 
 impl<'a> WifiConfig {
-    pub fn scan(ssid_filter: &[String]) -> Result<Vec<WifiConfig>> {
+    pub fn scan(ssid_filter: &[String], init_system: InitSystem) -> Result<Vec<WifiConfig>> {
         trace!("WifiConfig::scan: entered with {:?}", ssid_filter);
-        if !pidof("NetworkManager")?.is_empty() && dir_exists(NWMGR_CONFIG_DIR)? {
+        if init_system.is_running("NetworkManager")? && dir_exists(NWMGR_CONFIG_DIR)? {
             Ok(parse_nwmgr_config(ssid_filter)?)
-        } else if !pidof("wpa_supplicant")?.is_empty() && file_exists(WPA_CONFIG_FILE) {
+        } else if init_system.is_running("wpa_supplicant")? && file_exists(WPA_CONFIG_FILE) {
             Ok(WpaParser::parse_config(ssid_filter)?)
-        } else if !pidof("wpa_supplicant")?.is_empty() && dir_exists(CONNMGR_CONFIG_DIR)? {
+        } else if init_system.is_running("wpa_supplicant")? && dir_exists(CONNMGR_CONFIG_DIR)? {
             Ok(parse_connmgr_config(ssid_filter)?)
         } else {
             warn!("No supported network managers found, no wifis will be migrated");
@@ -89,6 +90,14 @@ impl<'a> WifiConfig {
         }
     }
 
+    // ssid and psk of a wifi scanned from the network manager settings, None for copied profiles
+    pub fn get_params(&'a self) -> Option<(&'a str, Option<&'a str>)> {
+        match self {
+            WifiConfig::Params(params) => Some((&params.ssid, params.psk.as_deref())),
+            WifiConfig::NwMgrFile(_) => None,
+        }
+    }
+
     pub(crate) fn create_nwmgr_file<P: AsRef<Path>>(
         &self,
         base_path: P,
@@ -1,22 +1,29 @@
 use std::fs::{
-    copy, create_dir, create_dir_all, read_dir, read_to_string, remove_dir, File, OpenOptions,
+    copy, create_dir, create_dir_all, read, read_dir, read_to_string, remove_dir, write, File,
+    OpenOptions,
 };
-use std::io::{self, Read, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
+use std::cmp::min;
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
 use std::process::{exit, Command, Stdio};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
 use nix::{
-    mount::{mount, umount, MsFlags},
+    mount::{mount, umount, umount2, MntFlags, MsFlags},
+    sys::signal::{self, SigHandler, Signal},
     unistd::sync,
 };
 
 use std::path::{Path, PathBuf};
 
 use flate2::read::GzDecoder;
-use libc::{ioctl, LINUX_REBOOT_CMD_RESTART, MS_RDONLY, MS_REMOUNT, SIGKILL, SIGTERM};
+use libc::{
+    ioctl, EBUSY, LINUX_REBOOT_CMD_RESTART, MS_RDONLY, MS_REMOUNT, O_EXCL, SIGKILL, SIGTERM,
+};
 use log::{debug, error, info, trace, warn, Level};
 use mod_logger::{LogDestination, Logger, NO_STREAM};
 
@@ -25,21 +32,61 @@ use crate::common::{
     call,
     defs::{
         IoctlReq, BACKUP_ARCH_NAME, BALENA_BOOT_FSTYPE, BALENA_BOOT_MP, BALENA_BOOT_PART,
-        BALENA_CONFIG_PATH, BALENA_DATA_FSTYPE, BALENA_DATA_PART, BALENA_IMAGE_NAME,
-        BALENA_IMAGE_PATH, BALENA_PART_MP, DD_CMD, DISK_BY_LABEL_PATH, EFIBOOTMGR_CMD, NIX_NONE,
-        OLD_ROOT_MP, STAGE2_CONFIG_NAME, SYSTEM_CONNECTIONS_DIR, SYS_EFI_DIR,
+        BALENA_CONFIG_PATH, BALENA_DATA_FSTYPE, BALENA_DATA_PART, BALENA_DELTA_NAME,
+        BALENA_IMAGE_NAME, BALENA_IMAGE_PATH, BALENA_OS_RELEASE_PATH, BALENA_PART_IMAGE_NAME,
+        BALENA_PART_MP, BOOT_CONFIG_NAME, DD_CMD, DISK_BY_LABEL_PATH, E2FSCK_CMD, EFIBOOTMGR_CMD,
+        MIGRATION_MARKER_NAME, NIX_NONE, RESIZE2FS_CMD, SGDISK_CMD, STAGE2_CONFIG_NAME,
+        SYSTEM_CONNECTIONS_DIR, SYS_EFI_DIR, TAKEOVER_LOGS_DIR,
     },
+    delta::{apply_delta, open_delta, verify_base},
     dir_exists,
-    disk_util::{Disk, PartInfo, PartitionIterator, DEF_BLOCK_SIZE},
-    error::{Error, ErrorKind, Result, ToError},
+    disk_util::{
+        add_logical_part, set_part_size, Disk, PartInfo, PartitionIterator, DEF_BLOCK_SIZE,
+        LINUX_PART_TYPE,
+    },
+    error::{report_post_pivot_failure, Error, ErrorKind, Result, ToError},
     file_exists, format_size_with_unit, get_mem_info,
     loop_device::LoopDevice,
+    migration_marker::{get_os_version, MigrationMarker},
     options::Options,
     path_append,
-    stage2_config::{Stage2Config, UmountPart},
+    progress::{add_observer, emit, FileObserver, JsonObserver, ProgressEvent, Stage},
+    set_old_root,
+    split_log::SplitLog,
+    stage2_config::{
+        FlashPart, FlashPause, KeepDataPart, MinWriteSpeed, OldRootCleanup, PartImage,
+        PartitionScheme, RebootMethod, RebootMode, Stage2Config, UmountPart,
+    },
     system::{fuser, get_process_infos},
 };
+use crate::stage1::{
+    device::{Device, PostFlashContext},
+    device_impl::get_device_for_type,
+    migrate_info::balena_cfg_json::{redacted, BalenaCfgJson},
+};
 use regex::Regex;
+use serde_json::Value;
+
+mod netlog;
+use netlog::NetLog;
+
+mod image_reader;
+use image_reader::open_image;
+
+mod pause_shell;
+use pause_shell::pause_for_inspection;
+
+mod reboot_method;
+use reboot_method::reboot_with_method;
+
+mod throttle;
+use throttle::FlashThrottle;
+
+mod watchdog;
+use watchdog::Watchdog;
+
+mod write_speed;
+use write_speed::WriteSpeedGuard;
 
 const DD_BLOCK_SIZE: usize = 128 * 1024; // 4_194_304;
 
@@ -50,8 +97,21 @@ const VALIDATE_BLOCK_SIZE: usize = 64 * 1024; // 4_194_304;
 const IOCTL_BLK_RRPART: IoctlReq = 0x1295;
 
 const TRANSFER_DIR: &str = "/transfer";
+const STAGE2_LOG_FILE: &str = "/stage2.log";
+const STAGE1_LOG_NAME: &str = "stage1.log";
+const STAGE2_LOG_NAME: &str = "stage2.log";
 
 const S2_XTRA_FS_SIZE: u64 = 10 * 1024 * 1024;
+const FLASH_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+// how long a lazily unmounted old root may keep the flash device busy
+const DEVICE_FREE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// config.json values that must have made it to the boot partition
+const VERIFY_CONFIG_KEYS: [&str; 3] = ["applicationId", "apiKey", "deviceType"];
+
+// ext2/3/4 superblock magic, located at offset 0x38 in the superblock at offset 1024
+const EXT_SB_MAGIC_OFFSET: u64 = 1024 + 0x38;
+const EXT_SB_MAGIC: [u8; 2] = [0x53, 0xEF];
 
 pub(crate) fn reboot() -> ! {
     trace!("reboot entered");
@@ -63,17 +123,52 @@ pub(crate) fn reboot() -> ! {
     exit(1);
 }
 
+// stop here without rebooting, leaving the device in stage2
+pub(crate) fn halt() -> ! {
+    trace!("halt entered");
+    Logger::flush();
+    sync();
+    loop {
+        sleep(Duration::from_secs(3600));
+    }
+}
+
+// reboot after a successful migration as configured by --reboot, using the reboot method
+fn reboot_with_mode(reboot_mode: &RebootMode, reboot_method: &RebootMethod) -> ! {
+    match reboot_mode {
+        RebootMode::Auto => reboot_with_method(reboot_method),
+        RebootMode::Delay(secs) => {
+            for remaining in (1..=*secs).rev() {
+                if remaining % 10 == 0 || remaining <= 5 || remaining == *secs {
+                    info!("Rebooting in {} seconds", remaining);
+                    Logger::flush();
+                }
+                sleep(Duration::from_secs(1));
+            }
+            reboot_with_method(reboot_method);
+        }
+        RebootMode::Manual => {
+            info!("Not rebooting due to manual reboot mode, please reboot the device to start balena OS");
+            halt();
+        }
+    }
+}
+
 fn get_required_space(s2_cfg: &Stage2Config) -> Result<u64> {
-    let curr_file = path_append(OLD_ROOT_MP, &s2_cfg.image_path);
-    let mut req_size = curr_file
-        .metadata()
-        .upstream_with_context(&format!(
-            "Failed to retrieve imagesize for '{}'",
-            curr_file.display()
-        ))?
-        .len() as u64;
+    let mut req_size = if s2_cfg.stream_image || s2_cfg.part_image.is_some() {
+        0
+    } else {
+        let curr_file = path_append(&s2_cfg.old_root, &s2_cfg.image_path);
+        curr_file
+            .metadata()
+            .upstream_with_context(&format!(
+                "Failed to retrieve imagesize for '{}'",
+                curr_file.display()
+            ))?
+            .len() as u64
+    };
 
-    let curr_file = path_append(OLD_ROOT_MP, &s2_cfg.config_path);
+    let curr_file = path_append(&s2_cfg.old_root, &s2_cfg.config_path);
     req_size += curr_file
         .metadata()
         .upstream_with_context(&format!(
@@ -83,7 +178,43 @@ fn get_required_space(s2_cfg: &Stage2Config) -> Result<u64> {
         .len() as u64;
 
     if let Some(ref backup_path) = s2_cfg.backup_path {
-        let curr_file = path_append(OLD_ROOT_MP, backup_path);
+        let curr_file = path_append(&s2_cfg.old_root, backup_path);
+        req_size += curr_file
+            .metadata()
+            .upstream_with_context(&format!(
+                "Failed to retrieve file size for '{}'",
+                curr_file.display()
+            ))?
+            .len() as u64;
+    }
+
+    if let Some(ref part_image) = s2_cfg.part_image {
+        let curr_file = path_append(&s2_cfg.old_root, &part_image.path);
+        req_size += curr_file
+            .metadata()
+            .upstream_with_context(&format!(
+                "Failed to retrieve file size for '{}'",
+                curr_file.display()
+            ))?
+            .len() as u64;
+    }
+
+    if let Some(ref delta_path) = s2_cfg.delta_path {
+        let curr_file = path_append(&s2_cfg.old_root, delta_path);
+        req_size += curr_file
+            .metadata()
+            .upstream_with_context(&format!(
+                "Failed to retrieve file size for '{}'",
+                curr_file.display()
+            ))?
+            .len() as u64;
+    }
+
+    if s2_cfg.boot_config {
+        let curr_file = path_append(
+            &s2_cfg.old_root,
+            path_append(&s2_cfg.work_dir, BOOT_CONFIG_NAME),
+        );
         req_size += curr_file
             .metadata()
             .upstream_with_context(&format!(
@@ -94,7 +225,7 @@ fn get_required_space(s2_cfg: &Stage2Config) -> Result<u64> {
     }
 
     let nwmgr_path = path_append(
-        OLD_ROOT_MP,
+        &s2_cfg.old_root,
         path_append(&s2_cfg.work_dir, SYSTEM_CONNECTIONS_DIR),
     );
 
@@ -158,16 +289,19 @@ fn copy_files(s2_cfg: &Stage2Config) -> Result<()> {
     // *********************************************************
     // write balena image to tmpfs
 
-    let src_path = path_append(OLD_ROOT_MP, &s2_cfg.image_path);
-    let to_path = path_append(TRANSFER_DIR, BALENA_IMAGE_NAME);
-    copy(&src_path, &to_path).upstream_with_context(&format!(
-        "Failed to copy '{}' to {}",
-        src_path.display(),
-        &to_path.display()
-    ))?;
-    info!("Copied image to '{}'", to_path.display());
+    // only the partition image is written with --flash-from-partition-image
+    if !s2_cfg.stream_image && s2_cfg.part_image.is_none() {
+        let src_path = path_append(&s2_cfg.old_root, &s2_cfg.image_path);
+        let to_path = path_append(TRANSFER_DIR, BALENA_IMAGE_NAME);
+        copy(&src_path, &to_path).upstream_with_context(&format!(
+            "Failed to copy '{}' to {}",
+            src_path.display(),
+            &to_path.display()
+        ))?;
+        info!("Copied image to '{}'", to_path.display());
+    }
 
-    let src_path = path_append(OLD_ROOT_MP, &s2_cfg.config_path);
+    let src_path = path_append(&s2_cfg.old_root, &s2_cfg.config_path);
     let to_path = path_append(TRANSFER_DIR, BALENA_CONFIG_PATH);
     copy(&src_path, &to_path).upstream_with_context(&format!(
         "Failed to copy '{}' to {}",
@@ -177,7 +311,7 @@ fn copy_files(s2_cfg: &Stage2Config) -> Result<()> {
     info!("Copied config to '{}'", to_path.display());
 
     if let Some(ref backup_path) = s2_cfg.backup_path {
-        let src_path = path_append(OLD_ROOT_MP, backup_path);
+        let src_path = path_append(&s2_cfg.old_root, backup_path);
         let to_path = path_append(TRANSFER_DIR, BACKUP_ARCH_NAME);
         copy(&src_path, &to_path).upstream_with_context(&format!(
             "Failed to copy '{}' to {}",
@@ -187,8 +321,58 @@ fn copy_files(s2_cfg: &Stage2Config) -> Result<()> {
         info!("Copied backup to '{}'", to_path.display());
     }
 
+    if s2_cfg.boot_config {
+        let src_path = path_append(
+            &s2_cfg.old_root,
+            path_append(&s2_cfg.work_dir, BOOT_CONFIG_NAME),
+        );
+        let to_path = path_append(TRANSFER_DIR, BOOT_CONFIG_NAME);
+        copy(&src_path, &to_path).upstream_with_context(&format!(
+            "Failed to copy '{}' to {}",
+            src_path.display(),
+            &to_path.display()
+        ))?;
+        info!("Copied boot settings to '{}'", to_path.display());
+    }
+
+    if let Some(ref part_image) = s2_cfg.part_image {
+        let src_path = path_append(&s2_cfg.old_root, &part_image.path);
+        let to_path = path_append(TRANSFER_DIR, BALENA_PART_IMAGE_NAME);
+        copy(&src_path, &to_path).upstream_with_context(&format!(
+            "Failed to copy '{}' to {}",
+            src_path.display(),
+            &to_path.display()
+        ))?;
+        info!("Copied partition image to '{}'", to_path.display());
+    }
+
+    if let Some(ref delta_path) = s2_cfg.delta_path {
+        let src_path = path_append(&s2_cfg.old_root, delta_path);
+        let to_path = path_append(TRANSFER_DIR, BALENA_DELTA_NAME);
+        copy(&src_path, &to_path).upstream_with_context(&format!(
+            "Failed to copy '{}' to {}",
+            src_path.display(),
+            &to_path.display()
+        ))?;
+        info!("Copied delta to '{}'", to_path.display());
+    }
+
+    if let Some(ref stage1_log) = s2_cfg.stage1_log {
+        let src_path = path_append(&s2_cfg.old_root, stage1_log);
+        let to_path = path_append(TRANSFER_DIR, STAGE1_LOG_NAME);
+        match copy(&src_path, &to_path) {
+            Ok(_) => info!("Copied stage1 log to '{}'", to_path.display()),
+            Err(why) => warn!(
+                "Failed to copy '{}' to '{}', error: {}",
+                src_path.display(),
+                to_path.display(),
+                why
+            ),
+        }
+    }
+
     let nwmgr_path = path_append(
-        OLD_ROOT_MP,
+        &s2_cfg.old_root,
         path_append(&s2_cfg.work_dir, SYSTEM_CONNECTIONS_DIR),
     );
 
@@ -265,24 +449,80 @@ pub(crate) fn read_stage2_config<P: AsRef<Path>>(path_prefix: Option<P>) -> Resu
     }
 }
 
-fn setup_logging(log_dev: Option<&LogDevice>) {
+// log to log_file at file_level and stderr at console_level, copying the file output to netlog if given
+fn set_log_stream(
+    log_file: &Path,
+    netlog: Option<SocketAddr>,
+    file_level: Level,
+    console_level: Level,
+) -> Result<()> {
+    let file = File::create(log_file)
+        .upstream_with_context(&format!("Failed to create file: '{}'", log_file.display()))?;
+
+    let mut stream: Box<dyn Write + Send> = if let Some(netlog) = netlog {
+        match NetLog::new(
+            netlog,
+            file.try_clone()
+                .upstream_with_context("Failed to clone log file handle")?,
+        ) {
+            Ok(netlog_stream) => {
+                info!("Sending stage2 log to '{}'", netlog);
+                Box::new(netlog_stream)
+            }
+            Err(why) => {
+                warn!("Failed to setup network logging, error: {}", why);
+                Box::new(file)
+            }
+        }
+    } else {
+        Box::new(file)
+    };
+
+    Logger::flush();
+    if let Some(buffer) = Logger::get_buffer() {
+        stream
+            .write_all(buffer.as_slice())
+            .upstream_with_context(&format!(
+                "Failed to write buffers to file: '{}'",
+                log_file.display()
+            ))?;
+    }
+
+    Logger::set_log_dest(
+        &LogDestination::Stream,
+        Some(SplitLog::new(stream, file_level, console_level)),
+    )
+    .upstream_with_context(&format!("Failed set log file to '{}'", log_file.display()))
+}
+
+// returns the file the stage2 log is written to
+fn setup_logging(
+    log_dev: Option<&LogDevice>,
+    netlog: Option<SocketAddr>,
+    file_level: Level,
+    console_level: Level,
+) -> Option<PathBuf> {
+    let mut log_file = None;
     if log_dev.is_some() {
         // Device should have been mounted by stage2-init
         match dir_exists("/mnt/log/") {
             Ok(exists) => {
                 if exists {
-                    Logger::set_log_file(
-                        &LogDestination::StreamStderr,
-                        PathBuf::from("/mnt/log/stage2.log").as_path(),
-                        false,
-                    )
-                    .unwrap_or_else(|why| {
-                        error!(
+                    match set_log_stream(
+                        Path::new("/mnt/log/stage2.log"),
+                        netlog,
+                        file_level,
+                        console_level,
+                    ) {
+                        Ok(_) => {
+                            info!("Set logfile to /mnt/log/stage2.log");
+                            log_file = Some(PathBuf::from("/mnt/log/stage2.log"));
+                        }
+                        Err(why) => error!(
                             "Failed to setup logging to /mnt/log/stage2.log, error: {:?}",
                             why
-                        )
-                    });
-                    info!("Set logfile to /mnt/log/stage2.log");
+                        ),
+                    }
                 }
             }
             Err(why) => {
@@ -291,21 +531,40 @@ fn setup_logging(log_dev: Option<&LogDevice>) {
         }
     }
 
+    if log_file.is_none() {
+        // no log device - keep a log on the pivoted filesystem so headless
+        // migrations can still be debugged, e.g. when stage2 halts
+        match set_log_stream(
+            Path::new(STAGE2_LOG_FILE),
+            netlog,
+            file_level,
+            console_level,
+        ) {
+            Ok(_) => log_file = Some(PathBuf::from(STAGE2_LOG_FILE)),
+            Err(why) => error!(
+                "Failed to setup logging to {}, error: {:?}",
+                STAGE2_LOG_FILE, why
+            ),
+        }
+    }
+
     Logger::flush();
     sync();
+    log_file
 }
 
-fn kill_procs(log_level: Level) -> Result<()> {
+fn kill_procs(old_root: &Path, log_level: Level) -> Result<()> {
     trace!("kill_procs: entered");
     let mut killed = false;
     let mut signal = SIGTERM;
     loop {
-        if fuser(OLD_ROOT_MP, signal, None)? > 0 {
+        if fuser(old_root, signal, None)? > 0 {
             killed = true
         } else {
             warn!(
                 "Failed to kill processes using '{}', signal: {}",
-                OLD_ROOT_MP, signal
+                old_root.display(),
+                signal
             );
         }
 
@@ -360,14 +619,21 @@ fn kill_procs(log_level: Level) -> Result<()> {
     } else {
         Err(Error::with_context(
             ErrorKind::InvState,
-            &format!("Failed to kill any processes using using '{}'", OLD_ROOT_MP,),
+            &format!(
+                "Failed to kill any processes using using '{}'",
+                old_root.display()
+            ),
         ))
     }
 }
 
-fn unmount_partitions(mountpoints: &[UmountPart]) -> Result<()> {
+fn unmount_partitions(
+    old_root: &Path,
+    mountpoints: &[UmountPart],
+    cleanup: OldRootCleanup,
+) -> Result<()> {
     for mpoint in mountpoints {
-        let mountpoint = path_append(OLD_ROOT_MP, &mpoint.mountpoint);
+        let mountpoint = path_append(old_root, &mpoint.mountpoint);
 
         info!(
             "Attempting to unmount '{}' from '{}'",
@@ -379,6 +645,24 @@ fn unmount_partitions(mountpoints: &[UmountPart]) -> Result<()> {
             Ok(_) => {
                 info!("Successfully unmounted '{}'", mountpoint.display());
             }
+            Err(why) if cleanup == OldRootCleanup::Lazy => {
+                warn!(
+                    "Failed to unmount partition '{}' from '{}', error : {:?}, trying lazy unmount",
+                    mpoint.dev_name.display(),
+                    mountpoint.display(),
+                    why
+                );
+                umount2(&mountpoint, MntFlags::MNT_DETACH).upstream_with_context(&format!(
+                    "Failed to lazily unmount '{}' from '{}'",
+                    mpoint.dev_name.display(),
+                    mountpoint.display()
+                ))?;
+                info!(
+                    "Lazily unmounted '{}' from '{}'",
+                    mpoint.dev_name.display(),
+                    mountpoint.display()
+                );
+            }
             Err(why) => {
                 warn!(
                     "Failed to unmount partition '{}' from '{}', error : {:?} ",
@@ -418,7 +702,132 @@ fn unmount_partitions(mountpoints: &[UmountPart]) -> Result<()> {
     Ok(())
 }
 
-#[allow(dead_code)]
+/******************************************************************
+ * List the device-mapper devices (LUKS, LVM) holding the flash device
+ * or one of its partitions, as found in /sys/block/<dev>/holders and
+ * /sys/block/<dev>/<part>/holders.
+ ******************************************************************/
+
+fn get_dm_holders(device: &Path) -> Result<Vec<String>> {
+    let device = device
+        .canonicalize()
+        .upstream_with_context(&format!("Failed to canonicalize '{}'", device.display()))?;
+    let dev_name = match device.file_name() {
+        Some(dev_name) => dev_name.to_string_lossy().to_string(),
+        None => {
+            return Err(Error::with_context(
+                ErrorKind::InvParam,
+                &format!("Invalid device path '{}'", device.display()),
+            ))
+        }
+    };
+
+    let sys_path = PathBuf::from(format!("/sys/block/{}", dev_name));
+    let mut holder_dirs = vec![sys_path.join("holders")];
+    for dir_entry in read_dir(&sys_path).upstream_with_context(&format!(
+        "Failed to read directory '{}'",
+        sys_path.display()
+    ))? {
+        let dir_entry = dir_entry.upstream_with_context(&format!(
+            "Failed to read directory entry in '{}'",
+            sys_path.display()
+        ))?;
+        // partitions show up as sub directories named after the device, eg. sda1, mmcblk0p1
+        if dir_entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(&dev_name)
+        {
+            holder_dirs.push(dir_entry.path().join("holders"));
+        }
+    }
+
+    let mut holders = Vec::new();
+    for holder_dir in holder_dirs {
+        if !holder_dir.is_dir() {
+            continue;
+        }
+        for dir_entry in read_dir(&holder_dir).upstream_with_context(&format!(
+            "Failed to read directory '{}'",
+            holder_dir.display()
+        ))? {
+            let dir_entry = dir_entry.upstream_with_context(&format!(
+                "Failed to read directory entry in '{}'",
+                holder_dir.display()
+            ))?;
+            let holder = dir_entry.file_name().to_string_lossy().to_string();
+            if holder.starts_with("dm-") && !holders.contains(&holder) {
+                holders.push(holder);
+            }
+        }
+    }
+    Ok(holders)
+}
+
+/******************************************************************
+ * Verify nothing holds the flash device any more. An exclusive open
+ * of a block device fails with EBUSY while any of its partitions is
+ * mounted, a lazily unmounted filesystem is released once its last
+ * user is gone, so the open is retried for a while.
+ * Device-mapper devices (LUKS, LVM) set up on the flash device keep
+ * the exclusive open failing even when nothing is mounted on them.
+ * stage2 has no tools to tear them down, so the check is skipped
+ * for such devices.
+ ******************************************************************/
+
+fn check_device_free(device: &Path) -> Result<()> {
+    match get_dm_holders(device) {
+        Ok(holders) if !holders.is_empty() => {
+            warn!(
+                "'{}' is held by device-mapper devices {}, not checking whether it is in use",
+                device.display(),
+                holders.join(", ")
+            );
+            return Ok(());
+        }
+        Ok(_) => (),
+        Err(why) => warn!(
+            "Failed to get the holders of '{}': {}",
+            device.display(),
+            why
+        ),
+    }
+
+    let start = Instant::now();
+    loop {
+        match OpenOptions::new()
+            .read(true)
+            .custom_flags(O_EXCL)
+            .open(device)
+        {
+            Ok(_) => {
+                info!("Verified '{}' is not in use", device.display());
+                return Ok(());
+            }
+            Err(why) if why.raw_os_error() == Some(EBUSY) => {
+                if start.elapsed() >= DEVICE_FREE_TIMEOUT {
+                    return Err(Error::with_context(
+                        ErrorKind::InvState,
+                        &format!(
+                            "'{}' is still in use {} seconds after unmounting the old root",
+                            device.display(),
+                            DEVICE_FREE_TIMEOUT.as_secs()
+                        ),
+                    ));
+                }
+                debug!("'{}' is still in use, waiting", device.display());
+                sleep(Duration::from_secs(1));
+            }
+            Err(why) => {
+                return Err(Error::from_upstream(
+                    Box::new(why),
+                    &format!("Failed to open '{}' exclusively", device.display()),
+                ))
+            }
+        }
+    }
+}
+
 fn part_reread(device: &Path) -> Result<()> {
     // try ioctrl #define BLKRRPART  _IO(0x12,95)	/* re-read partition table */
     let device_file = OpenOptions::new()
@@ -448,16 +857,59 @@ fn part_reread(device: &Path) -> Result<()> {
     }
 }
 
-fn transfer_boot_files<P: AsRef<Path>>(dev_root: P) -> Result<()> {
+fn verify_config(written: &BalenaCfgJson, intended: &BalenaCfgJson) -> Result<()> {
+    let display_val = |name: &str, value: Option<&Value>| {
+        if let Some(value) = value {
+            redacted(name, value)
+        } else {
+            "<missing>".to_string()
+        }
+    };
+
+    let mismatches: Vec<String> = VERIFY_CONFIG_KEYS
+        .iter()
+        .filter(|name| written.get_value(name) != intended.get_value(name))
+        .map(|name| {
+            format!(
+                "{}: expected {}, found {}",
+                name,
+                display_val(name, intended.get_value(name)),
+                display_val(name, written.get_value(name))
+            )
+        })
+        .collect();
+
+    if mismatches.is_empty() {
+        debug!("config.json on boot partition verified successfully");
+        Ok(())
+    } else {
+        Err(Error::with_context(
+            ErrorKind::Validation,
+            &format!(
+                "config.json on boot partition does not match the intended config, {}",
+                mismatches.join(", ")
+            ),
+        ))
+    }
+}
+
+// returns the SHA-256 of the config.json written to the boot partition
+fn transfer_boot_files<P: AsRef<Path>>(dev_root: P, pretty_config: bool) -> Result<String> {
     let src_path = path_append(TRANSFER_DIR, BALENA_CONFIG_PATH);
     let target_path = path_append(dev_root.as_ref(), BALENA_CONFIG_PATH);
-    copy(&src_path, &target_path).upstream_with_context(&format!(
-        "Failed to copy {} to {}",
-        src_path.display(),
+    let mut config = BalenaCfgJson::new(&src_path)?;
+    config.set_pretty(pretty_config);
+    let config_sha256 = config.write(&target_path)?;
+    sync();
+
+    // make sure the config landed on the boot partition
+    let written = BalenaCfgJson::new(&target_path).upstream_with_context(&format!(
+        "Failed to read back config.json from '{}'",
         target_path.display()
     ))?;
+    verify_config(&written, &config)?;
 
-    info!("Successfully copied config.json to boot partition",);
+    info!("Successfully wrote config.json to boot partition",);
 
     let src_path = path_append(TRANSFER_DIR, SYSTEM_CONNECTIONS_DIR);
     let dir_list = read_dir(&src_path).upstream_with_context(&format!(
@@ -515,7 +967,7 @@ fn transfer_boot_files<P: AsRef<Path>>(dev_root: P) -> Result<()> {
         }
     }
 
-    Ok(())
+    Ok(config_sha256)
 }
 
 fn get_partition_infos(device: &Path) -> Result<(PartInfo, PartInfo)> {
@@ -565,52 +1017,256 @@ fn get_partition_infos(device: &Path) -> Result<(PartInfo, PartInfo)> {
     }
 }
 
-fn efi_setup(device: &Path) -> Result<()> {
-    let efi_boot_mgr = format!("/bin/{}", EFIBOOTMGR_CMD);
-    if dir_exists(SYS_EFI_DIR)? {
-        match call_command!(&efi_boot_mgr, &[], "Failed to execute efibootmgr") {
-            Ok(cmd_stdout) => {
-                // TODO: setup efi boot
-                let efivar_regex =
-                    Regex::new(r#"\s*Boot([0-9,a-f,A-F]{4})\*?\s+resinOS.*"#).unwrap();
-                for line in cmd_stdout.lines() {
-                    if let Some(captures) = efivar_regex.captures(line) {
-                        let boot_num = captures.get(1).unwrap().as_str();
-                        match call_command!(&efi_boot_mgr, &["-B", "-b", boot_num]) {
-                            Ok(_) => (),
-                            Err(why) => {
-                                error!(
-                                    "Failed to delete boot manager '{}' as {}, error: {}",
-                                    line, boot_num, why
-                                );
-                            }
-                        }
-                    }
-                }
-                match call_command!(
-                    &efi_boot_mgr,
-                    &[
-                        "-c",
-                        "-d",
-                        &*device.to_string_lossy(),
-                        "-p",
-                        "1",
-                        "-L",
-                        "resinOS",
-                        "-l",
-                        r"\EFI\BOOT\bootx64.efi"
-                    ]
-                ) {
-                    Ok(_) => (),
-                    Err(why) => error!("Failed to setup EFI boot, error {}", why),
-                }
-            }
-            Err(why) => {
-                error!("Failed to execute '{}', error: {}", efi_boot_mgr, why);
-            }
+// restore the size of the kept data partition in the partition table written from the image
+fn restore_data_part(device: &Path, keep_data_part: &KeepDataPart) -> Result<()> {
+    let (_boot_part, data_part) = get_partition_infos(device)?;
+    if data_part.start_lba != keep_data_part.start_lba {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "The flashed data partition starts at sector {}, expected sector {}",
+                data_part.start_lba, keep_data_part.start_lba
+            ),
+        ));
+    }
+
+    if data_part.num_sectors != keep_data_part.num_sectors {
+        set_part_size(device, &data_part, keep_data_part.num_sectors)?;
+        info!(
+            "Resized data partition from {} to {} sectors",
+            data_part.num_sectors, keep_data_part.num_sectors
+        );
+        if let Err(why) = part_reread(device) {
+            warn!(
+                "Failed to reread partition table on '{}', error: {}",
+                device.display(),
+                why
+            );
         }
-    } else {
-        let efi_dir = path_append(BALENA_BOOT_MP, "EFI");
+    }
+
+    Ok(())
+}
+
+fn is_ext_fs(device: &Path, part: &PartInfo) -> Result<bool> {
+    let mut device_file = File::open(device)
+        .upstream_with_context(&format!("Failed to open device '{}'", device.display()))?;
+    device_file
+        .seek(SeekFrom::Start(
+            part.start_lba * DEF_BLOCK_SIZE as u64 + EXT_SB_MAGIC_OFFSET,
+        ))
+        .upstream_with_context(&format!("Failed to seek on device '{}'", device.display()))?;
+    let mut magic: [u8; 2] = [0; 2];
+    device_file
+        .read_exact(&mut magic)
+        .upstream_with_context(&format!(
+            "Failed to read from device '{}'",
+            device.display()
+        ))?;
+    Ok(magic == EXT_SB_MAGIC)
+}
+
+// grow the data partition to the end of the device and resize its filesystem
+fn expand_data_part(device: &Path) -> Result<()> {
+    let e2fsck_cmd = format!("/bin/{}", E2FSCK_CMD);
+    let resize2fs_cmd = format!("/bin/{}", RESIZE2FS_CMD);
+    if !file_exists(&e2fsck_cmd) || !file_exists(&resize2fs_cmd) {
+        warn!(
+            "'{}' or '{}' not found, not expanding data partition",
+            e2fsck_cmd, resize2fs_cmd
+        );
+        return Ok(());
+    }
+
+    let (_boot_part, data_part) = get_partition_infos(device)?;
+
+    if !is_ext_fs(device, &data_part)? {
+        warn!("Data partition does not contain an ext filesystem, not expanding data partition");
+        return Ok(());
+    }
+
+    let dev_sectors = File::open(device)
+        .upstream_with_context(&format!("Failed to open device '{}'", device.display()))?
+        .seek(SeekFrom::End(0))
+        .upstream_with_context(&format!(
+            "Failed to determine size of device '{}'",
+            device.display()
+        ))?
+        / DEF_BLOCK_SIZE as u64;
+
+    // the MBR partition table can not describe more than u32::MAX sectors, this also
+    // applies to the extended partition a logical data partition is grown with
+    let container_start = if data_part.table_lba > 0 {
+        Disk::from_drive_file(device, None)?
+            .get_part_table()?
+            .extended
+            .map_or(data_part.start_lba, |extended| extended.start_lba)
+    } else {
+        data_part.start_lba
+    };
+    let num_sectors = min(
+        dev_sectors - data_part.start_lba,
+        u64::from(u32::MAX) - (data_part.start_lba - container_start),
+    );
+    if num_sectors <= data_part.num_sectors {
+        info!("Data partition already fills the device, not expanding");
+        return Ok(());
+    }
+
+    // grows the extended partition as well, resize2fs must not run on a partition outside of it
+    set_part_size(device, &data_part, num_sectors)?;
+    info!(
+        "Expanded data partition from {} to {} sectors",
+        data_part.num_sectors, num_sectors
+    );
+    if let Err(why) = part_reread(device) {
+        warn!(
+            "Failed to reread partition table on '{}', error: {}",
+            device.display(),
+            why
+        );
+    }
+
+    let mut loop_device = LoopDevice::get_free(true)?;
+    loop_device.setup(
+        device,
+        Some(data_part.start_lba * DEF_BLOCK_SIZE as u64),
+        Some(num_sectors * DEF_BLOCK_SIZE as u64),
+    )?;
+    let loop_path = loop_device.get_path().to_string_lossy().to_string();
+
+    // e2fsck exits with 1 if errors were corrected
+    let cmd_res = call(&e2fsck_cmd, &["-f", "-p", &loop_path], true)?;
+    if cmd_res.status.code().unwrap_or(8) > 1 {
+        loop_device.unset()?;
+        return Err(Error::with_context(
+            ErrorKind::ExecProcess,
+            &format!(
+                "'{}' failed on data partition, stderr: {}",
+                e2fsck_cmd, cmd_res.stderr
+            ),
+        ));
+    }
+
+    let res = call_command!(
+        &resize2fs_cmd,
+        &[&loop_path],
+        &format!("'{}' failed on data partition", resize2fs_cmd)
+    );
+    loop_device.unset()?;
+    res?;
+
+    info!("Resized data partition filesystem");
+    Ok(())
+}
+
+// the type sgdisk gives the boot partition in a GPT, the EFI system partition
+const GPT_BOOT_PART_TYPE: &str = "1:ef00";
+
+/******************************************************************
+ * Convert the flashed partition table to a MBR or a GPT using
+ * sgdisk, the partitions keep their position. The GPT partitions
+ * of an image are listed as primary partitions of the MBR, stage1
+ * made sure they fit.
+ ******************************************************************/
+
+fn convert_part_table(device: &Path, scheme: PartitionScheme) -> Result<()> {
+    let sgdisk_cmd = format!("/bin/{}", SGDISK_CMD);
+    let device_arg = device.to_string_lossy();
+    let args = match scheme {
+        PartitionScheme::Gpt => vec![
+            "--mbrtogpt".to_string(),
+            format!("--typecode={}", GPT_BOOT_PART_TYPE),
+            device_arg.to_string(),
+        ],
+        PartitionScheme::Mbr => {
+            let table = Disk::from_drive_file(device, None)?.get_part_table()?;
+            let indices: Vec<String> = table
+                .parts
+                .iter()
+                .map(|part| part.index.to_string())
+                .collect();
+            vec![
+                format!("--gpttombr={}", indices.join(":")),
+                device_arg.to_string(),
+            ]
+        }
+        PartitionScheme::Image => return Ok(()),
+    };
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+
+    call_command!(
+        &sgdisk_cmd,
+        &args,
+        &format!(
+            "'{}' failed to convert the partition table on '{}' to {}",
+            sgdisk_cmd,
+            device.display(),
+            scheme
+        )
+    )?;
+    info!(
+        "Converted the partition table on '{}' to {}",
+        device.display(),
+        scheme
+    );
+
+    if let Err(why) = part_reread(device) {
+        warn!(
+            "Failed to reread partition table on '{}', error: {}",
+            device.display(),
+            why
+        );
+    }
+    Ok(())
+}
+
+fn efi_setup(device: &Path) -> Result<()> {
+    let efi_boot_mgr = format!("/bin/{}", EFIBOOTMGR_CMD);
+    if dir_exists(SYS_EFI_DIR)? {
+        match call_command!(&efi_boot_mgr, &[], "Failed to execute efibootmgr") {
+            Ok(cmd_stdout) => {
+                // TODO: setup efi boot
+                let efivar_regex =
+                    Regex::new(r#"\s*Boot([0-9,a-f,A-F]{4})\*?\s+resinOS.*"#).unwrap();
+                for line in cmd_stdout.lines() {
+                    if let Some(captures) = efivar_regex.captures(line) {
+                        let boot_num = captures.get(1).unwrap().as_str();
+                        match call_command!(&efi_boot_mgr, &["-B", "-b", boot_num]) {
+                            Ok(_) => (),
+                            Err(why) => {
+                                error!(
+                                    "Failed to delete boot manager '{}' as {}, error: {}",
+                                    line, boot_num, why
+                                );
+                            }
+                        }
+                    }
+                }
+                match call_command!(
+                    &efi_boot_mgr,
+                    &[
+                        "-c",
+                        "-d",
+                        &*device.to_string_lossy(),
+                        "-p",
+                        "1",
+                        "-L",
+                        "resinOS",
+                        "-l",
+                        r"\EFI\BOOT\bootx64.efi"
+                    ]
+                ) {
+                    Ok(_) => (),
+                    Err(why) => error!("Failed to setup EFI boot, error {}", why),
+                }
+            }
+            Err(why) => {
+                error!("Failed to execute '{}', error: {}", efi_boot_mgr, why);
+            }
+        }
+    } else {
+        let efi_dir = path_append(BALENA_BOOT_MP, "EFI");
         if dir_exists(&efi_dir)? {
             match remove_dir(&efi_dir) {
                 Ok(_) => {
@@ -629,7 +1285,51 @@ fn efi_setup(device: &Path) -> Result<()> {
     Ok(())
 }
 
-fn raw_mount_balena(device: &Path) -> Result<()> {
+// identify the device by the config.json and the version by the os-release of the flashed boot partition
+fn new_migration_marker(dev_root: &str, config_sha256: String) -> MigrationMarker {
+    let (uuid, device_type) =
+        match BalenaCfgJson::new(path_append(TRANSFER_DIR, BALENA_CONFIG_PATH)) {
+            Ok(config) => (
+                config
+                    .get_value("uuid")
+                    .and_then(|uuid| uuid.as_str())
+                    .map(String::from),
+                config.get_device_type().ok(),
+            ),
+            Err(why) => {
+                warn!(
+                    "Failed to read config.json for the migration marker, error: {}",
+                    why
+                );
+                (None, None)
+            }
+        };
+
+    let os_release = path_append(dev_root, BALENA_OS_RELEASE_PATH);
+    let os_version = match get_os_version(&os_release) {
+        Ok(os_version) => {
+            info!("Flashed balena-os version is {}", os_version);
+            Some(os_version)
+        }
+        Err(why) => {
+            warn!(
+                "Failed to determine the flashed balena-os version, error: {}",
+                why
+            );
+            None
+        }
+    };
+
+    MigrationMarker::new(uuid, device_type, os_version, Some(config_sha256))
+}
+
+fn raw_mount_balena(
+    device: &Path,
+    pretty_config: bool,
+    target: &dyn Device,
+    keep_logs: bool,
+    stage2_log: Option<&Path>,
+) -> Result<()> {
     debug!("raw_mount_balena called");
 
     if !dir_exists(BALENA_PART_MP)? {
@@ -683,7 +1383,19 @@ fn raw_mount_balena(device: &Path) -> Result<()> {
     );
     // TODO: copy files
 
-    transfer_boot_files(BALENA_PART_MP)?;
+    let config_sha256 = transfer_boot_files(BALENA_PART_MP, pretty_config)?;
+
+    let boot_config = path_append(TRANSFER_DIR, BOOT_CONFIG_NAME);
+    target.post_flash(&PostFlashContext {
+        boot_mountpoint: Path::new(BALENA_PART_MP),
+        boot_config: if file_exists(&boot_config) {
+            Some(&boot_config)
+        } else {
+            None
+        },
+    })?;
+
+    let marker = new_migration_marker(BALENA_PART_MP, config_sha256);
 
     efi_setup(device)?;
 
@@ -694,69 +1406,175 @@ fn raw_mount_balena(device: &Path) -> Result<()> {
     info!("Unmounted boot partition from {}", BALENA_PART_MP);
 
     let backup_path = path_append(TRANSFER_DIR, BACKUP_ARCH_NAME);
+    let has_backup = file_exists(&backup_path);
+
+    // the backup is required, the marker only confirms the migration later
+    if let Err(why) = transfer_data_files(
+        &mut loop_device,
+        device,
+        &data_part,
+        &marker,
+        has_backup,
+        keep_logs,
+        stage2_log,
+    ) {
+        if has_backup {
+            return Err(why);
+        }
+        warn!("Failed to write the migration marker, error: {}", why);
+    }
 
-    if file_exists(&backup_path) {
-        let byte_offset = data_part.start_lba * DEF_BLOCK_SIZE as u64;
-        let size_limit = data_part.num_sectors * DEF_BLOCK_SIZE as u64;
+    loop_device.unset()?;
 
-        loop_device.modify_offset(byte_offset, size_limit)?;
+    Ok(())
+}
 
-        info!(
-            "Setup device '{}' with offset {}, sizelimit {} on '{}'",
-            device.display(),
-            byte_offset,
-            size_limit,
-            loop_device.get_path().display()
+/******************************************************************
+ * Copy the stage1 log from the transfer dir and the stage2 log,
+ * or its buffer if no log file could be set up, to the mounted
+ * data partition. The secrets of config.json are redacted, logs
+ * are not copied if it can not be read. Failures only warn.
+ ******************************************************************/
+
+fn copy_logs(stage2_log: Option<&Path>) {
+    let config_path = path_append(TRANSFER_DIR, BALENA_CONFIG_PATH);
+    let config = match BalenaCfgJson::new(&config_path) {
+        Ok(config) => config,
+        Err(why) => {
+            warn!(
+                "Not keeping the logs, failed to read '{}' to redact them, error: {}",
+                config_path.display(),
+                why
+            );
+            return;
+        }
+    };
+
+    let logs_dir = path_append(BALENA_PART_MP, TAKEOVER_LOGS_DIR);
+    if let Err(why) = create_dir_all(&logs_dir) {
+        warn!(
+            "Failed to create directory '{}', error: {}",
+            logs_dir.display(),
+            why
         );
+        return;
+    }
 
-        mount(
-            Some(loop_device.get_path()),
-            BALENA_PART_MP,
-            Some(BALENA_DATA_FSTYPE.as_bytes()),
-            MsFlags::empty(),
-            NIX_NONE,
-        )
-        .upstream_with_context(&format!(
-            "Failed to mount {} on {}",
-            loop_device.get_path().display(),
-            BALENA_PART_MP
-        ))?;
+    Logger::flush();
 
-        info!(
-            "Mounted data partition as {} on {}",
-            loop_device.get_path().display(),
-            BALENA_PART_MP
-        );
+    let stage1_log = path_append(TRANSFER_DIR, STAGE1_LOG_NAME);
+    let stage1_txt = if file_exists(&stage1_log) {
+        read(&stage1_log).map(Some)
+    } else {
+        Ok(None)
+    };
+    let stage2_txt = if let Some(stage2_log) = stage2_log {
+        read(stage2_log).map(Some)
+    } else {
+        Ok(Logger::get_buffer())
+    };
+
+    for (name, log_txt) in &[(STAGE1_LOG_NAME, stage1_txt), (STAGE2_LOG_NAME, stage2_txt)] {
+        let to_path = path_append(&logs_dir, name);
+        match log_txt {
+            Ok(Some(log_txt)) => {
+                let log_txt = config.redact(&String::from_utf8_lossy(log_txt));
+                match write(&to_path, log_txt) {
+                    Ok(_) => info!("Kept {} in '{}'", name, to_path.display()),
+                    Err(why) => warn!("Failed to write '{}', error: {}", to_path.display(), why),
+                }
+            }
+            Ok(None) => debug!("No {} to keep", name),
+            Err(why) => warn!("Failed to read {}, error: {}", name, why),
+        }
+    }
+}
+
+fn transfer_data_files(
+    loop_device: &mut LoopDevice,
+    device: &Path,
+    data_part: &PartInfo,
+    marker: &MigrationMarker,
+    has_backup: bool,
+    keep_logs: bool,
+    stage2_log: Option<&Path>,
+) -> Result<()> {
+    let byte_offset = data_part.start_lba * DEF_BLOCK_SIZE as u64;
+    let size_limit = data_part.num_sectors * DEF_BLOCK_SIZE as u64;
+
+    loop_device.modify_offset(byte_offset, size_limit)?;
+
+    info!(
+        "Setup device '{}' with offset {}, sizelimit {} on '{}'",
+        device.display(),
+        byte_offset,
+        size_limit,
+        loop_device.get_path().display()
+    );
+
+    mount(
+        Some(loop_device.get_path()),
+        BALENA_PART_MP,
+        Some(BALENA_DATA_FSTYPE.as_bytes()),
+        MsFlags::empty(),
+        NIX_NONE,
+    )
+    .upstream_with_context(&format!(
+        "Failed to mount {} on {}",
+        loop_device.get_path().display(),
+        BALENA_PART_MP
+    ))?;
+
+    info!(
+        "Mounted data partition as {} on {}",
+        loop_device.get_path().display(),
+        BALENA_PART_MP
+    );
 
-        // TODO: copy files
+    let marker_path = path_append(BALENA_PART_MP, MIGRATION_MARKER_NAME);
+    if let Err(why) = marker.write(&marker_path) {
+        warn!("{}", why);
+    } else {
+        info!("Wrote migration marker to '{}'", marker_path.display());
+    }
 
+    if has_backup {
+        let backup_path = path_append(TRANSFER_DIR, BACKUP_ARCH_NAME);
         let target_path = path_append(BALENA_PART_MP, BACKUP_ARCH_NAME);
-        copy(&backup_path, &target_path).upstream_with_context(&format!(
-            "Failed to copy '{}' to '{}'",
-            backup_path.display(),
-            target_path.display()
-        ))?;
+        if let Err(why) = copy(&backup_path, &target_path) {
+            let _res = umount(BALENA_PART_MP);
+            return Err(Error::from_upstream(
+                Box::new(why),
+                &format!(
+                    "Failed to copy '{}' to '{}'",
+                    backup_path.display(),
+                    target_path.display()
+                ),
+            ));
+        }
 
         info!(
             "copied '{}' to '{}'",
             backup_path.display(),
             target_path.display()
         );
+    }
 
-        sync();
+    if keep_logs {
+        copy_logs(stage2_log);
+    }
 
-        umount(BALENA_PART_MP).upstream_with_context("Failed to unmount boot partition")?;
+    sync();
 
-        info!("Unmounted data partition from {}", BALENA_PART_MP);
-    }
+    umount(BALENA_PART_MP).upstream_with_context("Failed to unmount data partition")?;
 
-    loop_device.unset()?;
+    info!("Unmounted data partition from {}", BALENA_PART_MP);
 
     Ok(())
 }
 
 #[allow(dead_code)]
-fn sys_mount_balena() -> Result<()> {
+fn sys_mount_balena(pretty_config: bool) -> Result<()> {
     debug!("sys_mount_balena called");
     sleep(Duration::from_secs(1));
 
@@ -788,7 +1606,7 @@ fn sys_mount_balena() -> Result<()> {
         BALENA_BOOT_MP,
     ))?;
 
-    transfer_boot_files(BALENA_BOOT_MP)?;
+    transfer_boot_files(BALENA_BOOT_MP, pretty_config)?;
 
     umount(BALENA_BOOT_MP).upstream_with_context(&format!(
         "Failed to unmount '{}' from '{}'",
@@ -860,10 +1678,7 @@ fn fill_buffer<I: Read>(buffer: &mut [u8], input: &mut I) -> Result<usize> {
 fn validate(target_path: &Path, image_path: &Path) -> Result<bool> {
     debug!("Validate: opening: '{}'", image_path.display());
 
-    let mut decoder = GzDecoder::new(File::open(&image_path).upstream_with_context(&format!(
-        "Validate: Failed to open image file '{}'",
-        image_path.display(),
-    ))?);
+    let mut decoder = GzDecoder::new(open_image(image_path)?);
 
     debug!("Validate: opening output file '{}'", target_path.display());
     let mut target = OpenOptions::new()
@@ -925,17 +1740,286 @@ fn validate(target_path: &Path, image_path: &Path) -> Result<bool> {
     Ok(err_count == 0)
 }
 
-fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str) -> FlashState {
-    let mut fail_res = FlashState::FailRecoverable;
+// apply the delta if the flash device matches its base, returns false if the image has to be flashed instead
+fn apply_delta_update(s2_config: &Stage2Config, watchdog: &Watchdog) -> bool {
+    let delta_path = path_append(TRANSFER_DIR, BALENA_DELTA_NAME);
+    let header = match open_delta(&delta_path) {
+        Ok((header, _)) => header,
+        Err(why) => {
+            error!(
+                "Failed to read delta '{}', flashing the image instead, error: {}",
+                delta_path.display(),
+                why
+            );
+            return false;
+        }
+    };
 
-    let mut decoder = GzDecoder::new(match File::open(&image_path) {
-        Ok(file) => file,
+    match verify_base(&s2_config.flash_dev, &header) {
+        Ok(true) => info!("The flash device matches the delta base, applying delta"),
+        Ok(false) => {
+            warn!("The flash device does not match the delta base, flashing the image instead");
+            return false;
+        }
         Err(why) => {
             error!(
-                "Flash: Failed to open image file '{}', error: {:?}",
-                image_path.display(),
+                "Failed to verify the delta base, flashing the image instead, error: {}",
+                why
+            );
+            return false;
+        }
+    }
+    watchdog.feed();
+
+    let mut throttle = FlashThrottle::new(s2_config.flash_pause.as_ref(), watchdog);
+    let mut last_progress = Instant::now();
+    let mut progress = |bytes: u64| {
+        throttle.written(bytes);
+        watchdog.feed();
+        if last_progress.elapsed() >= FLASH_PROGRESS_INTERVAL {
+            last_progress = Instant::now();
+            emit(ProgressEvent::Flash {
+                bytes,
+                total: None,
+                paused_ms: throttle.paused_ms(),
+            });
+        }
+    };
+
+    match apply_delta(&s2_config.flash_dev, &delta_path, &mut progress) {
+        Ok(_) => true,
+        Err(why) => {
+            // the device is partially updated, the full image restores a consistent state
+            error!(
+                "Failed to apply delta, flashing the image instead, error: {}",
                 why
             );
+            false
+        }
+    }
+}
+
+/******************************************************************
+ * Write the given partitions from the image to the same location
+ * on target_path, everything else on target_path is left intact.
+ ******************************************************************/
+
+fn flash_partitions(
+    target_path: &Path,
+    image_path: &Path,
+    flash_parts: &[FlashPart],
+    flash_pause: Option<&FlashPause>,
+    min_write_speed: Option<&MinWriteSpeed>,
+    watchdog: &Watchdog,
+) -> Result<()> {
+    let mut decoder = GzDecoder::new(open_image(image_path)?);
+    let mut target = OpenOptions::new()
+        .write(true)
+        .open(target_path)
+        .upstream_with_context(&format!(
+            "Failed to open '{}' for writing",
+            target_path.display()
+        ))?;
+
+    let mut flash_parts = flash_parts.to_vec();
+    flash_parts.sort_by_key(|part| part.start_lba);
+
+    let total: u64 = flash_parts
+        .iter()
+        .map(|part| part.num_sectors * DEF_BLOCK_SIZE as u64)
+        .sum();
+    let mut buffer = vec![0u8; DD_BLOCK_SIZE];
+    let mut image_pos: u64 = 0;
+    let mut bytes_written: u64 = 0;
+    let mut throttle = FlashThrottle::new(flash_pause, watchdog);
+    let mut speed_guard = WriteSpeedGuard::new(min_write_speed);
+    let mut last_progress = Instant::now();
+
+    for part in &flash_parts {
+        let start = part.start_lba * DEF_BLOCK_SIZE as u64;
+        let size = part.num_sectors * DEF_BLOCK_SIZE as u64;
+
+        // the image is a gzip stream, skip everything in front of the partition
+        io::copy(&mut (&mut decoder).take(start - image_pos), &mut io::sink())
+            .upstream_with_context(&format!(
+                "Failed to read image '{}' up to partition {}",
+                image_path.display(),
+                part.name
+            ))?;
+        watchdog.feed();
+
+        target
+            .seek(SeekFrom::Start(start))
+            .upstream_with_context(&format!("Failed to seek on '{}'", target_path.display()))?;
+
+        let mut part_reader = (&mut decoder).take(size);
+        let mut part_written: u64 = 0;
+        loop {
+            let read = fill_buffer(&mut buffer, &mut part_reader)?;
+            if read == 0 {
+                break;
+            }
+            target
+                .write_all(&buffer[0..read])
+                .upstream_with_context(&format!(
+                    "Failed to write partition {} to '{}'",
+                    part.name,
+                    target_path.display()
+                ))?;
+            part_written += read as u64;
+            bytes_written += read as u64;
+            throttle.written(bytes_written);
+            speed_guard.written(bytes_written, throttle.paused())?;
+            watchdog.feed();
+            if last_progress.elapsed() >= FLASH_PROGRESS_INTERVAL {
+                last_progress = Instant::now();
+                emit(ProgressEvent::Flash {
+                    bytes: bytes_written,
+                    total: Some(total),
+                    paused_ms: throttle.paused_ms(),
+                });
+            }
+        }
+
+        if part_written != size {
+            return Err(Error::with_context(
+                ErrorKind::InvState,
+                &format!(
+                    "The image '{}' ended {} into partition {}, expected {}",
+                    image_path.display(),
+                    format_size_with_unit(part_written),
+                    part.name,
+                    format_size_with_unit(size)
+                ),
+            ));
+        }
+        image_pos = start + size;
+
+        info!(
+            "Wrote partition {}, {} at sector {} to '{}'",
+            part.name,
+            format_size_with_unit(size),
+            part.start_lba,
+            target_path.display()
+        );
+    }
+
+    target
+        .sync_all()
+        .upstream_with_context(&format!("Failed to sync '{}'", target_path.display()))?;
+
+    Ok(())
+}
+
+/******************************************************************
+ * Write a filesystem image into the partition of target_path it
+ * was resolved to by stage1, the partition table and all other
+ * partitions are left intact.
+ ******************************************************************/
+
+fn flash_part_image(
+    target_path: &Path,
+    part_image: &PartImage,
+    flash_pause: Option<&FlashPause>,
+    min_write_speed: Option<&MinWriteSpeed>,
+    watchdog: &Watchdog,
+) -> Result<()> {
+    let image_path = path_append(TRANSFER_DIR, BALENA_PART_IMAGE_NAME);
+    let image = open_image(&image_path)?;
+    let mut reader: Box<dyn Read> = if part_image.compressed {
+        Box::new(GzDecoder::new(image))
+    } else {
+        Box::new(image)
+    };
+
+    let start = part_image.start_lba * part_image.sector_size;
+    let size = part_image.num_sectors * part_image.sector_size;
+    let mut target = OpenOptions::new()
+        .write(true)
+        .open(target_path)
+        .upstream_with_context(&format!(
+            "Failed to open '{}' for writing",
+            target_path.display()
+        ))?;
+    target
+        .seek(SeekFrom::Start(start))
+        .upstream_with_context(&format!("Failed to seek on '{}'", target_path.display()))?;
+
+    // never write past the partition, stage1 checked that the image fits
+    let mut part_reader = (&mut reader).take(size);
+    let mut buffer = vec![0u8; DD_BLOCK_SIZE];
+    let mut bytes_written: u64 = 0;
+    let mut throttle = FlashThrottle::new(flash_pause, watchdog);
+    let mut speed_guard = WriteSpeedGuard::new(min_write_speed);
+    let mut last_progress = Instant::now();
+    loop {
+        let read = fill_buffer(&mut buffer, &mut part_reader)?;
+        if read == 0 {
+            break;
+        }
+        target
+            .write_all(&buffer[0..read])
+            .upstream_with_context(&format!(
+                "Failed to write partition {} to '{}'",
+                part_image.name,
+                target_path.display()
+            ))?;
+        bytes_written += read as u64;
+        throttle.written(bytes_written);
+        speed_guard.written(bytes_written, throttle.paused())?;
+        watchdog.feed();
+        if last_progress.elapsed() >= FLASH_PROGRESS_INTERVAL {
+            last_progress = Instant::now();
+            emit(ProgressEvent::Flash {
+                bytes: bytes_written,
+                total: Some(part_image.size),
+                paused_ms: throttle.paused_ms(),
+            });
+        }
+    }
+
+    if bytes_written != part_image.size {
+        return Err(Error::with_context(
+            ErrorKind::InvState,
+            &format!(
+                "The partition image '{}' ended after {}, expected {}",
+                image_path.display(),
+                format_size_with_unit(bytes_written),
+                format_size_with_unit(part_image.size)
+            ),
+        ));
+    }
+
+    target
+        .sync_all()
+        .upstream_with_context(&format!("Failed to sync '{}'", target_path.display()))?;
+
+    info!(
+        "Wrote partition {}, {} at sector {} to '{}'",
+        part_image.name,
+        format_size_with_unit(bytes_written),
+        part_image.start_lba,
+        target_path.display()
+    );
+    Ok(())
+}
+
+// write image to target_path, if max_bytes is given only the first max_bytes bytes are written
+fn flash_external(
+    target_path: &Path,
+    image_path: &Path,
+    dd_cmd: &str,
+    max_bytes: Option<u64>,
+    flash_pause: Option<&FlashPause>,
+    min_write_speed: Option<&MinWriteSpeed>,
+    watchdog: &Watchdog,
+) -> FlashState {
+    let mut fail_res = FlashState::FailRecoverable;
+
+    let mut decoder = GzDecoder::new(match open_image(image_path) {
+        Ok(image) => image,
+        Err(why) => {
+            error!("Flash: {}", why);
             return fail_res;
         }
     });
@@ -953,7 +2037,10 @@ fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str) -> FlashS
             if let Some(stdin) = dd_cmd.stdin.as_mut() {
                 let mut buffer: [u8; DD_BLOCK_SIZE] = [0; DD_BLOCK_SIZE];
                 let mut tot_bytes: u64 = 0;
+                let mut throttle = FlashThrottle::new(flash_pause, watchdog);
+                let mut speed_guard = WriteSpeedGuard::new(min_write_speed);
                 let start_time = Instant::now();
+                let mut last_progress = start_time;
                 fail_res = FlashState::FailNonRecoverable;
 
                 loop {
@@ -961,12 +2048,38 @@ fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str) -> FlashS
                     match fill_buffer(&mut buffer, &mut decoder) {
                         Ok(buff_fill) => {
                             if buff_fill > 0 {
-                                match stdin.write_all(&buffer) {
+                                let write_len = if let Some(max_bytes) = max_bytes {
+                                    min(buff_fill as u64, max_bytes - tot_bytes) as usize
+                                } else {
+                                    buff_fill
+                                };
+                                match stdin.write_all(&buffer[0..write_len]) {
                                     Ok(_) => {
-                                        tot_bytes += buff_fill as u64;
+                                        tot_bytes += write_len as u64;
+                                        throttle.written(tot_bytes);
+                                        if let Err(why) =
+                                            speed_guard.written(tot_bytes, throttle.paused())
+                                        {
+                                            error!("Aborting the flash: {}", why);
+                                            return fail_res;
+                                        }
+                                        watchdog.feed();
+                                        if last_progress.elapsed() >= FLASH_PROGRESS_INTERVAL {
+                                            last_progress = Instant::now();
+                                            emit(ProgressEvent::Flash {
+                                                bytes: tot_bytes,
+                                                total: max_bytes,
+                                                paused_ms: throttle.paused_ms(),
+                                            });
+                                        }
                                         if buff_fill < DD_BLOCK_SIZE {
                                             break;
                                         }
+                                        if let Some(max_bytes) = max_bytes {
+                                            if tot_bytes >= max_bytes {
+                                                break;
+                                            }
+                                        }
                                     }
                                     Err(why) => {
                                         error!("Failed to write to dd stdin at offset 0x{:x}:{} error {:?}",
@@ -993,14 +2106,27 @@ fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str) -> FlashS
                     };
                 }
 
-                let elapsed = Instant::now().duration_since(start_time).as_secs();
-                info!(
-                    "Wrote {} bytes, {} to dd in {} seconds @ {}/sec",
-                    tot_bytes,
-                    format_size_with_unit(tot_bytes),
-                    elapsed,
-                    format_size_with_unit(tot_bytes / elapsed),
-                );
+                let elapsed = Instant::now().duration_since(start_time);
+                if throttle.paused().as_secs() > 0 {
+                    // the rate is given for the time spent writing
+                    let write_secs = (elapsed - throttle.paused()).as_secs().max(1);
+                    info!(
+                        "Wrote {} bytes, {} to dd in {} seconds, paused for {} seconds, @ {}/sec while writing",
+                        tot_bytes,
+                        format_size_with_unit(tot_bytes),
+                        elapsed.as_secs(),
+                        throttle.paused().as_secs(),
+                        format_size_with_unit(tot_bytes / write_secs),
+                    );
+                } else {
+                    info!(
+                        "Wrote {} bytes, {} to dd in {} seconds @ {}/sec",
+                        tot_bytes,
+                        format_size_with_unit(tot_bytes),
+                        elapsed.as_secs(),
+                        format_size_with_unit(tot_bytes / elapsed.as_secs()),
+                    );
+                }
             } else {
                 error!("Failed to retrieve dd stdin");
                 return FlashState::FailRecoverable;
@@ -1032,9 +2158,31 @@ fn flash_external(target_path: &Path, image_path: &Path, dd_cmd: &str) -> FlashS
     }
 }
 
+// interrupting stage2 leaves the flash device unusable, commands like dd inherit the disposition
+fn ignore_signals() {
+    for sig in &[Signal::SIGINT, Signal::SIGTERM] {
+        if let Err(why) = unsafe { signal::signal(*sig, SigHandler::SigIgn) } {
+            warn!("Failed to ignore {}, error: {}", sig.as_str(), why);
+        }
+    }
+    info!("Ignoring SIGINT and SIGTERM, the migration can not be safely interrupted");
+}
+
+// the image file stays on the old root, detaching flushes it
+fn detach_flash_loop(flash_dev: &Path) {
+    match LoopDevice::from_path(flash_dev, false).and_then(|mut loop_device| loop_device.unset()) {
+        Ok(_) => info!("Detached loop device '{}'", flash_dev.display()),
+        Err(why) => warn!(
+            "Failed to detach loop device '{}', error: {}",
+            flash_dev.display(),
+            why
+        ),
+    }
+}
+
 #[allow(clippy::cognitive_complexity)]
 pub fn stage2(opts: &Options) -> ! {
-    Logger::set_default_level(opts.s2_log_level());
+    Logger::set_default_level(opts.s2_log_level().max(opts.s2_console_log_level()));
     Logger::set_brief_info(false);
     Logger::set_color(true);
 
@@ -1055,16 +2203,49 @@ pub fn stage2(opts: &Options) -> ! {
     };
 
     info!("Stage 2 config was read successfully");
+    // the panic hook logs to the old root
+    set_old_root(&s2_config.old_root);
+
+    let stage2_log = setup_logging(
+        s2_config.log_dev(),
+        s2_config.stage2_netlog,
+        opts.s2_log_level(),
+        opts.s2_console_log_level(),
+    );
 
-    setup_logging(s2_config.log_dev());
+    ignore_signals();
 
-    match kill_procs(opts.s2_log_level()) {
-        Ok(_) => (),
-        Err(why) => {
-            error!("kill_procs failed, error {}", why);
-            reboot();
+    if s2_config.progress_json {
+        add_observer(Box::new(JsonObserver));
+    }
+    if let Some(report_file) = &s2_config.report_file {
+        let report_file = path_append(&s2_config.old_root, report_file);
+        match FileObserver::new(&report_file) {
+            Ok(observer) => add_observer(Box::new(observer)),
+            Err(why) => warn!("Not writing stage2 events, error: {}", why),
         }
-    };
+    }
+    emit(ProgressEvent::Stage(Stage::Stage2));
+
+    let watchdog = Watchdog::new(s2_config.stage2_timeout);
+
+    if s2_config.old_root_cleanup == OldRootCleanup::Keep {
+        info!(
+            "Not killing processes using '{}' due to --old-root-cleanup keep",
+            s2_config.old_root.display()
+        );
+    } else {
+        info!("Killing processes using '{}'", s2_config.old_root.display());
+        match kill_procs(&s2_config.old_root, opts.s2_log_level()) {
+            Ok(_) => (),
+            Err(why) => {
+                error!("kill_procs failed, error {}", why);
+                reboot();
+            }
+        };
+    }
+
+    watchdog.feed();
 
     match copy_files(&s2_config) {
         Ok(_) => (),
@@ -1074,14 +2255,47 @@ pub fn stage2(opts: &Options) -> ! {
         }
     }
 
-    match unmount_partitions(&s2_config.umount_parts) {
-        Ok(_) => (),
-        Err(why) => {
-            error!("unmount_partitions failed; {:?}", why);
+    watchdog.feed();
+
+    // stage1 made sure there is a tty
+    if let (Some(pause_shell), Some(tty)) = (&s2_config.pause_shell, s2_config.tty()) {
+        if let Err(why) = pause_for_inspection(pause_shell, tty, &watchdog) {
+            error!("Failed to pause for inspection, error: {}", why);
+        }
+        watchdog.feed();
+    }
+
+    if s2_config.old_root_cleanup == OldRootCleanup::Keep {
+        // stage1 made sure no partition of the flash device is mounted
+        info!(
+            "Keeping '{}' mounted due to --old-root-cleanup keep",
+            s2_config.old_root.display()
+        );
+    } else {
+        match unmount_partitions(
+            &s2_config.old_root,
+            &s2_config.umount_parts,
+            s2_config.old_root_cleanup,
+        ) {
+            Ok(_) => (),
+            Err(why) => {
+                error!("unmount_partitions failed; {:?}", why);
+                reboot();
+            }
+        }
+    }
+
+    // the old system is still intact, rebooting is safer than flashing a device in use
+    if s2_config.old_root_cleanup == OldRootCleanup::Lazy {
+        let _pause = watchdog.pause("waiting for the flash device to be free");
+        if let Err(why) = check_device_free(&s2_config.flash_dev) {
+            error!("{}, not flashing", why);
             reboot();
         }
     }
 
+    watchdog.feed();
+
     if s2_config.pretend {
         info!("Not flashing due to pretend mode");
         reboot();
@@ -1089,21 +2303,145 @@ pub fn stage2(opts: &Options) -> ! {
 
     sync();
 
-    let image_path = path_append(TRANSFER_DIR, BALENA_IMAGE_PATH);
+    // a streamed image is read from the old root, stage1 made sure it is not on the flash device
+    let image_path = if s2_config.stream_image {
+        path_append(&s2_config.old_root, &s2_config.image_path)
+    } else {
+        path_append(TRANSFER_DIR, BALENA_IMAGE_PATH)
+    };
 
-    match flash_external(
-        &s2_config.flash_dev,
-        &image_path,
-        &format!("/bin/{}", DD_CMD),
-    ) {
-        FlashState::Success => (),
-        _ => {
-            sleep(Duration::from_secs(10));
-            reboot();
+    let max_bytes = if let Some(keep_data_part) = &s2_config.keep_data_part {
+        info!(
+            "Keeping data partition, writing image up to sector {}",
+            keep_data_part.start_lba
+        );
+        Some(keep_data_part.start_lba * DEF_BLOCK_SIZE as u64)
+    } else {
+        None
+    };
+
+    emit(ProgressEvent::Stage(Stage::Flash));
+    let delta_applied = s2_config.delta_path.is_some() && apply_delta_update(&s2_config, &watchdog);
+    if !delta_applied {
+        if let Some(part_image) = &s2_config.part_image {
+            if let Err(why) = flash_part_image(
+                &s2_config.flash_dev,
+                part_image,
+                s2_config.flash_pause.as_ref(),
+                s2_config.min_write_speed.as_ref(),
+                &watchdog,
+            ) {
+                report_post_pivot_failure(&Error::with_all(
+                    ErrorKind::PostPivotFailure,
+                    "Failed to write the partition image",
+                    Box::new(why),
+                ));
+                emit(ProgressEvent::Stage(Stage::Failed));
+                sleep(Duration::from_secs(10));
+                reboot();
+            }
+        } else if let Some(flash_parts) = &s2_config.flash_parts {
+            if let Err(why) = flash_partitions(
+                &s2_config.flash_dev,
+                &image_path,
+                flash_parts,
+                s2_config.flash_pause.as_ref(),
+                s2_config.min_write_speed.as_ref(),
+                &watchdog,
+            ) {
+                report_post_pivot_failure(&Error::with_all(
+                    ErrorKind::PostPivotFailure,
+                    "Failed to flash partitions",
+                    Box::new(why),
+                ));
+                emit(ProgressEvent::Stage(Stage::Failed));
+                sleep(Duration::from_secs(10));
+                reboot();
+            }
+        } else {
+            match flash_external(
+                &s2_config.flash_dev,
+                &image_path,
+                &format!("/bin/{}", DD_CMD),
+                max_bytes,
+                s2_config.flash_pause.as_ref(),
+                s2_config.min_write_speed.as_ref(),
+                &watchdog,
+            ) {
+                FlashState::Success => (),
+                FlashState::FailRecoverable => {
+                    emit(ProgressEvent::Stage(Stage::Failed));
+                    sleep(Duration::from_secs(10));
+                    reboot();
+                }
+                FlashState::FailNonRecoverable => {
+                    report_post_pivot_failure(&Error::with_context(
+                        ErrorKind::PostPivotFailure,
+                        &format!(
+                            "Failed to write the image to '{}'",
+                            s2_config.flash_dev.display()
+                        ),
+                    ));
+                    emit(ProgressEvent::Stage(Stage::Failed));
+                    sleep(Duration::from_secs(10));
+                    reboot();
+                }
+            }
         }
     }
 
     sync();
+
+    // stage2 reads and modifies MBR partition tables, a GPT is converted to last
+    if s2_config.convert_part_table == Some(PartitionScheme::Mbr) {
+        let _pause = watchdog.pause("converting the partition table");
+        if let Err(why) = convert_part_table(&s2_config.flash_dev, PartitionScheme::Mbr) {
+            error!("Failed to convert the partition table, error: {:?}", why);
+        }
+    }
+
+    if let Some(keep_data_part) = &s2_config.keep_data_part {
+        let _pause = watchdog.pause("restoring the data partition");
+        if let Err(why) = restore_data_part(&s2_config.flash_dev, keep_data_part) {
+            error!("Failed to restore data partition, error: {:?}", why);
+        }
+    }
+
+    if let Some(preserve_parts) = &s2_config.preserve_parts {
+        let _pause = watchdog.pause("adding the preserved partitions");
+        for part in preserve_parts {
+            match add_logical_part(
+                &s2_config.flash_dev,
+                part.start_lba,
+                part.num_sectors,
+                LINUX_PART_TYPE,
+            ) {
+                Ok(index) => info!(
+                    "Added preserved partition labeled '{}' as partition {}",
+                    part.label, index
+                ),
+                Err(why) => error!(
+                    "Failed to add preserved partition labeled '{}', error: {:?}",
+                    part.label, why
+                ),
+            }
+        }
+        if let Err(why) = part_reread(&s2_config.flash_dev) {
+            warn!(
+                "Failed to reread partition table on '{}', error: {}",
+                s2_config.flash_dev.display(),
+                why
+            );
+        }
+    }
+
+    if s2_config.expand_data {
+        let _pause = watchdog.pause("expanding the data partition");
+        if let Err(why) = expand_data_part(&s2_config.flash_dev) {
+            error!("Failed to expand data partition, error: {:?}", why);
+        }
+    }
+
     sleep(Duration::from_secs(5));
 
     if DO_VALIDATE {
@@ -1128,10 +2466,50 @@ pub fn stage2(opts: &Options) -> ! {
         check_loop_control("Stage2 after flash", "/dev");
     }
 
-    if let Err(why) = raw_mount_balena(&s2_config.flash_dev) {
-        error!("Failed to transfer files to balena OS, error: {:?}", why);
+    watchdog.feed();
+
+    let mount_res = raw_mount_balena(
+        &s2_config.flash_dev,
+        s2_config.pretty_config,
+        get_device_for_type(s2_config.device_type).as_ref(),
+        s2_config.keep_logs,
+        stage2_log.as_deref(),
+    );
+
+    if mount_res.is_ok() && s2_config.convert_part_table == Some(PartitionScheme::Gpt) {
+        let _pause = watchdog.pause("converting the partition table");
+        if let Err(why) = convert_part_table(&s2_config.flash_dev, PartitionScheme::Gpt) {
+            error!("Failed to convert the partition table, error: {:?}", why);
+        }
+    }
+
+    if s2_config.flash_loop {
+        detach_flash_loop(&s2_config.flash_dev);
+    }
+
+    if let Err(why) = mount_res {
+        if why.kind() == ErrorKind::Validation {
+            report_post_pivot_failure(&Error::with_all(
+                ErrorKind::PostPivotFailure,
+                "Failed to verify config.json on balena OS, the device is not provisioned correctly, not rebooting",
+                Box::new(why),
+            ));
+            emit(ProgressEvent::Stage(Stage::Failed));
+            watchdog.stop();
+            halt();
+        }
+        report_post_pivot_failure(&Error::with_all(
+            ErrorKind::PostPivotFailure,
+            "Failed to transfer files to balena OS",
+            Box::new(why),
+        ));
+        emit(ProgressEvent::Stage(Stage::Failed));
     } else {
         info!("Migration succeded successfully");
+        emit(ProgressEvent::Stage(Stage::Finished));
+        sync();
+        watchdog.stop();
+        reboot_with_mode(&s2_config.reboot_mode, &s2_config.reboot_method);
     }
 
     sync();
@@ -0,0 +1,158 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr::null_mut;
+use std::slice;
+
+use libc::c_void;
+use log::{debug, info};
+use nix::sys::mman::{madvise, mmap, munmap, MapFlags, MmapAdvise, ProtFlags};
+
+use super::TRANSFER_DIR;
+use crate::common::{Result, ToError};
+
+/******************************************************************
+ * Reads the compressed image either from a memory map or through
+ * a buffered file. Mapping saves copying every block of a local image
+ * into a read buffer, the image in the transfer dir already lives in
+ * memory. Only that copy is mapped, a read error on a mapped file
+ * raises SIGBUS instead of returning an error, so images streamed
+ * from the old root, pipes and devices are read through the file.
+ ******************************************************************/
+
+pub(crate) enum ImageReader {
+    Mapped(MappedImage),
+    Buffered(BufReader<File>),
+}
+
+pub(crate) struct MappedImage {
+    addr: *mut c_void,
+    len: usize,
+    pos: usize,
+}
+
+impl MappedImage {
+    fn new(file: &File, len: usize) -> Result<MappedImage> {
+        let addr = unsafe {
+            mmap(
+                null_mut(),
+                len,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        }
+        .upstream_with_context("Failed to map the image file")?;
+
+        // the image is read once from start to end
+        if let Err(why) = unsafe { madvise(addr, len, MmapAdvise::MADV_SEQUENTIAL) } {
+            debug!("Failed to advise sequential access to the image: {}", why);
+        }
+        Ok(MappedImage { addr, len, pos: 0 })
+    }
+}
+
+impl Read for MappedImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = unsafe { slice::from_raw_parts(self.addr as *const u8, self.len) };
+        let count = buf.len().min(self.len - self.pos);
+        buf[..count].copy_from_slice(&data[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+impl Drop for MappedImage {
+    fn drop(&mut self) {
+        if let Err(why) = unsafe { munmap(self.addr, self.len) } {
+            debug!("Failed to unmap the image: {}", why);
+        }
+    }
+}
+
+impl Read for ImageReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ImageReader::Mapped(mapped) => mapped.read(buf),
+            ImageReader::Buffered(reader) => reader.read(buf),
+        }
+    }
+}
+
+// open the image, the copy in the transfer dir is mapped
+pub(crate) fn open_image(image_path: &Path) -> Result<ImageReader> {
+    open_image_mapped(image_path, image_path.starts_with(TRANSFER_DIR))
+}
+
+fn open_image_mapped(image_path: &Path, map: bool) -> Result<ImageReader> {
+    let file = File::open(image_path).upstream_with_context(&format!(
+        "Failed to open image file '{}'",
+        image_path.display()
+    ))?;
+
+    let metadata = file.metadata().upstream_with_context(&format!(
+        "Failed to retrieve metadata for '{}'",
+        image_path.display()
+    ))?;
+    // an empty file can not be mapped, it is read as is
+    if map && metadata.is_file() && metadata.len() > 0 {
+        match MappedImage::new(&file, metadata.len() as usize) {
+            Ok(mapped) => {
+                info!(
+                    "Reading the image '{}' from a memory map",
+                    image_path.display()
+                );
+                return Ok(ImageReader::Mapped(mapped));
+            }
+            Err(why) => debug!(
+                "Reading the image '{}' from the file, error: {}",
+                image_path.display(),
+                why
+            ),
+        }
+    }
+
+    Ok(ImageReader::Buffered(BufReader::new(file)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::path_append;
+    use std::env::temp_dir;
+    use std::fs::{remove_file, write};
+
+    #[test]
+    fn test_open_image() {
+        let image_path = path_append(temp_dir(), format!("takeover-image-{}", std::process::id()));
+        let content: Vec<u8> = (0..10000u32).map(|idx| (idx % 251) as u8).collect();
+        write(&image_path, &content).unwrap();
+
+        // only files in the transfer dir are mapped
+        assert!(matches!(
+            open_image(&image_path).unwrap(),
+            ImageReader::Buffered(_)
+        ));
+
+        let mut reader = open_image_mapped(&image_path, true).unwrap();
+        assert!(matches!(reader, ImageReader::Mapped(_)));
+        let mut read = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let count = reader.read(&mut buffer).unwrap();
+            if count == 0 {
+                break;
+            }
+            read.extend_from_slice(&buffer[..count]);
+        }
+        assert_eq!(read, content);
+        remove_file(&image_path).unwrap();
+
+        // devices are read through the file
+        let mut reader = open_image_mapped(Path::new("/dev/null"), true).unwrap();
+        assert!(matches!(reader, ImageReader::Buffered(_)));
+        assert_eq!(reader.read(&mut buffer).unwrap(), 0);
+    }
+}
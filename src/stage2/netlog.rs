@@ -0,0 +1,45 @@
+use std::io::{self, Write};
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::common::{Result, ToError};
+
+// keep datagrams below a typical ethernet MTU
+const MAX_DATAGRAM_SIZE: usize = 1400;
+
+// Log stream that copies everything written to it to a remote UDP collector, netconsole style
+pub(crate) struct NetLog<W: Write> {
+    socket: UdpSocket,
+    inner: W,
+}
+
+impl<W: Write> NetLog<W> {
+    pub fn new(remote: SocketAddr, inner: W) -> Result<NetLog<W>> {
+        let local = if remote.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)
+            .upstream_with_context("Failed to bind UDP socket for network logging")?;
+        socket.connect(remote).upstream_with_context(&format!(
+            "Failed to connect network log socket to '{}'",
+            remote
+        ))?;
+        Ok(NetLog { socket, inner })
+    }
+}
+
+impl<W: Write> Write for NetLog<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        // best effort only, a lost datagram must not stop the migration
+        for chunk in buf[0..written].chunks(MAX_DATAGRAM_SIZE) {
+            let _res = self.socket.send(chunk);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,115 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use mod_logger::Logger;
+use nix::{
+    sys::signal::{killpg, Signal},
+    unistd::Pid,
+};
+
+use crate::{
+    common::{defs::BUSYBOX_CMD, stage2_config::PauseShell, Error, Result, ToError},
+    stage2::watchdog::Watchdog,
+};
+
+const SHELL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+fn open_tty_stdio(tty: &Path) -> Result<Stdio> {
+    Ok(Stdio::from(
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(tty)
+            .upstream_with_context(&format!("Failed to open tty '{}'", tty.display()))?,
+    ))
+}
+
+/******************************************************************
+ * Run an interactive shell on the tty after the pivot, before the
+ * flash device is unmounted and flashed, so the stage2 environment
+ * can be inspected. Exiting the shell continues the migration, a
+ * shell still running after the timeout is killed. The shell runs
+ * in its own session and process group, so the processes it started
+ * are killed with it. The stage2 watchdog is fed while the shell
+ * runs.
+ ******************************************************************/
+
+pub(crate) fn pause_for_inspection(
+    pause_shell: &PauseShell,
+    tty: &Path,
+    watchdog: &Watchdog,
+) -> Result<()> {
+    // stage1 copied the program to /bin of the stage2 root
+    let program = Path::new("/bin").join(&pause_shell.program);
+    let mut command = Command::new(&program);
+    if pause_shell.program == BUSYBOX_CMD {
+        command.arg("sh");
+    }
+    // only async-signal-safe calls are allowed between fork and exec
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        });
+    }
+
+    info!(
+        "Pausing before flashing, exit the shell on '{}' to continue, the migration continues in {} seconds",
+        tty.display(),
+        pause_shell.timeout_secs
+    );
+    Logger::flush();
+
+    let mut child = command
+        .stdin(open_tty_stdio(tty)?)
+        .stdout(open_tty_stdio(tty)?)
+        .stderr(open_tty_stdio(tty)?)
+        .spawn()
+        .upstream_with_context(&format!("Failed to start shell '{}'", program.display()))?;
+
+    let timeout = Duration::from_secs(pause_shell.timeout_secs);
+    let start = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                info!("The shell exited with {}, continuing the migration", status);
+                return Ok(());
+            }
+            Ok(None) => (),
+            Err(why) => {
+                return Err(Error::from_upstream(
+                    Box::new(why),
+                    "Failed to wait for the shell",
+                ));
+            }
+        }
+
+        if start.elapsed() >= timeout {
+            warn!(
+                "The shell is still running after {} seconds, continuing the migration",
+                pause_shell.timeout_secs
+            );
+            // the shell is the leader of its process group
+            if let Err(why) = killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL) {
+                error!("Failed to kill the shell and its processes, error: {}", why);
+                if let Err(why) = child.kill() {
+                    error!("Failed to kill the shell, error: {}", why);
+                }
+            }
+            let _res = child.wait();
+            return Ok(());
+        }
+
+        watchdog.feed();
+        sleep(SHELL_POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,144 @@
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::Duration;
+
+use libc::{SYS_reboot, LINUX_REBOOT_CMD_RESTART2, LINUX_REBOOT_MAGIC1, LINUX_REBOOT_MAGIC2};
+use log::{error, info, warn};
+use mod_logger::Logger;
+use nix::unistd::sync;
+
+use crate::{common::stage2_config::RebootMethod, stage2::reboot};
+
+const WATCHDOG_DEV: &str = "/dev/watchdog";
+// the longest a watchdog or reboot command gets to reset the board before falling back to the syscall
+const WATCHDOG_RESET_TIMEOUT: Duration = Duration::from_secs(120);
+const COMMAND_RESET_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn restart2(arg: &str) {
+    let arg = match CString::new(arg) {
+        Ok(arg) => arg,
+        Err(why) => {
+            error!("Invalid restart command '{}': {}", arg, why);
+            return;
+        }
+    };
+    let res = unsafe {
+        libc::syscall(
+            SYS_reboot,
+            LINUX_REBOOT_MAGIC1,
+            LINUX_REBOOT_MAGIC2,
+            LINUX_REBOOT_CMD_RESTART2,
+            arg.as_ptr(),
+        )
+    };
+    if res != 0 {
+        error!(
+            "The reboot syscall with restart command '{}' failed: {}",
+            arg.to_string_lossy(),
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+// opening the watchdog arms it, it resets the board once it is not written to for its timeout
+fn arm_watchdog() -> bool {
+    match OpenOptions::new().write(true).open(WATCHDOG_DEV) {
+        Ok(mut watchdog) => {
+            if let Err(why) = watchdog.write_all(b"1") {
+                warn!("Failed to write to '{}': {}", WATCHDOG_DEV, why);
+            }
+            // keep it open, closing it might disarm it
+            std::mem::forget(watchdog);
+            true
+        }
+        Err(why) => {
+            error!("Failed to open '{}': {}", WATCHDOG_DEV, why);
+            false
+        }
+    }
+}
+
+fn run_command(args: &[String]) -> bool {
+    let (program, args) = if let Some((program, args)) = args.split_first() {
+        (program, args)
+    } else {
+        return false;
+    };
+
+    // stage1 copied the program to /bin of the stage2 root
+    let program = if let Some(file_name) = Path::new(program).file_name() {
+        Path::new("/bin").join(file_name)
+    } else {
+        error!("Invalid reboot command '{}'", program);
+        return false;
+    };
+
+    match Command::new(&program).args(args).status() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            error!(
+                "The reboot command '{}' failed with {}",
+                program.display(),
+                status
+            );
+            false
+        }
+        Err(why) => {
+            error!(
+                "Failed to run the reboot command '{}': {}",
+                program.display(),
+                why
+            );
+            false
+        }
+    }
+}
+
+/******************************************************************
+ * Reboot into the flashed OS using the method of the device type
+ * or --reboot-command. Boards that do not come up from the method
+ * within a timeout, or a method that fails, are rebooted using the
+ * reboot syscall.
+ ******************************************************************/
+
+pub(crate) fn reboot_with_method(method: &RebootMethod) -> ! {
+    info!("Rebooting using {}", method);
+    Logger::flush();
+    sync();
+
+    let timeout = match method {
+        RebootMethod::Restart => reboot(),
+        RebootMethod::Restart2(arg) => {
+            restart2(arg);
+            None
+        }
+        RebootMethod::Watchdog => {
+            if arm_watchdog() {
+                Some(WATCHDOG_RESET_TIMEOUT)
+            } else {
+                None
+            }
+        }
+        RebootMethod::Command(args) => {
+            if run_command(args) {
+                Some(COMMAND_RESET_TIMEOUT)
+            } else {
+                None
+            }
+        }
+    };
+
+    if let Some(timeout) = timeout {
+        sleep(timeout);
+        error!(
+            "The device was not reset within {} seconds",
+            timeout.as_secs()
+        );
+    }
+    warn!("Falling back to the reboot syscall");
+    reboot();
+}
@@ -0,0 +1,66 @@
+use std::cmp::min;
+use std::thread::sleep;
+use std::time::Duration;
+
+use log::info;
+
+use crate::{
+    common::{format_size_with_unit, stage2_config::FlashPause},
+    stage2::watchdog::Watchdog,
+};
+
+// the watchdog is fed this often during a pause
+const FEED_INTERVAL: Duration = Duration::from_millis(500);
+
+// Inserts the --flash-pause pauses between flash writes and keeps track of the time paused
+pub(crate) struct FlashThrottle<'a> {
+    watchdog: &'a Watchdog,
+    pause: Option<FlashPause>,
+    next_pause: u64,
+    paused: Duration,
+}
+
+impl<'a> FlashThrottle<'a> {
+    pub fn new(pause: Option<&FlashPause>, watchdog: &'a Watchdog) -> FlashThrottle<'a> {
+        if let Some(pause) = pause {
+            info!(
+                "Pausing flash writes for {} ms after every {} written",
+                pause.pause_ms,
+                format_size_with_unit(pause.interval)
+            );
+        }
+
+        FlashThrottle {
+            watchdog,
+            pause: pause.cloned(),
+            next_pause: pause.map_or(0, |pause| pause.interval),
+            paused: Duration::from_secs(0),
+        }
+    }
+
+    // called after every write with the total of bytes written, a pause counts as progress for the watchdog
+    pub fn written(&mut self, bytes: u64) {
+        if let Some(pause) = &self.pause {
+            if bytes >= self.next_pause {
+                let duration = Duration::from_millis(pause.pause_ms);
+                let mut left = duration;
+                while left > Duration::from_secs(0) {
+                    let step = min(left, FEED_INTERVAL);
+                    sleep(step);
+                    self.watchdog.feed();
+                    left -= step;
+                }
+                self.paused += duration;
+                self.next_pause = (bytes / pause.interval + 1) * pause.interval;
+            }
+        }
+    }
+
+    pub fn paused(&self) -> Duration {
+        self.paused
+    }
+
+    pub fn paused_ms(&self) -> u64 {
+        self.paused.as_millis() as u64
+    }
+}
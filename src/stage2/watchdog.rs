@@ -0,0 +1,96 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info};
+
+use crate::stage2::reboot;
+
+const WATCHDOG_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Reboots the device if no progress was reported within the timeout
+pub(crate) struct Watchdog {
+    progress: Arc<AtomicU64>,
+    stopped: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+}
+
+// the watchdog does not fire until this is dropped
+pub(crate) struct WatchdogPause<'a> {
+    watchdog: &'a Watchdog,
+}
+
+impl Drop for WatchdogPause<'_> {
+    fn drop(&mut self) {
+        self.watchdog.paused.store(false, Ordering::Relaxed);
+        self.watchdog.feed();
+    }
+}
+
+impl Watchdog {
+    pub fn new(timeout: Option<u64>) -> Watchdog {
+        let watchdog = Watchdog {
+            progress: Arc::new(AtomicU64::new(0)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+        };
+
+        if let Some(timeout) = timeout {
+            info!("Starting stage2 watchdog with timeout {} seconds", timeout);
+            let timeout = Duration::from_secs(timeout);
+            let progress = watchdog.progress.clone();
+            let stopped = watchdog.stopped.clone();
+            let paused = watchdog.paused.clone();
+            spawn(move || {
+                let mut last_progress = progress.load(Ordering::Relaxed);
+                let mut last_time = Instant::now();
+                loop {
+                    sleep(WATCHDOG_POLL_INTERVAL);
+                    if stopped.load(Ordering::Relaxed) {
+                        break;
+                    }
+
+                    let curr_progress = progress.load(Ordering::Relaxed);
+                    if paused.load(Ordering::Relaxed) {
+                        last_time = Instant::now();
+                    } else if curr_progress != last_progress {
+                        last_progress = curr_progress;
+                        last_time = Instant::now();
+                    } else if last_time.elapsed() > timeout {
+                        error!(
+                            "Stage2 watchdog: no progress for {} seconds, aborting migration",
+                            timeout.as_secs()
+                        );
+                        reboot();
+                    }
+                }
+            });
+        }
+
+        watchdog
+    }
+
+    pub fn feed(&self) {
+        self.progress.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /******************************************************************
+     * Pause the watchdog while a step runs that reports no progress
+     * and must not be interrupted, eg. a partition table change or
+     * resize2fs. Rebooting in the middle of it would leave the device
+     * unbootable, which the watchdog is there to prevent.
+     ******************************************************************/
+
+    pub fn pause(&self, step: &str) -> WatchdogPause<'_> {
+        debug!("Pausing the stage2 watchdog while {}", step);
+        self.paused.store(true, Ordering::Relaxed);
+        WatchdogPause { watchdog: self }
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
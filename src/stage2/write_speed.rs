@@ -0,0 +1,146 @@
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::common::{
+    format_size_with_unit,
+    progress::{emit, ProgressEvent},
+    stage2_config::MinWriteSpeed,
+    Error, ErrorKind, Result,
+};
+
+/******************************************************************
+ * Measures the flash write speed over periods of --min-disk-write-
+ * speed seconds and fails once a whole period stayed below the
+ * minimum, a failing SD card would leave an unusable device behind.
+ * Time spent in --flash-pause pauses does not count as writing.
+ ******************************************************************/
+
+pub(crate) struct WriteSpeedGuard {
+    min_speed: Option<MinWriteSpeed>,
+    period_start: Instant,
+    period_bytes: u64,
+    period_paused: Duration,
+}
+
+impl WriteSpeedGuard {
+    pub fn new(min_speed: Option<&MinWriteSpeed>) -> WriteSpeedGuard {
+        if let Some(min_speed) = min_speed {
+            info!(
+                "Aborting the flash if less than {}/sec are written for {} seconds",
+                format_size_with_unit(min_speed.bytes_per_sec),
+                min_speed.period_secs
+            );
+        }
+
+        WriteSpeedGuard {
+            min_speed: min_speed.cloned(),
+            period_start: Instant::now(),
+            period_bytes: 0,
+            period_paused: Duration::from_secs(0),
+        }
+    }
+
+    // called after every write with the total of bytes written and the total time paused
+    pub fn written(&mut self, bytes: u64, paused: Duration) -> Result<()> {
+        self.written_at(bytes, paused, Instant::now())
+    }
+
+    fn written_at(&mut self, bytes: u64, paused: Duration, now: Instant) -> Result<()> {
+        let min_speed = if let Some(min_speed) = &self.min_speed {
+            min_speed
+        } else {
+            return Ok(());
+        };
+
+        let elapsed = now.duration_since(self.period_start);
+        if elapsed < Duration::from_secs(min_speed.period_secs) {
+            return Ok(());
+        }
+
+        let write_time = elapsed
+            .checked_sub(paused - self.period_paused)
+            .unwrap_or_else(|| Duration::from_secs(0));
+        let bytes_per_sec = if write_time.as_millis() > 0 {
+            ((bytes - self.period_bytes) as u128 * 1000 / write_time.as_millis()) as u64
+        } else {
+            u64::MAX
+        };
+        emit(ProgressEvent::WriteSpeed {
+            bytes_per_sec,
+            min_bytes_per_sec: min_speed.bytes_per_sec,
+        });
+
+        if bytes_per_sec < min_speed.bytes_per_sec {
+            return Err(Error::with_context(
+                ErrorKind::Validation,
+                &format!(
+                    "The flash device was written at {}/sec for {} seconds, below the minimum of {}/sec, the device might be failing",
+                    format_size_with_unit(bytes_per_sec),
+                    elapsed.as_secs(),
+                    format_size_with_unit(min_speed.bytes_per_sec)
+                ),
+            ));
+        }
+
+        info!(
+            "Wrote {}/sec over the last {} seconds",
+            format_size_with_unit(bytes_per_sec),
+            elapsed.as_secs()
+        );
+        self.period_start = now;
+        self.period_bytes = bytes;
+        self.period_paused = paused;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_speed_guard() {
+        let min_speed = MinWriteSpeed {
+            bytes_per_sec: 1024 * 1024,
+            period_secs: 10,
+        };
+        let mut guard = WriteSpeedGuard::new(Some(&min_speed));
+        let start = guard.period_start;
+        let mib = 1024 * 1024;
+
+        // not measured before the period is over
+        assert!(guard
+            .written_at(mib, Duration::from_secs(0), start + Duration::from_secs(5))
+            .is_ok());
+        assert!(guard
+            .written_at(
+                20 * mib,
+                Duration::from_secs(0),
+                start + Duration::from_secs(10)
+            )
+            .is_ok());
+        // 5 MiB in 10 seconds of which 5 were paused is fast enough
+        assert!(guard
+            .written_at(
+                25 * mib,
+                Duration::from_secs(5),
+                start + Duration::from_secs(20)
+            )
+            .is_ok());
+        // 5 MiB in 10 seconds is not
+        let why = guard
+            .written_at(
+                30 * mib,
+                Duration::from_secs(5),
+                start + Duration::from_secs(30),
+            )
+            .unwrap_err();
+        assert_eq!(why.kind(), ErrorKind::Validation);
+
+        let mut guard = WriteSpeedGuard::new(None);
+        assert!(guard
+            .written_at(0, Duration::from_secs(0), start + Duration::from_secs(3600))
+            .is_ok());
+    }
+}